@@ -1,15 +1,22 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use oyster_rewards::{HeatmapRequest, generate_heatmap, generate_synthetic_heatmap};
+use oyster_rewards::{HeatmapRequest, Latitude, Longitude, generate_heatmap, generate_synthetic_heatmap};
 
 fn heatmap_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("Heatmap Generation");
 
     let request = HeatmapRequest {
-        min_lat: 37.75,
-        max_lat: 37.8,
-        min_lon: -122.45,
-        max_lon: -122.4,
+        min_lat: Latitude::new(37.75).unwrap(),
+        max_lat: Latitude::new(37.8).unwrap(),
+        min_lon: Longitude::new(-122.45).unwrap(),
+        max_lon: Longitude::new(-122.4).unwrap(),
         privacy_level: 1.5,
+        time_range: None,
+        bucket: None,
+        epsilon: None,
+        delta: None,
+        sensitivity: None,
+        requester_id: None,
+        seed: Some(42),
     };
 
     group.bench_function("real_heatmap", |b| {
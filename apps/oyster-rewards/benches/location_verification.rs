@@ -1,22 +1,25 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use oyster_rewards::{Location, SensorData, WifiNetwork, CellTower, verify_location};
-use std::collections::HashMap;
+use oyster_rewards::{Location, WifiNetwork, CellTower, verify_location};
 use rand::Rng;
-use chrono::Utc;
 
 fn create_test_location() -> Location {
     let mut rng = rand::thread_rng();
     let lat = 37.7749 + (rng.gen::<f64>() - 0.5) * 0.1;
     let lon = -122.4194 + (rng.gen::<f64>() - 0.5) * 0.1;
+    let mut builder = Location::builder()
+        .position(lat, lon)
+        .user_id("benchmark_user")
+        .device_id("benchmark_device")
+        .accelerometer(vec![0.1, 0.2, 9.8])
+        .gyroscope(vec![0.01, 0.02, 0.03]);
     
-    // Create WiFi networks
-    let mut wifi_networks = Vec::new();
+    // Add WiFi networks
     for i in 0..3 {
         let bssid = format!("{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
             rng.gen::<u8>(), rng.gen::<u8>(), rng.gen::<u8>(),
             rng.gen::<u8>(), rng.gen::<u8>(), rng.gen::<u8>());
             
-        wifi_networks.push(WifiNetwork {
+        builder = builder.wifi_network(WifiNetwork {
             ssid: format!("WiFi-{}", i),
             bssid,
             signal_strength: -50 - (rng.gen::<f64>() * 40.0) as i32,
@@ -24,10 +27,9 @@ fn create_test_location() -> Location {
         });
     }
     
-    // Create cell towers
-    let mut cell_towers = Vec::new();
+    // Add cell towers
     for i in 0..2 {
-        cell_towers.push(CellTower {
+        builder = builder.cell_tower(CellTower {
             cell_id: format!("CELL-{}", i),
             signal_strength: -70 - (rng.gen::<f64>() * 40.0) as i32,
             mcc: 310,
@@ -36,25 +38,7 @@ fn create_test_location() -> Location {
         });
     }
     
-    // Create sensor data
-    let sensor_data = SensorData {
-        wifi_networks,
-        cell_towers,
-        accelerometer: Some(vec![0.1, 0.2, 9.8]),
-        gyroscope: Some(vec![0.01, 0.02, 0.03]),
-        is_mock_location: false,
-        additional_data: HashMap::new(),
-    };
-    
-    // Create location
-    Location {
-        lat,
-        lon,
-        timestamp: Utc::now().to_rfc3339(),
-        user_id: "benchmark_user".to_string(),
-        device_id: "benchmark_device".to_string(),
-        sensors: sensor_data,
-    }
+    builder.build().unwrap()
 }
 
 fn location_verification_benchmark(c: &mut Criterion) {
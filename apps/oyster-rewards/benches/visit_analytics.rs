@@ -0,0 +1,80 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use oyster_rewards::{
+    Latitude, Longitude, Location, VisitAnalyticsRequest, BoundingBox, TimeRange, UnitSystem,
+    register_location, generate_visit_analytics, aggregate
+};
+use oyster_rewards::analytics::ANALYTICS_CACHE;
+use oyster_rewards::heatmap::PRIVACY_BUDGET;
+use oyster_rewards::heatmap::privacy::PrivacyParams;
+use chrono::{DateTime, Duration, Utc};
+
+// Total points registered for each scenario
+const TOTAL_POINTS: usize = 100_000;
+const AGGREGATE_USERS: usize = 100;
+
+// A trace of one-minute fixes: 30 minute stays at a new place every half hour
+fn register_trace(user_id: &str, points: usize, start: DateTime<Utc>) {
+    for i in 0..points {
+        let place = (i / 30) as f64;
+        let location = Location::builder()
+            .position(37.70 + (place * 0.0013) % 0.1, -122.45 + (place * 0.0029) % 0.1)
+            .timestamp(start + Duration::minutes(i as i64))
+            .user_id(user_id)
+            .device_id("benchmark_device")
+            .accelerometer(vec![0.1, 0.2, 9.8])
+            .gyroscope(vec![0.01, 0.02, 0.03])
+            .build()
+            .unwrap();
+        register_location(location);
+    }
+}
+
+fn visit_analytics_benchmark(c: &mut Criterion) {
+    let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+    let end = start + Duration::minutes(TOTAL_POINTS as i64);
+
+    register_trace("benchmark_user", TOTAL_POINTS, start);
+    for user in 0..AGGREGATE_USERS {
+        register_trace(&format!("benchmark_user_{}", user), TOTAL_POINTS / AGGREGATE_USERS, start);
+    }
+    PRIVACY_BUDGET.lock().unwrap().set_limit(f64::INFINITY);
+
+    let mut group = c.benchmark_group("Visit Analytics");
+    group.sample_size(10);
+
+    // Non-default thresholds skip the rolling aggregates and decrypt the full history
+    let request = VisitAnalyticsRequest {
+        user_id: "benchmark_user".to_string(),
+        start_time: start,
+        end_time: end,
+        units: UnitSystem::Metric,
+        min_stay_seconds: Some(600),
+        cluster_radius_m: None,
+        max_gap_seconds: None,
+    };
+
+    group.bench_function("single_user_100k_points", |b| {
+        b.iter(|| {
+            ANALYTICS_CACHE.lock().unwrap().clear();
+            generate_visit_analytics(black_box(&request))
+        })
+    });
+
+    let bounds = BoundingBox {
+        min_lat: Latitude::new(37.6).unwrap(),
+        min_lon: Longitude::new(-122.5).unwrap(),
+        max_lat: Latitude::new(37.9).unwrap(),
+        max_lon: Longitude::new(-122.3).unwrap(),
+    };
+    let time_range = TimeRange { start, end };
+    let params = PrivacyParams { epsilon: 1.0, delta: 0.0, sensitivity: 1.0 };
+
+    group.bench_function("aggregate_100_users_100k_points", |b| {
+        b.iter(|| aggregate(black_box(&bounds), &time_range, &params, "benchmark"))
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, visit_analytics_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,17 @@
+// Generates the gRPC service served next to the REST API; only the server build needs it
+fn main() {
+    #[cfg(feature = "server")]
+    {
+        println!("cargo:rerun-if-changed=proto/orbitalmap.proto");
+        println!("cargo:rerun-if-changed=proto/models.proto");
+        // A vendored protoc, so building does not depend on one being installed
+        if std::env::var_os("PROTOC").is_none() {
+            let protoc = protoc_bin_vendored::protoc_bin_path().expect("No vendored protoc for this platform");
+            std::env::set_var("PROTOC", protoc);
+        }
+        tonic_prost_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/orbitalmap.proto"], &["proto"])
+            .expect("Failed to compile proto/orbitalmap.proto");
+    }
+}
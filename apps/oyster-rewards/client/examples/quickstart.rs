@@ -0,0 +1,58 @@
+use chrono::{Duration, Utc};
+use orbitalmap_client::{Client, HeatmapRequest, Latitude, Location, Longitude, UnitSystem, VisitAnalyticsRequest, WifiNetwork};
+
+// Register a location, claim its reward and read it back through the heatmap and analytics:
+//   cargo run --example quickstart -- http://localhost:8080
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let base_url = std::env::args().nth(1).unwrap_or_else(|| "http://localhost:8080".to_string());
+    let client = Client::new(&base_url)?;
+
+    let location = Location::builder()
+        .position(37.7749, -122.4194)
+        .user_id("quickstart-user")
+        .device_id("quickstart-device")
+        .wifi_network(WifiNetwork {
+            ssid: "Quickstart".to_string(),
+            bssid: "00:11:22:33:44:55".to_string(),
+            signal_strength: -60,
+            frequency: 2412,
+        })
+        .accelerometer(vec![0.1, 0.2, 9.8])
+        .gyroscope(vec![0.01, 0.02, 0.03])
+        .build()?;
+
+    let registration = client.register_location(&location).await?;
+    println!("Registered: {} ({})", registration.success, registration.message);
+
+    let claim = client.claim_reward(&location).await?;
+    println!("Claimed: {} ({}), cooldown {}s", claim.success, claim.message, claim.cooldown_seconds);
+
+    let heatmap = client.get_heatmap(&HeatmapRequest {
+        min_lat: Latitude::new(37.77)?,
+        min_lon: Longitude::new(-122.42)?,
+        max_lat: Latitude::new(37.78)?,
+        max_lon: Longitude::new(-122.41)?,
+        privacy_level: 1.0,
+        time_range: None,
+        bucket: None,
+        epsilon: None,
+        delta: None,
+        sensitivity: None,
+        requester_id: None,
+        seed: None,
+    }).await?;
+    println!("Heatmap: {} cell(s), error {:?}", heatmap.cells.len(), heatmap.error);
+
+    let visits = client.get_visits(&VisitAnalyticsRequest {
+        user_id: location.user_id.clone(),
+        start_time: Utc::now() - Duration::hours(1),
+        end_time: Utc::now() + Duration::hours(1),
+        units: UnitSystem::default(),
+        min_stay_seconds: None,
+        cluster_radius_m: None,
+        max_gap_seconds: None,
+    }).await?;
+    println!("Visits: {}", visits.visits.len());
+    Ok(())
+}
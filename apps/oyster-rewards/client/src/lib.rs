@@ -0,0 +1,198 @@
+use std::time::Duration;
+use rand::Rng;
+use reqwest::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+// The API's models, so callers build requests and read responses with the server's own types
+pub use oyster_rewards::models::*;
+
+// Defaults of a new client
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_RETRIES: u32 = 2;
+const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+// Headers the API reads credentials from
+pub const ADMIN_TOKEN_HEADER: &str = "X-Admin-Token";
+
+#[derive(Debug)]
+pub enum ClientError {
+    // The request could not be sent or its response not read
+    Transport(String),
+    // The API answered with a status whose body is not the endpoint's response model
+    Status { status: u16, message: String },
+    // A successful response whose body did not match the endpoint's response model
+    Decode(String),
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Transport(message) => write!(f, "request failed: {}", message),
+            ClientError::Status { status, message } => write!(f, "API answered {}: {}", status, message),
+            ClientError::Decode(message) => write!(f, "unexpected response body: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+// Async client for the Oyster Rewards API:
+//   let client = Client::new("http://localhost:8080")?.with_user_token(&token);
+//   let heatmap = client.get_heatmap(&request).await?;
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    admin_token: Option<String>,
+    user_token: Option<String>,
+    max_retries: u32,
+    retry_backoff: Duration,
+}
+
+impl Client {
+    pub fn new(base_url: &str) -> Result<Self, ClientError> {
+        Self::with_timeout(base_url, DEFAULT_TIMEOUT)
+    }
+
+    // A client whose every request gives up after `timeout`
+    pub fn with_timeout(base_url: &str, timeout: Duration) -> Result<Self, ClientError> {
+        let http = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|e| ClientError::Transport(e.to_string()))?;
+        Ok(Client {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            admin_token: None,
+            user_token: None,
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
+        })
+    }
+
+    // Sent as X-Admin-Token on every request, for the admin endpoints
+    pub fn with_admin_token(mut self, token: &str) -> Self {
+        self.admin_token = Some(token.to_string());
+        self
+    }
+
+    // Sent as a bearer token on every request: the user owner-only endpoints are called as, and
+    // whose privacy budget heatmap queries are charged to (without one, the caller's address's)
+    pub fn with_user_token(mut self, token: &str) -> Self {
+        self.user_token = Some(token.to_string());
+        self
+    }
+
+    // Retry policy: up to `max_retries` more attempts, waiting about `backoff` (with jitter),
+    // doubled for each further retry
+    pub fn with_retries(mut self, max_retries: u32, backoff: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.retry_backoff = backoff;
+        self
+    }
+
+    // Register a location. Rejected registrations (e.g. failed verification) are returned with
+    // `success: false` rather than as an error
+    pub async fn register_location(&self, location: &Location) -> Result<LocationRegistrationResponse, ClientError> {
+        self.send(Method::POST, "/api/v1/locations", Some(location), false).await
+    }
+
+    // Noised heatmap of an area; an exhausted privacy budget is reported in `error`
+    pub async fn get_heatmap(&self, request: &HeatmapRequest) -> Result<HeatmapResponse, ClientError> {
+        self.send(Method::POST, "/api/v1/heatmap", Some(request), true).await
+    }
+
+    // A user's visits over a time range
+    pub async fn get_visits(&self, request: &VisitAnalyticsRequest) -> Result<VisitAnalyticsResponse, ClientError> {
+        self.send(Method::POST, "/api/v1/analytics", Some(request), true).await
+    }
+
+    // Claim the reward of a verified location. A claim within the cell's cooldown is returned
+    // with `success: false` and the remaining `cooldown_seconds`
+    pub async fn claim_reward(&self, location: &Location) -> Result<RewardClaimResponse, ClientError> {
+        self.send(Method::POST, "/api/v1/rewards/claim", Some(location), false).await
+    }
+
+    // Send a request, retrying failures that are safe to retry: any request that never reached
+    // the server, and, for requests without side effects, timeouts and 5xx responses. The API
+    // answers rejected requests with the endpoint's own model, so any body that decodes as T
+    // is returned, whatever the status
+    async fn send<B: Serialize, T: DeserializeOwned>(&self, method: Method, path: &str, body: Option<&B>, idempotent: bool) -> Result<T, ClientError> {
+        let mut attempt = 0;
+        loop {
+            let result = self.send_once(method.clone(), path, body).await;
+            let retryable = match &result {
+                Err(Attempt::NotSent(_)) => true,
+                Err(Attempt::TimedOut(_)) => idempotent,
+                Err(Attempt::Failed(ClientError::Status { status, .. })) => idempotent && *status >= 500,
+                _ => false,
+            };
+            if !retryable || attempt >= self.max_retries {
+                return result.map_err(Attempt::into_error);
+            }
+
+            let backoff = self.retry_backoff.saturating_mul(2u32.saturating_pow(attempt));
+            let jitter = rand::thread_rng().gen_range(0.5..1.5);
+            tokio::time::sleep(backoff.mul_f64(jitter)).await;
+            attempt += 1;
+        }
+    }
+
+    async fn send_once<B: Serialize, T: DeserializeOwned>(&self, method: Method, path: &str, body: Option<&B>) -> Result<T, Attempt> {
+        let mut request = self.http.request(method, format!("{}{}", self.base_url, path));
+        if let Some(token) = &self.admin_token {
+            request = request.header(ADMIN_TOKEN_HEADER, token);
+        }
+        if let Some(token) = &self.user_token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+
+        let response = request.send().await.map_err(|e| {
+            if e.is_connect() {
+                Attempt::NotSent(e)
+            } else if e.is_timeout() {
+                Attempt::TimedOut(e)
+            } else {
+                Attempt::Failed(ClientError::Transport(e.to_string()))
+            }
+        })?;
+        let status = response.status();
+        let text = response.text().await.map_err(|e| Attempt::Failed(ClientError::Transport(e.to_string())))?;
+        match serde_json::from_str::<T>(&text) {
+            Ok(body) if status.is_success() || status.is_client_error() => Ok(body),
+            Ok(_) => Err(Attempt::Failed(status_error(status, &text))),
+            Err(e) if status.is_success() => Err(Attempt::Failed(ClientError::Decode(e.to_string()))),
+            Err(_) => Err(Attempt::Failed(status_error(status, &text))),
+        }
+    }
+}
+
+// How one attempt failed, which decides whether it may be retried
+enum Attempt {
+    // The connection failed, so the server never saw the request
+    NotSent(reqwest::Error),
+    // The server may or may not have acted on the request
+    TimedOut(reqwest::Error),
+    Failed(ClientError),
+}
+
+impl Attempt {
+    fn into_error(self) -> ClientError {
+        match self {
+            Attempt::NotSent(e) | Attempt::TimedOut(e) => ClientError::Transport(e.to_string()),
+            Attempt::Failed(e) => e,
+        }
+    }
+}
+
+// The API's error bodies are `{"success": false, "message": ...}`; anything else is passed on raw
+fn status_error(status: StatusCode, body: &str) -> ClientError {
+    let message = serde_json::from_str::<ApiResponse>(body)
+        .map(|response| response.message)
+        .unwrap_or_else(|_| body.to_string());
+    ClientError::Status { status: status.as_u16(), message }
+}
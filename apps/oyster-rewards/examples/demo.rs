@@ -1,9 +1,8 @@
 use oyster_rewards::{
-    Location, SensorData, WifiNetwork, CellTower,
+    Location, WifiNetwork, CellTower,
     register_location, get_location, generate_heatmap, generate_visit_analytics,
-    HeatmapRequest, VisitAnalyticsRequest
+    HeatmapRequest, VisitAnalyticsRequest, UnitSystem, Latitude, Longitude
 };
-use std::collections::HashMap;
 use chrono::Utc;
 
 fn main() {
@@ -49,11 +48,18 @@ fn main() {
     // Generate a heatmap of the locations
     println!("\nGenerating heatmap:");
     let heatmap_request = HeatmapRequest {
-        min_lat: 37.75,
-        max_lat: 37.8,
-        min_lon: -122.45,
-        max_lon: -122.4,
+        min_lat: Latitude::new(37.75).unwrap(),
+        max_lat: Latitude::new(37.8).unwrap(),
+        min_lon: Longitude::new(-122.45).unwrap(),
+        max_lon: Longitude::new(-122.4).unwrap(),
         privacy_level: 1.5,
+        time_range: None,
+        bucket: None,
+        epsilon: None,
+        delta: None,
+        sensitivity: None,
+        requester_id: None,
+        seed: None,
     };
     
     let heatmap = generate_heatmap(&heatmap_request);
@@ -74,12 +80,18 @@ fn main() {
     println!("\nGenerating visit analytics:");
     let analytics_request = VisitAnalyticsRequest {
         user_id: "user123".to_string(),
-        start_time: "2023-01-01T00:00:00Z".to_string(),
-        end_time: "2023-12-31T23:59:59Z".to_string(),
+        start_time: "2023-01-01T00:00:00Z".parse().unwrap(),
+        end_time: "2023-12-31T23:59:59Z".parse().unwrap(),
+        units: UnitSystem::Metric,
+        min_stay_seconds: None,
+        cluster_radius_m: None,
+        max_gap_seconds: None,
     };
     
     let analytics = generate_visit_analytics(&analytics_request);
     println!("Found {} significant visits", analytics.visits.len());
+    println!("Distance traveled: {:.2} {}",
+        analytics.distance_traveled.display_value, analytics.distance_traveled.display_unit);
     
     // Display visits
     for (i, visit) in analytics.visits.iter().enumerate() {
@@ -87,7 +99,14 @@ fn main() {
         println!("    Location: {:.6}, {:.6}", visit.lat, visit.lon);
         println!("    Arrival: {}", visit.arrival_time);
         println!("    Departure: {}", visit.departure_time);
-        println!("    Duration: {} minutes", visit.duration_seconds / 60);
+        println!("    Duration: {} minutes", visit.duration_seconds.duration().num_minutes());
+    }
+    
+    // Display trips between visits
+    for (i, trip) in analytics.trips.iter().enumerate() {
+        println!("  Trip {}: {:?}, {:.2} {} at {:.1} {}", i + 1, trip.mode,
+            trip.distance.display_value, trip.distance.display_unit,
+            trip.average_speed.display_value, trip.average_speed.display_unit);
     }
 }
 
@@ -164,12 +183,13 @@ fn generate_sample_locations() -> Vec<Location> {
     );
     
     // Sort by timestamp
-    locations.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    locations.sort_by_key(|location| location.timestamp);
     
     locations
 }
 
 // Helper to add a cluster of locations
+#[allow(clippy::too_many_arguments)]
 fn add_location_cluster(
     locations: &mut Vec<Location>,
     base_lat: f64,
@@ -183,10 +203,10 @@ fn add_location_cluster(
     use rand::Rng;
     let mut rng = rand::thread_rng();
     
-    // Parse base time
+    // Parse base time, on yesterday so that the whole day's trace is in the past
     let base_time = chrono::NaiveTime::parse_from_str(start_time, "%H:%M:%S").unwrap();
-    let today = Utc::now().date_naive();
-    let base_datetime = chrono::NaiveDateTime::new(today, base_time);
+    let yesterday = Utc::now().date_naive() - chrono::Duration::days(1);
+    let base_datetime = chrono::NaiveDateTime::new(yesterday, base_time);
     
     for i in 0..count {
         // Add some random variation to location
@@ -195,20 +215,29 @@ fn add_location_cluster(
         
         // Calculate timestamp
         let seconds_offset = i as u64 * seconds_between;
-        let timestamp = base_datetime + chrono::Duration::seconds(seconds_offset as i64);
-        let timestamp_str = timestamp.and_utc().to_rfc3339();
+        let timestamp = (base_datetime + chrono::Duration::seconds(seconds_offset as i64)).and_utc();
+        
+        let mut builder = Location::builder()
+            .position(base_lat + lat_jitter, base_lon + lon_jitter)
+            .timestamp(timestamp)
+            .user_id(user_id)
+            .device_id(device_id)
+            .accelerometer(vec![rng.gen::<f64>() * 2.0 - 1.0,
+                                rng.gen::<f64>() * 2.0 - 1.0,
+                                9.8 + rng.gen::<f64>() * 0.4 - 0.2])
+            .gyroscope(vec![rng.gen::<f64>() * 0.2 - 0.1,
+                            rng.gen::<f64>() * 0.2 - 0.1,
+                            rng.gen::<f64>() * 0.2 - 0.1]);
         
         // Generate some fake WiFi networks
         let wifi_count = 2 + (rng.gen::<f64>() * 3.0) as usize;
-        let mut wifi_networks = Vec::with_capacity(wifi_count);
-        
         for j in 0..wifi_count {
             // Generate bssid like 00:11:22:33:44:55
             let bssid = format!("{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
                 rng.gen::<u8>(), rng.gen::<u8>(), rng.gen::<u8>(),
                 rng.gen::<u8>(), rng.gen::<u8>(), rng.gen::<u8>());
                 
-            wifi_networks.push(WifiNetwork {
+            builder = builder.wifi_network(WifiNetwork {
                 bssid,
                 ssid: format!("WiFi-{}", j),
                 signal_strength: -50 - (rng.gen::<f64>() * 40.0) as i32,
@@ -218,10 +247,8 @@ fn add_location_cluster(
         
         // Generate some fake cell towers
         let cell_count = 1 + (rng.gen::<f64>() * 2.0) as usize;
-        let mut cell_towers = Vec::with_capacity(cell_count);
-        
         for j in 0..cell_count {
-            cell_towers.push(CellTower {
+            builder = builder.cell_tower(CellTower {
                 cell_id: format!("CELL-{}-{}", i, j),
                 signal_strength: -70 - (rng.gen::<f64>() * 40.0) as i32,
                 mcc: 310, // USA
@@ -230,28 +257,7 @@ fn add_location_cluster(
             });
         }
         
-        // Create sensor data
-        let sensor_data = SensorData {
-            wifi_networks,
-            cell_towers,
-            accelerometer: Some(vec![rng.gen::<f64>() * 2.0 - 1.0, 
-                                    rng.gen::<f64>() * 2.0 - 1.0, 
-                                    9.8 + rng.gen::<f64>() * 0.4 - 0.2]),
-            gyroscope: Some(vec![rng.gen::<f64>() * 0.2 - 0.1,
-                               rng.gen::<f64>() * 0.2 - 0.1,
-                               rng.gen::<f64>() * 0.2 - 0.1]),
-            is_mock_location: false,
-            additional_data: HashMap::new(),
-        };
-        
         // Create complete location
-        locations.push(Location {
-            lat: base_lat + lat_jitter,
-            lon: base_lon + lon_jitter,
-            timestamp: timestamp_str,
-            user_id: user_id.to_string(),
-            device_id: device_id.to_string(),
-            sensors: sensor_data,
-        });
+        locations.push(builder.build().unwrap());
     }
 } 
\ No newline at end of file
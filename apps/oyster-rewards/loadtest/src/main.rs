@@ -0,0 +1,184 @@
+mod report;
+mod scenarios;
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use report::{LoadTestReport, Sample, ScenarioTargets};
+use scenarios::Scenario;
+
+// Command line options
+struct Options {
+    scenario: Scenario,
+    target_url: String,
+    duration: Duration,
+    concurrency: usize,
+    targets_path: Option<String>,
+    report_path: Option<String>,
+}
+
+fn usage() -> String {
+    "Usage: oyster-loadtest <steady-ingest|heatmap-storm|mixed> \
+     [--target URL] [--duration SECONDS] [--concurrency N] \
+     [--targets targets.toml] [--report report.json]".to_string()
+}
+
+// Parse command line arguments (the target URL can also come from LOADTEST_TARGET)
+fn parse_options() -> Result<Options, String> {
+    let mut args = std::env::args().skip(1);
+    let scenario_name = args.next().ok_or_else(usage)?;
+    let scenario = Scenario::from_name(&scenario_name)
+        .ok_or_else(|| format!("Unknown scenario: {}\n{}", scenario_name, usage()))?;
+
+    let mut options = Options {
+        scenario,
+        target_url: std::env::var("LOADTEST_TARGET")
+            .unwrap_or_else(|_| "http://localhost:8080".to_string()),
+        duration: Duration::from_secs(30),
+        concurrency: 16,
+        targets_path: None,
+        report_path: None,
+    };
+
+    while let Some(flag) = args.next() {
+        let value = args.next().ok_or_else(|| format!("Missing value for {}", flag))?;
+        match flag.as_str() {
+            "--target" => options.target_url = value,
+            "--duration" => {
+                let seconds = value.parse::<u64>().map_err(|_| "Duration must be a number".to_string())?;
+                options.duration = Duration::from_secs(seconds);
+            },
+            "--concurrency" => {
+                options.concurrency = value.parse::<usize>().map_err(|_| "Concurrency must be a number".to_string())?;
+            },
+            "--targets" => options.targets_path = Some(value),
+            "--report" => options.report_path = Some(value),
+            _ => return Err(format!("Unknown option: {}\n{}", flag, usage())),
+        }
+    }
+
+    Ok(options)
+}
+
+// Load the throughput targets for a scenario
+fn load_targets(path: &str, scenario: Scenario) -> Result<Option<ScenarioTargets>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read targets file {}: {}", path, e))?;
+    let mut targets: HashMap<String, ScenarioTargets> = toml::from_str(&contents)
+        .map_err(|e| format!("Failed to parse targets file {}: {}", path, e))?;
+
+    Ok(targets.remove(scenario.name()))
+}
+
+// Issue requests for one virtual user until the deadline
+async fn run_worker(
+    client: reqwest::Client,
+    target_url: String,
+    scenario: Scenario,
+    deadline: Instant,
+) -> Vec<Sample> {
+    let mut rng = StdRng::from_entropy();
+    let mut samples = Vec::new();
+
+    while Instant::now() < deadline {
+        let request = scenario.next_request(&mut rng);
+        let started = Instant::now();
+        let result = client
+            .post(format!("{}{}", target_url, request.path))
+            .json(&request.body)
+            .send()
+            .await;
+
+        let success = match result {
+            Ok(response) => {
+                let ok = response.status().is_success();
+                // Drain the body so the timing covers the full response
+                let _ = response.bytes().await;
+                ok
+            },
+            Err(_) => false,
+        };
+
+        samples.push(Sample {
+            name: request.name,
+            latency: started.elapsed(),
+            success,
+        });
+    }
+
+    samples
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match parse_options() {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(2);
+        }
+    };
+
+    let targets = match &options.targets_path {
+        Some(path) => match load_targets(path, options.scenario) {
+            Ok(targets) => targets,
+            Err(message) => {
+                eprintln!("{}", message);
+                std::process::exit(2);
+            }
+        },
+        None => None,
+    };
+
+    eprintln!("Running {} against {} for {}s with {} workers",
+        options.scenario.name(), options.target_url,
+        options.duration.as_secs(), options.concurrency);
+
+    let client = reqwest::Client::new();
+    let started = Instant::now();
+    let deadline = started + options.duration;
+
+    let workers: Vec<_> = (0..options.concurrency)
+        .map(|_| tokio::spawn(run_worker(
+            client.clone(),
+            options.target_url.clone(),
+            options.scenario,
+            deadline,
+        )))
+        .collect();
+
+    let mut samples = Vec::new();
+    for worker in workers {
+        if let Ok(worker_samples) = worker.await {
+            samples.extend(worker_samples);
+        }
+    }
+
+    let report = LoadTestReport::new(
+        options.scenario.name(),
+        &options.target_url,
+        options.concurrency,
+        started.elapsed(),
+        &samples,
+        targets,
+    );
+
+    let json = serde_json::to_string_pretty(&report).expect("report serializes to JSON");
+    match &options.report_path {
+        Some(path) => {
+            if let Err(e) = std::fs::write(path, &json) {
+                eprintln!("Failed to write report to {}: {}", path, e);
+                std::process::exit(2);
+            }
+        },
+        None => println!("{}", json),
+    }
+
+    for violation in &report.violations {
+        eprintln!("Target missed: {}", violation);
+    }
+    if !report.passed {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+// Throughput targets for a scenario, loaded from targets.toml
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScenarioTargets {
+    pub min_requests_per_second: f64,
+    pub max_p95_latency_ms: f64,
+    pub max_error_rate: f64,
+}
+
+// Outcome of a single request
+pub struct Sample {
+    pub name: &'static str,
+    pub latency: Duration,
+    pub success: bool,
+}
+
+// Latency and error statistics for one request type (or the whole run)
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RequestStats {
+    pub requests: usize,
+    pub errors: usize,
+    pub error_rate: f64,
+    pub requests_per_second: f64,
+    pub p50_latency_ms: f64,
+    pub p95_latency_ms: f64,
+    pub p99_latency_ms: f64,
+    pub max_latency_ms: f64,
+}
+
+// Machine-readable load test report
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LoadTestReport {
+    pub scenario: String,
+    pub target_url: String,
+    pub concurrency: usize,
+    pub duration_seconds: f64,
+    pub overall: RequestStats,
+    pub by_request: HashMap<String, RequestStats>,
+    pub targets: Option<ScenarioTargets>,
+    pub violations: Vec<String>,
+    pub passed: bool,
+}
+
+impl LoadTestReport {
+    pub fn new(
+        scenario: &str,
+        target_url: &str,
+        concurrency: usize,
+        elapsed: Duration,
+        samples: &[Sample],
+        targets: Option<ScenarioTargets>,
+    ) -> Self {
+        let overall = compute_stats(samples.iter(), elapsed);
+
+        // Group samples by request type
+        let mut grouped: HashMap<&str, Vec<&Sample>> = HashMap::new();
+        for sample in samples {
+            grouped.entry(sample.name).or_default().push(sample);
+        }
+        let by_request = grouped.into_iter()
+            .map(|(name, group)| (name.to_string(), compute_stats(group.into_iter(), elapsed)))
+            .collect();
+
+        let violations = match &targets {
+            Some(targets) => check_targets(&overall, targets),
+            None => Vec::new(),
+        };
+
+        LoadTestReport {
+            scenario: scenario.to_string(),
+            target_url: target_url.to_string(),
+            concurrency,
+            duration_seconds: elapsed.as_secs_f64(),
+            overall,
+            by_request,
+            targets,
+            passed: violations.is_empty(),
+            violations,
+        }
+    }
+}
+
+// Compute statistics for a set of samples
+fn compute_stats<'a, I>(samples: I, elapsed: Duration) -> RequestStats
+where
+    I: Iterator<Item = &'a Sample>,
+{
+    let mut latencies = Vec::new();
+    let mut errors = 0;
+    for sample in samples {
+        latencies.push(sample.latency.as_secs_f64() * 1000.0);
+        if !sample.success {
+            errors += 1;
+        }
+    }
+    latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let requests = latencies.len();
+    let seconds = elapsed.as_secs_f64();
+
+    RequestStats {
+        requests,
+        errors,
+        error_rate: if requests > 0 { errors as f64 / requests as f64 } else { 0.0 },
+        requests_per_second: if seconds > 0.0 { requests as f64 / seconds } else { 0.0 },
+        p50_latency_ms: percentile(&latencies, 0.50),
+        p95_latency_ms: percentile(&latencies, 0.95),
+        p99_latency_ms: percentile(&latencies, 0.99),
+        max_latency_ms: latencies.last().copied().unwrap_or(0.0),
+    }
+}
+
+// Nearest-rank percentile of sorted values
+fn percentile(sorted: &[f64], quantile: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (quantile * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+// List every target the run missed
+fn check_targets(stats: &RequestStats, targets: &ScenarioTargets) -> Vec<String> {
+    let mut violations = Vec::new();
+
+    if stats.requests_per_second < targets.min_requests_per_second {
+        violations.push(format!("throughput {:.1} req/s is below target {:.1} req/s",
+            stats.requests_per_second, targets.min_requests_per_second));
+    }
+    if stats.p95_latency_ms > targets.max_p95_latency_ms {
+        violations.push(format!("p95 latency {:.1} ms exceeds target {:.1} ms",
+            stats.p95_latency_ms, targets.max_p95_latency_ms));
+    }
+    if stats.error_rate > targets.max_error_rate {
+        violations.push(format!("error rate {:.3} exceeds target {:.3}",
+            stats.error_rate, targets.max_error_rate));
+    }
+
+    violations
+}
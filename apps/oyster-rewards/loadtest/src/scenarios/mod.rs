@@ -0,0 +1,109 @@
+use rand::Rng;
+use serde_json::{json, Value};
+use chrono::Utc;
+
+// Area used for generated traffic (San Francisco)
+const BASE_LAT: f64 = 37.7749;
+const BASE_LON: f64 = -122.4194;
+
+// Scripted load test scenarios
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scenario {
+    SteadyIngest,
+    HeatmapStorm,
+    Mixed,
+}
+
+// A single request to issue against the API
+pub struct ScenarioRequest {
+    pub name: &'static str,
+    pub path: &'static str,
+    pub body: Value,
+}
+
+impl Scenario {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "steady-ingest" => Some(Scenario::SteadyIngest),
+            "heatmap-storm" => Some(Scenario::HeatmapStorm),
+            "mixed" => Some(Scenario::Mixed),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scenario::SteadyIngest => "steady-ingest",
+            Scenario::HeatmapStorm => "heatmap-storm",
+            Scenario::Mixed => "mixed",
+        }
+    }
+
+    // Pick the next request for this scenario
+    pub fn next_request(&self, rng: &mut impl Rng) -> ScenarioRequest {
+        match self {
+            Scenario::SteadyIngest => location_request(rng),
+            Scenario::HeatmapStorm => heatmap_request(rng),
+            Scenario::Mixed => {
+                // Mostly ingest with a steady trickle of heatmap reads
+                if rng.gen::<f64>() < 0.8 {
+                    location_request(rng)
+                } else {
+                    heatmap_request(rng)
+                }
+            },
+        }
+    }
+}
+
+// Build a location registration with plausible sensor data
+fn location_request(rng: &mut impl Rng) -> ScenarioRequest {
+    let user = rng.gen_range(0..1000);
+    let wifi_networks: Vec<Value> = (0..3)
+        .map(|i| json!({
+            "ssid": format!("WiFi-{}", i),
+            "bssid": format!("00:11:22:33:{:02X}:{:02X}", user % 256, i),
+            "signal_strength": -50 - rng.gen_range(0..40),
+            "frequency": 2412,
+        }))
+        .collect();
+
+    ScenarioRequest {
+        name: "register_location",
+        path: "/api/v1/locations",
+        body: json!({
+            "lat": BASE_LAT + (rng.gen::<f64>() - 0.5) * 0.05,
+            "lon": BASE_LON + (rng.gen::<f64>() - 0.5) * 0.05,
+            "timestamp": Utc::now().to_rfc3339(),
+            "user_id": format!("loadtest-user-{}", user),
+            "device_id": format!("loadtest-device-{}", user),
+            "sensors": {
+                "wifi_networks": wifi_networks,
+                "cell_towers": [],
+                "accelerometer": [0.1, 0.2, 9.8],
+                "gyroscope": [0.01, 0.02, 0.03],
+                "is_mock_location": false,
+                "additional_data": {},
+            },
+        }),
+    }
+}
+
+// Build a heatmap query over a random window around the base area
+fn heatmap_request(rng: &mut impl Rng) -> ScenarioRequest {
+    let size = 0.01 + rng.gen::<f64>() * 0.04;
+    let min_lat = BASE_LAT - size / 2.0 + (rng.gen::<f64>() - 0.5) * 0.02;
+    let min_lon = BASE_LON - size / 2.0 + (rng.gen::<f64>() - 0.5) * 0.02;
+
+    ScenarioRequest {
+        name: "heatmap",
+        path: "/api/v1/heatmap",
+        body: json!({
+            "min_lat": min_lat,
+            "max_lat": min_lat + size,
+            "min_lon": min_lon,
+            "max_lon": min_lon + size,
+            "privacy_level": 1.5,
+        }),
+    }
+}
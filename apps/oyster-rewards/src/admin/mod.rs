@@ -0,0 +1,68 @@
+use chrono::{DateTime, Duration, Utc};
+use crate::models::{AdminStats, CacheInvalidationResponse, RetentionRequest, RetentionResponse};
+use crate::analytics::ANALYTICS_CACHE;
+use crate::heatmap::{HEATMAP_CACHE, HEATMAP_DATA};
+use crate::location::{self, LOCATION_HISTORY, NEARBY_STATIONS};
+use crate::rewards::{events, redemption};
+use crate::rewards::ledger::REWARD_LEDGER;
+use crate::webhooks;
+
+// Operator actions behind the admin API: state sizes, retention and cache invalidation
+
+// Longest retention period an operator can set, about ten years
+pub const MAX_RETENTION_DAYS: u32 = 3650;
+
+pub fn stats(now: DateTime<Utc>) -> AdminStats {
+    let users = LOCATION_HISTORY.len();
+    let stored_locations = LOCATION_HISTORY.iter().map(|records| records.len()).sum();
+
+    AdminStats {
+        users,
+        stored_locations,
+        heatmap_cells: HEATMAP_DATA.lock().unwrap().len(),
+        station_cells: NEARBY_STATIONS.len(),
+        reward_accounts: REWARD_LEDGER.lock().unwrap().account_count(),
+        pending_redemptions: redemption::pending_redemptions(),
+        boost_events: events::list_events(now).len(),
+        webhooks: webhooks::list_endpoints().len(),
+        webhook_queue_depth: webhooks::queue_depth(),
+        heatmap_cache_entries: HEATMAP_CACHE.len(),
+        analytics_cache_entries: ANALYTICS_CACHE.lock().unwrap().len(),
+    }
+}
+
+// Remove stored locations older than the retention period
+pub fn apply_retention(request: &RetentionRequest, now: DateTime<Utc>) -> Result<RetentionResponse, String> {
+    validate_retention_days(request.retention_days)?;
+    let cutoff = now - Duration::days(request.retention_days as i64);
+    let removed_locations = location::purge_locations_before(cutoff);
+    let remaining_locations = LOCATION_HISTORY.iter().map(|records| records.len()).sum();
+
+    Ok(RetentionResponse {
+        cutoff,
+        removed_locations,
+        remaining_locations,
+    })
+}
+
+pub fn validate_retention_days(retention_days: u32) -> Result<(), String> {
+    if !(1..=MAX_RETENTION_DAYS).contains(&retention_days) {
+        return Err(format!("retention_days must be between 1 and {}", MAX_RETENTION_DAYS));
+    }
+    Ok(())
+}
+
+// Drop every cached heatmap and analytics response, e.g. after changing data behind their back
+pub fn invalidate_caches() -> CacheInvalidationResponse {
+    let heatmap_entries = HEATMAP_CACHE.len();
+    HEATMAP_CACHE.clear();
+
+    let mut analytics_cache = ANALYTICS_CACHE.lock().unwrap();
+    let analytics_entries = analytics_cache.len();
+    analytics_cache.clear();
+
+    CacheInvalidationResponse {
+        heatmap_entries,
+        analytics_entries,
+    }
+}
@@ -0,0 +1,123 @@
+use rand::Rng;
+use rayon::prelude::*;
+use crate::models::{AggregateAnalyticsResponse, BoundingBox, TimeRange};
+use crate::heatmap::{check_time_range, PRIVACY_BUDGET};
+use crate::heatmap::privacy::PrivacyParams;
+use crate::location::LOCATION_HISTORY;
+use super::{detect_visits, load_user_locations, VisitDetectionParams};
+
+// Visits counted per user, bounding how much one user can move any statistic
+const MAX_VISITS_PER_USER: usize = 10;
+
+// Statistics are withheld when fewer (noisy) visitors than this were seen
+pub const K_ANONYMITY_FLOOR: u64 = 5;
+
+// Dwell times are clamped to a day and the median is picked from 5 minute steps
+const MAX_DWELL_SECONDS: i64 = 24 * 60 * 60;
+const DWELL_STEP_SECONDS: i64 = 5 * 60;
+
+// The three released statistics share epsilon equally
+const RELEASED_STATISTICS: f64 = 3.0;
+
+// Population-level visit statistics for an area and time range across all users.
+//
+// Each statistic gets a third of epsilon; the requester is charged the full epsilon
+// even when the result is suppressed.
+pub fn aggregate(bounds: &BoundingBox, time_range: &TimeRange, params: &PrivacyParams, requester: &str) -> AggregateAnalyticsResponse {
+    let mut response = AggregateAnalyticsResponse {
+        total_visits: None,
+        unique_visitors: None,
+        median_dwell_seconds: None,
+        suppressed: false,
+        epsilon_spent: 0.0,
+        privacy_budget_remaining: None,
+        error: None,
+    };
+
+    if let Err(error) = bounds.validate() {
+        response.error = Some(error);
+        return response;
+    }
+    let (start_time, end_time) = match check_time_range(time_range) {
+        Ok(range) => range,
+        Err(error) => {
+            response.error = Some(error);
+            return response;
+        },
+    };
+
+    match PRIVACY_BUDGET.lock().unwrap().charge(requester, params.epsilon) {
+        Ok(remaining) => response.privacy_budget_remaining = Some(remaining),
+        Err(error) => {
+            response.error = Some(error);
+            return response;
+        },
+    }
+    response.epsilon_spent = params.epsilon;
+
+    // Dwell times of the (capped) visits of every user inside the bounds, users in parallel
+    let user_ids: Vec<String> = LOCATION_HISTORY.iter().map(|records| records.key().clone()).collect();
+    let per_user: Vec<Vec<i64>> = user_ids.par_iter()
+        .map(|user_id| {
+            let (locations, _) = load_user_locations(user_id, start_time, end_time);
+            detect_visits(&locations, &VisitDetectionParams::default())
+                .into_iter()
+                .filter(|visit| bounds.contains(visit.lat, visit.lon))
+                .take(MAX_VISITS_PER_USER)
+                .map(|visit| visit.duration_seconds.0.clamp(0, MAX_DWELL_SECONDS))
+                .collect()
+        })
+        .filter(|visits: &Vec<i64>| !visits.is_empty())
+        .collect();
+    let visitors = per_user.len() as u64;
+    let mut dwell_times: Vec<i64> = per_user.into_iter().flatten().collect();
+
+    let mut rng = rand::thread_rng();
+    let share = PrivacyParams { epsilon: params.epsilon / RELEASED_STATISTICS, ..*params };
+
+    // One user adds at most one visitor...
+    let noisy_visitors = noisy_count(visitors, &PrivacyParams { sensitivity: 1.0, ..share }, &mut rng);
+    if noisy_visitors < K_ANONYMITY_FLOOR {
+        response.suppressed = true;
+        return response;
+    }
+
+    // ...and at most MAX_VISITS_PER_USER visits
+    let user_sensitivity = PrivacyParams { sensitivity: MAX_VISITS_PER_USER as f64, ..share };
+    response.unique_visitors = Some(noisy_visitors);
+    response.total_visits = Some(noisy_count(dwell_times.len() as u64, &user_sensitivity, &mut rng));
+    response.median_dwell_seconds = Some(private_median(&mut dwell_times, &user_sensitivity, &mut rng));
+
+    response
+}
+
+fn noisy_count(count: u64, params: &PrivacyParams, rng: &mut impl Rng) -> u64 {
+    (count as f64 + params.sample_noise(rng)).round().max(0.0) as u64
+}
+
+// Exponential mechanism over dwell steps, scoring each candidate by how evenly it splits the data
+fn private_median(dwell_times: &mut [i64], params: &PrivacyParams, rng: &mut impl Rng) -> i64 {
+    dwell_times.sort_unstable();
+
+    let candidates: Vec<i64> = (0..=MAX_DWELL_SECONDS).step_by(DWELL_STEP_SECONDS as usize).collect();
+    let log_weights: Vec<f64> = candidates.iter()
+        .map(|&candidate| {
+            // Half the imbalance changes by at most one per changed visit
+            let below = dwell_times.partition_point(|&dwell| dwell < candidate) as f64;
+            let above = (dwell_times.len() - dwell_times.partition_point(|&dwell| dwell <= candidate)) as f64;
+            -(below - above).abs() / 2.0 * params.epsilon / (2.0 * params.sensitivity)
+        })
+        .collect();
+
+    // Sample proportionally to exp(log_weight) without overflowing
+    let max_weight = log_weights.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let weights: Vec<f64> = log_weights.iter().map(|weight| (weight - max_weight).exp()).collect();
+    let mut target = rng.gen::<f64>() * weights.iter().sum::<f64>();
+    for (candidate, weight) in candidates.iter().zip(&weights) {
+        if target < *weight {
+            return *candidate;
+        }
+        target -= weight;
+    }
+    *candidates.last().unwrap()
+}
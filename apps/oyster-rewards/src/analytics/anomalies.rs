@@ -0,0 +1,128 @@
+use chrono::{Duration, Timelike};
+use crate::models::{AnomaliesRequest, AnomaliesResponse, AnomalousVisit, AnomalyReason, LocationVisit, Seconds};
+use super::poi::label_visits;
+use super::{detect_visits, load_user_locations, VisitDetectionParams};
+
+const DEFAULT_HISTORY_DAYS: i64 = 90;
+const DEFAULT_MIN_SCORE: f64 = 0.5;
+
+// Distance from the nearest known place at which the location part of the score reaches 0.5
+const NEW_AREA_SCALE_METERS: f64 = 10_000.0;
+
+// Arrivals within this many hours of a visit's arrival hour count as "at the same hour"
+const HOUR_WINDOW: i32 = 1;
+
+// An hour alone never makes a visit more anomalous than this
+const HOUR_WEIGHT: f64 = 0.6;
+
+// Sparse traces would otherwise merge days at the same place into one long visit
+const MAX_VISIT_GAP_SECONDS: i64 = 3 * 3600;
+
+// Component scores from which a reason is reported
+const NEW_AREA_REASON_SCORE: f64 = 0.5;
+const UNUSUAL_HOUR_REASON_SCORE: f64 = 0.8;
+
+// The user's history: where they have been and when they tend to arrive
+struct ActivityEnvelope<'a> {
+    visits: Vec<&'a LocationVisit>,
+    arrivals_by_hour: [usize; 24],
+}
+
+// Score the visits between start_time and end_time against the user's earlier visits
+pub fn detect_anomalies(request: &AnomaliesRequest) -> AnomaliesResponse {
+    let mut response = AnomaliesResponse {
+        anomalies: Vec::new(),
+        baseline_visits: 0,
+        error: None,
+    };
+
+    let (start_time, end_time) = (request.start_time, request.end_time);
+
+    let history_days = request.history_days.unwrap_or(DEFAULT_HISTORY_DAYS);
+    if history_days <= 0 {
+        response.error = Some("history_days must be positive".to_string());
+        return response;
+    }
+    let min_score = request.min_score.unwrap_or(DEFAULT_MIN_SCORE);
+    if !(0.0..=1.0).contains(&min_score) {
+        response.error = Some("min_score must be between 0 and 1".to_string());
+        return response;
+    }
+
+    // Detect visits over history and window together so a stay spanning start_time is not cut in two
+    let history_start = start_time - Duration::days(history_days);
+    let (locations, _) = load_user_locations(&request.user_id, history_start, end_time);
+    let params = VisitDetectionParams {
+        max_gap_seconds: Some(Seconds(MAX_VISIT_GAP_SECONDS)),
+        ..VisitDetectionParams::default()
+    };
+    let mut visits = detect_visits(&locations, &params);
+    label_visits(&mut visits);
+
+    let (baseline, window): (Vec<&LocationVisit>, Vec<&LocationVisit>) = visits.iter()
+        .partition(|visit| visit.arrival_time < start_time);
+    let envelope = ActivityEnvelope::new(baseline);
+    response.baseline_visits = envelope.visits.len();
+
+    for visit in window {
+        let scored = envelope.score(visit);
+        if scored.anomaly_score >= min_score {
+            response.anomalies.push(scored);
+        }
+    }
+
+    response
+}
+
+impl<'a> ActivityEnvelope<'a> {
+    fn new(visits: Vec<&'a LocationVisit>) -> Self {
+        let mut arrivals_by_hour = [0; 24];
+        for arrival in visits.iter().map(|visit| visit.arrival_time) {
+            arrivals_by_hour[arrival.hour() as usize] += 1;
+        }
+        ActivityEnvelope { visits, arrivals_by_hour }
+    }
+
+    // Arrivals within HOUR_WINDOW of the given hour
+    fn arrivals_around(&self, hour: u32) -> usize {
+        (-HOUR_WINDOW..=HOUR_WINDOW)
+            .map(|offset| self.arrivals_by_hour[(hour as i32 + offset).rem_euclid(24) as usize])
+            .sum()
+    }
+
+    fn score(&self, visit: &LocationVisit) -> AnomalousVisit {
+        let nearest = self.visits.iter()
+            .map(|known| known.coordinate().distance(visit.coordinate()))
+            .min_by(|a, b| a.0.total_cmp(&b.0));
+
+        // Without any history every place is new
+        let distance_score = match nearest {
+            Some(meters) => 1.0 - (-meters.0 * std::f64::consts::LN_2 / NEW_AREA_SCALE_METERS).exp(),
+            None => 1.0,
+        };
+
+        // How rare the arrival hour is compared to the user's busiest hour
+        let busiest = (0..24).map(|hour| self.arrivals_around(hour)).max().unwrap_or(0);
+        let hour_score = if busiest > 0 {
+            1.0 - self.arrivals_around(visit.arrival_time.hour()) as f64 / busiest as f64
+        } else {
+            0.0
+        };
+
+        let mut reasons = Vec::new();
+        if distance_score >= NEW_AREA_REASON_SCORE {
+            reasons.push(AnomalyReason::NewArea);
+        }
+        if hour_score >= UNUSUAL_HOUR_REASON_SCORE {
+            reasons.push(AnomalyReason::UnusualHour);
+        }
+
+        AnomalousVisit {
+            visit: visit.clone(),
+            // Either signal on its own is enough; both together push the score towards 1
+            anomaly_score: 1.0 - (1.0 - distance_score) * (1.0 - HOUR_WEIGHT * hour_score),
+            reasons,
+            nearest_known_place_meters: nearest,
+        }
+    }
+}
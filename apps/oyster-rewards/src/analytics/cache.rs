@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::models::VisitAnalyticsResponse;
+
+// Default bounds for the shared visit analytics cache
+pub const DEFAULT_CACHE_CAPACITY: usize = 256;
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(300);
+
+// A cached analytics response with its bookkeeping
+struct CacheEntry {
+    user_id: String,
+    response: VisitAnalyticsResponse,
+    inserted_at: Instant,
+    last_used: u64,
+}
+
+// Visit analytics cache with a time-to-live, LRU capacity bound and per-user invalidation
+pub struct AnalyticsCache {
+    entries: HashMap<String, CacheEntry>,
+    capacity: usize,
+    ttl: Duration,
+    clock: u64,
+    // Bumped by every invalidation of a user and by every clear, so a response computed
+    // while the data changed can be told apart from a current one
+    generations: HashMap<String, u64>,
+    cleared: u64,
+    // Lookups answered from the cache and lookups that missed, for the metrics endpoint
+    hits: u64,
+    misses: u64,
+}
+
+impl AnalyticsCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        AnalyticsCache {
+            entries: HashMap::new(),
+            capacity,
+            ttl,
+            clock: 0,
+            generations: HashMap::new(),
+            cleared: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    // Get a fresh cached response, dropping it if it has expired
+    pub fn get(&mut self, key: &str) -> Option<VisitAnalyticsResponse> {
+        self.clock += 1;
+        let clock = self.clock;
+        
+        let expired = match self.entries.get_mut(key) {
+            Some(entry) if entry.inserted_at.elapsed() < self.ttl => {
+                entry.last_used = clock;
+                self.hits += 1;
+                return Some(entry.response.clone());
+            },
+            Some(_) => true,
+            None => false,
+        };
+        
+        if expired {
+            self.entries.remove(key);
+        }
+        self.misses += 1;
+        None
+    }
+
+    // Lookups so far as (hits, misses)
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    // Generation of a user's cached data; read it before reading the data a response is
+    // computed from. Both counters only grow, so their sum changes whenever either does
+    pub fn generation(&self, user_id: &str) -> u64 {
+        self.cleared + self.generations.get(user_id).copied().unwrap_or(0)
+    }
+
+    // Insert a user's response computed at `generation`, evicting the least recently used
+    // entry when full. A response computed before an invalidation is stale and dropped
+    pub fn insert(&mut self, key: String, user_id: &str, generation: u64, response: VisitAnalyticsResponse) {
+        if self.capacity == 0 || generation != self.generation(user_id) {
+            return;
+        }
+        
+        self.clock += 1;
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            self.evict_expired();
+            if self.entries.len() >= self.capacity {
+                self.evict_least_recently_used();
+            }
+        }
+        
+        self.entries.insert(key, CacheEntry {
+            user_id: user_id.to_string(),
+            response,
+            inserted_at: Instant::now(),
+            last_used: self.clock,
+        });
+    }
+
+    // Drop every cached response of the user
+    pub fn invalidate_user(&mut self, user_id: &str) {
+        *self.generations.entry(user_id.to_string()).or_insert(0) += 1;
+        self.entries.retain(|_, entry| entry.user_id != user_id);
+    }
+
+    pub fn clear(&mut self) {
+        self.cleared += 1;
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Drop expired entries, returning how many were dropped
+    pub fn evict_expired(&mut self) -> usize {
+        let ttl = self.ttl;
+        let before = self.entries.len();
+        self.entries.retain(|_, entry| entry.inserted_at.elapsed() < ttl);
+        before - self.entries.len()
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        let oldest = self.entries.iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone());
+        
+        if let Some(key) = oldest {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+impl Default for AnalyticsCache {
+    fn default() -> Self {
+        AnalyticsCache::new(DEFAULT_CACHE_CAPACITY, DEFAULT_CACHE_TTL)
+    }
+}
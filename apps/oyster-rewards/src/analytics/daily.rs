@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use crate::location::LOCATION_HISTORY;
+use crate::models::Meters;
+use super::rolling::{count_locations_by_hour, count_unique_places, calculate_total_distance};
+
+// Totals of past days per (user, date), materialized by the scheduler so daily reports
+// are lookups; a user's entries are dropped whenever their history changes
+static DAILY_TOTALS: Lazy<Mutex<HashMap<(String, NaiveDate), DailyTotals>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// A user's activity over one UTC day
+#[derive(Debug, Clone)]
+pub struct DailyTotals {
+    pub hourly_counts: Vec<usize>,
+    pub unique_places: usize,
+    pub distance_meters: Meters,
+}
+
+// Totals of a day, from the materialized ones when available
+pub fn daily_totals(user_id: &str, date: NaiveDate) -> DailyTotals {
+    if let Some(totals) = DAILY_TOTALS.lock().unwrap().get(&(user_id.to_string(), date)) {
+        return totals.clone();
+    }
+    compute_totals(user_id, date)
+}
+
+// Materialize the totals of the day for every user active on it, returning how many were added
+pub fn materialize(date: NaiveDate) -> usize {
+    let users: Vec<String> = LOCATION_HISTORY.iter().map(|records| records.key().clone()).collect();
+
+    let mut added = 0;
+    for user_id in users {
+        let key = (user_id, date);
+        if DAILY_TOTALS.lock().unwrap().contains_key(&key) {
+            continue;
+        }
+        let totals = compute_totals(&key.0, date);
+        if totals.hourly_counts.iter().all(|&count| count == 0) {
+            continue;
+        }
+        DAILY_TOTALS.lock().unwrap().insert(key, totals);
+        added += 1;
+    }
+    added
+}
+
+// Drop the materialized totals of a user whose history changed
+pub fn invalidate_user(user_id: &str) {
+    DAILY_TOTALS.lock().unwrap().retain(|(user, _), _| user != user_id);
+}
+
+pub fn materialized_count() -> usize {
+    DAILY_TOTALS.lock().unwrap().len()
+}
+
+fn compute_totals(user_id: &str, date: NaiveDate) -> DailyTotals {
+    let start_of_day: DateTime<Utc> = date.and_hms_opt(0, 0, 0).unwrap().and_utc();
+    let end_of_day = start_of_day + Duration::days(1);
+
+    // Hourly counts as a 24-entry histogram
+    let counts_by_hour = count_locations_by_hour(user_id, start_of_day, end_of_day);
+    let hourly_counts = (0..24)
+        .map(|hour| counts_by_hour.get(&hour).copied().unwrap_or(0))
+        .collect();
+
+    DailyTotals {
+        hourly_counts,
+        unique_places: count_unique_places(user_id, start_of_day, end_of_day),
+        distance_meters: calculate_total_distance(user_id, start_of_day, end_of_day),
+    }
+}
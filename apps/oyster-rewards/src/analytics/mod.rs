@@ -1,109 +1,298 @@
-use std::collections::{HashMap, HashSet};
-use chrono::{DateTime, Duration, Utc, NaiveDateTime, Timelike};
-use crate::models::{Location, EncryptedLocation, VisitAnalyticsRequest, VisitAnalyticsResponse, LocationVisit};
+pub mod aggregate;
+pub mod anomalies;
+pub mod cache;
+pub mod daily;
+pub mod poi;
+pub mod predictions;
+pub mod rolling;
+pub mod routes;
+pub mod routines;
+pub mod trips;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+use rayon::prelude::*;
+use chrono::{DateTime, Utc, NaiveDate, NaiveDateTime};
+use crate::models::{
+    Coordinate, Latitude, Longitude, Location, VisitAnalyticsRequest, VisitAnalyticsResponse, LocationVisit,
+    DailySummaryRequest, DailySummaryResponse, UnitSystem, Trip,
+    AnalyticsWarning, AnalyticsWarningCode, Meters, Seconds
+};
 use crate::location::LOCATION_HISTORY;
 use crate::crypto;
+use cache::AnalyticsCache;
+
+// In-memory cache for visit analytics, invalidated per user as locations are registered
+pub static ANALYTICS_CACHE: Lazy<Mutex<AnalyticsCache>> = Lazy::new(|| Mutex::new(AnalyticsCache::default()));
 
 // Minimum time in seconds that defines a "stay" at a location
-const MIN_STAY_DURATION_SECONDS: i64 = 300; // 5 minutes
+pub const DEFAULT_MIN_STAY_SECONDS: i64 = 300; // 5 minutes
+
+// Maximum distance in meters between consecutive points of the "same" location
+pub const DEFAULT_CLUSTER_RADIUS_METERS: f64 = 30.0;
+
+// Points per chunk when clustering a long trace in parallel
+const CLUSTER_CHUNK_POINTS: usize = 10_000;
+
+// Approximate number of meters in one degree of latitude
+pub(crate) const METERS_PER_DEGREE: f64 = 111000.0;
+
+// Thresholds used to turn a location trace into visits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VisitDetectionParams {
+    pub min_stay_seconds: Seconds,
+    pub cluster_radius_m: Meters,
+    // Consecutive points further apart in time than this never belong to one visit
+    pub max_gap_seconds: Option<Seconds>,
+}
+
+impl Default for VisitDetectionParams {
+    fn default() -> Self {
+        VisitDetectionParams {
+            min_stay_seconds: Seconds(DEFAULT_MIN_STAY_SECONDS),
+            cluster_radius_m: Meters(DEFAULT_CLUSTER_RADIUS_METERS),
+            max_gap_seconds: None,
+        }
+    }
+}
+
+impl VisitDetectionParams {
+    // Thresholds of a request, falling back to the defaults for omitted fields
+    pub fn from_request(request: &VisitAnalyticsRequest) -> Result<Self, String> {
+        let defaults = VisitDetectionParams::default();
+        let params = VisitDetectionParams {
+            min_stay_seconds: request.min_stay_seconds.map(Seconds).unwrap_or(defaults.min_stay_seconds),
+            cluster_radius_m: request.cluster_radius_m.map(Meters).unwrap_or(defaults.cluster_radius_m),
+            max_gap_seconds: request.max_gap_seconds.map(Seconds),
+        };
+        
+        if params.min_stay_seconds.0 < 0 {
+            return Err("min_stay_seconds must not be negative".to_string());
+        }
+        if !(params.cluster_radius_m.0 > 0.0 && params.cluster_radius_m.0.is_finite()) {
+            return Err("cluster_radius_m must be a positive number".to_string());
+        }
+        if params.max_gap_seconds.is_some_and(|gap| gap.0 <= 0) {
+            return Err("max_gap_seconds must be positive".to_string());
+        }
+        Ok(params)
+    }
+}
+
+// Stored records of a user that analytics had to leave out
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SkippedRecords {
+    pub undecryptable: usize,
+}
 
-// Maximum distance in degrees that counts as the "same" location
-const SAME_LOCATION_THRESHOLD: f64 = 0.0003; // ~30 meters
+impl SkippedRecords {
+    pub(crate) fn warnings(&self) -> Vec<AnalyticsWarning> {
+        let mut warnings = Vec::new();
+        if self.undecryptable > 0 {
+            warnings.push(AnalyticsWarning {
+                code: AnalyticsWarningCode::UndecryptableRecord,
+                skipped_records: self.undecryptable,
+                message: format!("{} stored locations could not be decrypted and were skipped", self.undecryptable),
+            });
+        }
+        warnings
+    }
+}
 
 // Generate analytics for user visits
 pub fn generate_visit_analytics(request: &VisitAnalyticsRequest) -> VisitAnalyticsResponse {
     let user_id = &request.user_id;
     
-    // Extract time range from request
-    let start_time = match DateTime::parse_from_rfc3339(&request.start_time) {
-        Ok(time) => time.with_timezone(&Utc),
-        Err(_) => {
-            return VisitAnalyticsResponse {
-                visits: Vec::new(),
-                error: Some("Invalid start time format".to_string()),
-            };
-        }
+    let params = match VisitDetectionParams::from_request(request) {
+        Ok(params) => params,
+        Err(error) => return empty_analytics_response(request.units, Some(error)),
     };
     
-    let end_time = match DateTime::parse_from_rfc3339(&request.end_time) {
-        Ok(time) => time.with_timezone(&Utc),
-        Err(_) => {
-            return VisitAnalyticsResponse {
-                visits: Vec::new(),
-                error: Some("Invalid end time format".to_string()),
-            };
-        }
-    };
+    let (start_time, end_time) = (request.start_time, request.end_time);
     
-    // Get user location history
-    let history_lock = LOCATION_HISTORY.lock().unwrap();
-    let user_history = match history_lock.get(user_id) {
-        Some(history) => history,
-        None => {
-            return VisitAnalyticsResponse {
-                visits: Vec::new(),
-                error: None, // No error, just no data for this user
-            };
+    // Repeated polling of the same view is served from the cache
+    let cache_key = format!("{}-{}-{}-{:?}-{:?}",
+        user_id, start_time.timestamp(), end_time.timestamp(), request.units, params);
+    let generation = {
+        let mut cache = ANALYTICS_CACHE.lock().unwrap();
+        if let Some(cached_response) = cache.get(&cache_key) {
+            return cached_response;
         }
+        cache.generation(user_id)
     };
     
-    // Decrypt and filter locations within time range
-    let mut locations: Vec<Location> = Vec::new();
-    for encrypted_location in user_history {
-        if let Ok(location) = crypto::decrypt_location(encrypted_location) {
-            // Parse timestamp
-            if let Ok(timestamp) = DateTime::parse_from_rfc3339(&location.timestamp) {
-                let utc_timestamp = timestamp.with_timezone(&Utc);
-                
-                // Filter by time range
-                if utc_timestamp >= start_time && utc_timestamp <= end_time {
-                    locations.push(location);
-                }
-            }
+    // Not cached if the user's locations changed while it was computed
+    let response = compute_visit_analytics(user_id, start_time, end_time, &params, request.units);
+    ANALYTICS_CACHE.lock().unwrap().insert(cache_key, user_id, generation, response.clone());
+    response
+}
+
+fn compute_visit_analytics(
+    user_id: &str,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    params: &VisitDetectionParams,
+    units: UnitSystem
+) -> VisitAnalyticsResponse {
+    let histograms = rolling::activity_histograms(user_id, start_time, end_time);
+    
+    // The rolling aggregates cover the default thresholds without decrypting anything
+    if *params == VisitDetectionParams::default() {
+        if let Some(mut summary) = rolling::visit_summary(user_id, start_time, end_time, units) {
+            poi::label_visits(&mut summary.visits);
+            let mut response = visit_analytics_response(summary.visits, summary.trips, summary.distance_meters, summary.elapsed_seconds, histograms, units);
+            response.warnings = rolling::skipped_records(user_id).warnings();
+            return response;
         }
     }
     
-    // Sort locations by timestamp
-    locations.sort_by(|a, b| {
-        let a_time = DateTime::parse_from_rfc3339(&a.timestamp).unwrap();
-        let b_time = DateTime::parse_from_rfc3339(&b.timestamp).unwrap();
-        a_time.cmp(&b_time)
-    });
+    // Decrypted locations within the time range, oldest first
+    let (locations, skipped) = load_user_locations(user_id, start_time, end_time);
     
     // Extract significant stays (visits)
-    let visits = detect_visits(&locations);
+    let mut visits = detect_visits(&locations, params);
+    poi::label_visits(&mut visits);
+    let trips = trips::detect_trips(&locations, &visits, units);
+    
+    // Distance and average speed over the whole period, in SI units
+    let distance_meters = path_distance_meters(&locations);
+    let elapsed_seconds = match (locations.first(), locations.last()) {
+        (Some(first), Some(last)) => Seconds::from(last.timestamp - first.timestamp),
+        _ => Seconds(0),
+    };
+    
+    let mut response = visit_analytics_response(visits, trips, distance_meters, elapsed_seconds, histograms, units);
+    response.warnings = skipped.warnings();
+    response
+}
+
+fn visit_analytics_response(
+    visits: Vec<LocationVisit>,
+    trips: Vec<Trip>,
+    distance_meters: Meters,
+    elapsed_seconds: Seconds,
+    (hourly_counts, weekday_counts): (Vec<usize>, Vec<usize>),
+    units: UnitSystem
+) -> VisitAnalyticsResponse {
+    let average_speed = distance_meters.per_second(elapsed_seconds);
     
     VisitAnalyticsResponse {
         visits,
+        trips,
+        hourly_counts,
+        weekday_counts,
+        units,
+        distance_traveled: units.distance(distance_meters),
+        average_speed: units.speed(average_speed),
+        warnings: Vec::new(),
         error: None,
     }
 }
 
+// Decrypt a user's locations between start_time and end_time (inclusive), sorted by timestamp.
+// Records that cannot be decrypted are counted and skipped.
+fn load_user_locations(user_id: &str, start_time: DateTime<Utc>, end_time: DateTime<Utc>) -> (Vec<Location>, SkippedRecords) {
+    // Copy the ciphertexts out so registrations are not blocked while decrypting
+    let user_history = match LOCATION_HISTORY.get(user_id) {
+        Some(user_history) => user_history.clone(),
+        None => return (Vec::new(), SkippedRecords::default()),
+    };
+    
+    // Decrypt in parallel; None marks a record that could not be decrypted
+    let decrypted: Vec<Option<Location>> = user_history.par_iter()
+        .map(|encrypted_location| crypto::decrypt_location(encrypted_location).ok())
+        .collect();
+    
+    let mut locations: Vec<Location> = Vec::new();
+    let mut skipped = SkippedRecords::default();
+    for record in decrypted {
+        match record {
+            // Filter by time range
+            Some(location) if location.timestamp >= start_time && location.timestamp <= end_time => locations.push(location),
+            Some(_) => {},
+            None => skipped.undecryptable += 1,
+        }
+    }
+    
+    // Sort locations by timestamp
+    locations.par_sort_by_key(|location| location.timestamp);
+    
+    (locations, skipped)
+}
+
+// Build an analytics response without any visits
+fn empty_analytics_response(units: UnitSystem, error: Option<String>) -> VisitAnalyticsResponse {
+    VisitAnalyticsResponse {
+        visits: Vec::new(),
+        trips: Vec::new(),
+        hourly_counts: vec![0; 24],
+        weekday_counts: vec![0; 7],
+        units,
+        distance_traveled: units.distance(Meters(0.0)),
+        average_speed: units.speed(0.0),
+        warnings: Vec::new(),
+        error,
+    }
+}
+
 // Detect significant visits from a chronological sequence of locations
-fn detect_visits(locations: &[Location]) -> Vec<LocationVisit> {
-    if locations.is_empty() {
-        return Vec::new();
+fn detect_visits(locations: &[Location], params: &VisitDetectionParams) -> Vec<LocationVisit> {
+    // Points that cannot share a cluster split long traces into chunks clustered in parallel
+    let mut chunks = Vec::new();
+    let mut chunk_start = 0;
+    for index in 1..locations.len() {
+        if index - chunk_start >= CLUSTER_CHUNK_POINTS && !same_cluster(&locations[index - 1], &locations[index], params) {
+            chunks.push(&locations[chunk_start..index]);
+            chunk_start = index;
+        }
     }
+    chunks.push(&locations[chunk_start..]);
+    
+    chunks.par_iter()
+        .flat_map_iter(|chunk| cluster_visits(chunk, params))
+        .collect()
+}
+
+// Whether a point continues the cluster its predecessor belongs to
+fn same_cluster(previous: &Location, current: &Location, params: &VisitDetectionParams) -> bool {
+    // Calculate distance between current and previous location
+    let distance = current.coordinate().distance(previous.coordinate());
+    
+    // A long silence between two points also ends the visit
+    let within_gap = match params.max_gap_seconds {
+        Some(max_gap) => Seconds::from(current.timestamp - previous.timestamp) <= max_gap,
+        None => true,
+    };
+    
+    // Floors of a venue are different places, though they share a position
+    let same_floor = match (previous.floor_level, current.floor_level) {
+        (Some(previous_floor), Some(current_floor)) => previous_floor == current_floor,
+        _ => true,
+    };
+    
+    distance <= params.cluster_radius_m && within_gap && same_floor
+}
+
+// Sequential visit detection over one chunk of the trace
+fn cluster_visits(locations: &[Location], params: &VisitDetectionParams) -> Vec<LocationVisit> {
+    let (first, rest) = match locations.split_first() {
+        Some(split) => split,
+        None => return Vec::new(),
+    };
     
     let mut visits = Vec::new();
-    let mut current_cluster: Vec<&Location> = vec![&locations[0]];
+    let mut current_cluster: Vec<&Location> = vec![first];
     
-    for i in 1..locations.len() {
-        let current_loc = &locations[i];
-        let previous_loc = current_cluster.last().unwrap();
-        
-        // Calculate distance between current and previous location
-        let distance = calculate_distance(
-            current_loc.lat, current_loc.lon,
-            previous_loc.lat, previous_loc.lon
-        );
+    for current_loc in rest {
+        let previous_loc = current_cluster[current_cluster.len() - 1];
         
-        if distance <= SAME_LOCATION_THRESHOLD {
+        if same_cluster(previous_loc, current_loc, params) {
             // Same location cluster, add to current cluster
             current_cluster.push(current_loc);
         } else {
             // New location, process the previous cluster if it was significant
-            process_cluster(&current_cluster, &mut visits);
+            process_cluster(&current_cluster, params, &mut visits);
             
             // Start new cluster
             current_cluster = vec![current_loc];
@@ -111,203 +300,140 @@ fn detect_visits(locations: &[Location]) -> Vec<LocationVisit> {
     }
     
     // Process the final cluster
-    process_cluster(&current_cluster, &mut visits);
+    process_cluster(&current_cluster, params, &mut visits);
     
     visits
 }
 
 // Process a cluster of locations to determine if it's a significant visit
-fn process_cluster(cluster: &[&Location], visits: &mut Vec<LocationVisit>) {
-    if cluster.len() < 2 {
-        return; // Need at least 2 points to determine a stay
+fn process_cluster(cluster: &[&Location], params: &VisitDetectionParams, visits: &mut Vec<LocationVisit>) {
+    let Some((first, rest)) = cluster.split_first() else {
+        return;
+    };
+    let mut summary = ClusterSummary::new(first);
+    for loc in rest {
+        summary.push(loc);
     }
-    
-    // Get first and last timestamp in cluster
-    let first_time = DateTime::parse_from_rfc3339(&cluster[0].timestamp).unwrap();
-    let last_time = DateTime::parse_from_rfc3339(&cluster.last().unwrap().timestamp).unwrap();
-    
-    // Calculate duration
-    let duration = (last_time - first_time).num_seconds();
-    
-    // If stayed for minimum required time, consider it a visit
-    if duration >= MIN_STAY_DURATION_SECONDS {
-        // Calculate average location (center of cluster)
-        let mut lat_sum = 0.0;
-        let mut lon_sum = 0.0;
-        
-        for loc in cluster {
-            lat_sum += loc.lat;
-            lon_sum += loc.lon;
+    visits.extend(summary.visit(params));
+}
+
+// What a visit is made of, folded in point by point so a long stay takes no more room
+#[derive(Debug, Clone)]
+pub(crate) struct ClusterSummary {
+    first_time: DateTime<Utc>,
+    last_time: DateTime<Utc>,
+    last_coordinate: Coordinate,
+    lat_sum: f64,
+    lon_sum: f64,
+    count: u32,
+    floor_counts: HashMap<i32, usize>,
+}
+
+impl ClusterSummary {
+    pub(crate) fn new(location: &Location) -> Self {
+        let mut summary = ClusterSummary {
+            first_time: location.timestamp,
+            last_time: location.timestamp,
+            last_coordinate: location.coordinate(),
+            lat_sum: 0.0,
+            lon_sum: 0.0,
+            count: 0,
+            floor_counts: HashMap::new(),
+        };
+        summary.push(location);
+        summary
+    }
+
+    // Add the next point of the cluster, in time order
+    pub(crate) fn push(&mut self, location: &Location) {
+        self.last_time = location.timestamp;
+        self.last_coordinate = location.coordinate();
+        self.lat_sum += location.lat.degrees();
+        self.lon_sum += location.lon.degrees();
+        self.count += 1;
+        if let Some(floor) = location.floor_level {
+            *self.floor_counts.entry(floor).or_default() += 1;
+        }
+    }
+
+    pub(crate) fn last_coordinate(&self) -> Coordinate {
+        self.last_coordinate
+    }
+
+    // Whether the points so far were a stay long enough to be a visit
+    pub(crate) fn is_visit(&self, params: &VisitDetectionParams) -> bool {
+        // Need at least 2 points to determine a stay
+        self.count >= 2 && Seconds::from(self.last_time - self.first_time) >= params.min_stay_seconds
+    }
+
+    pub(crate) fn visit(&self, params: &VisitDetectionParams) -> Option<LocationVisit> {
+        if !self.is_visit(params) {
+            return None;
         }
         
-        let avg_lat = lat_sum / cluster.len() as f64;
-        let avg_lon = lon_sum / cluster.len() as f64;
-        
-        // Format times for display
-        let arrival_time = first_time.to_rfc3339();
-        let departure_time = last_time.to_rfc3339();
-        
-        visits.push(LocationVisit {
-            lat: avg_lat,
-            lon: avg_lon,
-            arrival_time,
-            departure_time,
-            duration_seconds: duration,
-            point_count: cluster.len() as u32,
-        });
+        // The center of the cluster, on the floor most of its points with one were on
+        Some(LocationVisit {
+            lat: Latitude::clamped(self.lat_sum / self.count as f64),
+            lon: Longitude::clamped(self.lon_sum / self.count as f64),
+            arrival_time: self.first_time,
+            departure_time: self.last_time,
+            duration_seconds: Seconds::from(self.last_time - self.first_time),
+            point_count: self.count,
+            poi: None,
+            floor_level: self.floor_counts.iter()
+                .max_by_key(|&(&floor, &count)| (count, std::cmp::Reverse(floor)))
+                .map(|(&floor, _)| floor),
+        })
     }
 }
 
-// Calculate distance between two points (simple approximation using Euclidean distance)
-fn calculate_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
-    let dlat = lat2 - lat1;
-    let dlon = lon2 - lon1;
+// Calculate distance between two points in degrees (simple approximation using Euclidean distance)
+fn calculate_distance(from: Coordinate, to: Coordinate) -> f64 {
+    let dlat = to.lat.degrees() - from.lat.degrees();
+    let dlon = to.lon.degrees() - from.lon.degrees();
     (dlat * dlat + dlon * dlon).sqrt()
 }
 
-// Get daily summary of user activity
-pub fn generate_daily_summary(user_id: &str, date_str: &str) -> HashMap<String, usize> {
-    let mut summary = HashMap::new();
+// Length of a chronologically sorted path
+fn path_distance_meters(locations: &[Location]) -> Meters {
+    locations.windows(2)
+        .map(|pair| pair[0].coordinate().distance(pair[1].coordinate()))
+        .sum()
+}
+
+// Get a daily report of user activity with distances in the requested units
+pub fn generate_daily_report(request: &DailySummaryRequest) -> DailySummaryResponse {
+    let units = request.units;
     
     // Parse date
-    let date = match NaiveDateTime::parse_from_str(&format!("{} 00:00:00", date_str), "%Y-%m-%d %H:%M:%S") {
+    let date = match NaiveDateTime::parse_from_str(&format!("{} 00:00:00", request.date), "%Y-%m-%d %H:%M:%S") {
         Ok(date) => date,
         Err(_) => {
-            summary.insert("error".to_string(), 1);
-            return summary;
+            return DailySummaryResponse {
+                date: request.date.clone(),
+                hourly_counts: vec![0; 24],
+                unique_places: 0,
+                units,
+                distance_traveled: units.distance(Meters(0.0)),
+                error: Some("Invalid date format".to_string()),
+            };
         }
     };
     
-    // Calculate start and end of day in UTC
-    let start_of_day = DateTime::<Utc>::from_utc(date, Utc);
-    let end_of_day = start_of_day + Duration::days(1);
-    
-    // Count locations by hour
-    let hourly_counts = count_locations_by_hour(user_id, start_of_day, end_of_day);
+    let totals = daily::daily_totals(&request.user_id, date.date());
     
-    // Add hourly counts to summary
-    for (hour, count) in hourly_counts {
-        summary.insert(format!("hour_{}", hour), count);
+    DailySummaryResponse {
+        date: request.date.clone(),
+        hourly_counts: totals.hourly_counts,
+        unique_places: totals.unique_places,
+        units,
+        distance_traveled: units.distance(totals.distance_meters),
+        error: None,
     }
-    
-    // Count unique places visited
-    let unique_places = count_unique_places(user_id, start_of_day, end_of_day);
-    summary.insert("unique_places".to_string(), unique_places);
-    
-    // Count total distance traveled
-    let total_distance = calculate_total_distance(user_id, start_of_day, end_of_day);
-    summary.insert("distance_traveled".to_string(), total_distance.round() as usize);
-    
-    summary
 }
 
-// Count locations by hour of day
-fn count_locations_by_hour(user_id: &str, start_time: DateTime<Utc>, end_time: DateTime<Utc>) -> HashMap<u32, usize> {
-    let mut hourly_counts = HashMap::new();
-    
-    // Initialize all hours to zero
-    for hour in 0..24 {
-        hourly_counts.insert(hour, 0);
-    }
-    
-    // Get user location history
-    let history_lock = LOCATION_HISTORY.lock().unwrap();
-    if let Some(user_history) = history_lock.get(user_id) {
-        for encrypted_location in user_history {
-            if let Ok(location) = crypto::decrypt_location(encrypted_location) {
-                // Parse timestamp
-                if let Ok(timestamp) = DateTime::parse_from_rfc3339(&location.timestamp) {
-                    let utc_timestamp = timestamp.with_timezone(&Utc);
-                    
-                    // Check if in time range
-                    if utc_timestamp >= start_time && utc_timestamp < end_time {
-                        // Get hour and increment count
-                        let hour = utc_timestamp.hour();
-                        *hourly_counts.entry(hour).or_insert(0) += 1;
-                    }
-                }
-            }
-        }
-    }
-    
-    hourly_counts
+// A user's activity over one UTC day, given as YYYY-MM-DD
+pub fn generate_daily_summary(user_id: &str, date_str: &str) -> Result<daily::DailyTotals, String> {
+    let date = NaiveDate::parse_from_str(date_str, "%Y-%m-%d").map_err(|_| "Invalid date format".to_string())?;
+    Ok(daily::daily_totals(user_id, date))
 }
-
-// Count unique places visited (based on grid cells)
-fn count_unique_places(user_id: &str, start_time: DateTime<Utc>, end_time: DateTime<Utc>) -> usize {
-    let mut unique_places = HashSet::new();
-    
-    // Get user location history
-    let history_lock = LOCATION_HISTORY.lock().unwrap();
-    if let Some(user_history) = history_lock.get(user_id) {
-        for encrypted_location in user_history {
-            if let Ok(location) = crypto::decrypt_location(encrypted_location) {
-                // Parse timestamp
-                if let Ok(timestamp) = DateTime::parse_from_rfc3339(&location.timestamp) {
-                    let utc_timestamp = timestamp.with_timezone(&Utc);
-                    
-                    // Check if in time range
-                    if utc_timestamp >= start_time && utc_timestamp < end_time {
-                        // Round coordinates to create grid cells (roughly 100m)
-                        let lat_key = ((location.lat * 1000.0).round() / 1000.0 * 1000.0) as i32;
-                        let lon_key = ((location.lon * 1000.0).round() / 1000.0 * 1000.0) as i32;
-                        unique_places.insert((lat_key, lon_key));
-                    }
-                }
-            }
-        }
-    }
-    
-    unique_places.len()
-}
-
-// Calculate total distance traveled
-fn calculate_total_distance(user_id: &str, start_time: DateTime<Utc>, end_time: DateTime<Utc>) -> f64 {
-    let mut total_distance = 0.0;
-    let mut last_lat = None;
-    let mut last_lon = None;
-    
-    // Get user location history
-    let history_lock = LOCATION_HISTORY.lock().unwrap();
-    if let Some(user_history) = history_lock.get(user_id) {
-        // Collect and sort locations by timestamp
-        let mut locations: Vec<Location> = Vec::new();
-        for encrypted_location in user_history {
-            if let Ok(location) = crypto::decrypt_location(encrypted_location) {
-                // Parse timestamp
-                if let Ok(timestamp) = DateTime::parse_from_rfc3339(&location.timestamp) {
-                    let utc_timestamp = timestamp.with_timezone(&Utc);
-                    
-                    // Check if in time range
-                    if utc_timestamp >= start_time && utc_timestamp < end_time {
-                        locations.push(location);
-                    }
-                }
-            }
-        }
-        
-        // Sort locations by timestamp
-        locations.sort_by(|a, b| {
-            let a_time = DateTime::parse_from_rfc3339(&a.timestamp).unwrap();
-            let b_time = DateTime::parse_from_rfc3339(&b.timestamp).unwrap();
-            a_time.cmp(&b_time)
-        });
-        
-        // Calculate distances between consecutive points
-        for location in locations {
-            if let (Some(prev_lat), Some(prev_lon)) = (last_lat, last_lon) {
-                // Calculate distance
-                let distance = calculate_distance(prev_lat, prev_lon, location.lat, location.lon);
-                
-                // Convert to meters (approximately) and add to total
-                // 1 degree of latitude is roughly 111km
-                total_distance += distance * 111000.0;
-            }
-            
-            last_lat = Some(location.lat);
-            last_lon = Some(location.lon);
-        }
-    }
-    
-    total_distance
-} 
\ No newline at end of file
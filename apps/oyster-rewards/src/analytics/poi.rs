@@ -0,0 +1,77 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+use crate::models::{Coordinate, LocationVisit, Poi};
+
+// Source of named places that visits can be matched against
+pub trait PoiProvider: Send {
+    // The POI whose area contains the point, preferring the closest one
+    fn lookup(&self, coordinate: Coordinate) -> Option<Poi>;
+    fn add_poi(&mut self, poi: Poi) -> Result<(), String>;
+}
+
+// Active provider; an empty in-memory dataset unless the API server loads one
+pub static POI_PROVIDER: Lazy<Mutex<Box<dyn PoiProvider>>> = Lazy::new(|| Mutex::new(Box::new(PoiDataset::default())));
+
+// Replace the active provider
+pub fn set_poi_provider(provider: Box<dyn PoiProvider>) {
+    *POI_PROVIDER.lock().unwrap() = provider;
+    super::ANALYTICS_CACHE.lock().unwrap().clear();
+}
+
+// Register a POI with the active provider
+pub fn register_poi(poi: Poi) -> Result<(), String> {
+    if !(poi.radius_meters > 0.0 && poi.radius_meters.is_finite()) {
+        return Err("radius_meters must be a positive number".to_string());
+    }
+    if poi.name.trim().is_empty() {
+        return Err("POI name must not be empty".to_string());
+    }
+    POI_PROVIDER.lock().unwrap().add_poi(poi)?;
+
+    // Cached visits may now fall inside the new POI
+    super::ANALYTICS_CACHE.lock().unwrap().clear();
+    Ok(())
+}
+
+// Annotate each visit with the POI it took place at, if any
+pub fn label_visits(visits: &mut [LocationVisit]) {
+    let provider = POI_PROVIDER.lock().unwrap();
+    for visit in visits.iter_mut() {
+        visit.poi = provider.lookup(visit.coordinate());
+    }
+}
+
+// POIs held in memory: an offline dataset plus places registered at runtime
+#[derive(Default)]
+pub struct PoiDataset {
+    pois: Vec<Poi>,
+}
+
+impl PoiDataset {
+    // Load a JSON array of POIs
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, String> {
+        let json = fs::read(path.as_ref()).map_err(|e| format!("Failed to read POI dataset: {}", e))?;
+        let pois = serde_json::from_slice(&json).map_err(|e| format!("Invalid POI dataset: {}", e))?;
+        Ok(PoiDataset { pois })
+    }
+}
+
+impl PoiProvider for PoiDataset {
+    fn lookup(&self, coordinate: Coordinate) -> Option<Poi> {
+        self.pois.iter()
+            .map(|poi| (poi.coordinate().distance(coordinate), poi))
+            .filter(|(distance, poi)| distance.0 <= poi.radius_meters)
+            .min_by(|a, b| a.0.0.total_cmp(&b.0.0))
+            .map(|(_, poi)| poi.clone())
+    }
+
+    fn add_poi(&mut self, poi: Poi) -> Result<(), String> {
+        if self.pois.iter().any(|existing| existing.id == poi.id) {
+            return Err(format!("POI {} already exists", poi.id));
+        }
+        self.pois.push(poi);
+        Ok(())
+    }
+}
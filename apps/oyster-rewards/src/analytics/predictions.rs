@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use chrono::{Duration, Timelike, Utc};
+use crate::models::{Coordinate, LocationVisit, PredictedPlace, PredictionsRequest, PredictionsResponse, Seconds};
+use super::poi::POI_PROVIDER;
+use super::routines::{group_into_places, mode, Place};
+use super::{detect_visits, load_user_locations, VisitDetectionParams};
+
+const DEFAULT_HISTORY_DAYS: i64 = 90;
+const MAX_HISTORY_DAYS: i64 = 365;
+const DEFAULT_PREDICTIONS: usize = 5;
+pub const MAX_PREDICTIONS: usize = 20;
+
+// Weight (in transitions) of the overall place popularity mixed into the transition counts,
+// so places never left towards a destination before still get a small share
+const PRIOR_WEIGHT: f64 = 2.0;
+
+// Sparse traces would otherwise merge days at the same place into one long visit
+const MAX_VISIT_GAP_SECONDS: i64 = 3 * 3600;
+
+// Predict where the user goes after their latest visit with a first-order Markov model
+// over the places they visited, smoothed towards how often each place is visited at all
+pub fn predict_next_visits(request: &PredictionsRequest) -> PredictionsResponse {
+    let mut response = PredictionsResponse {
+        current_place: None,
+        predictions: Vec::new(),
+        hourly_visit_likelihood: vec![0.0; 24],
+        history_visits: 0,
+        error: None,
+    };
+
+    let at = request.at.unwrap_or_else(Utc::now);
+    let history_days = request.history_days.unwrap_or(DEFAULT_HISTORY_DAYS);
+    if !(1..=MAX_HISTORY_DAYS).contains(&history_days) {
+        response.error = Some(format!("history_days must be between 1 and {}", MAX_HISTORY_DAYS));
+        return response;
+    }
+    let n = request.n.unwrap_or(DEFAULT_PREDICTIONS);
+    if n > MAX_PREDICTIONS {
+        response.error = Some(format!("n must be at most {}", MAX_PREDICTIONS));
+        return response;
+    }
+
+    let (locations, _) = load_user_locations(&request.user_id, at - Duration::days(history_days), at);
+    let params = VisitDetectionParams {
+        max_gap_seconds: Some(Seconds(MAX_VISIT_GAP_SECONDS)),
+        ..VisitDetectionParams::default()
+    };
+    let visits = detect_visits(&locations, &params);
+    response.history_visits = visits.len();
+    if visits.is_empty() {
+        return response;
+    }
+
+    for visit in &visits {
+        response.hourly_visit_likelihood[visit.arrival_time.hour() as usize] += 1.0 / visits.len() as f64;
+    }
+
+    // Place of every visit, in visit order
+    let places = group_into_places(&visits);
+    let place_index: HashMap<*const LocationVisit, usize> = places.iter()
+        .enumerate()
+        .flat_map(|(index, place)| place.visits.iter().map(move |visit| (*visit as *const LocationVisit, index)))
+        .collect();
+    let sequence: Vec<usize> = visits.iter().map(|visit| place_index[&(visit as *const LocationVisit)]).collect();
+
+    // Transitions out of the current place, ignoring stays split in two
+    let current = sequence[sequence.len() - 1];
+    let mut transitions = vec![0usize; places.len()];
+    for pair in sequence.windows(2) {
+        if pair[0] == current && pair[1] != current {
+            transitions[pair[1]] += 1;
+        }
+    }
+    let total_transitions: usize = transitions.iter().sum();
+    let other_visits = visits.len() - places[current].visits.len();
+
+    let mut predictions: Vec<(usize, f64)> = (0..places.len())
+        .filter(|&index| index != current)
+        .map(|index| {
+            let popularity = places[index].visits.len() as f64 / other_visits as f64;
+            let probability = (transitions[index] as f64 + PRIOR_WEIGHT * popularity) / (total_transitions as f64 + PRIOR_WEIGHT);
+            (index, probability)
+        })
+        .collect();
+    predictions.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    response.current_place = Some(predicted_place(&places[current], 1.0));
+    response.predictions = predictions.into_iter()
+        .take(n)
+        .map(|(index, probability)| predicted_place(&places[index], probability))
+        .collect();
+
+    response
+}
+
+fn predicted_place(place: &Place, probability: f64) -> PredictedPlace {
+    PredictedPlace {
+        lat: place.lat,
+        lon: place.lon,
+        probability,
+        visit_count: place.visits.len(),
+        typical_arrival_hour: mode(place.visits.iter().map(|visit| visit.arrival_time.hour())),
+        poi: POI_PROVIDER.lock().unwrap().lookup(Coordinate::new(place.lat, place.lon)),
+    }
+}
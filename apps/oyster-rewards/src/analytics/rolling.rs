@@ -0,0 +1,347 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use crate::models::{Coordinate, EncryptedLocation, Location, LocationVisit, Meters, Seconds, Trip, UnitSystem, VisitCompletion, WebhookEventKind};
+use crate::crypto;
+use crate::location::LOCATION_HISTORY;
+use crate::webhooks;
+use super::trips::{TripBuilder, TripPoint};
+use super::{ClusterSummary, SkippedRecords, VisitDetectionParams};
+
+const SECONDS_PER_HOUR: i64 = 3600;
+
+// Per-user aggregates, updated as locations are registered so analytics never decrypt the history.
+// Sharded by user like LOCATION_HISTORY; a user's entry is held while their history is written,
+// and neither is held while a rebuild decrypts
+static USER_AGGREGATES: Lazy<DashMap<String, UserAggregates>> = Lazy::new(DashMap::new);
+
+// What is kept of each point: no exact coordinates, only the ~100m place it fell in
+struct TimelinePoint {
+    time: DateTime<Utc>,
+    // Path length from the user's first point up to this one
+    cumulative_meters: Meters,
+    place: (i32, i32),
+}
+
+// Rolling aggregates of one user's trace (visits use the default detection thresholds)
+#[derive(Default)]
+struct UserAggregates {
+    timeline: Vec<TimelinePoint>,
+    // Points per hour since the epoch
+    hourly_counts: BTreeMap<i64, usize>,
+    last_point: Option<TripPoint>,
+    visits: Vec<LocationVisit>,
+    // trips[i] leads from visits[i] to visits[i + 1]
+    trips: Vec<Trip>,
+    // Movement since the departure of the last closed visit, up to the open cluster
+    trip: TripBuilder,
+    open_cluster: Option<ClusterSummary>,
+    // Points of the open cluster while it is too short to be a visit, since they belong to
+    // the trip if it never becomes one; once it is a visit only its arrival is kept
+    open_points: Vec<TripPoint>,
+    // Registered records left out of the aggregates
+    skipped: SkippedRecords,
+    // Bumped by every change, so a rebuild only replaces the aggregates it started from
+    generation: u64,
+}
+
+// Visits, trips and movement of a time range, read from the aggregates
+pub(crate) struct VisitSummary {
+    pub visits: Vec<LocationVisit>,
+    pub trips: Vec<Trip>,
+    pub distance_meters: Meters,
+    pub elapsed_seconds: Seconds,
+}
+
+// Store a newly registered location in its user's history and fold it into their aggregates.
+// `store` runs with the user's aggregates held, so a concurrent rebuild sees the record in
+// both or in neither. A point arriving out of order rebuilds the aggregates after both are
+// released. A visit the location closes is announced to the user's webhooks; rebuilds
+// announce nothing.
+pub(crate) fn record_location(location: &Location, store: impl FnOnce()) {
+    let mut user = USER_AGGREGATES.entry(location.user_id.clone()).or_default();
+    store();
+    user.generation += 1;
+
+    let in_order = user.timeline.last().is_none_or(|last| last.time <= location.timestamp);
+    if !in_order {
+        drop(user);
+        rebuild_user(&location.user_id);
+        return;
+    }
+    let completion = user.push(location.clone());
+    drop(user);
+    if let Some(completion) = completion {
+        webhooks::emit(WebhookEventKind::VisitCompleted, &location.user_id, serde_json::to_value(&completion).unwrap_or_default());
+    }
+}
+
+// Rebuild a user's aggregates from their history, e.g. after records were removed or inserted.
+// The history is copied out and decrypted without holding either map; if the aggregates
+// changed meanwhile, the rebuild starts over from the newer history.
+pub(crate) fn rebuild_user(user_id: &str) {
+    loop {
+        let (generation, user_history) = {
+            let user = USER_AGGREGATES.entry(user_id.to_string()).or_default();
+            let user_history: Vec<EncryptedLocation> = LOCATION_HISTORY.get(user_id)
+                .map(|records| records.clone())
+                .unwrap_or_default();
+            (user.generation, user_history)
+        };
+        let rebuilt = UserAggregates::rebuild(&user_history);
+
+        let mut user = USER_AGGREGATES.entry(user_id.to_string()).or_default();
+        if user.generation == generation {
+            *user = UserAggregates { generation: generation + 1, ..rebuilt };
+            drop(user);
+            if user_history.is_empty() {
+                USER_AGGREGATES.remove_if(user_id, |_, user| user.generation == generation + 1);
+            }
+            return;
+        }
+    }
+}
+
+// Records of the user the aggregates had to skip
+pub(crate) fn skipped_records(user_id: &str) -> SkippedRecords {
+    USER_AGGREGATES.get(user_id).map(|user| user.skipped).unwrap_or_default()
+}
+
+// Location counts per hour of day over [start_time, end_time), at whole-hour granularity
+pub(crate) fn count_locations_by_hour(user_id: &str, start_time: DateTime<Utc>, end_time: DateTime<Utc>) -> HashMap<u32, usize> {
+    let mut hourly_counts: HashMap<u32, usize> = (0..24).map(|hour| (hour, 0)).collect();
+
+    if let Some(user) = USER_AGGREGATES.get(user_id) {
+        let hours = start_time.timestamp().div_euclid(SECONDS_PER_HOUR)..end_time.timestamp().div_euclid(SECONDS_PER_HOUR);
+        for (hour, count) in user.hourly_counts.range(hours) {
+            *hourly_counts.entry(hour.rem_euclid(24) as u32).or_insert(0) += count;
+        }
+    }
+
+    hourly_counts
+}
+
+// Distinct ~100m places seen in [start_time, end_time)
+pub(crate) fn count_unique_places(user_id: &str, start_time: DateTime<Utc>, end_time: DateTime<Utc>) -> usize {
+    match USER_AGGREGATES.get(user_id) {
+        Some(user) => user.points_between(start_time, end_time, false).iter()
+            .map(|point| point.place)
+            .collect::<HashSet<_>>()
+            .len(),
+        None => 0,
+    }
+}
+
+// Path length in meters over [start_time, end_time)
+pub(crate) fn calculate_total_distance(user_id: &str, start_time: DateTime<Utc>, end_time: DateTime<Utc>) -> Meters {
+    match USER_AGGREGATES.get(user_id) {
+        Some(user) => path_length(user.points_between(start_time, end_time, false)),
+        None => Meters(0.0),
+    }
+}
+
+// Visits and trips inside [start_time, end_time] plus the movement over that range.
+//
+// Returns None when a visit straddles either end of the range: cut at the boundary it
+// could turn into a different visit, so the caller has to look at the raw points.
+pub(crate) fn visit_summary(user_id: &str, start_time: DateTime<Utc>, end_time: DateTime<Utc>, units: UnitSystem) -> Option<VisitSummary> {
+    let user = match USER_AGGREGATES.get(user_id) {
+        Some(user) => user,
+        None => return Some(VisitSummary { visits: Vec::new(), trips: Vec::new(), distance_meters: Meters(0.0), elapsed_seconds: Seconds(0) }),
+    };
+
+    let (visits, trips) = user.visits_and_trips();
+    let mut summary = VisitSummary { visits: Vec::new(), trips: Vec::new(), distance_meters: Meters(0.0), elapsed_seconds: Seconds(0) };
+
+    for visit in visits {
+        let (arrival, departure) = (visit.arrival_time, visit.departure_time);
+        if (arrival < start_time && departure >= start_time) || (arrival <= end_time && departure > end_time) {
+            return None;
+        }
+        if arrival >= start_time && departure <= end_time {
+            summary.visits.push(visit);
+        }
+    }
+
+    // Trips are stored in SI units
+    for trip in trips {
+        let (departure, arrival) = (trip.start_time, trip.end_time);
+        if departure >= start_time && arrival <= end_time {
+            summary.trips.push(Trip {
+                distance: units.distance(Meters(trip.distance.value)),
+                average_speed: units.speed(trip.average_speed.value),
+                ..trip
+            });
+        }
+    }
+
+    let points = user.points_between(start_time, end_time, true);
+    summary.distance_meters = path_length(points);
+    if let (Some(first), Some(last)) = (points.first(), points.last()) {
+        summary.elapsed_seconds = Seconds::from(last.time - first.time);
+    }
+
+    Some(summary)
+}
+
+// Every user's visits that start inside the time range (or all of them), oldest first
+pub(crate) fn visits_by_user(time_range: Option<(DateTime<Utc>, DateTime<Utc>)>) -> Vec<Vec<LocationVisit>> {
+    USER_AGGREGATES.iter()
+        .map(|user| {
+            user.visits_and_trips().0.into_iter()
+                .filter(|visit| time_range.is_none_or(|(start, end)| visit.arrival_time >= start && visit.arrival_time <= end))
+                .collect()
+        })
+        .collect()
+}
+
+// Locations per hour of day and per day of week (Monday first) in [start_time, end_time]
+pub(crate) fn activity_histograms(user_id: &str, start_time: DateTime<Utc>, end_time: DateTime<Utc>) -> (Vec<usize>, Vec<usize>) {
+    let mut hourly_counts = vec![0; 24];
+    let mut weekday_counts = vec![0; 7];
+
+    if let Some(user) = USER_AGGREGATES.get(user_id) {
+        for point in user.points_between(start_time, end_time, true) {
+            hourly_counts[point.time.hour() as usize] += 1;
+            weekday_counts[point.time.weekday().num_days_from_monday() as usize] += 1;
+        }
+    }
+
+    (hourly_counts, weekday_counts)
+}
+
+impl UserAggregates {
+    fn rebuild(user_history: &[EncryptedLocation]) -> Self {
+        let mut skipped = SkippedRecords::default();
+        let mut locations: Vec<Location> = Vec::new();
+        for encrypted in user_history {
+            match crypto::decrypt_location(encrypted) {
+                Ok(location) => locations.push(location),
+                Err(_) => skipped.undecryptable += 1,
+            }
+        }
+        locations.sort_by_key(|location| location.timestamp);
+
+        let mut user = UserAggregates { skipped, ..UserAggregates::default() };
+        for location in locations {
+            user.push(location);
+        }
+        user
+    }
+
+    // Append a point that is not older than any point seen so far, returning the visit it closed
+    fn push(&mut self, location: Location) -> Option<VisitCompletion> {
+        let time = location.timestamp;
+        let point = TripPoint::from(&location);
+        // Same per-segment distance as the scanning path length
+        let segment_meters = self.last_point
+            .map(|last| last.coordinate.distance(point.coordinate))
+            .unwrap_or_default();
+        let cumulative_meters = self.timeline.last().map(|point| point.cumulative_meters).unwrap_or_default() + segment_meters;
+        self.timeline.push(TimelinePoint { time, cumulative_meters, place: place_key(point.coordinate) });
+        *self.hourly_counts.entry(time.timestamp().div_euclid(SECONDS_PER_HOUR)).or_insert(0) += 1;
+
+        let completion = self.extend_visits(&location, point);
+        self.last_point = Some(point);
+        completion
+    }
+
+    // Continue the open cluster or close it, mirroring detect_visits
+    fn extend_visits(&mut self, location: &Location, point: TripPoint) -> Option<VisitCompletion> {
+        let params = VisitDetectionParams::default();
+
+        if let Some(cluster) = self.open_cluster.as_mut() {
+            if point.coordinate.distance(cluster.last_coordinate()) <= params.cluster_radius_m {
+                cluster.push(location);
+                if cluster.is_visit(&params) {
+                    self.open_points.truncate(1);
+                } else {
+                    self.open_points.push(point);
+                }
+                return None;
+            }
+        }
+
+        let mut completion = None;
+        if let Some(cluster) = self.open_cluster.take() {
+            match cluster.visit(&params) {
+                Some(visit) => {
+                    let trip = self.trip_to(&visit);
+                    if let Some(trip) = &trip {
+                        self.trips.push(trip.clone());
+                    }
+                    self.visits.push(visit.clone());
+                    completion = Some(VisitCompletion { visit, trip });
+
+                    // The next trip starts at this departure
+                    self.trip = TripBuilder::default();
+                    if let Some(departure) = self.last_point {
+                        self.trip.push(departure);
+                    }
+                },
+                // Too short for a visit: its points were part of the trip
+                None => {
+                    for open_point in self.open_points.drain(..) {
+                        self.trip.push(open_point);
+                    }
+                },
+            }
+        }
+
+        // Without a visit behind them, earlier points never belong to a trip
+        if self.visits.is_empty() {
+            self.trip = TripBuilder::default();
+        }
+        self.open_cluster = Some(ClusterSummary::new(location));
+        self.open_points = vec![point];
+        completion
+    }
+
+    // Trip from the last closed visit to the given one, which arrived at the open cluster
+    fn trip_to(&self, visit: &LocationVisit) -> Option<Trip> {
+        let previous = self.visits.last()?;
+        let mut trip = self.trip.clone();
+        if let Some(&arrival) = self.open_points.first() {
+            trip.push(arrival);
+        }
+        Some(trip.finish(previous.departure_time, visit.arrival_time, UnitSystem::Metric))
+    }
+
+    // Closed visits and trips, plus the open cluster if it already qualifies as a visit
+    fn visits_and_trips(&self) -> (Vec<LocationVisit>, Vec<Trip>) {
+        let mut visits = self.visits.clone();
+        let mut trips = self.trips.clone();
+
+        let open = self.open_cluster.as_ref().and_then(|cluster| cluster.visit(&VisitDetectionParams::default()));
+        if let Some(visit) = open {
+            if let Some(trip) = self.trip_to(&visit) {
+                trips.push(trip);
+            }
+            visits.push(visit);
+        }
+
+        (visits, trips)
+    }
+
+    // Timeline points in the range, with an inclusive or exclusive end
+    fn points_between(&self, start_time: DateTime<Utc>, end_time: DateTime<Utc>, inclusive_end: bool) -> &[TimelinePoint] {
+        let start = self.timeline.partition_point(|point| point.time < start_time);
+        let end = self.timeline.partition_point(|point| point.time < end_time || (inclusive_end && point.time == end_time));
+        &self.timeline[start..end.max(start)]
+    }
+}
+
+fn path_length(points: &[TimelinePoint]) -> Meters {
+    match (points.first(), points.last()) {
+        (Some(first), Some(last)) => last.cumulative_meters - first.cumulative_meters,
+        _ => Meters(0.0),
+    }
+}
+
+// Round coordinates to create grid cells (roughly 100m)
+fn place_key(coordinate: Coordinate) -> (i32, i32) {
+    let lat_key = ((coordinate.lat.degrees() * 1000.0).round() / 1000.0 * 1000.0) as i32;
+    let lon_key = ((coordinate.lon.degrees() * 1000.0).round() / 1000.0 * 1000.0) as i32;
+    (lat_key, lon_key)
+}
@@ -0,0 +1,116 @@
+use std::collections::{HashMap, HashSet};
+use crate::models::{BoundingBox, Coordinate, HeatmapRequest, Latitude, Longitude, PopularRoute, PopularRoutesResponse};
+use crate::heatmap::{check_time_range, PRIVACY_BUDGET};
+use crate::heatmap::privacy::{PrivacyParams, ANONYMOUS_REQUESTER};
+use super::rolling;
+
+// Visits are snapped to cells of this size (degrees, ~500m) so trips share endpoints
+const ROUTE_CELL_SIZE: f64 = 0.005;
+
+// Distinct corridors counted per user, bounding one user's influence on the counts
+const MAX_ROUTES_PER_USER: usize = 20;
+
+// Noisy counts below this are never released, so rare (identifying) routes stay hidden
+const MIN_ROUTE_COUNT: f64 = 5.0;
+
+// Upper bound on routes returned per request
+pub const MAX_ROUTES: usize = 100;
+
+// Undirected edge between two route cells, smaller cell first
+type Corridor = ((i64, i64), (i64, i64));
+
+// Chain every user's consecutive visits into corridors, count the users traveling each
+// corridor inside the request bounds (and time range) and return the `n` busiest
+pub fn popular_routes(request: &HeatmapRequest, n: usize) -> PopularRoutesResponse {
+    let mut response = PopularRoutesResponse {
+        routes: Vec::new(),
+        epsilon_spent: 0.0,
+        privacy_budget_remaining: None,
+        error: None,
+    };
+
+    let bounds = BoundingBox::from_request(request);
+    let resolved = bounds.validate()
+        .and_then(|_| PrivacyParams::from_request(request))
+        .and_then(|params| match &request.time_range {
+            Some(range) => Ok((params, Some(check_time_range(range)?))),
+            None => Ok((params, None)),
+        });
+    let (params, time_range) = match resolved {
+        Ok(resolved) => resolved,
+        Err(error) => {
+            response.error = Some(error);
+            return response;
+        }
+    };
+
+    let requester = request.requester_id.as_deref().unwrap_or(ANONYMOUS_REQUESTER);
+    match PRIVACY_BUDGET.lock().unwrap().charge(requester, params.epsilon) {
+        Ok(remaining) => {
+            response.epsilon_spent = params.epsilon;
+            response.privacy_budget_remaining = Some(remaining);
+        },
+        Err(error) => {
+            response.error = Some(error);
+            return response;
+        }
+    }
+
+    // Each user counts once per corridor
+    let mut counts: HashMap<Corridor, u32> = HashMap::new();
+    for visits in rolling::visits_by_user(time_range) {
+        let mut corridors: HashSet<Corridor> = HashSet::new();
+        for pair in visits.windows(2) {
+            if !bounds.contains(pair[0].lat, pair[0].lon) || !bounds.contains(pair[1].lat, pair[1].lon) {
+                continue;
+            }
+            let (from, to) = (route_cell(pair[0].coordinate()), route_cell(pair[1].coordinate()));
+            if from != to && corridors.len() < MAX_ROUTES_PER_USER {
+                corridors.insert((from.min(to), from.max(to)));
+            }
+        }
+        for corridor in corridors {
+            *counts.entry(corridor).or_insert(0) += 1;
+        }
+    }
+
+    // One user changes up to MAX_ROUTES_PER_USER counts by one each
+    let noise = PrivacyParams { sensitivity: params.sensitivity * MAX_ROUTES_PER_USER as f64, ..params };
+    let mut rng = rand::thread_rng();
+    let mut noisy: Vec<(Corridor, u32)> = counts.into_iter()
+        .filter_map(|(corridor, count)| {
+            let noisy_count = count as f64 + noise.sample_noise(&mut rng);
+            (noisy_count >= MIN_ROUTE_COUNT).then_some((corridor, noisy_count.round() as u32))
+        })
+        .collect();
+    noisy.sort_by_key(|&(corridor, count)| (std::cmp::Reverse(count), corridor));
+
+    for (index, (corridor, count)) in noisy.into_iter().take(n.min(MAX_ROUTES)).enumerate() {
+        let (from, to) = (cell_center(corridor.0), cell_center(corridor.1));
+        response.routes.push(PopularRoute {
+            rank: index + 1,
+            from_lat: from.lat,
+            from_lon: from.lon,
+            to_lat: to.lat,
+            to_lon: to.lon,
+            distance_meters: from.distance(to),
+            count,
+        });
+    }
+
+    response
+}
+
+fn route_cell(coordinate: Coordinate) -> (i64, i64) {
+    (
+        (coordinate.lat.degrees() / ROUTE_CELL_SIZE).floor() as i64,
+        (coordinate.lon.degrees() / ROUTE_CELL_SIZE).floor() as i64,
+    )
+}
+
+fn cell_center((lat_cell, lon_cell): (i64, i64)) -> Coordinate {
+    Coordinate::new(
+        Latitude::clamped((lat_cell as f64 + 0.5) * ROUTE_CELL_SIZE),
+        Longitude::clamped((lon_cell as f64 + 0.5) * ROUTE_CELL_SIZE),
+    )
+}
@@ -0,0 +1,196 @@
+use std::collections::{BTreeMap, BTreeSet};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Timelike, Utc, Weekday};
+use crate::models::{
+    Coordinate, Latitude, Longitude, LocationVisit, RoutinesRequest, RoutinesResponse, RoutineSummary, RoutineLabel, CommuteSummary, Seconds
+};
+use super::{calculate_distance, detect_visits, load_user_locations, VisitDetectionParams};
+
+// Visits closer than this (in degrees) belong to the same place
+const PLACE_RADIUS: f64 = 0.001; // ~100 meters
+
+// A place has to be seen on this many distinct days to count as a routine
+const MIN_ROUTINE_DAYS: usize = 3;
+
+// Hours (UTC) counted as night for home detection and as working hours on weekdays
+const NIGHT_HOURS: [u32; 8] = [22, 23, 0, 1, 2, 3, 4, 5];
+const WORK_HOURS: std::ops::Range<u32> = 9..17;
+
+// Visits grouped by place
+pub(super) struct Place<'a> {
+    pub lat: Latitude,
+    pub lon: Longitude,
+    pub visits: Vec<&'a LocationVisit>,
+}
+
+// Detect recurring home/work/frequent places and the home-work commute of a user
+pub fn generate_routines(request: &RoutinesRequest) -> RoutinesResponse {
+    let mut response = RoutinesResponse {
+        routines: Vec::new(),
+        commute: None,
+        error: None,
+    };
+
+    let (start_time, end_time) = (request.start_time, request.end_time);
+
+    let (locations, _) = load_user_locations(&request.user_id, start_time, end_time);
+    let visits = detect_visits(&locations, &VisitDetectionParams::default());
+    let places = group_into_places(&visits);
+
+    // Home: the place with the most night-time presence
+    let home = places.iter()
+        .enumerate()
+        .map(|(index, place)| (index, hours_matching(place, |time| NIGHT_HOURS.contains(&time.hour()))))
+        .filter(|&(index, hours)| hours > 0 && distinct_days(&places[index]).len() >= MIN_ROUTINE_DAYS)
+        .max_by_key(|&(_, hours)| hours)
+        .map(|(index, _)| index);
+
+    // Work: another place with the most weekday working-hour presence
+    let work = places.iter()
+        .enumerate()
+        .filter(|&(index, _)| Some(index) != home)
+        .map(|(index, place)| (index, hours_matching(place, |time| {
+            time.weekday().number_from_monday() <= 5 && WORK_HOURS.contains(&time.hour())
+        })))
+        .filter(|&(index, hours)| hours > 0 && distinct_days(&places[index]).len() >= MIN_ROUTINE_DAYS)
+        .max_by_key(|&(_, hours)| hours)
+        .map(|(index, _)| index);
+
+    for (index, place) in places.iter().enumerate() {
+        let label = if Some(index) == home {
+            RoutineLabel::Home
+        } else if Some(index) == work {
+            RoutineLabel::Work
+        } else if distinct_days(place).len() >= MIN_ROUTINE_DAYS {
+            RoutineLabel::Frequent
+        } else {
+            continue;
+        };
+        response.routines.push(summarize_place(place, label));
+    }
+    response.routines.sort_by_key(|routine| (routine.label, std::cmp::Reverse(routine.total_duration_seconds)));
+
+    if let (Some(home), Some(work)) = (home, work) {
+        response.commute = detect_commute(&places[home], &places[work]);
+    }
+
+    response
+}
+
+// Greedily assign each visit to the nearest known place within PLACE_RADIUS
+pub(super) fn group_into_places(visits: &[LocationVisit]) -> Vec<Place<'_>> {
+    let mut places: Vec<Place> = Vec::new();
+
+    for visit in visits {
+        let nearest = places.iter_mut()
+            .map(|place| (calculate_distance(Coordinate::new(place.lat, place.lon), visit.coordinate()), place))
+            .filter(|(distance, _)| *distance <= PLACE_RADIUS)
+            .min_by(|a, b| a.0.total_cmp(&b.0));
+
+        match nearest {
+            Some((_, place)) => {
+                // Keep the place centered on its visits
+                let n = place.visits.len() as f64;
+                place.lat = Latitude::clamped((place.lat.degrees() * n + visit.lat.degrees()) / (n + 1.0));
+                place.lon = Longitude::clamped((place.lon.degrees() * n + visit.lon.degrees()) / (n + 1.0));
+                place.visits.push(visit);
+            },
+            None => places.push(Place { lat: visit.lat, lon: visit.lon, visits: vec![visit] }),
+        }
+    }
+
+    places
+}
+
+fn visit_times(visit: &LocationVisit) -> (DateTime<Utc>, DateTime<Utc>) {
+    (visit.arrival_time, visit.departure_time)
+}
+
+// Number of started hours spent at the place that satisfy the predicate
+fn hours_matching(place: &Place, predicate: impl Fn(DateTime<Utc>) -> bool) -> usize {
+    let mut hours = 0;
+    for (arrival, departure) in place.visits.iter().map(|visit| visit_times(visit)) {
+        let mut time = arrival;
+        while time <= departure {
+            if predicate(time) {
+                hours += 1;
+            }
+            time += Duration::hours(1);
+        }
+    }
+    hours
+}
+
+fn distinct_days(place: &Place) -> BTreeSet<NaiveDate> {
+    place.visits.iter()
+        .map(|visit| visit_times(visit))
+        .map(|(arrival, _)| arrival.date_naive())
+        .collect()
+}
+
+// Most common value, preferring the smallest on ties
+pub(super) fn mode(values: impl Iterator<Item = u32>) -> Option<u32> {
+    let mut counts: BTreeMap<u32, usize> = BTreeMap::new();
+    for value in values {
+        *counts.entry(value).or_insert(0) += 1;
+    }
+    counts.into_iter()
+        .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+        .map(|(value, _)| value)
+}
+
+fn summarize_place(place: &Place, label: RoutineLabel) -> RoutineSummary {
+    let times: Vec<(DateTime<Utc>, DateTime<Utc>)> = place.visits.iter()
+        .map(|visit| visit_times(visit))
+        .collect();
+
+    let weekdays: BTreeSet<u32> = times.iter()
+        .map(|(arrival, _)| arrival.weekday().num_days_from_monday())
+        .collect();
+
+    RoutineSummary {
+        label,
+        lat: place.lat,
+        lon: place.lon,
+        visit_count: place.visits.len(),
+        distinct_days: distinct_days(place).len(),
+        days_of_week: weekdays.into_iter().map(weekday_name).collect(),
+        typical_arrival_hour: mode(times.iter().map(|(arrival, _)| arrival.hour())),
+        typical_departure_hour: mode(times.iter().map(|(_, departure)| departure.hour())),
+        total_duration_seconds: place.visits.iter().map(|visit| visit.duration_seconds).sum(),
+    }
+}
+
+fn weekday_name(days_from_monday: u32) -> String {
+    let weekday = Weekday::try_from(days_from_monday as u8).unwrap_or(Weekday::Mon);
+    weekday.to_string()
+}
+
+// Days on which the user left home and then arrived at work
+fn detect_commute(home: &Place, work: &Place) -> Option<CommuteSummary> {
+    let home_times: Vec<(DateTime<Utc>, DateTime<Utc>)> = home.visits.iter().map(|visit| visit_times(visit)).collect();
+    let work_times: Vec<(DateTime<Utc>, DateTime<Utc>)> = work.visits.iter().map(|visit| visit_times(visit)).collect();
+
+    let mut trips = Vec::new();
+    for &(arrival_at_work, _) in &work_times {
+        // Last departure from home earlier on the same day
+        let left_home = home_times.iter()
+            .map(|&(_, departure)| departure)
+            .filter(|departure| *departure < arrival_at_work && departure.date_naive() == arrival_at_work.date_naive())
+            .max();
+        if let Some(left_home) = left_home {
+            trips.push((left_home, arrival_at_work));
+        }
+    }
+
+    if trips.is_empty() {
+        return None;
+    }
+
+    let total_seconds: i64 = trips.iter().map(|(left, arrived)| (*arrived - *left).num_seconds()).sum();
+    Some(CommuteSummary {
+        days: trips.iter().map(|(left, _)| left.date_naive()).collect::<BTreeSet<_>>().len(),
+        typical_departure_hour: mode(trips.iter().map(|(left, _)| left.hour())),
+        typical_arrival_hour: mode(trips.iter().map(|(_, arrived)| arrived.hour())),
+        average_duration_seconds: Seconds(total_seconds / trips.len() as i64),
+    })
+}
@@ -0,0 +1,186 @@
+use chrono::{DateTime, Utc};
+use crate::models::{Coordinate, Location, LocationVisit, Meters, Seconds, TransportMode, Trip, UnitSystem};
+
+// Typical speeds (m/s) separating the modes
+const MAX_WALKING_SPEED: f64 = 2.5; // 9 km/h
+const MAX_CYCLING_SPEED: f64 = 7.0; // 25 km/h
+const MIN_RAIL_SPEED: f64 = 40.0; // 144 km/h, faster than road traffic
+
+// Variance of the accelerometer magnitude ((m/s²)²): feet shake the phone, vehicles barely do
+const MIN_WALKING_ACCEL_VARIANCE: f64 = 3.0;
+const MAX_VEHICLE_ACCEL_VARIANCE: f64 = 0.3;
+
+// Segments slower than this count as standing still
+const STOP_SPEED: f64 = 0.5;
+
+// Share of stopped segments above which a motorized trip is treated as transit
+const MIN_TRANSIT_STOP_SHARE: f64 = 0.25;
+
+// Segment speeds kept for the percentiles; longer trips keep an evenly spaced sample
+const MAX_SPEED_SAMPLES: usize = 4096;
+
+// Movement statistics of the points between two visits
+struct TripStats {
+    median_speed: f64,
+    high_speed: f64,
+    stop_share: f64,
+    accel_variance: Option<f64>,
+}
+
+// What trip detection reads from a location: no identifiers and no raw sensor readings
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TripPoint {
+    pub time: DateTime<Utc>,
+    pub coordinate: Coordinate,
+    pub accel_magnitude: Option<f64>,
+}
+
+impl From<&Location> for TripPoint {
+    fn from(location: &Location) -> Self {
+        let accel_magnitude = location.sensors.accelerometer.as_ref()
+            .filter(|axes| !axes.is_empty())
+            .map(|axes| axes.iter().map(|value| value * value).sum::<f64>().sqrt());
+        TripPoint { time: location.timestamp, coordinate: location.coordinate(), accel_magnitude }
+    }
+}
+
+// Movement between two visits, folded in point by point so it takes the same room however
+// long the trip is
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TripBuilder {
+    last: Option<TripPoint>,
+    point_count: u32,
+    distance_meters: Meters,
+    // Every speed_stride-th speed of the segments that took time
+    speeds: Vec<f64>,
+    speed_stride: usize,
+    timed_segments: usize,
+    stopped_segments: usize,
+    // Running mean and sum of squared deviations of the accelerometer magnitudes
+    accel_count: usize,
+    accel_mean: f64,
+    accel_m2: f64,
+}
+
+impl TripBuilder {
+    // Add the next point of the trip, in time order
+    pub fn push(&mut self, point: TripPoint) {
+        if let Some(last) = self.last {
+            let meters = last.coordinate.distance(point.coordinate);
+            self.distance_meters += meters;
+            let seconds = Seconds::from(point.time - last.time);
+            if seconds.0 > 0 {
+                self.push_speed(meters.per_second(seconds));
+            }
+        }
+        if let Some(magnitude) = point.accel_magnitude {
+            self.accel_count += 1;
+            let delta = magnitude - self.accel_mean;
+            self.accel_mean += delta / self.accel_count as f64;
+            self.accel_m2 += delta * (magnitude - self.accel_mean);
+        }
+        self.point_count += 1;
+        self.last = Some(point);
+    }
+
+    fn push_speed(&mut self, speed: f64) {
+        if speed < STOP_SPEED {
+            self.stopped_segments += 1;
+        }
+        let stride = self.speed_stride.max(1);
+        if self.timed_segments.is_multiple_of(stride) {
+            self.speeds.push(speed);
+        }
+        self.timed_segments += 1;
+
+        // Halve the sample and keep every other speed from now on
+        if self.speeds.len() > MAX_SPEED_SAMPLES {
+            let mut index = 0;
+            self.speeds.retain(|_| {
+                index += 1;
+                index % 2 == 1
+            });
+            self.speed_stride = stride * 2;
+        }
+    }
+
+    pub fn finish(&self, departure: DateTime<Utc>, arrival: DateTime<Utc>, units: UnitSystem) -> Trip {
+        let duration_seconds = Seconds::from(arrival - departure);
+        let average_speed = self.distance_meters.per_second(duration_seconds);
+
+        let mode = if self.speeds.is_empty() {
+            TransportMode::Unknown
+        } else {
+            let mut speeds = self.speeds.clone();
+            speeds.sort_by(|a, b| a.total_cmp(b));
+            classify(&TripStats {
+                median_speed: percentile(&speeds, 0.5),
+                high_speed: percentile(&speeds, 0.85),
+                stop_share: self.stopped_segments as f64 / self.timed_segments as f64,
+                accel_variance: self.accel_variance(),
+            })
+        };
+
+        Trip {
+            start_time: departure,
+            end_time: arrival,
+            duration_seconds,
+            point_count: self.point_count,
+            distance: units.distance(self.distance_meters),
+            average_speed: units.speed(average_speed),
+            mode,
+        }
+    }
+
+    // Population variance, if there are at least two samples
+    fn accel_variance(&self) -> Option<f64> {
+        (self.accel_count >= 2).then(|| self.accel_m2 / self.accel_count as f64)
+    }
+}
+
+// The trips between consecutive visits, each labelled with its most likely transport mode
+pub(crate) fn detect_trips(locations: &[Location], visits: &[LocationVisit], units: UnitSystem) -> Vec<Trip> {
+    visits.windows(2)
+        .map(|pair| {
+            let (departure, arrival) = (pair[0].departure_time, pair[1].arrival_time);
+            let mut trip = TripBuilder::default();
+            for point in locations.iter().filter(|point| point.timestamp >= departure && point.timestamp <= arrival) {
+                trip.push(TripPoint::from(point));
+            }
+            trip.finish(departure, arrival, units)
+        })
+        .collect()
+}
+
+fn classify(stats: &TripStats) -> TransportMode {
+    // Only trains keep up these speeds
+    if stats.high_speed >= MIN_RAIL_SPEED {
+        return TransportMode::Transit;
+    }
+
+    if stats.median_speed < MAX_WALKING_SPEED {
+        return TransportMode::Walking;
+    }
+
+    if stats.median_speed < MAX_CYCLING_SPEED {
+        // Slow but shaky is someone running, slow and smooth is a vehicle in traffic
+        return match stats.accel_variance {
+            Some(variance) if variance >= MIN_WALKING_ACCEL_VARIANCE => TransportMode::Walking,
+            Some(variance) if variance <= MAX_VEHICLE_ACCEL_VARIANCE => TransportMode::Driving,
+            _ => TransportMode::Cycling,
+        };
+    }
+
+    // Buses stop far more often than cars
+    if stats.stop_share >= MIN_TRANSIT_STOP_SHARE {
+        TransportMode::Transit
+    } else {
+        TransportMode::Driving
+    }
+}
+
+// Value at the given fraction of an ascending, non-empty slice
+fn percentile(sorted: &[f64], fraction: f64) -> f64 {
+    let index = ((sorted.len() - 1) as f64 * fraction).round() as usize;
+    sorted[index]
+}
@@ -1,15 +1,219 @@
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use actix_web::{http::header, web, App, HttpRequest, HttpResponse, HttpServer, Responder, ResponseError};
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::middleware::{from_fn, Next};
+use std::time::Instant;
 use actix_cors::Cors;
+use futures::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
 use oyster_rewards::{
-    Location, HeatmapRequest, VisitAnalyticsRequest,
-    register_location, get_location, generate_heatmap, generate_visit_analytics
+    Coordinate, Latitude, Longitude, Location, HeatmapRequest, HeatmapResponse, BoundingBox, HeatmapDiffRequest, TimeRange, VisitAnalyticsRequest, DailySummaryRequest,
+    AnonymousContributionRequest, ApiResponse, RoutinesRequest, AnomaliesRequest, PredictionsRequest, Poi, RewardDraw, RewardVrfKey, RewardDrawVerification, LeaderboardPeriod, RedeemRequest, RedemptionKey, BoostEventRequest, WebhookEndpointRequest, UserLocationsRequest, UserLocationsResponse, LocationRegistrationResponse, RetentionRequest,
+    register_location_from, get_location, list_user_locations, verify_user_token, contribute_anonymous,
+    generate_heatmap, generate_heatmap_diff, generate_adaptive_heatmap, heatmap_history, top_hotspots,
+    generate_visit_analytics, generate_daily_report, generate_routines, detect_anomalies, register_poi, aggregate, popular_routes,
+    predict_next_visits, quote_reward, claim_reward, verify_draw, discoveries, redeem, fulfill_redemption, active_events, list_events, create_event, update_event, delete_event, quest_statuses, leaderboard
 };
+use oyster_rewards::models::{schema, timestamp};
+use oyster_rewards::heatmap::PRIVACY_BUDGET;
+use oyster_rewards::heatmap::privacy::{address_requester, user_requester, PrivacyParams, ANONYMOUS_REQUESTER, BUDGET_EXHAUSTED_ERROR};
+use oyster_rewards::heatmap::adaptive::DEFAULT_ADAPTIVE_CELLS;
+use oyster_rewards::heatmap::live::subscribe;
+use oyster_rewards::store::{set_store, FileStore};
+use oyster_rewards::location::import::{import_locations, ImportFormat};
+use oyster_rewards::v2::{self, ApiError, ErrorCode, Page, V1_DEPRECATED_AT};
+use oyster_rewards::analytics::poi::{set_poi_provider, PoiDataset};
+use oyster_rewards::rewards::{set_reward_config, set_reward_engine, RewardConfig, RewardEngine, REWARD_ENGINE, vrf};
+use oyster_rewards::admin::{apply_retention, invalidate_caches, stats as admin_stats};
+use oyster_rewards::jobs::{job_statuses, run_job, scheduled_interval, set_interval, set_next_run, set_precompute_limit, set_retention_days, HEATMAP_SNAPSHOTS, JOBS};
+use oyster_rewards::rewards::vrf::VRF_CONTEXT;
+use oyster_rewards::rewards::redemption::{redemption_public_key, user_redemptions, REDEMPTION_CONTEXT};
+use oyster_rewards::rewards::ledger::{REWARD_LEDGER, DEFAULT_HISTORY_PAGE};
+use oyster_rewards::quests::{set_quest_config, QuestConfig};
+use oyster_rewards::leaderboard::DEFAULT_LEADERBOARD_SIZE;
+use oyster_rewards::metrics::{record_request, render as render_metrics, UNMATCHED_ROUTE};
+use oyster_rewards::webhooks::{delete_endpoint, list_endpoints, list_user_endpoints, register_endpoint, run_dispatcher};
+use oyster_rewards::grpc::serve as serve_grpc;
+use oyster_rewards::graphql::{build_schema, AuthenticatedUser, OrbitalmapSchema, RequesterId};
+use oyster_rewards::heatmap::stream::stream_heatmap;
+use oyster_rewards::heatmap::render::{render_png, Palette, RenderOptions};
+use oyster_rewards::heatmap::tiles::{encode_heatmap_tile, generate_tile};
+use oyster_rewards::heatmap::format::HeatmapFormat;
 
 // State to be shared across API handlers
 struct AppState {
     api_version: String,
+    anonymous_contributions_enabled: bool,
+    // Secret admin endpoints require in X-Admin-Token; they are disabled without one
+    admin_token: Option<String>,
+    // Secret user bearer tokens are signed with; owner-only endpoints are disabled without one
+    user_token_secret: Option<Vec<u8>>,
+    // HTTP date after which deprecated v1 routes may be removed, announced in their Sunset header
+    v1_sunset: Option<String>,
+}
+
+// v1 routes with a v2 successor at the same path under /api/v2
+const V2_SUCCESSORS: &[&str] = &[
+    "/api/v1/locations",
+    "/api/v1/users/{id}/locations",
+    "/api/v1/heatmap",
+    "/api/v1/rewards/history",
+];
+
+// Output format selection for the heatmap endpoint; without `format` the Accept header decides
+#[derive(Deserialize)]
+struct HeatmapFormatQuery {
+    format: Option<String>,
+    // Continuation token of a streamed (ndjson) heatmap
+    continuation: Option<String>,
+    // Image settings of the png format
+    width: Option<u32>,
+    height: Option<u32>,
+    palette: Option<Palette>,
+}
+
+// Query parameters for the rendered heatmap image
+#[derive(Deserialize)]
+struct HeatmapImageQuery {
+    // min_lon,min_lat,max_lon,max_lat
+    bbox: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    palette: Option<Palette>,
+    privacy_level: Option<f64>,
+}
+
+// Cell budget of an adaptive heatmap
+#[derive(Deserialize)]
+struct AdaptiveHeatmapQuery {
+    max_cells: Option<usize>,
+}
+
+// Query parameters for a historical heatmap snapshot
+#[derive(Deserialize)]
+struct HeatmapHistoryQuery {
+    // YYYY-MM-DD (UTC)
+    date: String,
+    // min_lon,min_lat,max_lon,max_lat
+    bbox: String,
+    privacy_level: Option<f64>,
+}
+
+// Query parameters for live heatmap updates
+#[derive(Deserialize)]
+struct LiveHeatmapQuery {
+    // min_lon,min_lat,max_lon,max_lat
+    bbox: String,
+    // Seconds between two pushed deltas
+    interval: Option<u64>,
+    privacy_level: Option<f64>,
+}
+
+// Allowed range of the live update interval, in seconds
+const MIN_LIVE_INTERVAL: u64 = 1;
+const MAX_LIVE_INTERVAL: u64 = 300;
+const DEFAULT_LIVE_INTERVAL: u64 = 10;
+
+// Query parameters for hotspot extraction
+#[derive(Deserialize)]
+struct HotspotsQuery {
+    // min_lon,min_lat,max_lon,max_lat
+    bbox: String,
+    n: Option<usize>,
+    // Optional time range, RFC3339 or Unix seconds
+    #[serde(default, with = "timestamp::option")]
+    start: Option<DateTime<Utc>>,
+    #[serde(default, with = "timestamp::option")]
+    end: Option<DateTime<Utc>>,
+    privacy_level: Option<f64>,
+}
+
+// Query parameters for popular routes
+#[derive(Deserialize)]
+struct RoutesQuery {
+    // min_lon,min_lat,max_lon,max_lat
+    bbox: String,
+    n: Option<usize>,
+    // Optional time range, RFC3339 or Unix seconds
+    #[serde(default, with = "timestamp::option")]
+    start: Option<DateTime<Utc>>,
+    #[serde(default, with = "timestamp::option")]
+    end: Option<DateTime<Utc>>,
+    privacy_level: Option<f64>,
+}
+
+// Query parameters for cross-user aggregate analytics
+#[derive(Deserialize)]
+struct AggregateQuery {
+    // min_lon,min_lat,max_lon,max_lat
+    bbox: String,
+    // Time range, RFC3339 or Unix seconds
+    #[serde(with = "timestamp")]
+    start: DateTime<Utc>,
+    #[serde(with = "timestamp")]
+    end: DateTime<Utc>,
+    privacy_level: Option<f64>,
+    epsilon: Option<f64>,
+}
+
+// Query parameters for a reward quote
+#[derive(Deserialize)]
+struct RewardQuoteQuery {
+    lat: Latitude,
+    lon: Longitude,
+}
+
+// Query parameters of a user's reward balance and history
+#[derive(Deserialize)]
+struct RewardAccountQuery {
+    user_id: String,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+// Query parameters of a user's location listing
+#[derive(Deserialize)]
+struct UserLocationsQuery {
+    #[serde(default, with = "timestamp::option")]
+    from: Option<DateTime<Utc>>,
+    #[serde(default, with = "timestamp::option")]
+    to: Option<DateTime<Utc>>,
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+// Query parameters of the token owner's reward history in v2
+#[derive(Deserialize)]
+struct LedgerPageQuery {
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+// Query parameters of a user's redemptions
+#[derive(Deserialize)]
+struct RedemptionsQuery {
+    user_id: String,
+}
+
+// Query parameters of the daily quests
+#[derive(Deserialize)]
+struct QuestsQuery {
+    user_id: Option<String>,
+}
+
+// Query parameters of the leaderboard
+#[derive(Deserialize)]
+struct LeaderboardQuery {
+    period: Option<LeaderboardPeriod>,
+    limit: Option<usize>,
+}
+
+// Query parameters for vector tiles
+#[derive(Deserialize)]
+struct TileQuery {
+    privacy_level: Option<f64>,
 }
 
 // API info response
@@ -28,6 +232,18 @@ async fn health_check() -> impl Responder {
     }))
 }
 
+// JSON Schema of the models, for clients to generate their types from
+async fn schema_handler() -> impl Responder {
+    HttpResponse::Ok().json(schema::document())
+}
+
+// Prometheus metrics
+async fn metrics_handler() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(render_metrics())
+}
+
 async fn get_api_info(data: web::Data<AppState>) -> impl Responder {
     let info = ApiInfo {
         version: data.api_version.clone(),
@@ -38,16 +254,47 @@ async fn get_api_info(data: web::Data<AppState>) -> impl Responder {
     HttpResponse::Ok().json(info)
 }
 
+// Register a location on behalf of the request's client, shared by both API versions
+fn register(http_req: &HttpRequest, location: Location) -> Result<v2::LocationRegistration, ApiError> {
+    // The socket's peer, not forwarding headers, which clients could spoof to dodge the Sybil checks
+    let client_ip = http_req.peer_addr().map(|addr| addr.ip().to_string());
+    register_location_from(location, client_ip.as_deref()).try_into()
+}
+
 async fn register_location_handler(
+    http_req: HttpRequest,
     location: web::Json<Location>,
 ) -> impl Responder {
-    match register_location(location.into_inner()) {
-        response if response.success => {
-            HttpResponse::Created().json(response)
-        },
-        response => {
-            HttpResponse::BadRequest().json(response)
-        }
+    match register(&http_req, location.into_inner()) {
+        Ok(registration) => HttpResponse::Created().json(LocationRegistrationResponse::from(registration)),
+        Err(error) => HttpResponse::BadRequest().json(LocationRegistrationResponse::from(error)),
+    }
+}
+
+async fn register_location_v2_handler(
+    http_req: HttpRequest,
+    location: web::Json<v2::Location>,
+) -> Result<HttpResponse, ApiError> {
+    let registration = register(&http_req, location.into_inner().into())?;
+    Ok(HttpResponse::Created().json(registration))
+}
+
+async fn anonymous_contribution_handler(
+    data: web::Data<AppState>,
+    contribution: web::Json<AnonymousContributionRequest>,
+) -> impl Responder {
+    if !data.anonymous_contributions_enabled {
+        return HttpResponse::Forbidden().json(ApiResponse {
+            success: false,
+            message: "Anonymous contributions are disabled".to_string(),
+        });
+    }
+    
+    let response = contribute_anonymous(&contribution);
+    if response.success {
+        HttpResponse::Accepted().json(response)
+    } else {
+        HttpResponse::BadRequest().json(response)
     }
 }
 
@@ -69,18 +316,1216 @@ async fn get_location_handler(
     }
 }
 
+// Identify whose privacy budget a heatmap query is charged to: the user of a valid bearer
+// token, else the address the connection came from
+fn requester_id(data: &AppState, http_req: &HttpRequest) -> String {
+    match authenticated_user(data, http_req) {
+        Ok(user_id) => user_requester(&user_id),
+        Err(_) => address_requester(http_req.peer_addr().map(|address| address.ip())),
+    }
+}
+
+// Whether the request carries the configured admin token
+fn is_admin(data: &AppState, http_req: &HttpRequest) -> bool {
+    let provided = http_req.headers()
+        .get("X-Admin-Token")
+        .and_then(|value| value.to_str().ok());
+    // Compare digests, so the comparison takes the same time wherever the tokens differ
+    matches!((&data.admin_token, provided),
+        (Some(expected), Some(provided)) if Sha256::digest(expected.as_bytes()) == Sha256::digest(provided.as_bytes()))
+}
+
+// Guards the admin scope: requests without the admin token never reach its handlers
+async fn require_admin(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let authorized = req.app_data::<web::Data<AppState>>()
+        .is_some_and(|data| is_admin(data, req.request()));
+    if !authorized {
+        let response = HttpResponse::Forbidden().json(ApiResponse {
+            success: false,
+            message: "Admin token required".to_string(),
+        });
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+// A v2 error in the v1 error body, with the same status
+fn v1_error(error: ApiError) -> HttpResponse {
+    HttpResponse::build(error.status_code()).json(ApiResponse::from(error))
+}
+
+// Mark responses of v1 routes that have a v2 successor as deprecated (RFC 9745), linking to
+// the successor and announcing the sunset when one is configured
+async fn deprecate_v1(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let sunset = req.app_data::<web::Data<AppState>>().and_then(|data| data.v1_sunset.clone());
+    let mut response = next.call(req).await?;
+    
+    let superseded = response.request().match_pattern()
+        .is_some_and(|pattern| V2_SUCCESSORS.contains(&pattern.as_str()));
+    if superseded {
+        let successor = response.request().path().replacen("/api/v1/", "/api/v2/", 1);
+        let headers = response.headers_mut();
+        if let Ok(value) = header::HeaderValue::from_str(&format!("@{}", V1_DEPRECATED_AT)) {
+            headers.insert(header::HeaderName::from_static("deprecation"), value);
+        }
+        if let Ok(value) = header::HeaderValue::from_str(&format!("<{}>; rel=\"successor-version\"", successor)) {
+            headers.insert(header::LINK, value);
+        }
+        if let Some(value) = sunset.and_then(|sunset| header::HeaderValue::from_str(&sunset).ok()) {
+            headers.insert(header::HeaderName::from_static("sunset"), value);
+        }
+    }
+    Ok(response)
+}
+
+// The user an `Authorization: Bearer` user token was issued for
+fn authenticated_user(data: &AppState, http_req: &HttpRequest) -> Result<String, String> {
+    let secret = data.user_token_secret.as_ref().ok_or("User tokens are not enabled on this server")?;
+    let token = http_req.headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or("User token required")?;
+    verify_user_token(secret, token.trim(), Utc::now())
+}
+
+// Entity tag of a representation, a hash of its content. Weak tags are derived from content
+// that leaves out fields which change per request without changing the data.
+fn entity_tag(content: &[u8], weak: bool) -> String {
+    let tag: String = Sha256::digest(content)[..16].iter().map(|byte| format!("{:02x}", byte)).collect();
+    if weak {
+        format!("W/\"{}\"", tag)
+    } else {
+        format!("\"{}\"", tag)
+    }
+}
+
+// Whether If-None-Match already names the entity tag (weak comparison)
+fn etag_matches(http_req: &HttpRequest, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    http_req.headers().get_all(header::IF_NONE_MATCH)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|candidate| candidate.trim() == "*" || opaque(candidate) == opaque(etag))
+}
+
+// The body with its ETag, or an empty 304 when the client already has it. Heatmap and
+// analytics POSTs are read-only queries, so they answer conditional requests like GETs.
+fn conditional_response(http_req: &HttpRequest, etag: String, content_type: &str, body: Vec<u8>) -> HttpResponse {
+    if etag_matches(http_req, &etag) {
+        return HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag))
+            .finish();
+    }
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((header::ETAG, etag))
+        .body(body)
+}
+
+fn conditional_json<T: Serialize>(http_req: &HttpRequest, value: &T) -> HttpResponse {
+    let body = serde_json::to_vec(value).unwrap_or_default();
+    conditional_response(http_req, entity_tag(&body, false), "application/json", body)
+}
+
+// Exhausted privacy budgets are rate limits, anything else is a bad request
+fn heatmap_error_status(error: &str) -> actix_web::HttpResponseBuilder {
+    if error.starts_with(BUDGET_EXHAUSTED_ERROR) {
+        HttpResponse::TooManyRequests()
+    } else {
+        HttpResponse::BadRequest()
+    }
+}
+
 async fn generate_heatmap_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    query: web::Query<HeatmapFormatQuery>,
+    req: web::Json<HeatmapRequest>,
+) -> impl Responder {
+    let mut request = req.into_inner();
+    request.requester_id = Some(requester_id(&data, &http_req));
+    
+    // An explicit `format` wins over the Accept header
+    let format = match query.format.as_deref() {
+        Some(name) => match HeatmapFormat::from_name(name) {
+            Ok(format) => format,
+            Err(error) => return HttpResponse::BadRequest().json(serde_json::json!({
+                "success": false,
+                "message": error
+            })),
+        },
+        None => match accepted_heatmap_format(&http_req) {
+            Some(format) => format,
+            None => return HttpResponse::NotAcceptable().json(serde_json::json!({
+                "success": false,
+                "message": "Heatmaps are available as application/json, application/geo+json, application/x-ndjson, image/png and application/vnd.mapbox-vector-tile"
+            })),
+        },
+    };
+    let defaults = RenderOptions::default();
+    let options = RenderOptions {
+        width: query.width.unwrap_or(defaults.width),
+        height: query.height.unwrap_or(defaults.height),
+        palette: query.palette.unwrap_or(defaults.palette),
+    };
+    
+    let mut response = heatmap_response(&http_req, &request, format, &options, query.continuation.as_deref());
+    response.headers_mut().insert(header::VARY, header::HeaderValue::from_static("Accept"));
+    response
+}
+
+// JSON heatmap with typed frame times; other formats stay on v1 until they move over
+async fn heatmap_v2_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    req: web::Json<HeatmapRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let mut request = req.into_inner();
+    request.requester_id = Some(requester_id(&data, &http_req));
+    let heatmap = v2::Heatmap::try_from(generate_heatmap(&request))?;
+    Ok(HttpResponse::Ok().json(heatmap))
+}
+
+// Heatmap format the Accept header asks for, JSON when there is none
+fn accepted_heatmap_format(http_req: &HttpRequest) -> Option<HeatmapFormat> {
+    match http_req.headers().get(header::ACCEPT).and_then(|value| value.to_str().ok()) {
+        Some(accept) if !accept.trim().is_empty() => HeatmapFormat::from_accept(accept),
+        _ => Some(HeatmapFormat::Json),
+    }
+}
+
+// Generate a heatmap and serialize it; every heatmap format goes through the same validation
+// and privacy accounting in generate_heatmap
+fn heatmap_response(http_req: &HttpRequest, request: &HeatmapRequest, format: HeatmapFormat, options: &RenderOptions, continuation: Option<&str>) -> HttpResponse {
+    if format == HeatmapFormat::Ndjson {
+        return stream_heatmap_response(request, continuation);
+    }
+    
+    let heatmap = generate_heatmap(request);
+    if let Some(error) = &heatmap.error {
+        return heatmap_error_status(error).json(heatmap);
+    }
+    
+    // A cached heatmap is re-served with fresh privacy accounting, so the JSON tag covers only
+    // the data; clients polling the same bbox get 304s until the heatmap itself changes
+    let (body, etag) = match format {
+        HeatmapFormat::GeoJson => {
+            let body = serde_json::to_vec(&heatmap.to_geojson()).unwrap_or_default();
+            let etag = entity_tag(&body, false);
+            (body, etag)
+        },
+        HeatmapFormat::Png => match render_png(&heatmap, options) {
+            Ok(image) => {
+                let etag = entity_tag(&image, false);
+                (image, etag)
+            },
+            Err(error) => return HttpResponse::BadRequest().json(serde_json::json!({
+                "success": false,
+                "message": error
+            })),
+        },
+        HeatmapFormat::Mvt => {
+            let body = encode_heatmap_tile(&heatmap);
+            let etag = entity_tag(&body, false);
+            (body, etag)
+        },
+        HeatmapFormat::Json | HeatmapFormat::Ndjson => {
+            let data = HeatmapResponse { epsilon_spent: 0.0, privacy_budget_remaining: None, ..heatmap.clone() };
+            let etag = entity_tag(&serde_json::to_vec(&data).unwrap_or_default(), true);
+            (serde_json::to_vec(&heatmap).unwrap_or_default(), etag)
+        },
+    };
+    conditional_response(http_req, etag, format.content_type(), body)
+}
+
+// Stream heatmap cells as newline-delimited JSON: one `{"cells": [...]}` line per batch,
+// then a trailer line with the continuation token and the privacy budget
+fn stream_heatmap_response(request: &HeatmapRequest, continuation: Option<&str>) -> HttpResponse {
+    let mut stream = match stream_heatmap(request, continuation) {
+        Ok(stream) => stream,
+        Err(error) => {
+            return heatmap_error_status(&error).json(serde_json::json!({
+                "success": false,
+                "message": error
+            }));
+        }
+    };
+    
+    // Produce batches on a blocking thread; the bounded channel keeps memory flat
+    let (mut sender, receiver) = futures::channel::mpsc::channel::<web::Bytes>(4);
+    tokio::task::spawn_blocking(move || {
+        let send_line = |sender: &mut futures::channel::mpsc::Sender<_>, value: serde_json::Value| {
+            let mut line = value.to_string();
+            line.push('\n');
+            futures::executor::block_on(sender.send(web::Bytes::from(line))).is_ok()
+        };
+        
+        for cells in stream.by_ref() {
+            // Stop early when the client went away
+            if !send_line(&mut sender, serde_json::json!({ "cells": cells })) {
+                return;
+            }
+        }
+        send_line(&mut sender, serde_json::json!({
+            "next_token": stream.next_token(),
+            "epsilon_spent": stream.epsilon_spent,
+            "privacy_budget_remaining": stream.privacy_budget_remaining,
+        }));
+    });
+    
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(receiver.map(Ok::<_, actix_web::Error>))
+}
+
+async fn adaptive_heatmap_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    query: web::Query<AdaptiveHeatmapQuery>,
     req: web::Json<HeatmapRequest>,
 ) -> impl Responder {
-    let heatmap = generate_heatmap(&req);
-    HttpResponse::Ok().json(heatmap)
+    let mut request = req.into_inner();
+    request.requester_id = Some(requester_id(&data, &http_req));
+    
+    let heatmap = generate_adaptive_heatmap(&request, query.max_cells.unwrap_or(DEFAULT_ADAPTIVE_CELLS));
+    match &heatmap.error {
+        Some(error) => heatmap_error_status(error).json(heatmap),
+        None => HttpResponse::Ok().json(heatmap),
+    }
+}
+
+async fn heatmap_diff_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    req: web::Json<HeatmapDiffRequest>,
+) -> impl Responder {
+    let mut diff_request = req.into_inner();
+    diff_request.heatmap.requester_id = Some(requester_id(&data, &http_req));
+    
+    let diff = generate_heatmap_diff(&diff_request.heatmap, &diff_request.period_a, &diff_request.period_b);
+    match &diff.error {
+        Some(error) => heatmap_error_status(error).json(diff),
+        None => HttpResponse::Ok().json(diff),
+    }
+}
+
+// Parse a "min_lon,min_lat,max_lon,max_lat" bounding box
+fn parse_bbox(bbox: &str) -> Result<BoundingBox, String> {
+    let error = "bbox must be four numbers: min_lon,min_lat,max_lon,max_lat".to_string();
+    let bounds: Vec<f64> = bbox.split(',')
+        .map(|v| v.trim().parse::<f64>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| error.clone())?;
+    let [min_lon, min_lat, max_lon, max_lat] = bounds[..] else {
+        return Err(error);
+    };
+    Ok(BoundingBox {
+        min_lat: Latitude::new(min_lat).map_err(|e| e.to_string())?,
+        min_lon: Longitude::new(min_lon).map_err(|e| e.to_string())?,
+        max_lat: Latitude::new(max_lat).map_err(|e| e.to_string())?,
+        max_lon: Longitude::new(max_lon).map_err(|e| e.to_string())?,
+    })
+}
+
+async fn heatmap_image_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    query: web::Query<HeatmapImageQuery>,
+) -> impl Responder {
+    let bounds = match parse_bbox(&query.bbox) {
+        Ok(bounds) => bounds,
+        Err(error) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "success": false,
+                "message": error
+            }));
+        }
+    };
+    
+    let request = HeatmapRequest {
+        min_lon: bounds.min_lon,
+        min_lat: bounds.min_lat,
+        max_lon: bounds.max_lon,
+        max_lat: bounds.max_lat,
+        privacy_level: query.privacy_level.unwrap_or(1.0),
+        time_range: None,
+        bucket: None,
+        epsilon: None,
+        delta: None,
+        sensitivity: None,
+        requester_id: Some(requester_id(&data, &http_req)),
+        seed: None,
+    };
+    
+    let defaults = RenderOptions::default();
+    let options = RenderOptions {
+        width: query.width.unwrap_or(defaults.width),
+        height: query.height.unwrap_or(defaults.height),
+        palette: query.palette.unwrap_or(defaults.palette),
+    };
+    heatmap_response(&http_req, &request, HeatmapFormat::Png, &options, None)
+}
+
+async fn heatmap_history_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    query: web::Query<HeatmapHistoryQuery>,
+) -> impl Responder {
+    let bounds = match parse_bbox(&query.bbox) {
+        Ok(bounds) => bounds,
+        Err(error) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "success": false,
+                "message": error
+            }));
+        }
+    };
+    
+    let request = HeatmapRequest {
+        min_lon: bounds.min_lon,
+        min_lat: bounds.min_lat,
+        max_lon: bounds.max_lon,
+        max_lat: bounds.max_lat,
+        privacy_level: query.privacy_level.unwrap_or(1.0),
+        time_range: None,
+        bucket: None,
+        epsilon: None,
+        delta: None,
+        sensitivity: None,
+        requester_id: Some(requester_id(&data, &http_req)),
+        seed: None,
+    };
+    
+    let heatmap = heatmap_history(&query.date, &request);
+    match &heatmap.error {
+        Some(error) => heatmap_error_status(error).json(heatmap),
+        None => HttpResponse::Ok().json(heatmap),
+    }
+}
+
+// Server-sent events with the noisy cell deltas of new registrations inside bbox
+async fn live_heatmap_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    query: web::Query<LiveHeatmapQuery>,
+) -> impl Responder {
+    let bounds = match parse_bbox(&query.bbox) {
+        Ok(bounds) => bounds,
+        Err(error) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "success": false,
+                "message": error
+            }));
+        }
+    };
+    
+    let request = HeatmapRequest {
+        min_lon: bounds.min_lon,
+        min_lat: bounds.min_lat,
+        max_lon: bounds.max_lon,
+        max_lat: bounds.max_lat,
+        privacy_level: query.privacy_level.unwrap_or(1.0),
+        time_range: None,
+        bucket: None,
+        epsilon: None,
+        delta: None,
+        sensitivity: None,
+        requester_id: Some(requester_id(&data, &http_req)),
+        seed: None,
+    };
+    
+    let subscription = match subscribe(&request) {
+        Ok(subscription) => subscription,
+        Err(error) => {
+            return heatmap_error_status(&error).json(serde_json::json!({
+                "success": false,
+                "message": error
+            }));
+        }
+    };
+    
+    // Announce the budget charge first, then one delta event (or keep-alive) per interval
+    let opening = format!("event: subscribed\ndata: {}\n\n", serde_json::json!({
+        "epsilon_spent": subscription.epsilon_spent,
+        "privacy_budget_remaining": subscription.privacy_budget_remaining,
+    }));
+    let interval = query.interval.unwrap_or(DEFAULT_LIVE_INTERVAL).clamp(MIN_LIVE_INTERVAL, MAX_LIVE_INTERVAL);
+    let mut ticker = actix_web::rt::time::interval(std::time::Duration::from_secs(interval));
+    ticker.reset();
+    
+    let updates = futures::stream::unfold((subscription, ticker), |(mut subscription, mut ticker)| async move {
+        ticker.tick().await;
+        let cells = subscription.flush();
+        let event = if cells.is_empty() {
+            ": keep-alive\n\n".to_string()
+        } else {
+            format!("event: delta\ndata: {}\n\n", serde_json::json!({ "cells": cells }))
+        };
+        Some((Ok::<_, actix_web::Error>(web::Bytes::from(event)), (subscription, ticker)))
+    });
+    let events = futures::stream::once(async move { Ok::<_, actix_web::Error>(web::Bytes::from(opening)) })
+        .chain(updates);
+    
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events)
+}
+
+async fn hotspots_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    query: web::Query<HotspotsQuery>,
+) -> impl Responder {
+    let bounds = match parse_bbox(&query.bbox) {
+        Ok(bounds) => bounds,
+        Err(error) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "success": false,
+                "message": error
+            }));
+        }
+    };
+    let time_range = match (&query.start, &query.end) {
+        (Some(start), Some(end)) => Some(TimeRange { start: *start, end: *end }),
+        (None, None) => None,
+        _ => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "success": false,
+                "message": "start and end must be given together"
+            }));
+        }
+    };
+    
+    let request = HeatmapRequest {
+        min_lon: bounds.min_lon,
+        min_lat: bounds.min_lat,
+        max_lon: bounds.max_lon,
+        max_lat: bounds.max_lat,
+        privacy_level: query.privacy_level.unwrap_or(1.0),
+        time_range,
+        bucket: None,
+        epsilon: None,
+        delta: None,
+        sensitivity: None,
+        requester_id: Some(requester_id(&data, &http_req)),
+        seed: None,
+    };
+    
+    let hotspots = top_hotspots(&request, query.n.unwrap_or(10));
+    match &hotspots.error {
+        Some(error) => heatmap_error_status(error).json(hotspots),
+        None => HttpResponse::Ok().json(hotspots),
+    }
+}
+
+async fn heatmap_tile_handler(
+    http_req: HttpRequest,
+    path: web::Path<(u32, u32, u32)>,
+    query: web::Query<TileQuery>,
+) -> impl Responder {
+    let (z, x, y) = path.into_inner();
+    
+    match generate_tile(z, x, y, query.privacy_level.unwrap_or(1.0)) {
+        Ok(tile) => {
+            let etag = entity_tag(&tile, false);
+            let mut response = conditional_response(&http_req, etag, "application/vnd.mapbox-vector-tile", tile);
+            response.headers_mut().insert(header::CACHE_CONTROL, header::HeaderValue::from_static("public, max-age=60"));
+            response
+        },
+        Err(error) => heatmap_error_status(&error).json(serde_json::json!({
+            "success": false,
+            "message": error
+        })),
+    }
 }
 
 async fn generate_analytics_handler(
+    http_req: HttpRequest,
     req: web::Json<VisitAnalyticsRequest>,
 ) -> impl Responder {
     let analytics = generate_visit_analytics(&req);
-    HttpResponse::Ok().json(analytics)
+    conditional_json(&http_req, &analytics)
+}
+
+async fn routines_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    query: web::Query<RoutinesRequest>,
+) -> impl Responder {
+    if let Err(error) = require_owner(&data, &http_req, &query.user_id, "Routines can only be read by their owner") {
+        return v1_error(error);
+    }
+    let routines = generate_routines(&query);
+    if routines.error.is_some() {
+        HttpResponse::BadRequest().json(routines)
+    } else {
+        conditional_json(&http_req, &routines)
+    }
+}
+
+async fn register_poi_handler(
+    poi: web::Json<Poi>,
+) -> impl Responder {
+    match register_poi(poi.into_inner()) {
+        Ok(()) => HttpResponse::Created().json(ApiResponse {
+            success: true,
+            message: "POI registered".to_string(),
+        }),
+        Err(error) => HttpResponse::BadRequest().json(ApiResponse {
+            success: false,
+            message: error,
+        }),
+    }
+}
+
+async fn reward_quote_handler(
+    query: web::Query<RewardQuoteQuery>,
+) -> impl Responder {
+    HttpResponse::Ok().json(quote_reward(Coordinate::new(query.lat, query.lon)))
+}
+
+async fn reward_claim_handler(
+    http_req: HttpRequest,
+    location: web::Json<Location>,
+) -> impl Responder {
+    let client_ip = http_req.peer_addr().map(|addr| addr.ip().to_string());
+    let response = claim_reward(&location, client_ip.as_deref());
+    if response.success {
+        HttpResponse::Ok().json(response)
+    } else if response.cooldown_seconds > 0 {
+        HttpResponse::TooManyRequests().json(response)
+    } else {
+        HttpResponse::BadRequest().json(response)
+    }
+}
+
+async fn reward_vrf_key_handler() -> impl Responder {
+    HttpResponse::Ok().json(RewardVrfKey {
+        public_key: REWARD_ENGINE.lock().unwrap().vrf_public_key(),
+        context: String::from_utf8_lossy(VRF_CONTEXT).into_owned(),
+    })
+}
+
+async fn reward_verify_handler(
+    draw: web::Json<RewardDraw>,
+) -> impl Responder {
+    let verification = match verify_draw(&draw) {
+        Ok(roll) => RewardDrawVerification { valid: true, roll: Some(roll), error: None },
+        Err(error) => RewardDrawVerification { valid: false, roll: None, error: Some(error) },
+    };
+    HttpResponse::Ok().json(verification)
+}
+
+async fn reward_balance_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    query: web::Query<RewardAccountQuery>,
+) -> impl Responder {
+    if let Err(error) = require_owner(&data, &http_req, &query.user_id, "Balances can only be read by their owner") {
+        return v1_error(error);
+    }
+    let balance = REWARD_LEDGER.lock().unwrap().balance(&query.user_id);
+    HttpResponse::Ok().json(balance)
+}
+
+// Check that the bearer token belongs to the user a resource is owned by
+fn require_owner(data: &AppState, http_req: &HttpRequest, user_id: &str, forbidden: &str) -> Result<(), ApiError> {
+    match authenticated_user(data, http_req) {
+        Err(error) => Err(ApiError::new(ErrorCode::Unauthorized, error)),
+        Ok(owner) if owner != user_id => Err(ApiError::new(ErrorCode::Forbidden, forbidden)),
+        Ok(_) => Ok(()),
+    }
+}
+
+// A page of the user's stored locations, for the token owner only; shared by both API versions
+fn owned_locations(data: &AppState, http_req: &HttpRequest, user_id: String, query: UserLocationsQuery) -> Result<UserLocationsResponse, ApiError> {
+    require_owner(data, http_req, &user_id, "Locations can only be listed by their owner")?;
+    
+    let request = UserLocationsRequest {
+        user_id,
+        from: query.from,
+        to: query.to,
+        cursor: query.cursor,
+        limit: query.limit,
+    };
+    list_user_locations(&request).map_err(|error| ApiError::new(ErrorCode::InvalidRequest, error))
+}
+
+async fn user_locations_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<UserLocationsQuery>,
+) -> impl Responder {
+    match owned_locations(&data, &http_req, path.into_inner(), query.into_inner()) {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(error) => v1_error(error),
+    }
+}
+
+async fn user_locations_v2_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<UserLocationsQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let page = owned_locations(&data, &http_req, path.into_inner(), query.into_inner())?;
+    Ok(HttpResponse::Ok().json(Page::<v2::StoredLocation>::from(page)))
+}
+
+#[derive(Debug, Deserialize)]
+struct LocationImportQuery {
+    // gpx or csv; defaults to the Content-Type, then to sniffing the upload
+    format: Option<String>,
+    device_id: Option<String>,
+}
+
+// Largest GPX or CSV upload accepted
+const MAX_IMPORT_BYTES: usize = 16 * 1024 * 1024;
+
+// Seed the token owner's history from a GPX or CSV export of another tracking app
+async fn import_locations_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    query: web::Query<LocationImportQuery>,
+    body: web::Bytes,
+) -> impl Responder {
+    let user_id = match authenticated_user(&data, &http_req) {
+        Ok(user_id) => user_id,
+        Err(error) => return HttpResponse::Unauthorized().json(ApiResponse {
+            success: false,
+            message: error,
+        }),
+    };
+    
+    let upload = match String::from_utf8(body.to_vec()) {
+        Ok(upload) => upload,
+        Err(_) => return HttpResponse::BadRequest().json(ApiResponse {
+            success: false,
+            message: "Uploads must be UTF-8 text".to_string(),
+        }),
+    };
+    let content_type = http_req.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+    let format = match query.format.as_deref() {
+        Some(name) => match ImportFormat::from_name(name) {
+            Ok(format) => format,
+            Err(error) => return HttpResponse::BadRequest().json(ApiResponse {
+                success: false,
+                message: error,
+            }),
+        },
+        None => content_type
+            .and_then(ImportFormat::from_content_type)
+            .unwrap_or_else(|| ImportFormat::sniff(&upload)),
+    };
+    let device_id = query.device_id.clone().unwrap_or_else(|| "import".to_string());
+    
+    match web::block(move || import_locations(&user_id, &device_id, format, &upload, Utc::now())).await {
+        Ok(Ok(response)) => HttpResponse::Ok().json(response),
+        Ok(Err(error)) => HttpResponse::BadRequest().json(ApiResponse {
+            success: false,
+            message: error,
+        }),
+        Err(error) => HttpResponse::InternalServerError().json(ApiResponse {
+            success: false,
+            message: error.to_string(),
+        }),
+    }
+}
+
+// Composed queries over locations, visits, heatmaps and rewards; `me` needs a user token
+async fn graphql_handler(
+    data: web::Data<AppState>,
+    schema: web::Data<OrbitalmapSchema>,
+    http_req: HttpRequest,
+    request: web::Json<async_graphql::Request>,
+) -> impl Responder {
+    let request = request.into_inner()
+        .data(AuthenticatedUser(authenticated_user(&data, &http_req).ok()))
+        .data(RequesterId(Some(requester_id(&data, &http_req))));
+    HttpResponse::Ok().json(schema.execute(request).await)
+}
+
+async fn reward_history_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    query: web::Query<RewardAccountQuery>,
+) -> impl Responder {
+    if let Err(error) = require_owner(&data, &http_req, &query.user_id, "Reward history can only be read by its owner") {
+        return v1_error(error);
+    }
+    let history = REWARD_LEDGER.lock().unwrap().history(
+        &query.user_id,
+        query.offset.unwrap_or(0),
+        query.limit.unwrap_or(DEFAULT_HISTORY_PAGE),
+    );
+    HttpResponse::Ok().json(history)
+}
+
+// The token owner's ledger, newest first
+async fn reward_history_v2_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    query: web::Query<LedgerPageQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = authenticated_user(&data, &http_req)
+        .map_err(|error| ApiError::new(ErrorCode::Unauthorized, error))?;
+    let offset = v2::offset_cursor(query.cursor.as_deref())?;
+    let history = REWARD_LEDGER.lock().unwrap().history(&user_id, offset, query.limit.unwrap_or(DEFAULT_HISTORY_PAGE));
+    Ok(HttpResponse::Ok().json(Page::<v2::LedgerEntry>::from(history)))
+}
+
+async fn discoveries_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    query: web::Query<RewardAccountQuery>,
+) -> impl Responder {
+    if let Err(error) = require_owner(&data, &http_req, &query.user_id, "Discoveries can only be listed by their owner") {
+        return v1_error(error);
+    }
+    HttpResponse::Ok().json(discoveries(
+        &query.user_id,
+        query.offset.unwrap_or(0),
+        query.limit.unwrap_or(DEFAULT_HISTORY_PAGE),
+    ))
+}
+
+async fn redeem_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    request: web::Json<RedeemRequest>,
+) -> impl Responder {
+    if let Err(error) = require_owner(&data, &http_req, &request.user_id, "Balances can only be redeemed by their owner") {
+        return v1_error(error);
+    }
+    match redeem(&request) {
+        Ok(response) => HttpResponse::Created().json(response),
+        Err(error) => HttpResponse::BadRequest().json(ApiResponse {
+            success: false,
+            message: error,
+        }),
+    }
+}
+
+async fn redemptions_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    query: web::Query<RedemptionsQuery>,
+) -> impl Responder {
+    match require_owner(&data, &http_req, &query.user_id, "Redemptions can only be listed by their owner") {
+        Ok(()) => HttpResponse::Ok().json(user_redemptions(&query.user_id)),
+        Err(error) => v1_error(error),
+    }
+}
+
+async fn redemption_key_handler() -> impl Responder {
+    HttpResponse::Ok().json(RedemptionKey {
+        public_key: redemption_public_key(),
+        context: String::from_utf8_lossy(REDEMPTION_CONTEXT).into_owned(),
+    })
+}
+
+async fn fulfill_redemption_handler(
+    path: web::Path<String>,
+) -> impl Responder {
+    match fulfill_redemption(&path) {
+        Ok(redemption) => HttpResponse::Ok().json(redemption),
+        Err(error) => HttpResponse::Conflict().json(ApiResponse {
+            success: false,
+            message: error,
+        }),
+    }
+}
+
+async fn active_events_handler() -> impl Responder {
+    HttpResponse::Ok().json(active_events(Utc::now()))
+}
+
+async fn list_events_handler() -> impl Responder {
+    HttpResponse::Ok().json(list_events(Utc::now()))
+}
+
+async fn create_event_handler(
+    request: web::Json<BoostEventRequest>,
+) -> impl Responder {
+    match create_event(&request) {
+        Ok(event) => HttpResponse::Created().json(event),
+        Err(error) => HttpResponse::BadRequest().json(ApiResponse {
+            success: false,
+            message: error,
+        }),
+    }
+}
+
+async fn update_event_handler(
+    path: web::Path<String>,
+    request: web::Json<BoostEventRequest>,
+) -> impl Responder {
+    match update_event(&path, &request) {
+        Ok(event) => HttpResponse::Ok().json(event),
+        Err(error) if error.ends_with("not found") => HttpResponse::NotFound().json(ApiResponse {
+            success: false,
+            message: error,
+        }),
+        Err(error) => HttpResponse::BadRequest().json(ApiResponse {
+            success: false,
+            message: error,
+        }),
+    }
+}
+
+async fn delete_event_handler(
+    path: web::Path<String>,
+) -> impl Responder {
+    match delete_event(&path) {
+        Ok(()) => HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            message: "Event deleted".to_string(),
+        }),
+        Err(error) => HttpResponse::NotFound().json(ApiResponse {
+            success: false,
+            message: error,
+        }),
+    }
+}
+
+async fn admin_stats_handler() -> impl Responder {
+    HttpResponse::Ok().json(admin_stats(Utc::now()))
+}
+
+async fn retention_cleanup_handler(
+    request: web::Json<RetentionRequest>,
+) -> impl Responder {
+    match apply_retention(&request, Utc::now()) {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(error) => HttpResponse::BadRequest().json(ApiResponse {
+            success: false,
+            message: error,
+        }),
+    }
+}
+
+async fn reward_config_handler() -> impl Responder {
+    HttpResponse::Ok().json(REWARD_ENGINE.lock().unwrap().config().clone())
+}
+
+async fn update_reward_config_handler(
+    request: web::Json<RewardConfig>,
+) -> impl Responder {
+    match set_reward_config(request.into_inner()) {
+        Ok(config) => HttpResponse::Ok().json(config),
+        Err(error) => HttpResponse::BadRequest().json(ApiResponse {
+            success: false,
+            message: error,
+        }),
+    }
+}
+
+async fn invalidate_cache_handler() -> impl Responder {
+    HttpResponse::Ok().json(invalidate_caches())
+}
+
+async fn jobs_handler() -> impl Responder {
+    HttpResponse::Ok().json(job_statuses())
+}
+
+async fn run_job_handler(
+    path: web::Path<String>,
+) -> impl Responder {
+    let name = path.into_inner();
+    match web::block(move || run_job(&name, Utc::now())).await {
+        Ok(Ok(status)) => HttpResponse::Ok().json(status),
+        Ok(Err(error)) if error.ends_with("not found") => HttpResponse::NotFound().json(ApiResponse {
+            success: false,
+            message: error,
+        }),
+        Ok(Err(error)) => HttpResponse::Conflict().json(ApiResponse {
+            success: false,
+            message: error,
+        }),
+        Err(error) => HttpResponse::InternalServerError().json(ApiResponse {
+            success: false,
+            message: error.to_string(),
+        }),
+    }
+}
+
+// Run every enabled background job on its own interval, one run at a time per job
+fn start_scheduler() {
+    for job in JOBS {
+        let Some(interval) = scheduled_interval(job.name) else {
+            log::info!("Background job {} is disabled", job.name);
+            continue;
+        };
+        let period = std::time::Duration::from_secs(interval);
+        actix_web::rt::spawn(async move {
+            loop {
+                set_next_run(job.name, Utc::now() + chrono::Duration::seconds(interval as i64));
+                actix_web::rt::time::sleep(period).await;
+                match web::block(move || run_job(job.name, Utc::now())).await {
+                    Ok(Ok(status)) => match status.last_error {
+                        Some(error) => log::error!("Background job {} failed: {}", job.name, error),
+                        None => log::debug!("Background job {}: {}", job.name, status.last_result.unwrap_or_default()),
+                    },
+                    // Still running from a manual trigger
+                    Ok(Err(error)) => log::warn!("{}", error),
+                    Err(error) => log::error!("Background job {} panicked: {}", job.name, error),
+                }
+            }
+        });
+    }
+}
+
+async fn register_webhook_handler(
+    request: web::Json<WebhookEndpointRequest>,
+) -> impl Responder {
+    match register_endpoint(&request, None).await {
+        Ok(registration) => HttpResponse::Created().json(registration),
+        Err(error) => HttpResponse::BadRequest().json(ApiResponse {
+            success: false,
+            message: error,
+        }),
+    }
+}
+
+async fn list_webhooks_handler() -> impl Responder {
+    HttpResponse::Ok().json(list_endpoints())
+}
+
+async fn delete_webhook_handler(
+    path: web::Path<String>,
+) -> impl Responder {
+    match delete_endpoint(&path, None) {
+        Ok(()) => HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            message: "Webhook deleted".to_string(),
+        }),
+        Err(error) => HttpResponse::NotFound().json(ApiResponse {
+            success: false,
+            message: error,
+        }),
+    }
+}
+
+// Callbacks a user registers for their own events, e.g. visit_completed for automatic check-ins
+async fn register_user_webhook_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+    request: web::Json<WebhookEndpointRequest>,
+) -> impl Responder {
+    if let Err(error) = require_owner(&data, &http_req, &path, "Webhooks can only be registered by their owner") {
+        return v1_error(error);
+    }
+    match register_endpoint(&request, Some(&path)).await {
+        Ok(registration) => HttpResponse::Created().json(registration),
+        Err(error) => HttpResponse::BadRequest().json(ApiResponse {
+            success: false,
+            message: error,
+        }),
+    }
+}
+
+async fn list_user_webhooks_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    path: web::Path<String>,
+) -> impl Responder {
+    match require_owner(&data, &http_req, &path, "Webhooks can only be listed by their owner") {
+        Ok(()) => HttpResponse::Ok().json(list_user_endpoints(&path)),
+        Err(error) => v1_error(error),
+    }
+}
+
+async fn delete_user_webhook_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (user_id, id) = path.into_inner();
+    if let Err(error) = require_owner(&data, &http_req, &user_id, "Webhooks can only be deleted by their owner") {
+        return v1_error(error);
+    }
+    match delete_endpoint(&id, Some(&user_id)) {
+        Ok(()) => HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            message: "Webhook deleted".to_string(),
+        }),
+        Err(error) => HttpResponse::NotFound().json(ApiResponse {
+            success: false,
+            message: error,
+        }),
+    }
+}
+
+async fn quests_handler(
+    query: web::Query<QuestsQuery>,
+) -> impl Responder {
+    HttpResponse::Ok().json(quest_statuses(query.user_id.as_deref()))
+}
+
+// With a user token, the board also shows where the token's user stands; their handle is
+// never handed out for any other user
+async fn leaderboard_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    query: web::Query<LeaderboardQuery>,
+) -> impl Responder {
+    let user_id = authenticated_user(&data, &http_req).ok();
+    let board = leaderboard(
+        query.period.unwrap_or(LeaderboardPeriod::Week),
+        query.limit.unwrap_or(DEFAULT_LEADERBOARD_SIZE),
+        user_id.as_deref(),
+    );
+    HttpResponse::Ok().json(board)
+}
+
+async fn popular_routes_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    query: web::Query<RoutesQuery>,
+) -> impl Responder {
+    let bounds = match parse_bbox(&query.bbox) {
+        Ok(bounds) => bounds,
+        Err(error) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "success": false,
+                "message": error
+            }));
+        }
+    };
+    let time_range = match (&query.start, &query.end) {
+        (Some(start), Some(end)) => Some(TimeRange { start: *start, end: *end }),
+        (None, None) => None,
+        _ => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "success": false,
+                "message": "start and end must be given together"
+            }));
+        }
+    };
+    
+    let request = HeatmapRequest {
+        min_lon: bounds.min_lon,
+        min_lat: bounds.min_lat,
+        max_lon: bounds.max_lon,
+        max_lat: bounds.max_lat,
+        privacy_level: query.privacy_level.unwrap_or(1.0),
+        time_range,
+        bucket: None,
+        epsilon: None,
+        delta: None,
+        sensitivity: None,
+        requester_id: Some(requester_id(&data, &http_req)),
+        seed: None,
+    };
+    
+    let routes = popular_routes(&request, query.n.unwrap_or(10));
+    match &routes.error {
+        Some(error) => heatmap_error_status(error).json(routes),
+        None => HttpResponse::Ok().json(routes),
+    }
+}
+
+async fn aggregate_analytics_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    query: web::Query<AggregateQuery>,
+) -> impl Responder {
+    let bounds = match parse_bbox(&query.bbox) {
+        Ok(bounds) => bounds,
+        Err(error) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "success": false,
+                "message": error
+            }));
+        }
+    };
+    
+    // Resolve epsilon the same way heatmap requests do
+    let request = HeatmapRequest {
+        min_lon: bounds.min_lon,
+        min_lat: bounds.min_lat,
+        max_lon: bounds.max_lon,
+        max_lat: bounds.max_lat,
+        privacy_level: query.privacy_level.unwrap_or(1.0),
+        time_range: None,
+        bucket: None,
+        epsilon: query.epsilon,
+        delta: None,
+        sensitivity: None,
+        requester_id: Some(requester_id(&data, &http_req)),
+        seed: None,
+    };
+    let params = match PrivacyParams::from_request(&request) {
+        Ok(params) => params,
+        Err(error) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "success": false,
+                "message": error
+            }));
+        }
+    };
+    
+    let time_range = TimeRange { start: query.start, end: query.end };
+    let requester = request.requester_id.as_deref().unwrap_or(ANONYMOUS_REQUESTER);
+    let stats = aggregate(&BoundingBox::from_request(&request), &time_range, &params, requester);
+    match &stats.error {
+        Some(error) => heatmap_error_status(error).json(stats),
+        None => HttpResponse::Ok().json(stats),
+    }
+}
+
+async fn anomalies_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    query: web::Query<AnomaliesRequest>,
+) -> impl Responder {
+    if let Err(error) = require_owner(&data, &http_req, &query.user_id, "Anomalies can only be read by their owner") {
+        return v1_error(error);
+    }
+    let anomalies = detect_anomalies(&query);
+    if anomalies.error.is_some() {
+        HttpResponse::BadRequest().json(anomalies)
+    } else {
+        conditional_json(&http_req, &anomalies)
+    }
+}
+
+async fn predictions_handler(
+    data: web::Data<AppState>,
+    http_req: HttpRequest,
+    query: web::Query<PredictionsRequest>,
+) -> impl Responder {
+    if let Err(error) = require_owner(&data, &http_req, &query.user_id, "Predictions can only be read by their owner") {
+        return v1_error(error);
+    }
+    let predictions = predict_next_visits(&query);
+    if predictions.error.is_some() {
+        HttpResponse::BadRequest().json(predictions)
+    } else {
+        conditional_json(&http_req, &predictions)
+    }
+}
+
+async fn daily_summary_handler(
+    http_req: HttpRequest,
+    req: web::Json<DailySummaryRequest>,
+) -> impl Responder {
+    let report = generate_daily_report(&req);
+    if report.error.is_some() {
+        HttpResponse::BadRequest().json(report)
+    } else {
+        conditional_json(&http_req, &report)
+    }
 }
 
 #[actix_web::main]
@@ -96,12 +1541,121 @@ async fn main() -> std::io::Result<()> {
         .parse::<u16>()
         .expect("PORT must be a number");
     
+    // Anonymous heatmap-only contributions can be turned off per deployment
+    let anonymous_contributions_enabled = std::env::var("ANONYMOUS_CONTRIBUTIONS")
+        .map(|value| value != "false" && value != "0")
+        .unwrap_or(true);
+    
+    // Secret for admin endpoints such as fulfilling redemptions
+    let admin_token = std::env::var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty());
+    
+    // Secret shared with the identity service that issues user bearer tokens
+    let user_token_secret = std::env::var("USER_TOKEN_SECRET").ok()
+        .filter(|secret| !secret.is_empty())
+        .map(String::into_bytes);
+    
+    // Removal date of deprecated v1 routes, as RFC 3339
+    let v1_sunset = std::env::var("API_V1_SUNSET").ok().map(|sunset| {
+        chrono::DateTime::parse_from_rfc3339(&sunset)
+            .expect("API_V1_SUNSET must be an RFC 3339 date")
+            .with_timezone(&Utc)
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string()
+    });
+    
+    // Epsilon each requester may spend on heatmap queries per day
+    if let Ok(budget) = std::env::var("PRIVACY_EPSILON_BUDGET") {
+        let budget = budget.parse::<f64>().expect("PRIVACY_EPSILON_BUDGET must be a number");
+        PRIVACY_BUDGET.lock().unwrap().set_limit(budget);
+    }
+    
+    // Persist heatmap snapshots to disk when a store directory is configured
+    if let Ok(dir) = std::env::var("STORE_DIR") {
+        match FileStore::new(&dir) {
+            Ok(store) => set_store(Box::new(store)),
+            Err(error) => panic!("Cannot open store at {}: {}", dir, error),
+        }
+    }
+    
+    // Label visits with places from an offline POI dataset when one is configured
+    if let Ok(path) = std::env::var("POI_DATASET") {
+        match PoiDataset::from_file(&path) {
+            Ok(dataset) => set_poi_provider(Box::new(dataset)),
+            Err(error) => panic!("Cannot load POI dataset {}: {}", path, error),
+        }
+    }
+    
+    // Reward tier curve and, for reproducible payouts, the seed of the VRF key behind bonus draws
+    let reward_config = match std::env::var("REWARD_TIERS") {
+        Ok(path) => RewardConfig::from_file(&path)
+            .unwrap_or_else(|error| panic!("Cannot load reward tiers {}: {}", path, error)),
+        Err(_) => RewardConfig::default(),
+    };
+    let reward_seed = std::env::var("REWARD_SEED")
+        .ok()
+        .map(|seed| vrf::parse_seed(&seed).unwrap_or_else(|error| panic!("Invalid REWARD_SEED: {}", error)));
+    set_reward_engine(RewardEngine::new(reward_config, reward_seed));
+    
+    // Daily quests of the current campaign
+    if let Ok(path) = std::env::var("QUESTS") {
+        match QuestConfig::from_file(&path) {
+            Ok(config) => set_quest_config(config),
+            Err(error) => panic!("Cannot load quests {}: {}", path, error),
+        }
+    }
+    
+    // Background job schedule: <JOB>_INTERVAL_SECS per job, 0 disables scheduled runs
+    for job in JOBS {
+        let variable = match job.name {
+            HEATMAP_SNAPSHOTS => "HEATMAP_SNAPSHOT_INTERVAL_SECS".to_string(),
+            name => format!("{}_INTERVAL_SECS", name.to_uppercase()),
+        };
+        if let Ok(interval) = std::env::var(&variable) {
+            let interval = interval.parse::<u64>().unwrap_or_else(|_| panic!("{} must be a number", variable));
+            set_interval(job.name, interval).unwrap();
+        }
+    }
+    // Stored locations are only cleaned up on a schedule when a retention period is set
+    if let Ok(days) = std::env::var("LOCATION_RETENTION_DAYS") {
+        let days = days.parse::<u32>().expect("LOCATION_RETENTION_DAYS must be a number");
+        set_retention_days(Some(days)).unwrap_or_else(|error| panic!("Invalid LOCATION_RETENTION_DAYS: {}", error));
+    }
+    if let Ok(limit) = std::env::var("HEATMAP_PRECOMPUTE_LIMIT") {
+        set_precompute_limit(limit.parse::<usize>().expect("HEATMAP_PRECOMPUTE_LIMIT must be a number"));
+    }
+    start_scheduler();
+    
+    // Deliver reward, geofence, quest and visit events to registered webhooks
+    actix_web::rt::spawn(run_dispatcher());
+    
+    // gRPC API for backend consumers, next to the REST API on its own port
+    let grpc_port = std::env::var("GRPC_PORT")
+        .unwrap_or_else(|_| "50051".to_string())
+        .parse::<u16>()
+        .expect("GRPC_PORT must be a number");
+    let grpc_addr = std::net::ToSocketAddrs::to_socket_addrs(&(host.as_str(), grpc_port))
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .expect("HOST must resolve to an address to serve gRPC on");
+    let grpc_user_token_secret = user_token_secret.clone();
+    actix_web::rt::spawn(async move {
+        log::info!("Starting gRPC server at {}", grpc_addr);
+        if let Err(error) = serve_grpc(grpc_addr, grpc_user_token_secret).await {
+            log::error!("{}", error);
+        }
+    });
+    
     log::info!("Starting server at {}:{}", host, port);
     
     // Create shared state
     let app_state = web::Data::new(AppState {
         api_version: "1.0.0".to_string(),
+        anonymous_contributions_enabled,
+        admin_token,
+        user_token_secret,
+        v1_sunset,
     });
+    let graphql_schema = web::Data::new(build_schema());
     
     // Start HTTP server
     HttpServer::new(move || {
@@ -115,16 +1669,103 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .wrap(cors)
             .wrap(actix_web::middleware::Logger::default())
+            // Count every request under its route pattern
+            .wrap_fn(|req, srv| {
+                let started = Instant::now();
+                let response = srv.call(req);
+                async move {
+                    let response = response.await?;
+                    let route = response.request().match_pattern().unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+                    record_request(response.request().method().as_str(), &route, response.status().as_u16(), started.elapsed());
+                    Ok(response)
+                }
+            })
             .app_data(app_state.clone())
+            .app_data(graphql_schema.clone())
             // API routes
             .route("/", web::get().to(get_api_info))
             .route("/health", web::get().to(health_check))
+            .route("/metrics", web::get().to(metrics_handler))
             .service(
                 web::scope("/api/v1")
+                    .wrap(from_fn(deprecate_v1))
+                    .route("/schema", web::get().to(schema_handler))
                     .route("/locations", web::post().to(register_location_handler))
+                    .route("/locations/anonymous", web::post().to(anonymous_contribution_handler))
+                    .service(
+                        web::resource("/locations/import")
+                            .app_data(web::PayloadConfig::new(MAX_IMPORT_BYTES))
+                            .route(web::post().to(import_locations_handler))
+                    )
                     .route("/locations/{id}", web::get().to(get_location_handler))
+                    .route("/users/{id}/locations", web::get().to(user_locations_handler))
+                    .route("/users/{id}/webhooks", web::post().to(register_user_webhook_handler))
+                    .route("/users/{id}/webhooks", web::get().to(list_user_webhooks_handler))
+                    .route("/users/{id}/webhooks/{webhook_id}", web::delete().to(delete_user_webhook_handler))
+                    .route("/graphql", web::post().to(graphql_handler))
                     .route("/heatmap", web::post().to(generate_heatmap_handler))
+                    .route("/heatmap/adaptive", web::post().to(adaptive_heatmap_handler))
+                    .route("/heatmap/history", web::get().to(heatmap_history_handler))
+                    .route("/heatmap/stream", web::get().to(live_heatmap_handler))
+                    .route("/heatmap/diff", web::post().to(heatmap_diff_handler))
+                    .route("/heatmap.png", web::get().to(heatmap_image_handler))
+                    .route("/hotspots", web::get().to(hotspots_handler))
+                    .route("/routes", web::get().to(popular_routes_handler))
+                    .route("/tiles/{z}/{x}/{y}.mvt", web::get().to(heatmap_tile_handler))
+                    .route("/pois", web::post().to(register_poi_handler))
                     .route("/analytics", web::post().to(generate_analytics_handler))
+                    .route("/analytics/daily", web::post().to(daily_summary_handler))
+                    .route("/analytics/routines", web::get().to(routines_handler))
+                    .route("/analytics/aggregate", web::get().to(aggregate_analytics_handler))
+                    .route("/analytics/anomalies", web::get().to(anomalies_handler))
+                    .route("/analytics/predictions", web::get().to(predictions_handler))
+                    .route("/rewards/quote", web::get().to(reward_quote_handler))
+                    .route("/rewards/claim", web::post().to(reward_claim_handler))
+                    .route("/rewards/vrf-key", web::get().to(reward_vrf_key_handler))
+                    .route("/rewards/verify", web::post().to(reward_verify_handler))
+                    .route("/rewards/balance", web::get().to(reward_balance_handler))
+                    .route("/rewards/history", web::get().to(reward_history_handler))
+                    .route("/rewards/discoveries", web::get().to(discoveries_handler))
+                    .route("/rewards/redeem", web::post().to(redeem_handler))
+                    .route("/rewards/redemptions", web::get().to(redemptions_handler))
+                    .route("/rewards/redemptions/key", web::get().to(redemption_key_handler))
+                    .route("/events/active", web::get().to(active_events_handler))
+                    .route("/quests", web::get().to(quests_handler))
+                    .route("/leaderboard", web::get().to(leaderboard_handler))
+                    // Operator endpoints, all behind the admin token
+                    .service(
+                        web::scope("/admin")
+                            .wrap(from_fn(require_admin))
+                            .route("/stats", web::get().to(admin_stats_handler))
+                            .route("/retention/cleanup", web::post().to(retention_cleanup_handler))
+                            .route("/cache/invalidate", web::post().to(invalidate_cache_handler))
+                            .route("/jobs", web::get().to(jobs_handler))
+                            .route("/jobs/{name}/run", web::post().to(run_job_handler))
+                            .route("/rewards/config", web::get().to(reward_config_handler))
+                            .route("/rewards/config", web::put().to(update_reward_config_handler))
+                            .route("/redemptions/{id}/fulfill", web::post().to(fulfill_redemption_handler))
+                            .route("/events", web::get().to(list_events_handler))
+                            .route("/events", web::post().to(create_event_handler))
+                            .route("/events/{id}", web::put().to(update_event_handler))
+                            .route("/events/{id}", web::delete().to(delete_event_handler))
+                            .route("/webhooks", web::post().to(register_webhook_handler))
+                            .route("/webhooks", web::get().to(list_webhooks_handler))
+                            .route("/webhooks/{id}", web::delete().to(delete_webhook_handler))
+                    )
+            )
+            // Breaking-change surface; malformed requests get typed errors too
+            .service(
+                web::scope("/api/v2")
+                    .app_data(web::JsonConfig::default().error_handler(|error, _| {
+                        ApiError::new(ErrorCode::InvalidRequest, error.to_string()).into()
+                    }))
+                    .app_data(web::QueryConfig::default().error_handler(|error, _| {
+                        ApiError::new(ErrorCode::InvalidRequest, error.to_string()).into()
+                    }))
+                    .route("/locations", web::post().to(register_location_v2_handler))
+                    .route("/users/{id}/locations", web::get().to(user_locations_v2_handler))
+                    .route("/heatmap", web::post().to(heatmap_v2_handler))
+                    .route("/rewards/history", web::get().to(reward_history_v2_handler))
             )
     })
     .bind((host, port))?
@@ -7,7 +7,7 @@ use rand::Rng;
 use sha2::{Sha256, Digest};
 use base64::{Engine as _, engine::general_purpose};
 use once_cell::sync::Lazy;
-use crate::models::{Location, EncryptedLocation};
+use crate::models::{Location, EncryptedLocation, ENCRYPTED_LOCATION_VERSION};
 
 // Generate stable keys for the application
 static PRIVATE_KEY_BYTES: Lazy<[u8; 32]> = Lazy::new(|| {
@@ -18,17 +18,16 @@ static PRIVATE_KEY_BYTES: Lazy<[u8; 32]> = Lazy::new(|| {
 
 static PRIVATE_KEY: Lazy<EphemeralSecret> = Lazy::new(|| {
     // Use OsRng to create EphemeralSecret
-    let secret = EphemeralSecret::random_from_rng(OsRng);
-    secret
+    EphemeralSecret::random_from_rng(OsRng)
 });
 
-static PUBLIC_KEY: Lazy<PublicKey> = Lazy::new(|| PublicKey::from(&*PRIVATE_KEY));
+pub static PUBLIC_KEY: Lazy<PublicKey> = Lazy::new(|| PublicKey::from(&*PRIVATE_KEY));
 
 // Get a derived key for encryption/decryption
 pub fn get_derived_key() -> Key {
     let mut hasher = Sha256::new();
     // For consistency, still use PRIVATE_KEY_BYTES
-    hasher.update(&*PRIVATE_KEY_BYTES);
+    hasher.update(*PRIVATE_KEY_BYTES);
     let hashed_key = hasher.finalize();
     *Key::from_slice(&hashed_key[0..32])
 }
@@ -41,9 +40,7 @@ pub fn encrypt_location(location: &Location) -> Result<EncryptedLocation, String
     rng.fill(&mut nonce_bytes);
     let nonce = Nonce::from_slice(&nonce_bytes);
 
-    // Serialize location data
-    let location_json = serde_json::to_string(location)
-        .map_err(|e| format!("Serialization error: {}", e))?;
+    let plaintext = location_plaintext(location)?;
 
     // Get the derived key
     let key = get_derived_key();
@@ -51,18 +48,24 @@ pub fn encrypt_location(location: &Location) -> Result<EncryptedLocation, String
     // Create cipher and encrypt
     let cipher = ChaCha20Poly1305::new(&key);
     let encrypted = cipher
-        .encrypt(nonce, location_json.as_bytes())
+        .encrypt(nonce, plaintext.as_slice())
         .map_err(|e| format!("Encryption error: {}", e))?;
 
     Ok(EncryptedLocation {
+        version: ENCRYPTED_LOCATION_VERSION,
         enc_data: general_purpose::STANDARD.encode(encrypted),
-        timestamp: location.timestamp.clone(),
+        timestamp: location.timestamp,
         nonce: general_purpose::STANDARD.encode(nonce),
+        imported: false,
     })
 }
 
 // Function to decrypt location data
 pub fn decrypt_location(encrypted: &EncryptedLocation) -> Result<Location, String> {
+    if encrypted.version > ENCRYPTED_LOCATION_VERSION {
+        return Err(format!("Encrypted location version {} is newer than this build reads ({})", encrypted.version, ENCRYPTED_LOCATION_VERSION));
+    }
+
     // Get the same derived key
     let key = get_derived_key();
 
@@ -80,9 +83,24 @@ pub fn decrypt_location(encrypted: &EncryptedLocation) -> Result<Location, Strin
         .decrypt(nonce, ciphertext.as_ref())
         .map_err(|e| format!("Decryption error: {}", e))?;
 
-    // Deserialize back to Location
-    let location: Location = serde_json::from_slice(&decrypted)
-        .map_err(|e| format!("Deserialization error: {}", e))?;
+    read_plaintext(&decrypted)
+}
+
+// What an encrypted location holds: CBOR with the "cbor" feature, JSON otherwise
+fn location_plaintext(location: &Location) -> Result<Vec<u8>, String> {
+    #[cfg(feature = "cbor")]
+    let plaintext = crate::models::cbor::to_vec(location)?;
+    #[cfg(not(feature = "cbor"))]
+    let plaintext = serde_json::to_vec(location).map_err(|e| format!("Serialization error: {}", e))?;
+    Ok(plaintext)
+}
 
-    Ok(location)
+// JSON plaintexts are objects, so locations encrypted before the "cbor" feature was turned on
+// still decrypt; a CBOR map never starts with '{'
+fn read_plaintext(plaintext: &[u8]) -> Result<Location, String> {
+    #[cfg(feature = "cbor")]
+    if plaintext.first() != Some(&b'{') {
+        return crate::models::cbor::from_slice(plaintext);
+    }
+    serde_json::from_slice(plaintext).map_err(|e| format!("Deserialization error: {}", e))
 } 
\ No newline at end of file
@@ -0,0 +1,109 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use crate::models::{FraudAssessment, FraudSignal, FraudSignalKind, Location};
+
+// Risk added per other account sharing each trait; the sum is capped at the signal's maximum
+const SHARED_DEVICE_RISK: (f64, f64) = (0.5, 1.0);
+const CLONED_MOTION_RISK: (f64, f64) = (0.4, 0.9);
+const SHARED_WIFI_RISK: (f64, f64) = (0.15, 0.5);
+const CROWDED_IP_RISK: (f64, f64) = (0.1, 0.8);
+
+// Accounts behind one IP address before it counts as crowded (carrier NAT, campus Wi-Fi)
+pub const MAX_ACCOUNTS_PER_IP: usize = 5;
+
+// Wi-Fi environments with fewer networks are too common to single anyone out
+const MIN_WIFI_ENVIRONMENT: usize = 3;
+
+// Accounts seen with each device, sensor fingerprint and IP; keys are hashed so raw
+// identifiers do not linger in memory
+static ACCOUNT_LINKS: Lazy<Mutex<AccountLinks>> = Lazy::new(|| Mutex::new(AccountLinks::default()));
+
+#[derive(Default)]
+struct AccountLinks {
+    devices: HashMap<[u8; 32], HashSet<String>>,
+    motion: HashMap<[u8; 32], HashSet<String>>,
+    wifi: HashMap<[u8; 32], HashSet<String>>,
+    ips: HashMap<[u8; 32], HashSet<String>>,
+}
+
+// Record a verified location and score how likely its user is one of several accounts run by
+// the same person; rewards are weighted by `reward_weight` (1 - score)
+pub fn assess(location: &Location, client_ip: Option<&str>) -> FraudAssessment {
+    let user = &location.user_id;
+    let mut links = ACCOUNT_LINKS.lock().unwrap();
+    let mut signals = Vec::new();
+
+    let device = fingerprint(&[location.device_id.as_str()]);
+    let others = link(&mut links.devices, device, user);
+    push_signal(&mut signals, FraudSignalKind::SharedDevice, others, others, SHARED_DEVICE_RISK);
+
+    // Real sensors never report identical noise, so matching readings mean replayed or emulated data
+    if let Some(motion) = motion_signature(location) {
+        let others = link(&mut links.motion, fingerprint(&[motion.as_str()]), user);
+        push_signal(&mut signals, FraudSignalKind::ClonedMotion, others, others, CLONED_MOTION_RISK);
+    }
+
+    let mut bssids: Vec<String> = location.sensors.wifi_networks.iter().map(|network| network.bssid.to_lowercase()).collect();
+    bssids.sort();
+    bssids.dedup();
+    if bssids.len() >= MIN_WIFI_ENVIRONMENT {
+        let bssids: Vec<&str> = bssids.iter().map(String::as_str).collect();
+        let others = link(&mut links.wifi, fingerprint(&bssids), user);
+        push_signal(&mut signals, FraudSignalKind::SharedWifiEnvironment, others, others, SHARED_WIFI_RISK);
+    }
+
+    if let Some(ip) = client_ip {
+        let others = link(&mut links.ips, fingerprint(&[ip]), user);
+        // Only accounts beyond what a shared network explains add risk
+        let excess = (others + 1).saturating_sub(MAX_ACCOUNTS_PER_IP);
+        push_signal(&mut signals, FraudSignalKind::CrowdedIp, others, excess, CROWDED_IP_RISK);
+    }
+
+    // Signals are treated as independent evidence
+    let score = round(1.0 - signals.iter().map(|signal| 1.0 - signal.score).product::<f64>());
+    FraudAssessment {
+        score,
+        reward_weight: round(1.0 - score),
+        signals,
+    }
+}
+
+// Add the user to the accounts sharing a trait, returning how many other accounts share it
+fn link(accounts: &mut HashMap<[u8; 32], HashSet<String>>, key: [u8; 32], user_id: &str) -> usize {
+    let users = accounts.entry(key).or_default();
+    users.insert(user_id.to_string());
+    users.len() - 1
+}
+
+// Record a signal if `suspicious_accounts` of the other accounts add risk
+fn push_signal(signals: &mut Vec<FraudSignal>, kind: FraudSignalKind, other_accounts: usize, suspicious_accounts: usize, (per_account, max): (f64, f64)) {
+    if suspicious_accounts > 0 {
+        signals.push(FraudSignal {
+            kind,
+            other_accounts,
+            score: round((suspicious_accounts as f64 * per_account).min(max)),
+        });
+    }
+}
+
+fn round(score: f64) -> f64 {
+    (score * 1000.0).round() / 1000.0
+}
+
+// Accelerometer and gyroscope readings, exactly as reported
+fn motion_signature(location: &Location) -> Option<String> {
+    let accelerometer = location.sensors.accelerometer.as_ref()?;
+    let gyroscope = location.sensors.gyroscope.as_deref().unwrap_or(&[]);
+    Some(format!("{:?}|{:?}", accelerometer, gyroscope))
+}
+
+fn fingerprint(parts: &[&str]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part.as_bytes());
+    }
+    hasher.finalize().into()
+}
@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use async_graphql::{Context, EmptyMutation, EmptySubscription, Error, Object, Result, Schema, SimpleObject};
+use chrono::{DateTime, Duration, Utc};
+use crate::models::{self, Latitude, Longitude, UserLocationsRequest, VisitAnalyticsRequest, UnitSystem};
+use crate::analytics::generate_visit_analytics;
+use crate::heatmap::generate_heatmap;
+use crate::heatmap::privacy::user_requester;
+use crate::location::{list_user_locations, GRID_SIZE};
+use crate::rewards::events::active_events;
+use crate::rewards::ledger::{REWARD_LEDGER, DEFAULT_HISTORY_PAGE};
+
+// GraphQL view over locations, visits, heatmaps and rewards, so a dashboard can compose what
+// would otherwise take several REST round trips. Resolvers call the same library functions as
+// the REST handlers.
+
+pub type OrbitalmapSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+// Bounds on what one query may ask for
+const MAX_QUERY_DEPTH: usize = 8;
+const MAX_QUERY_COMPLEXITY: usize = 500;
+
+// Visits returned when `last` is not given, and the most a query may ask for
+const DEFAULT_VISITS: usize = 10;
+const MAX_VISITS: usize = 100;
+
+// Visits are looked for this far back when `from` is not given
+const DEFAULT_VISIT_WINDOW_DAYS: i64 = 90;
+
+// User a request's bearer token was issued for, if any; set on every request
+pub struct AuthenticatedUser(pub Option<String>);
+
+// Whose privacy budget the request's heatmaps are charged to; set on every request
+pub struct RequesterId(pub Option<String>);
+
+pub fn build_schema() -> OrbitalmapSchema {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .limit_depth(MAX_QUERY_DEPTH)
+        .limit_complexity(MAX_QUERY_COMPLEXITY)
+        .finish()
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    // The authenticated user's own data; requires a user token
+    async fn me(&self, ctx: &Context<'_>) -> Result<Me> {
+        match &ctx.data::<AuthenticatedUser>()?.0 {
+            Some(user_id) => Ok(Me { user_id: user_id.clone() }),
+            None => Err(Error::new("User token required")),
+        }
+    }
+
+    // Noised heatmap of an area, charged to the requester's privacy budget like POST /heatmap
+    #[allow(clippy::too_many_arguments)]
+    async fn heatmap(
+        &self,
+        ctx: &Context<'_>,
+        min_lat: f64,
+        min_lon: f64,
+        max_lat: f64,
+        max_lon: f64,
+        #[graphql(default = 1.0)] privacy_level: f64,
+        start: Option<String>,
+        end: Option<String>,
+    ) -> Result<Heatmap> {
+        let time_range = match (start, end) {
+            (Some(start), Some(end)) => Some(models::TimeRange { start: parse_time(&start)?, end: parse_time(&end)? }),
+            (None, None) => None,
+            _ => return Err(Error::new("start and end must be given together")),
+        };
+        let request = heatmap_request(
+            Latitude::new(min_lat)?, Longitude::new(min_lon)?, Latitude::new(max_lat)?, Longitude::new(max_lon)?,
+            privacy_level, time_range, ctx.data::<RequesterId>()?.0.clone(),
+        );
+        let heatmap = generate_heatmap(&request);
+        if let Some(error) = heatmap.error {
+            return Err(Error::new(error));
+        }
+
+        Ok(Heatmap {
+            cells: heatmap.cells.into_iter()
+                .map(|cell| HeatmapCell { lat: cell.lat.degrees(), lon: cell.lon.degrees(), intensity: cell.intensity, count: cell.count })
+                .collect(),
+            min_lat: heatmap.min_lat.degrees(),
+            min_lon: heatmap.min_lon.degrees(),
+            max_lat: heatmap.max_lat.degrees(),
+            max_lon: heatmap.max_lon.degrees(),
+            epsilon_spent: heatmap.epsilon_spent,
+            privacy_budget_remaining: heatmap.privacy_budget_remaining,
+        })
+    }
+
+    // Boost events running now
+    async fn active_events(&self) -> Vec<BoostEvent> {
+        active_events(Utc::now()).into_iter()
+            .map(|event| BoostEvent {
+                id: event.id,
+                name: event.name,
+                min_lat: event.bounds.min_lat.degrees(),
+                min_lon: event.bounds.min_lon.degrees(),
+                max_lat: event.bounds.max_lat.degrees(),
+                max_lon: event.bounds.max_lon.degrees(),
+                start: event.start.to_rfc3339(),
+                end: event.end.to_rfc3339(),
+                multiplier: event.multiplier,
+            })
+            .collect()
+    }
+}
+
+pub struct Me {
+    user_id: String,
+}
+
+#[Object]
+impl Me {
+    async fn user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    // Stored locations, oldest first, paged like GET /users/{id}/locations
+    async fn locations(&self, from: Option<String>, to: Option<String>, cursor: Option<String>, limit: Option<usize>) -> Result<LocationPage> {
+        let page = list_user_locations(&UserLocationsRequest {
+            user_id: self.user_id.clone(),
+            from: from.as_deref().map(parse_time).transpose()?,
+            to: to.as_deref().map(parse_time).transpose()?,
+            cursor,
+            limit,
+        })?;
+
+        Ok(LocationPage {
+            locations: page.locations.into_iter()
+                .map(|location| LocationPoint { id: location.id, lat: location.lat.degrees(), lon: location.lon.degrees(), timestamp: location.timestamp.to_rfc3339(), imported: location.imported })
+                .collect(),
+            next_cursor: page.next_cursor,
+        })
+    }
+
+    // The latest visits in [from, to], newest last. Each visit lists the rewards earned while it
+    // lasted (by ledger time) and, when asked for, the intensity of its cell in a heatmap of the
+    // visits' area, charged once to the user's privacy budget.
+    async fn visits(
+        &self,
+        ctx: &Context<'_>,
+        last: Option<usize>,
+        from: Option<String>,
+        to: Option<String>,
+        #[graphql(default = 1.0)] privacy_level: f64,
+    ) -> Result<Vec<Visit>> {
+        let to = match to {
+            Some(to) => parse_time(&to)?,
+            None => Utc::now(),
+        };
+        let from = match from {
+            Some(from) => parse_time(&from)?,
+            None => to - Duration::days(DEFAULT_VISIT_WINDOW_DAYS),
+        };
+        let analytics = generate_visit_analytics(&VisitAnalyticsRequest {
+            user_id: self.user_id.clone(),
+            start_time: from,
+            end_time: to,
+            units: UnitSystem::default(),
+            min_stay_seconds: None,
+            cluster_radius_m: None,
+            max_gap_seconds: None,
+        });
+        if let Some(error) = analytics.error {
+            return Err(Error::new(error));
+        }
+
+        let last = last.unwrap_or(DEFAULT_VISITS).clamp(1, MAX_VISITS);
+        let skip = analytics.visits.len().saturating_sub(last);
+        let found: Vec<models::LocationVisit> = analytics.visits.into_iter().skip(skip).collect();
+
+        let intensities = if ctx.look_ahead().field("heatmapIntensity").exists() {
+            Some(visit_intensities(&found, privacy_level, &self.user_id)?)
+        } else {
+            None
+        };
+
+        let ledger = REWARD_LEDGER.lock().unwrap();
+        let mut visits = Vec::with_capacity(found.len());
+        for (index, visit) in found.into_iter().enumerate() {
+            let rewards = ledger.entries_between(&self.user_id, visit.arrival_time, visit.departure_time)
+                .into_iter()
+                .filter(|entry| entry.kind == models::LedgerEntryKind::Earned)
+                .map(Reward::from)
+                .collect();
+            visits.push(Visit {
+                lat: visit.lat.degrees(),
+                lon: visit.lon.degrees(),
+                arrival_time: visit.arrival_time.to_rfc3339(),
+                departure_time: visit.departure_time.to_rfc3339(),
+                duration_seconds: visit.duration_seconds.0,
+                point_count: visit.point_count,
+                poi: visit.poi.map(|poi| poi.name),
+                heatmap_intensity: intensities.as_ref().map(|intensities| intensities[index]),
+                rewards,
+            });
+        }
+        Ok(visits)
+    }
+
+    async fn reward_balance(&self) -> RewardBalance {
+        let balance = REWARD_LEDGER.lock().unwrap().balance(&self.user_id);
+        RewardBalance {
+            earned: balance.earned,
+            spent: balance.spent,
+            balance: balance.balance,
+            earned_today: balance.earned_today,
+            daily_remaining: balance.daily_remaining,
+        }
+    }
+
+    // Ledger entries, newest first
+    async fn reward_history(&self, #[graphql(default = 0)] offset: usize, limit: Option<usize>) -> RewardHistory {
+        let history = REWARD_LEDGER.lock().unwrap().history(&self.user_id, offset, limit.unwrap_or(DEFAULT_HISTORY_PAGE));
+        RewardHistory {
+            entries: history.entries.into_iter().map(Reward::from).collect(),
+            total: history.total,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct LocationPoint {
+    // Encrypted ID the location is looked up by
+    id: String,
+    lat: f64,
+    lon: f64,
+    timestamp: String,
+    // Imported from a GPX or CSV trace instead of registered with sensor data
+    imported: bool,
+}
+
+#[derive(SimpleObject)]
+pub struct LocationPage {
+    locations: Vec<LocationPoint>,
+    // Cursor of the next page, null on the last one
+    next_cursor: Option<String>,
+}
+
+#[derive(SimpleObject)]
+pub struct Visit {
+    lat: f64,
+    lon: f64,
+    arrival_time: String,
+    departure_time: String,
+    duration_seconds: i64,
+    point_count: u32,
+    // Name of the POI the visit took place at, if known
+    poi: Option<String>,
+    // Noised intensity (0 to 1) of the visit's cell relative to the densest cell around the visits
+    heatmap_intensity: Option<f64>,
+    rewards: Vec<Reward>,
+}
+
+#[derive(SimpleObject)]
+pub struct Reward {
+    id: u64,
+    // earned, spent or refunded
+    kind: String,
+    points: u64,
+    reason: String,
+    balance_after: u64,
+    timestamp: String,
+}
+
+impl From<models::LedgerEntry> for Reward {
+    fn from(entry: models::LedgerEntry) -> Self {
+        Reward {
+            id: entry.id,
+            kind: serde_json::to_value(entry.kind).ok()
+                .and_then(|kind| kind.as_str().map(|kind| kind.to_string()))
+                .unwrap_or_default(),
+            points: entry.points,
+            reason: entry.reason,
+            balance_after: entry.balance_after,
+            timestamp: entry.timestamp.to_rfc3339(),
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct RewardBalance {
+    earned: u64,
+    spent: u64,
+    balance: u64,
+    earned_today: u64,
+    daily_remaining: Option<u64>,
+}
+
+#[derive(SimpleObject)]
+pub struct RewardHistory {
+    entries: Vec<Reward>,
+    // Entries across all pages
+    total: usize,
+}
+
+#[derive(SimpleObject)]
+pub struct HeatmapCell {
+    lat: f64,
+    lon: f64,
+    intensity: f64,
+    count: u32,
+}
+
+#[derive(SimpleObject)]
+pub struct Heatmap {
+    cells: Vec<HeatmapCell>,
+    min_lat: f64,
+    min_lon: f64,
+    max_lat: f64,
+    max_lon: f64,
+    epsilon_spent: f64,
+    privacy_budget_remaining: Option<f64>,
+}
+
+#[derive(SimpleObject)]
+pub struct BoostEvent {
+    id: String,
+    name: String,
+    min_lat: f64,
+    min_lon: f64,
+    max_lat: f64,
+    max_lon: f64,
+    start: String,
+    end: String,
+    multiplier: f64,
+}
+
+// Intensity of each visit's cell in one heatmap spanning all of them, aligned to the grid so
+// every visit falls in exactly one cell
+fn visit_intensities(visits: &[models::LocationVisit], privacy_level: f64, user_id: &str) -> Result<Vec<f64>> {
+    if visits.is_empty() {
+        return Ok(Vec::new());
+    }
+    let cell_of = |value: f64| (value / GRID_SIZE).floor();
+    let min_lat = visits.iter().map(|visit| cell_of(visit.lat.degrees())).fold(f64::INFINITY, f64::min) * GRID_SIZE;
+    let min_lon = visits.iter().map(|visit| cell_of(visit.lon.degrees())).fold(f64::INFINITY, f64::min) * GRID_SIZE;
+    let max_lat = (visits.iter().map(|visit| cell_of(visit.lat.degrees())).fold(f64::NEG_INFINITY, f64::max) + 1.0) * GRID_SIZE;
+    let max_lon = (visits.iter().map(|visit| cell_of(visit.lon.degrees())).fold(f64::NEG_INFINITY, f64::max) + 1.0) * GRID_SIZE;
+
+    let request = heatmap_request(
+        Latitude::clamped(min_lat), Longitude::clamped(min_lon), Latitude::clamped(max_lat), Longitude::clamped(max_lon),
+        privacy_level, None, Some(user_requester(user_id)),
+    );
+    let heatmap = generate_heatmap(&request);
+    if let Some(error) = heatmap.error {
+        return Err(Error::new(error));
+    }
+
+    let offset = |lat: Latitude, lon: Longitude| ((lat.degrees() - min_lat) / GRID_SIZE, (lon.degrees() - min_lon) / GRID_SIZE);
+    let cells: HashMap<(i64, i64), f64> = heatmap.cells.iter()
+        .map(|cell| {
+            let (lat_offset, lon_offset) = offset(cell.lat, cell.lon);
+            ((lat_offset.round() as i64, lon_offset.round() as i64), cell.intensity)
+        })
+        .collect();
+    Ok(visits.iter()
+        .map(|visit| {
+            let (lat_offset, lon_offset) = offset(visit.lat, visit.lon);
+            cells.get(&(lat_offset.floor() as i64, lon_offset.floor() as i64)).copied().unwrap_or(0.0)
+        })
+        .collect())
+}
+
+fn heatmap_request(
+    min_lat: Latitude,
+    min_lon: Longitude,
+    max_lat: Latitude,
+    max_lon: Longitude,
+    privacy_level: f64,
+    time_range: Option<models::TimeRange>,
+    requester_id: Option<String>,
+) -> models::HeatmapRequest {
+    models::HeatmapRequest {
+        min_lat,
+        min_lon,
+        max_lat,
+        max_lon,
+        privacy_level,
+        time_range,
+        bucket: None,
+        epsilon: None,
+        delta: None,
+        sensitivity: None,
+        requester_id,
+        seed: None,
+    }
+}
+
+// Times are RFC 3339 strings or Unix seconds, as in the JSON API
+fn parse_time(time: &str) -> Result<DateTime<Utc>> {
+    models::timestamp::parse(time).ok_or_else(|| Error::new(format!("Invalid time {}: expected RFC 3339 or Unix seconds", time)))
+}
@@ -0,0 +1,285 @@
+use std::net::SocketAddr;
+use chrono::{DateTime, Utc};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+use crate::models::{self, timestamp, CoordinateError, Latitude, Longitude, TimeRange, UnitSystem};
+use crate::analytics::generate_visit_analytics;
+use crate::auth::verify_user_token;
+use crate::heatmap::privacy::{address_requester, user_requester, BUDGET_EXHAUSTED_ERROR};
+use crate::heatmap::stream::stream_heatmap;
+use crate::location::register_location_from;
+use crate::rewards::claim::claim_reward;
+
+// Types and service generated from proto/orbitalmap.proto
+pub mod proto {
+    tonic::include_proto!("orbitalmap.v1");
+}
+
+use proto::orbitalmap_server::{Orbitalmap, OrbitalmapServer};
+
+// Messages a streaming call buffers before its producer waits for the client
+const STREAM_BUFFER: usize = 4;
+
+// The gRPC service; each call runs the library function behind its REST counterpart
+pub struct OrbitalmapService {
+    // Secret user tokens are signed with, as in the REST API; owner-only calls are refused
+    // without one
+    user_token_secret: Option<Vec<u8>>,
+}
+
+impl OrbitalmapService {
+    // The user of the `authorization: Bearer` token in the call's metadata
+    fn authenticated_user<T>(&self, request: &Request<T>) -> Result<String, Status> {
+        let secret = self.user_token_secret.as_ref()
+            .ok_or_else(|| Status::unauthenticated("User tokens are not enabled on this server"))?;
+        let token = request.metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("User token required"))?;
+        verify_user_token(secret, token.trim(), Utc::now()).map_err(Status::unauthenticated)
+    }
+
+    // Check that the call's token belongs to the user whose data it reads or changes
+    fn require_owner<T>(&self, request: &Request<T>, user_id: &str, forbidden: &str) -> Result<(), Status> {
+        match self.authenticated_user(request)? {
+            owner if owner == user_id => Ok(()),
+            _ => Err(Status::permission_denied(forbidden)),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Orbitalmap for OrbitalmapService {
+    async fn register_location(&self, request: Request<proto::Location>) -> Result<Response<proto::RegisterLocationResponse>, Status> {
+        // The peer address, like the REST API, so the Sybil checks see the caller's IP
+        let client_ip = request.remote_addr().map(|addr| addr.ip().to_string());
+        let location = to_location(request.into_inner())?;
+        let response = blocking(move || register_location_from(location, client_ip.as_deref())).await?;
+
+        Ok(Response::new(proto::RegisterLocationResponse {
+            success: response.success,
+            message: response.message,
+            encrypted_location_id: response.encrypted_location_id,
+            streak: response.streak.map(|streak| proto::StreakStatus {
+                current_days: streak.current_days,
+                longest_days: streak.longest_days,
+                multiplier: streak.multiplier,
+            }),
+            completed_quests: response.completed_quests.into_iter().map(|quest| quest.id).collect(),
+            fraud_score: response.fraud.map(|fraud| fraud.score),
+            daily_remaining: response.daily_remaining,
+        }))
+    }
+
+    type GetHeatmapStream = ReceiverStream<Result<proto::HeatmapChunk, Status>>;
+
+    async fn get_heatmap(&self, request: Request<proto::HeatmapRequest>) -> Result<Response<Self::GetHeatmapStream>, Status> {
+        let requester_id = match self.authenticated_user(&request) {
+            Ok(user_id) => user_requester(&user_id),
+            Err(_) => address_requester(request.remote_addr().map(|address| address.ip())),
+        };
+        let request = request.into_inner();
+        let time_range = match (request.start_time, request.end_time) {
+            (Some(start), Some(end)) => Some(TimeRange { start: parse_time("start_time", &start)?, end: parse_time("end_time", &end)? }),
+            (None, None) => None,
+            _ => return Err(Status::invalid_argument("start_time and end_time must be given together")),
+        };
+        let heatmap_request = models::HeatmapRequest {
+            min_lat: Latitude::new(request.min_lat).map_err(invalid_coordinate)?,
+            min_lon: Longitude::new(request.min_lon).map_err(invalid_coordinate)?,
+            max_lat: Latitude::new(request.max_lat).map_err(invalid_coordinate)?,
+            max_lon: Longitude::new(request.max_lon).map_err(invalid_coordinate)?,
+            privacy_level: request.privacy_level,
+            time_range,
+            bucket: None,
+            epsilon: request.epsilon,
+            delta: None,
+            sensitivity: None,
+            requester_id: Some(requester_id),
+            seed: None,
+        };
+        let continuation = request.continuation;
+
+        // Counting the area and charging the budget happen before the first message
+        let mut stream = blocking(move || stream_heatmap(&heatmap_request, continuation.as_deref()))
+            .await?
+            .map_err(|error| heatmap_status(&error))?;
+
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            for cells in stream.by_ref() {
+                let chunk = proto::HeatmapChunk {
+                    cells: cells.into_iter().map(to_proto_cell).collect(),
+                    ..Default::default()
+                };
+                // Stop early when the client went away
+                if sender.blocking_send(Ok(chunk)).is_err() {
+                    return;
+                }
+            }
+            let _ = sender.blocking_send(Ok(proto::HeatmapChunk {
+                cells: Vec::new(),
+                next_token: stream.next_token(),
+                epsilon_spent: Some(stream.epsilon_spent),
+                privacy_budget_remaining: Some(stream.privacy_budget_remaining),
+            }));
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    type GetVisitsStream = ReceiverStream<Result<proto::Visit, Status>>;
+
+    async fn get_visits(&self, request: Request<proto::VisitsRequest>) -> Result<Response<Self::GetVisitsStream>, Status> {
+        self.require_owner(&request, &request.get_ref().user_id, "Visits can only be read by their owner")?;
+        let request = request.into_inner();
+        let analytics_request = models::VisitAnalyticsRequest {
+            user_id: request.user_id,
+            start_time: parse_time("start_time", &request.start_time)?,
+            end_time: parse_time("end_time", &request.end_time)?,
+            units: UnitSystem::default(),
+            min_stay_seconds: request.min_stay_seconds,
+            cluster_radius_m: request.cluster_radius_m,
+            max_gap_seconds: request.max_gap_seconds,
+        };
+        let analytics = blocking(move || generate_visit_analytics(&analytics_request)).await?;
+        if let Some(error) = analytics.error {
+            return Err(Status::invalid_argument(error));
+        }
+
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        tokio::spawn(async move {
+            for visit in analytics.visits {
+                let visit = proto::Visit {
+                    lat: visit.lat.degrees(),
+                    lon: visit.lon.degrees(),
+                    arrival_time: visit.arrival_time.to_rfc3339(),
+                    departure_time: visit.departure_time.to_rfc3339(),
+                    duration_seconds: visit.duration_seconds.0,
+                    point_count: visit.point_count,
+                    poi: visit.poi.map(|poi| poi.name),
+                };
+                if sender.send(Ok(visit)).await.is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn claim_reward(&self, request: Request<proto::Location>) -> Result<Response<proto::ClaimRewardResponse>, Status> {
+        self.require_owner(&request, &request.get_ref().user_id, "Rewards can only be claimed by their owner")?;
+        let client_ip = request.remote_addr().map(|addr| addr.ip().to_string());
+        let location = to_location(request.into_inner())?;
+        let response = blocking(move || claim_reward(&location, client_ip.as_deref())).await?;
+
+        Ok(Response::new(proto::ClaimRewardResponse {
+            success: response.success,
+            message: response.message,
+            tier: response.tier.and_then(|tier| serde_json::to_value(tier).ok())
+                .and_then(|tier| tier.as_str().map(|tier| tier.to_string())),
+            points: response.reward.as_ref().map(|entry| entry.points).unwrap_or(0),
+            balance_after: response.reward.map(|entry| entry.balance_after),
+            draw: response.draw.map(|draw| proto::RewardDraw {
+                user_id: draw.user_id,
+                nonce: draw.nonce,
+                roll: draw.roll,
+                public_key: draw.public_key,
+                output: draw.output,
+                proof: draw.proof,
+            }),
+            multiplier: response.multiplier,
+            cooldown_seconds: response.cooldown_seconds,
+            daily_remaining: response.daily_remaining,
+        }))
+    }
+}
+
+// Serve the gRPC API on `addr` until the process exits
+pub async fn serve(addr: SocketAddr, user_token_secret: Option<Vec<u8>>) -> Result<(), String> {
+    tonic::transport::Server::builder()
+        .add_service(OrbitalmapServer::new(OrbitalmapService { user_token_secret }))
+        .serve(addr)
+        .await
+        .map_err(|e| format!("gRPC server failed: {}", e))
+}
+
+// Run library work, which locks shared state and may be heavy, off the async workers
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> Result<T, Status> {
+    tokio::task::spawn_blocking(work).await.map_err(|e| Status::internal(e.to_string()))
+}
+
+// Exhausted privacy budgets are rate limits, anything else is a bad request
+fn heatmap_status(error: &str) -> Status {
+    if error.starts_with(BUDGET_EXHAUSTED_ERROR) {
+        Status::resource_exhausted(error)
+    } else {
+        Status::invalid_argument(error)
+    }
+}
+
+fn invalid_coordinate(error: CoordinateError) -> Status {
+    Status::invalid_argument(error.to_string())
+}
+
+// Times are RFC 3339 strings or Unix seconds, as in the JSON API
+fn parse_time(field: &str, value: &str) -> Result<DateTime<Utc>, Status> {
+    timestamp::parse(value).ok_or_else(|| Status::invalid_argument(format!("{} must be an RFC 3339 time or Unix seconds", field)))
+}
+
+fn to_location(location: proto::Location) -> Result<models::Location, Status> {
+    let sensors = location.sensors.ok_or_else(|| Status::invalid_argument("sensors is required"))?;
+    let reading = |values: Vec<f64>| if values.is_empty() { None } else { Some(values) };
+
+    let mut builder = models::Location::builder()
+        .position(location.lat, location.lon)
+        .timestamp(parse_time("timestamp", &location.timestamp)?)
+        .user_id(location.user_id)
+        .device_id(location.device_id)
+        .sensors(models::SensorData {
+            wifi_networks: sensors.wifi_networks.into_iter().map(|network| models::WifiNetwork {
+                ssid: network.ssid,
+                bssid: network.bssid,
+                signal_strength: network.signal_strength,
+                frequency: network.frequency,
+            }).collect(),
+            cell_towers: sensors.cell_towers.into_iter().map(|tower| models::CellTower {
+                cell_id: tower.cell_id,
+                signal_strength: tower.signal_strength,
+                mcc: tower.mcc,
+                mnc: tower.mnc,
+                lac: tower.lac,
+            }).collect(),
+            ble_beacons: sensors.ble_beacons.into_iter().map(to_beacon).collect::<Result<_, _>>()?,
+            accelerometer: reading(sensors.accelerometer),
+            gyroscope: reading(sensors.gyroscope),
+            pressure_hpa: sensors.pressure_hpa,
+            is_mock_location: sensors.is_mock_location,
+            additional_data: sensors.additional_data.into_iter().collect(),
+        });
+    if let Some(floor_level) = location.floor_level {
+        builder = builder.floor_level(floor_level);
+    }
+    builder.build().map_err(|e| Status::invalid_argument(e.to_string()))
+}
+
+fn to_beacon(beacon: proto::BleBeacon) -> Result<models::BleBeacon, Status> {
+    let part = |value: u32| u16::try_from(value)
+        .map_err(|_| Status::invalid_argument("A beacon's major and minor must fit in 16 bits"));
+    Ok(models::BleBeacon {
+        uuid: beacon.uuid,
+        major: part(beacon.major)?,
+        minor: part(beacon.minor)?,
+        rssi: beacon.rssi,
+    })
+}
+
+fn to_proto_cell(cell: models::HeatmapCell) -> proto::HeatmapCell {
+    proto::HeatmapCell {
+        lat: cell.lat.degrees(),
+        lon: cell.lon.degrees(),
+        intensity: cell.intensity,
+        count: cell.count,
+    }
+}
@@ -0,0 +1,197 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use crate::models::{BoundingBox, Latitude, Longitude, HeatmapRequest, AdaptiveHeatmapResponse, AdaptiveHeatmapCell};
+use crate::location::GRID_SIZE;
+use super::privacy::{PrivacyParams, ANONYMOUS_REQUESTER};
+use super::{collect_points, floor_to_hour, check_time_range, split_request, HeatmapPoint, PRIVACY_BUDGET};
+
+// Default and upper bound on the number of cells in an adaptive heatmap
+pub const DEFAULT_ADAPTIVE_CELLS: usize = 1000;
+pub const MAX_ADAPTIVE_CELLS: usize = 10_000;
+
+// Nodes with fewer (noisy) points than this are not subdivided
+const SPLIT_THRESHOLD: f64 = 10.0;
+
+// Deepest quadtree level, whatever the size of the bounding box
+const MAX_DEPTH: u32 = 20;
+
+// A quadtree node waiting to be emitted or subdivided
+struct QuadNode<'a> {
+    min_lat: f64,
+    min_lon: f64,
+    lat_size: f64,
+    lon_size: f64,
+    depth: u32,
+    noisy_count: f64,
+    points: Vec<&'a HeatmapPoint>,
+}
+
+// Densest nodes are subdivided first
+impl PartialEq for QuadNode<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.noisy_count == other.noisy_count
+    }
+}
+
+impl Eq for QuadNode<'_> {}
+
+impl PartialOrd for QuadNode<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QuadNode<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.noisy_count.total_cmp(&other.noisy_count)
+    }
+}
+
+// Generate a heatmap whose cells are subdivided where points are dense and kept
+// coarse where they are sparse, with at most `max_cells` cells.
+//
+// Every point falls in one node per depth, so each depth gets an equal share of epsilon.
+pub fn generate_adaptive_heatmap(request: &HeatmapRequest, max_cells: usize) -> AdaptiveHeatmapResponse {
+    let mut response = AdaptiveHeatmapResponse {
+        cells: Vec::new(),
+        privacy_level: request.privacy_level,
+        min_lat: request.min_lat,
+        max_lat: request.max_lat,
+        min_lon: request.min_lon,
+        max_lon: request.max_lon,
+        epsilon_spent: 0.0,
+        privacy_budget_remaining: None,
+        error: None,
+    };
+
+    let bounds = BoundingBox::from_request(request);
+    let resolved = bounds.validate()
+        .and_then(|_| PrivacyParams::from_request(request))
+        .and_then(|params| match &request.time_range {
+            Some(range) => {
+                let (start, end) = check_time_range(range)?;
+                Ok((params, Some((floor_to_hour(start), end))))
+            },
+            None => Ok((params, None)),
+        });
+    let (params, time_range) = match resolved {
+        Ok(resolved) => resolved,
+        Err(error) => {
+            response.error = Some(error);
+            return response;
+        }
+    };
+
+    let requester = request.requester_id.as_deref().unwrap_or(ANONYMOUS_REQUESTER);
+    match PRIVACY_BUDGET.lock().unwrap().charge(requester, params.epsilon) {
+        Ok(remaining) => {
+            response.epsilon_spent = params.epsilon;
+            response.privacy_budget_remaining = Some(remaining);
+        },
+        Err(error) => {
+            response.error = Some(error);
+            return response;
+        }
+    }
+
+    // Depth needed to reach grid resolution, and the epsilon share of each depth
+    let largest_span = bounds.lat_span().max(bounds.lon_span()).max(GRID_SIZE);
+    let max_depth = ((largest_span / GRID_SIZE).log2().ceil() as u32).min(MAX_DEPTH);
+    let level_params = PrivacyParams {
+        epsilon: params.epsilon / (max_depth + 1) as f64,
+        ..params
+    };
+
+    let max_cells = max_cells.clamp(1, MAX_ADAPTIVE_CELLS);
+    let points = collect_points(request, time_range);
+    let mut rng = rand::thread_rng();
+    let mut noisy_count = |points: &[&HeatmapPoint]| {
+        let count: u32 = points.iter().map(|point| point.weight).sum();
+        count as f64 + level_params.sample_noise(&mut rng)
+    };
+
+    // One root per side of the antimeridian
+    let mut heap = BinaryHeap::new();
+    for part in split_request(request) {
+        let part_points: Vec<&HeatmapPoint> = points.iter()
+            .filter(|point| BoundingBox::from_request(&part).contains(point.lat, point.lon))
+            .collect();
+        heap.push(QuadNode {
+            min_lat: part.min_lat.degrees(),
+            min_lon: part.min_lon.degrees(),
+            lat_size: part.max_lat.degrees() - part.min_lat.degrees(),
+            lon_size: part.max_lon.degrees() - part.min_lon.degrees(),
+            depth: 0,
+            noisy_count: noisy_count(&part_points),
+            points: part_points,
+        });
+    }
+
+    // Subdivide the densest node while the cell budget allows replacing it with four
+    let mut leaves = Vec::new();
+    while let Some(node) = heap.pop() {
+        let can_split = node.depth < max_depth
+            && node.noisy_count >= SPLIT_THRESHOLD
+            && node.lat_size.max(node.lon_size) > GRID_SIZE
+            && leaves.len() + heap.len() + 4 <= max_cells;
+        if !can_split {
+            leaves.push(node);
+            continue;
+        }
+
+        let lat_size = node.lat_size / 2.0;
+        let lon_size = node.lon_size / 2.0;
+        for (lat_half, lon_half) in [(0.0, 0.0), (0.0, 1.0), (1.0, 0.0), (1.0, 1.0)] {
+            let min_lat = node.min_lat + lat_half * lat_size;
+            let min_lon = node.min_lon + lon_half * lon_size;
+            let child_points: Vec<&HeatmapPoint> = node.points.iter()
+                .copied()
+                .filter(|point| {
+                    in_half(point.lat.degrees(), min_lat, lat_size, lat_half) &&
+                    in_half(point.lon.degrees(), min_lon, lon_size, lon_half)
+                })
+                .collect();
+            heap.push(QuadNode {
+                min_lat,
+                min_lon,
+                lat_size,
+                lon_size,
+                depth: node.depth + 1,
+                noisy_count: noisy_count(&child_points),
+                points: child_points,
+            });
+        }
+    }
+
+    // Intensity is the density of a cell relative to the densest one
+    let density = |node: &QuadNode| node.noisy_count.max(0.0) / (node.lat_size * node.lon_size / (GRID_SIZE * GRID_SIZE)).max(1.0);
+    let max_density = leaves.iter().map(density).fold(0.0, f64::max);
+
+    for node in &leaves {
+        let count = node.noisy_count.round().max(0.0) as u32;
+        if count == 0 {
+            continue;
+        }
+        response.cells.push(AdaptiveHeatmapCell {
+            lat: Latitude::clamped(node.min_lat),
+            lon: Longitude::clamped(node.min_lon),
+            lat_size: node.lat_size,
+            lon_size: node.lon_size,
+            depth: node.depth,
+            count,
+            intensity: if max_density > 0.0 { density(node) / max_density } else { 0.0 },
+        });
+    }
+
+    response
+}
+
+// Whether a coordinate falls in the lower (0) or upper (1) half of a split node;
+// the upper half keeps the node's closing edge
+fn in_half(value: f64, start: f64, size: f64, half: f64) -> bool {
+    if half == 0.0 {
+        value < start + size
+    } else {
+        value >= start
+    }
+}
@@ -0,0 +1,146 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use dashmap::DashMap;
+use crate::models::{BoundingBox, HeatmapResponse, Latitude, Longitude};
+
+// Default bounds for the shared heatmap cache
+pub const DEFAULT_CACHE_CAPACITY: usize = 256;
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
+
+// A cached heatmap with its bookkeeping
+struct CacheEntry {
+    response: HeatmapResponse,
+    inserted_at: Instant,
+    last_used: u64,
+}
+
+// Heatmap cache with a time-to-live, LRU capacity bound and bbox invalidation. Entries are
+// sharded, so lookups of different queries do not wait on each other; the capacity bound is
+// approximate while several insertions race
+pub struct HeatmapCache {
+    entries: DashMap<String, CacheEntry>,
+    capacity: usize,
+    ttl: Duration,
+    clock: AtomicU64,
+    // Lookups answered from the cache and lookups that missed, for the metrics endpoint
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl HeatmapCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        HeatmapCache {
+            entries: DashMap::new(),
+            capacity,
+            ttl,
+            clock: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    // Get a fresh cached response, dropping it if it has expired
+    pub fn get(&self, key: &str) -> Option<HeatmapResponse> {
+        let clock = self.clock.fetch_add(1, Ordering::Relaxed) + 1;
+        
+        if let Some(mut entry) = self.entries.get_mut(key) {
+            if entry.inserted_at.elapsed() < self.ttl {
+                entry.last_used = clock;
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Some(entry.response.clone());
+            }
+        }
+        
+        self.entries.remove_if(key, |_, entry| entry.inserted_at.elapsed() >= self.ttl);
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    // Lookups so far as (hits, misses)
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+
+    // Insert a response, evicting the least recently used entry when full
+    pub fn insert(&self, key: String, response: HeatmapResponse) {
+        if self.capacity == 0 {
+            return;
+        }
+        
+        let clock = self.clock.fetch_add(1, Ordering::Relaxed) + 1;
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            self.evict_expired();
+            if self.entries.len() >= self.capacity {
+                self.evict_least_recently_used();
+            }
+        }
+        
+        self.entries.insert(key, CacheEntry {
+            response,
+            inserted_at: Instant::now(),
+            last_used: clock,
+        });
+    }
+
+    // Drop every cached heatmap whose bounding box contains the point, including boxes
+    // that cross the antimeridian
+    pub fn invalidate_point(&self, lat: Latitude, lon: Longitude) {
+        self.entries.retain(|_, entry| {
+            let response = &entry.response;
+            let bounds = BoundingBox {
+                min_lat: response.min_lat,
+                min_lon: response.min_lon,
+                max_lat: response.max_lat,
+                max_lon: response.max_lon,
+            };
+            !bounds.contains(lat, lon)
+        });
+    }
+
+    // Whether a fresh response is cached, without counting a lookup
+    pub fn contains_fresh(&self, key: &str) -> bool {
+        self.entries.get(key).is_some_and(|entry| entry.inserted_at.elapsed() < self.ttl)
+    }
+
+    pub fn clear(&self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Drop expired entries, returning how many were dropped
+    pub fn evict_expired(&self) -> usize {
+        let mut evicted = 0;
+        self.entries.retain(|_, entry| {
+            let fresh = entry.inserted_at.elapsed() < self.ttl;
+            if !fresh {
+                evicted += 1;
+            }
+            fresh
+        });
+        evicted
+    }
+
+    fn evict_least_recently_used(&self) {
+        // The key is copied out, since removing while iterating would wait on the shard read lock
+        let oldest = self.entries.iter()
+            .min_by_key(|entry| entry.last_used)
+            .map(|entry| entry.key().clone());
+        
+        if let Some(key) = oldest {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+impl Default for HeatmapCache {
+    fn default() -> Self {
+        HeatmapCache::new(DEFAULT_CACHE_CAPACITY, DEFAULT_CACHE_TTL)
+    }
+}
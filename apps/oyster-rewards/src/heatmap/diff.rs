@@ -0,0 +1,94 @@
+use chrono::{DateTime, Utc};
+use crate::models::{Latitude, Longitude, HeatmapRequest, HeatmapDiffResponse, HeatmapDiffCell, TimeRange};
+use crate::location::GRID_SIZE;
+use super::privacy::{PrivacyParams, ANONYMOUS_REQUESTER};
+use super::{build_noisy_grid, collect_points, floor_to_hour, check_time_range, split_request, validate_bounds, PRIVACY_BUDGET};
+
+// Compare the density of period B against period A over the request bounds.
+// Both windows are released with the request epsilon, so the diff costs twice that.
+pub fn generate_heatmap_diff(
+    request: &HeatmapRequest,
+    period_a: &TimeRange,
+    period_b: &TimeRange
+) -> HeatmapDiffResponse {
+    let mut response = HeatmapDiffResponse {
+        cells: Vec::new(),
+        period_a: period_a.clone(),
+        period_b: period_b.clone(),
+        min_lat: request.min_lat,
+        max_lat: request.max_lat,
+        min_lon: request.min_lon,
+        max_lon: request.max_lon,
+        epsilon_spent: 0.0,
+        privacy_budget_remaining: None,
+        error: None,
+    };
+
+    // Validate the bounds and both periods, and resolve the noise mechanism
+    let resolved = validate_bounds(request)
+        .and_then(|_| PrivacyParams::from_request(request))
+        .and_then(|params| Ok((resolve_period(period_a)?, resolve_period(period_b)?, params)));
+    let (range_a, range_b, params) = match resolved {
+        Ok(resolved) => resolved,
+        Err(error) => {
+            response.error = Some(error);
+            return response;
+        }
+    };
+
+    // Charge the budget for both releases
+    let requester = request.requester_id.as_deref().unwrap_or(ANONYMOUS_REQUESTER);
+    let epsilon_spent = 2.0 * params.epsilon;
+    match PRIVACY_BUDGET.lock().unwrap().charge(requester, epsilon_spent) {
+        Ok(remaining) => {
+            response.epsilon_spent = epsilon_spent;
+            response.privacy_budget_remaining = Some(remaining);
+        },
+        Err(error) => {
+            response.error = Some(error);
+            return response;
+        }
+    }
+
+    let points_a = collect_points(request, Some(range_a));
+    let points_b = collect_points(request, Some(range_b));
+
+    // Each side of the antimeridian is gridded separately
+    for part in split_request(request) {
+        let grid_a = build_noisy_grid(&points_a, &part, &params);
+        let grid_b = build_noisy_grid(&points_b, &part, &params);
+
+        // Keep cells that are populated in either window
+        for (i, (row_a, row_b)) in grid_a.iter().zip(grid_b.iter()).enumerate() {
+            for (j, (&count_a, &count_b)) in row_a.iter().zip(row_b.iter()).enumerate() {
+                if count_a == 0 && count_b == 0 {
+                    continue;
+                }
+
+                let delta = count_b as i64 - count_a as i64;
+                let percent_change = if count_a > 0 {
+                    Some(delta as f64 / count_a as f64 * 100.0)
+                } else {
+                    None
+                };
+
+                response.cells.push(HeatmapDiffCell {
+                    lat: Latitude::clamped(part.min_lat.degrees() + (i as f64 * GRID_SIZE)),
+                    lon: Longitude::clamped(part.min_lon.degrees() + (j as f64 * GRID_SIZE)),
+                    count_a,
+                    count_b,
+                    delta,
+                    percent_change,
+                });
+            }
+        }
+    }
+
+    response
+}
+
+// Parse a period, aligned to the hourly resolution of the aggregates
+fn resolve_period(period: &TimeRange) -> Result<(DateTime<Utc>, DateTime<Utc>), String> {
+    let (start, end) = check_time_range(period)?;
+    Ok((floor_to_hour(start), end))
+}
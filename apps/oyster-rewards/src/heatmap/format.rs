@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+// Serializations a heatmap can be returned in
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HeatmapFormat {
+    #[default]
+    Json,
+    GeoJson,
+    // Cells streamed in batches, resumable with a continuation token
+    Ndjson,
+    Png,
+    // One vector tile whose extent is the requested bounding box
+    Mvt,
+}
+
+// Media types understood in Accept headers, in the order they are preferred on equal quality
+const MEDIA_TYPES: &[(&str, HeatmapFormat)] = &[
+    ("application/json", HeatmapFormat::Json),
+    ("application/geo+json", HeatmapFormat::GeoJson),
+    ("application/x-ndjson", HeatmapFormat::Ndjson),
+    ("image/png", HeatmapFormat::Png),
+    ("application/vnd.mapbox-vector-tile", HeatmapFormat::Mvt),
+    ("application/x-protobuf", HeatmapFormat::Mvt),
+    ("image/*", HeatmapFormat::Png),
+    ("application/*", HeatmapFormat::Json),
+    ("*/*", HeatmapFormat::Json),
+];
+
+impl HeatmapFormat {
+    // Format named by a `format` query parameter
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "json" => Ok(HeatmapFormat::Json),
+            "geojson" => Ok(HeatmapFormat::GeoJson),
+            "ndjson" => Ok(HeatmapFormat::Ndjson),
+            "png" => Ok(HeatmapFormat::Png),
+            "mvt" => Ok(HeatmapFormat::Mvt),
+            other => Err(format!("Unsupported heatmap format: {}", other)),
+        }
+    }
+
+    // Best format an Accept header asks for: the highest quality wins, then the order the client
+    // listed types in. None when nothing acceptable is supported.
+    pub fn from_accept(accept: &str) -> Option<Self> {
+        let mut best: Option<(f64, HeatmapFormat)> = None;
+        for range in accept.split(',') {
+            let mut parts = range.split(';');
+            let media_type = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f64>().ok())
+                .unwrap_or(1.0);
+            if quality <= 0.0 {
+                continue;
+            }
+            let Some(&(_, format)) = MEDIA_TYPES.iter().find(|(name, _)| *name == media_type) else { continue };
+            if best.is_none_or(|(best_quality, _)| quality > best_quality) {
+                best = Some((quality, format));
+            }
+        }
+        best.map(|(_, format)| format)
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            HeatmapFormat::Json => "application/json",
+            HeatmapFormat::GeoJson => "application/geo+json",
+            HeatmapFormat::Ndjson => "application/x-ndjson",
+            HeatmapFormat::Png => "image/png",
+            HeatmapFormat::Mvt => "application/vnd.mapbox-vector-tile",
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use serde_json::{json, Value};
+use crate::models::{HeatmapResponse, HeatmapCell};
+use crate::location::GRID_SIZE;
+
+impl HeatmapResponse {
+    // Convert the heatmap into a GeoJSON FeatureCollection of cell polygons
+    pub fn to_geojson(&self) -> Value {
+        let features: Vec<Value> = self.cells.iter()
+            .map(cell_to_feature)
+            .collect();
+        
+        json!({
+            "type": "FeatureCollection",
+            "features": features,
+            "bbox": [self.min_lon, self.min_lat, self.max_lon, self.max_lat],
+            "properties": {
+                "privacy_level": self.privacy_level,
+            },
+        })
+    }
+}
+
+// Convert a single cell into a polygon feature (GeoJSON uses lon/lat order)
+fn cell_to_feature(cell: &HeatmapCell) -> Value {
+    let (min_lon, min_lat) = (cell.lon.degrees(), cell.lat.degrees());
+    let (max_lon, max_lat) = (min_lon + GRID_SIZE, min_lat + GRID_SIZE);
+    
+    json!({
+        "type": "Feature",
+        "geometry": {
+            "type": "Polygon",
+            "coordinates": [[
+                [min_lon, min_lat],
+                [max_lon, min_lat],
+                [max_lon, max_lat],
+                [min_lon, max_lat],
+                [min_lon, min_lat],
+            ]],
+        },
+        "properties": {
+            "intensity": cell.intensity,
+            "count": cell.count,
+        },
+    })
+}
@@ -0,0 +1,110 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+use chrono::{DateTime, NaiveDate, Utc};
+use crate::models::{BoundingBox, GridCell, HeatmapRequest, HeatmapResponse, HeatmapSnapshot, HeatmapSnapshotCell};
+use crate::location::GRID_SIZE;
+use crate::store::STORE;
+use super::privacy::{PrivacyParams, ANONYMOUS_REQUESTER};
+use super::{
+    build_heatmap_cells, empty_heatmap_response, validate_bounds, HeatmapPoint,
+    HEATMAP_DATA, PRIVACY_BUDGET, SECONDS_PER_HOUR
+};
+
+// Days whose aggregates changed since they were last persisted
+static DIRTY_DAYS: Lazy<Mutex<BTreeSet<NaiveDate>>> = Lazy::new(|| Mutex::new(BTreeSet::new()));
+
+const HOURS_PER_DAY: i64 = 24;
+
+// Remember that the snapshot of the day containing `time` is out of date
+pub(super) fn mark_dirty(time: DateTime<Utc>) {
+    DIRTY_DAYS.lock().unwrap().insert(time.date_naive());
+}
+
+// Write the snapshot of every changed day to the store, returning how many were written
+pub fn persist_snapshots() -> Result<usize, String> {
+    let days: Vec<NaiveDate> = std::mem::take(&mut *DIRTY_DAYS.lock().unwrap()).into_iter().collect();
+
+    for (index, day) in days.iter().enumerate() {
+        let snapshot = current_snapshot(*day);
+        if let Err(error) = snapshot.and_then(|snapshot| STORE.lock().unwrap().save_heatmap_snapshot(&snapshot)) {
+            // Retry the days that were not written on the next run
+            DIRTY_DAYS.lock().unwrap().extend(days[index..].iter().copied());
+            return Err(error);
+        }
+    }
+
+    Ok(days.len())
+}
+
+// Snapshot of a day: the live aggregates merged with what was persisted earlier,
+// so cells whose raw data has since been purged keep their persisted counts
+fn current_snapshot(day: NaiveDate) -> Result<HeatmapSnapshot, String> {
+    let date = day.format("%Y-%m-%d").to_string();
+    let mut counts: HashMap<GridCell, u32> = HashMap::new();
+
+    if let Some(stored) = STORE.lock().unwrap().load_heatmap_snapshot(&date)? {
+        for cell in stored.cells {
+            counts.insert(GridCell { lat_grid: cell.lat_grid, lon_grid: cell.lon_grid }, cell.count);
+        }
+    }
+
+    let start_hour = day.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp() / SECONDS_PER_HOUR;
+    let data = HEATMAP_DATA.lock().unwrap();
+    for (grid_cell, aggregate) in data.iter() {
+        let live: u32 = aggregate.hourly.range(start_hour..start_hour + HOURS_PER_DAY).map(|(_, &count)| count).sum();
+        if live > 0 {
+            let count = counts.entry(grid_cell.clone()).or_insert(0);
+            *count = (*count).max(live);
+        }
+    }
+    drop(data);
+
+    let mut cells: Vec<HeatmapSnapshotCell> = counts.into_iter()
+        .map(|(cell, count)| HeatmapSnapshotCell { lat_grid: cell.lat_grid, lon_grid: cell.lon_grid, count })
+        .collect();
+    cells.sort_by_key(|cell| (cell.lat_grid, cell.lon_grid));
+
+    Ok(HeatmapSnapshot { date, cells })
+}
+
+// Privacy-preserving heatmap of one past day (YYYY-MM-DD), read from the persisted snapshot
+pub fn heatmap_history(date: &str, request: &HeatmapRequest) -> HeatmapResponse {
+    let day = match NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        Ok(day) => day,
+        Err(_) => return empty_heatmap_response(request, Some("date must be formatted as YYYY-MM-DD".to_string())),
+    };
+    if let Err(error) = validate_bounds(request) {
+        return empty_heatmap_response(request, Some(error));
+    }
+    let params = match PrivacyParams::from_request(request) {
+        Ok(params) => params,
+        Err(error) => return empty_heatmap_response(request, Some(error)),
+    };
+
+    let snapshot = match current_snapshot(day) {
+        Ok(snapshot) => snapshot,
+        Err(error) => return empty_heatmap_response(request, Some(error)),
+    };
+
+    let requester = request.requester_id.as_deref().unwrap_or(ANONYMOUS_REQUESTER);
+    let privacy_budget_remaining = match PRIVACY_BUDGET.lock().unwrap().charge(requester, params.epsilon) {
+        Ok(remaining) => remaining,
+        Err(error) => return empty_heatmap_response(request, Some(error)),
+    };
+
+    let bounds = BoundingBox::from_request(request);
+    let points: Vec<HeatmapPoint> = snapshot.cells.iter()
+        .filter_map(|cell| {
+            let (lat, lon) = GridCell { lat_grid: cell.lat_grid, lon_grid: cell.lon_grid }.to_coordinates(GRID_SIZE);
+            bounds.contains(lat, lon).then_some(HeatmapPoint { lat, lon, timestamp: None, weight: cell.count })
+        })
+        .collect();
+
+    HeatmapResponse {
+        cells: build_heatmap_cells(&points, request, &params),
+        epsilon_spent: params.epsilon,
+        privacy_budget_remaining: Some(privacy_budget_remaining),
+        ..empty_heatmap_response(request, None)
+    }
+}
@@ -0,0 +1,155 @@
+use crate::models::{HeatmapRequest, HotspotsResponse, HeatmapHotspot, Latitude, Longitude};
+use crate::location::GRID_SIZE;
+use crate::analytics::METERS_PER_DEGREE;
+use super::privacy::{PrivacyParams, ANONYMOUS_REQUESTER};
+use super::{build_noisy_grid, collect_points, floor_to_hour, check_time_range, split_request, validate_bounds, PRIVACY_BUDGET};
+
+// Cells at or above this fraction of the busiest cell count as high-density
+const HOTSPOT_THRESHOLD: f64 = 0.25;
+
+// Upper bound on hotspots returned per request
+pub const MAX_HOTSPOTS: usize = 100;
+
+// Grid positions (row, column) and counts of adjacent high-density cells
+type Cluster = Vec<(usize, usize, u32)>;
+
+// Cluster the high-density cells of the request bounds (and time range) into
+// hotspots and return the `n` busiest, ranked by visits
+pub fn top_hotspots(request: &HeatmapRequest, n: usize) -> HotspotsResponse {
+    let mut response = HotspotsResponse {
+        hotspots: Vec::new(),
+        epsilon_spent: 0.0,
+        privacy_budget_remaining: None,
+        error: None,
+    };
+
+    let resolved = validate_bounds(request)
+        .and_then(|_| PrivacyParams::from_request(request))
+        .and_then(|params| match &request.time_range {
+            Some(range) => {
+                let (start, end) = check_time_range(range)?;
+                Ok((params, Some((floor_to_hour(start), end))))
+            },
+            None => Ok((params, None)),
+        });
+    let (params, time_range) = match resolved {
+        Ok(resolved) => resolved,
+        Err(error) => {
+            response.error = Some(error);
+            return response;
+        }
+    };
+
+    // Clustering only post-processes the noisy grid, so one release is charged
+    let requester = request.requester_id.as_deref().unwrap_or(ANONYMOUS_REQUESTER);
+    match PRIVACY_BUDGET.lock().unwrap().charge(requester, params.epsilon) {
+        Ok(remaining) => {
+            response.epsilon_spent = params.epsilon;
+            response.privacy_budget_remaining = Some(remaining);
+        },
+        Err(error) => {
+            response.error = Some(error);
+            return response;
+        }
+    }
+
+    // Each side of the antimeridian is gridded separately
+    let points = collect_points(request, time_range);
+    let grids: Vec<(HeatmapRequest, Vec<Vec<u32>>)> = split_request(request)
+        .into_iter()
+        .map(|part| {
+            let grid = build_noisy_grid(&points, &part, &params);
+            (part, grid)
+        })
+        .collect();
+
+    let max_count = grids.iter().flat_map(|(_, grid)| grid.iter().flatten()).copied().max().unwrap_or(0);
+    if max_count == 0 {
+        return response;
+    }
+    let threshold = ((max_count as f64 * HOTSPOT_THRESHOLD).ceil() as u32).max(1);
+
+    let mut clusters: Vec<(&HeatmapRequest, Cluster)> = grids.iter()
+        .flat_map(|(part, grid)| find_clusters(grid, threshold).into_iter().map(move |cluster| (part, cluster)))
+        .collect();
+    clusters.sort_by_key(|(_, cluster)| std::cmp::Reverse(cluster.iter().map(|&(_, _, count)| count).sum::<u32>()));
+
+    for (index, (part, cluster)) in clusters.into_iter().take(n.min(MAX_HOTSPOTS)).enumerate() {
+        response.hotspots.push(cluster_to_hotspot(&cluster, index + 1, part));
+    }
+
+    response
+}
+
+// Group adjacent cells (including diagonals) at or above the threshold
+fn find_clusters(grid: &[Vec<u32>], threshold: u32) -> Vec<Cluster> {
+    let rows = grid.len();
+    let cols = grid.first().map(|row| row.len()).unwrap_or(0);
+    let mut visited = vec![vec![false; cols]; rows];
+    let mut clusters = Vec::new();
+
+    for i in 0..rows {
+        for j in 0..cols {
+            if visited[i][j] || grid[i][j] < threshold {
+                continue;
+            }
+
+            // Flood fill from this cell
+            let mut cluster = Vec::new();
+            let mut pending = vec![(i, j)];
+            visited[i][j] = true;
+            while let Some((ci, cj)) = pending.pop() {
+                cluster.push((ci, cj, grid[ci][cj]));
+
+                for ni in ci.saturating_sub(1)..=(ci + 1).min(rows - 1) {
+                    for nj in cj.saturating_sub(1)..=(cj + 1).min(cols - 1) {
+                        if !visited[ni][nj] && grid[ni][nj] >= threshold {
+                            visited[ni][nj] = true;
+                            pending.push((ni, nj));
+                        }
+                    }
+                }
+            }
+            clusters.push(cluster);
+        }
+    }
+
+    clusters
+}
+
+// Summarize a cluster as a visit-weighted centroid and the radius covering its cells
+fn cluster_to_hotspot(cluster: &[(usize, usize, u32)], rank: usize, request: &HeatmapRequest) -> HeatmapHotspot {
+    // Cell centers
+    let center = |i: usize, j: usize| (
+        request.min_lat.degrees() + (i as f64 + 0.5) * GRID_SIZE,
+        request.min_lon.degrees() + (j as f64 + 0.5) * GRID_SIZE,
+    );
+
+    let count: u32 = cluster.iter().map(|&(_, _, count)| count).sum();
+    let (mut lat, mut lon) = (0.0, 0.0);
+    for &(i, j, cell_count) in cluster {
+        let (cell_lat, cell_lon) = center(i, j);
+        lat += cell_lat * cell_count as f64;
+        lon += cell_lon * cell_count as f64;
+    }
+    lat /= count as f64;
+    lon /= count as f64;
+
+    // Farthest cell center, plus half a cell so single-cell hotspots have a size
+    let radius_degrees = cluster.iter()
+        .map(|&(i, j, _)| {
+            let (cell_lat, cell_lon) = center(i, j);
+            ((cell_lat - lat).powi(2) + (cell_lon - lon).powi(2)).sqrt()
+        })
+        .fold(0.0, f64::max) + GRID_SIZE / 2.0;
+
+    HeatmapHotspot {
+        rank,
+        name: format!("Hotspot #{} ({:.4}, {:.4})", rank, lat, lon),
+        lat: Latitude::clamped(lat),
+        lon: Longitude::clamped(lon),
+        radius_meters: radius_degrees * METERS_PER_DEGREE,
+        cell_count: cluster.len(),
+        count,
+    }
+}
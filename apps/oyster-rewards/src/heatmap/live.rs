@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use once_cell::sync::Lazy;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use tokio::sync::broadcast::{self, error::TryRecvError};
+use crate::models::{BoundingBox, GridCell, HeatmapCell, HeatmapRequest, Latitude, Longitude};
+use crate::location::GRID_SIZE;
+use super::privacy::{PrivacyParams, ANONYMOUS_REQUESTER};
+use super::{grid_dimensions, PRIVACY_BUDGET};
+
+// Registrations buffered per subscriber between two flushes
+const LIVE_CHANNEL_CAPACITY: usize = 4096;
+
+// Largest area a live subscription may cover (every cell is noised on each flush)
+pub const MAX_LIVE_GRID_CELLS: u64 = 250_000;
+
+// Noisy deltas below this are not pushed, so empty cells rarely show up
+const LIVE_RELEASE_THRESHOLD: f64 = 1.5;
+
+// Grid cells of newly recorded points
+static LIVE_UPDATES: Lazy<broadcast::Sender<GridCell>> = Lazy::new(|| broadcast::channel(LIVE_CHANNEL_CAPACITY).0);
+
+// Announce a recorded point to live subscribers
+pub(super) fn publish(grid_cell: &GridCell) {
+    // Sending only fails when nobody is subscribed
+    let _ = LIVE_UPDATES.send(grid_cell.clone());
+}
+
+// A subscriber's view of new points inside its bounds.
+//
+// Every point lands in exactly one flush window, so the windows compose in parallel
+// and the subscription is charged epsilon once.
+pub struct LiveSubscription {
+    receiver: broadcast::Receiver<GridCell>,
+    request: HeatmapRequest,
+    bounds: BoundingBox,
+    params: PrivacyParams,
+    rng: StdRng,
+    pub epsilon_spent: f64,
+    pub privacy_budget_remaining: f64,
+}
+
+// Start receiving heatmap deltas for the request bounds
+pub fn subscribe(request: &HeatmapRequest) -> Result<LiveSubscription, String> {
+    let bounds = BoundingBox::from_request(request);
+    bounds.validate()?;
+    if bounds.crosses_antimeridian() {
+        return Err("Live heatmaps cannot cross the antimeridian; subscribe to each side separately".to_string());
+    }
+    if bounds.grid_cells(GRID_SIZE) > MAX_LIVE_GRID_CELLS {
+        return Err(format!("Live heatmaps may cover at most {} grid cells", MAX_LIVE_GRID_CELLS));
+    }
+    let params = PrivacyParams::from_request(request)?;
+
+    let requester = request.requester_id.as_deref().unwrap_or(ANONYMOUS_REQUESTER);
+    let privacy_budget_remaining = PRIVACY_BUDGET.lock().unwrap().charge(requester, params.epsilon)?;
+
+    Ok(LiveSubscription {
+        receiver: LIVE_UPDATES.subscribe(),
+        request: request.clone(),
+        bounds,
+        params,
+        rng: StdRng::from_entropy(),
+        epsilon_spent: params.epsilon,
+        privacy_budget_remaining,
+    })
+}
+
+impl LiveSubscription {
+    // Noisy per-cell counts of the points recorded since the previous flush
+    pub fn flush(&mut self) -> Vec<HeatmapCell> {
+        let (lat_cells, lon_cells) = grid_dimensions(&self.request);
+        let mut deltas: HashMap<(usize, usize), u32> = HashMap::new();
+
+        loop {
+            match self.receiver.try_recv() {
+                Ok(grid_cell) => {
+                    let (lat, lon) = grid_cell.to_coordinates(GRID_SIZE);
+                    if !self.bounds.contains(lat, lon) {
+                        continue;
+                    }
+                    let lat_idx = ((lat.degrees() - self.request.min_lat.degrees()) / GRID_SIZE).floor() as usize;
+                    let lon_idx = ((lon.degrees() - self.request.min_lon.degrees()) / GRID_SIZE).floor() as usize;
+                    if lat_idx < lat_cells && lon_idx < lon_cells {
+                        *deltas.entry((lat_idx, lon_idx)).or_insert(0) += 1;
+                    }
+                },
+                // Points missed by a slow subscriber are simply not reported
+                Err(TryRecvError::Lagged(_)) => continue,
+                Err(TryRecvError::Empty) | Err(TryRecvError::Closed) => break,
+            }
+        }
+
+        // Noise every cell, not only the touched ones, so quiet cells are not revealed
+        let mut cells = Vec::new();
+        for i in 0..lat_cells {
+            for j in 0..lon_cells {
+                let delta = deltas.get(&(i, j)).copied().unwrap_or(0) as f64;
+                let noisy = delta + self.params.sample_noise(&mut self.rng);
+                if noisy >= LIVE_RELEASE_THRESHOLD {
+                    cells.push(HeatmapCell {
+                        lat: Latitude::clamped(self.request.min_lat.degrees() + (i as f64 * GRID_SIZE)),
+                        lon: Longitude::clamped(self.request.min_lon.degrees() + (j as f64 * GRID_SIZE)),
+                        intensity: 0.0,
+                        count: noisy.round() as u32,
+                    });
+                }
+            }
+        }
+
+        // Normalize intensity within the flush
+        let max_count = cells.iter().map(|cell| cell.count).max().unwrap_or(0);
+        for cell in cells.iter_mut() {
+            cell.intensity = cell.count as f64 / max_count as f64;
+        }
+
+        cells
+    }
+}
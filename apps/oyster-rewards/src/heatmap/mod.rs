@@ -1,103 +1,426 @@
-use std::collections::HashMap;
+pub mod adaptive;
+pub mod cache;
+pub mod diff;
+pub mod format;
+pub mod geojson;
+pub mod history;
+pub mod live;
+pub mod popular;
+pub mod hotspots;
+pub mod privacy;
+pub mod render;
+pub mod stream;
+pub mod tiles;
+
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Mutex;
 use once_cell::sync::Lazy;
 use rand::Rng;
-use rand::rngs::ThreadRng;
-use rand_distr::{Normal, Distribution};
-use crate::models::{GridCell, HeatmapRequest, HeatmapResponse, HeatmapCell};
-use crate::location::{LOCATION_HISTORY, GRID_SIZE};
-use crate::crypto;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use chrono::{DateTime, Duration, Utc};
+use crate::models::{BoundingBox, GridCell, Latitude, Longitude, HeatmapRequest, HeatmapResponse, HeatmapCell, HeatmapFrame, TimeRange, TimeBucket};
+use crate::location::GRID_SIZE;
+use cache::HeatmapCache;
+use privacy::{PrivacyBudgetLedger, PrivacyParams, ANONYMOUS_REQUESTER};
 
 // In-memory cache for heatmap data
-pub static HEATMAP_CACHE: Lazy<Mutex<HashMap<String, HeatmapResponse>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+pub static HEATMAP_CACHE: Lazy<HeatmapCache> = Lazy::new(HeatmapCache::default);
+
+// Epsilon spent per requester
+pub static PRIVACY_BUDGET: Lazy<Mutex<PrivacyBudgetLedger>> = Lazy::new(|| Mutex::new(PrivacyBudgetLedger::default()));
+
+// Running per-cell counts, updated at registration time so heatmaps never decrypt history
+pub static HEATMAP_DATA: Lazy<Mutex<HashMap<GridCell, CellAggregate>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+const SECONDS_PER_HOUR: i64 = 3600;
+
+// Aggregated visits for one grid cell
+#[derive(Debug, Clone, Default)]
+pub struct CellAggregate {
+    // Every point recorded in the cell, including ones without a usable timestamp
+    pub total: u32,
+    // Points per hour, keyed by hours since the Unix epoch
+    pub hourly: BTreeMap<i64, u32>,
+}
+
+// Largest grid materialized in one response; bigger areas have to be streamed
+pub const MAX_HEATMAP_GRID_CELLS: usize = 4_000_000;
+
+// Maximum number of frames returned for a time-bucketed heatmap
+const MAX_HEATMAP_FRAMES: usize = 744; // one month of hourly frames
+
+// Most grid cells built across the frames of one response; every frame is a full noisy grid
+pub const MAX_HEATMAP_FRAME_CELLS: u64 = 16_000_000;
+
+// A weighted point counted towards the heatmap
+struct HeatmapPoint {
+    lat: Latitude,
+    lon: Longitude,
+    timestamp: Option<DateTime<Utc>>,
+    weight: u32,
+}
 
 // Generate a privacy-preserving heatmap
 pub fn generate_heatmap(request: &HeatmapRequest) -> HeatmapResponse {
-    let cache_key = format!("{}-{}-{}-{}-{}", 
+    let response = compute_heatmap(request);
+    
+    // Answered queries count towards the popular ones the scheduler keeps warm
+    if response.error.is_none() {
+        popular::record_request(request);
+    }
+    response
+}
+
+// Key of a query in the heatmap cache; the requester is not part of it
+fn cache_key(request: &HeatmapRequest) -> String {
+    format!("{}-{}-{}-{}-{}-{:?}-{:?}-{:?}-{:?}-{:?}", 
         request.min_lat, request.max_lat, 
         request.min_lon, request.max_lon, 
-        request.privacy_level);
+        request.privacy_level,
+        request.time_range, request.bucket,
+        request.epsilon, request.delta, request.sensitivity)
+}
+
+fn compute_heatmap(request: &HeatmapRequest) -> HeatmapResponse {
+    let cache_key = cache_key(request);
+    let requester = request.requester_id.as_deref().unwrap_or(ANONYMOUS_REQUESTER);
     
-    // Check if we have a cached result
-    let cache = HEATMAP_CACHE.lock().unwrap();
-    if let Some(cached_response) = cache.get(&cache_key) {
-        return cached_response.clone();
+    // Check if we have a cached result (re-serving the same noisy answer costs no budget)
+    if let Some(mut cached_response) = HEATMAP_CACHE.get(&cache_key) {
+        cached_response.epsilon_spent = 0.0;
+        cached_response.privacy_budget_remaining = Some(PRIVACY_BUDGET.lock().unwrap().remaining(requester));
+        return cached_response;
     }
-    drop(cache); // Release lock before expensive operation
     
-    // Calculate grid boundaries
-    let lat_cells = ((request.max_lat - request.min_lat) / GRID_SIZE).ceil() as usize;
-    let lon_cells = ((request.max_lon - request.min_lon) / GRID_SIZE).ceil() as usize;
+    let bounds = match validate_bounds(request) {
+        Ok(bounds) => bounds,
+        Err(error) => return empty_heatmap_response(request, Some(error)),
+    };
     
-    // Initialize grid with zeros
-    let mut grid: Vec<Vec<u32>> = vec![vec![0; lon_cells]; lat_cells];
+    // Resolve the noise mechanism
+    let params = match PrivacyParams::from_request(request) {
+        Ok(params) => params,
+        Err(error) => return empty_heatmap_response(request, Some(error)),
+    };
     
-    // Populate grid with real data
-    let history = LOCATION_HISTORY.lock().unwrap();
-    for (_, user_locations) in history.iter() {
-        for encrypted_location in user_locations {
-            // Try to decrypt the location
-            if let Ok(location) = crypto::decrypt_location(encrypted_location) {
-                // Check if it's in our request bounds
-                if location.lat >= request.min_lat && location.lat <= request.max_lat &&
-                   location.lon >= request.min_lon && location.lon <= request.max_lon {
-                    
-                    // Calculate grid position
-                    let lat_idx = ((location.lat - request.min_lat) / GRID_SIZE).floor() as usize;
-                    let lon_idx = ((location.lon - request.min_lon) / GRID_SIZE).floor() as usize;
-                    
-                    // Increment count for this cell
-                    if lat_idx < lat_cells && lon_idx < lon_cells {
-                        grid[lat_idx][lon_idx] += 1;
-                    }
-                }
-            }
+    // Parse the optional time range (aggregates have hourly resolution)
+    let time_range = match &request.time_range {
+        Some(range) => match check_time_range(range) {
+            Ok((start, end)) => Some((floor_to_hour(start), end)),
+            Err(error) => return empty_heatmap_response(request, Some(error)),
+        },
+        None => None,
+    };
+    
+    // Refuse frame sets too large to build before any budget is spent on them
+    let frame_bucket = time_range.and(request.bucket);
+    if let (Some((start, end)), Some(bucket)) = (time_range, frame_bucket) {
+        let frame_cells = (frame_count(start, end, bucket) as u64).saturating_mul(bounds.grid_cells(GRID_SIZE));
+        if frame_cells > MAX_HEATMAP_FRAME_CELLS {
+            return empty_heatmap_response(request, Some(format!(
+                "Frames would cover more than {} grid cells in total; request a smaller box, a shorter time range or a larger bucket",
+                MAX_HEATMAP_FRAME_CELLS
+            )));
         }
     }
     
-    // Apply differential privacy based on privacy level
-    let dp_grid = apply_differential_privacy(&grid, request.privacy_level);
+    // Charge the requester's budget: the aggregate and the frames are two releases,
+    // while the frames themselves cover disjoint time windows (parallel composition)
+    let epsilon_spent = if frame_bucket.is_some() { 2.0 * params.epsilon } else { params.epsilon };
+    let privacy_budget_remaining = match PRIVACY_BUDGET.lock().unwrap().charge(requester, epsilon_spent) {
+        Ok(remaining) => remaining,
+        Err(error) => return empty_heatmap_response(request, Some(error)),
+    };
+    
+    // Collect the points inside the requested bounds (and time range)
+    let points = collect_points(request, time_range);
     
-    // Convert to output format
-    let cells = grid_to_heatmap_cells(&dp_grid, request.min_lat, request.min_lon);
+    // Aggregate over the whole period
+    let cells = build_heatmap_cells(&points, request, &params);
+    
+    // Split into frames when a bucket size is requested
+    let frames = match (time_range, frame_bucket) {
+        (Some((start, end)), Some(bucket)) => {
+            build_heatmap_frames(&points, request, &params, start, end, bucket)
+        },
+        _ => Vec::new(),
+    };
     
     // Create response
     let response = HeatmapResponse {
         cells,
+        frames,
         privacy_level: request.privacy_level,
         min_lat: request.min_lat,
         max_lat: request.max_lat,
         min_lon: request.min_lon,
         max_lon: request.max_lon,
+        epsilon_spent,
+        privacy_budget_remaining: Some(privacy_budget_remaining),
+        error: None,
     };
     
     // Cache the result
-    let mut cache = HEATMAP_CACHE.lock().unwrap();
-    cache.insert(cache_key, response.clone());
+    HEATMAP_CACHE.insert(cache_key, response.clone());
     
     response
 }
 
-// Apply differential privacy to the grid
-fn apply_differential_privacy(grid: &Vec<Vec<u32>>, privacy_level: f64) -> Vec<Vec<u32>> {
-    let mut rng = rand::thread_rng();
-    let mut dp_grid = grid.clone();
+// Record a point in the per-cell aggregates
+pub fn record_point(lat: Latitude, lon: Longitude, timestamp: Option<DateTime<Utc>>) {
+    let grid_cell = GridCell::from_location(lat, lon, GRID_SIZE);
+    let mut data = HEATMAP_DATA.lock().unwrap();
+    let aggregate = data.entry(grid_cell.clone()).or_default();
+    
+    aggregate.total += 1;
+    if let Some(time) = timestamp {
+        *aggregate.hourly.entry(hour_index(time)).or_insert(0) += 1;
+    }
+    drop(data);
+    
+    // Push the point to live subscribers
+    live::publish(&grid_cell);
     
-    // Scale noise based on privacy level (higher level = more privacy = more noise)
-    let noise_scale = privacy_level * 2.0;
+    // The daily snapshot has to be persisted again
+    if let Some(time) = timestamp {
+        history::mark_dirty(time);
+    }
+    
+    // Cached heatmaps covering this point are now stale
+    HEATMAP_CACHE.invalidate_point(lat, lon);
+}
+
+// Hours since the Unix epoch
+fn hour_index(time: DateTime<Utc>) -> i64 {
+    time.timestamp().div_euclid(SECONDS_PER_HOUR)
+}
+
+// Truncate a time to the start of its hour
+fn floor_to_hour(time: DateTime<Utc>) -> DateTime<Utc> {
+    DateTime::from_timestamp(hour_index(time) * SECONDS_PER_HOUR, 0).unwrap_or(time)
+}
+
+// Number of grid rows and columns covering the request bounds
+fn grid_dimensions(request: &HeatmapRequest) -> (usize, usize) {
+    let lat_cells = ((request.max_lat.degrees() - request.min_lat.degrees()) / GRID_SIZE).ceil() as usize;
+    let lon_cells = ((request.max_lon.degrees() - request.min_lon.degrees()) / GRID_SIZE).ceil() as usize;
+    (lat_cells, lon_cells)
+}
+
+// Validate the request bounds and refuse boxes whose grid is too large to build in memory
+fn validate_bounds(request: &HeatmapRequest) -> Result<BoundingBox, String> {
+    let bounds = BoundingBox::from_request(request);
+    bounds.validate()?;
+    
+    if bounds.grid_cells(GRID_SIZE) > MAX_HEATMAP_GRID_CELLS as u64 {
+        return Err(format!(
+            "Bounding box covers more than {} grid cells; request it as a stream (format=ndjson)",
+            MAX_HEATMAP_GRID_CELLS
+        ));
+    }
+    Ok(bounds)
+}
+
+// Requests for each side of the antimeridian (a single request when it is not crossed)
+fn split_request(request: &HeatmapRequest) -> Vec<HeatmapRequest> {
+    BoundingBox::from_request(request)
+        .split_antimeridian()
+        .iter()
+        .map(|part| request.with_bounds(part))
+        .collect()
+}
+
+// Build a heatmap response without any cells
+fn empty_heatmap_response(request: &HeatmapRequest, error: Option<String>) -> HeatmapResponse {
+    HeatmapResponse {
+        cells: Vec::new(),
+        frames: Vec::new(),
+        privacy_level: request.privacy_level,
+        min_lat: request.min_lat,
+        max_lat: request.max_lat,
+        min_lon: request.min_lon,
+        max_lon: request.max_lon,
+        epsilon_spent: 0.0,
+        privacy_budget_remaining: None,
+        error,
+    }
+}
+
+// Bounds of a time range, which must not end before it starts
+pub(crate) fn check_time_range(range: &TimeRange) -> Result<(DateTime<Utc>, DateTime<Utc>), String> {
+    let (start, end) = (range.start, range.end);
+    if end < start {
+        return Err("End time is before start time".to_string());
+    }
+    
+    Ok((start, end))
+}
+
+// Read the aggregated counts for the cells inside the request bounds (and time range)
+fn collect_points(
+    request: &HeatmapRequest,
+    time_range: Option<(DateTime<Utc>, DateTime<Utc>)>
+) -> Vec<HeatmapPoint> {
+    let mut points = Vec::new();
     
-    // Create normal distribution
-    let normal = Normal::new(0.0, noise_scale).unwrap();
+    let bounds = BoundingBox::from_request(request);
+    let data = HEATMAP_DATA.lock().unwrap();
+    for (grid_cell, aggregate) in data.iter() {
+        // Check if the cell is in our request bounds
+        let (lat, lon) = grid_cell.to_coordinates(GRID_SIZE);
+        if !bounds.contains(lat, lon) {
+            continue;
+        }
+        
+        match time_range {
+            // Untimed heatmaps use every point ever recorded in the cell
+            None => points.push(HeatmapPoint {
+                lat,
+                lon,
+                timestamp: None,
+                weight: aggregate.total,
+            }),
+            // Timed heatmaps use the hourly counters overlapping the range
+            Some((start, end)) => {
+                let hours = hour_index(start)..=hour_index(end);
+                for (&hour, &count) in aggregate.hourly.range(hours) {
+                    points.push(HeatmapPoint {
+                        lat,
+                        lon,
+                        timestamp: DateTime::from_timestamp(hour * SECONDS_PER_HOUR, 0),
+                        weight: count,
+                    });
+                }
+            },
+        }
+    }
+    
+    points
+}
+
+// Aggregate points into a grid, apply differential privacy and convert to cells
+fn build_heatmap_cells<'a, I>(points: I, request: &HeatmapRequest, params: &PrivacyParams) -> Vec<HeatmapCell>
+where
+    I: IntoIterator<Item = &'a HeatmapPoint>,
+{
+    let points: Vec<&HeatmapPoint> = points.into_iter().collect();
+    let parts = split_request(request);
+    
+    let mut cells = Vec::new();
+    for part in &parts {
+        let dp_grid = build_noisy_grid(points.iter().copied(), part, params);
+        
+        // Convert to output format
+        cells.extend(grid_to_heatmap_cells(&dp_grid, part.min_lat, part.min_lon));
+    }
+    
+    // Both sides of the antimeridian share one intensity scale
+    if parts.len() > 1 {
+        let max_value = cells.iter().map(|cell| cell.count).max().unwrap_or(0);
+        for cell in cells.iter_mut() {
+            cell.intensity = if max_value > 0 { cell.count as f64 / max_value as f64 } else { 0.0 };
+        }
+    }
+    
+    cells
+}
+
+// Aggregate points into a grid of counts and apply differential privacy
+fn build_noisy_grid<'a, I>(points: I, request: &HeatmapRequest, params: &PrivacyParams) -> Vec<Vec<u32>>
+where
+    I: IntoIterator<Item = &'a HeatmapPoint>,
+{
+    // Calculate grid boundaries
+    let (lat_cells, lon_cells) = grid_dimensions(request);
+    
+    // Initialize grid with zeros
+    let mut grid: Vec<Vec<u32>> = vec![vec![0; lon_cells]; lat_cells];
+    
+    for point in points {
+        // Skip points outside the grid (e.g. on the other side of the antimeridian)
+        if point.lat < request.min_lat || point.lon < request.min_lon {
+            continue;
+        }
+        
+        // Calculate grid position
+        let lat_idx = ((point.lat.degrees() - request.min_lat.degrees()) / GRID_SIZE).floor() as usize;
+        let lon_idx = ((point.lon.degrees() - request.min_lon.degrees()) / GRID_SIZE).floor() as usize;
+        
+        // Increment count for this cell
+        if lat_idx < lat_cells && lon_idx < lon_cells {
+            grid[lat_idx][lon_idx] += point.weight;
+        }
+    }
+    
+    // Apply differential privacy
+    apply_differential_privacy(&grid, params)
+}
+
+// Split the points into consecutive time buckets
+fn build_heatmap_frames(
+    points: &[HeatmapPoint],
+    request: &HeatmapRequest,
+    params: &PrivacyParams,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    bucket: TimeBucket
+) -> Vec<HeatmapFrame> {
+    let bucket_duration = bucket_duration(bucket);
+    let mut frames = Vec::new();
+    let mut frame_start = start;
+    while frame_start <= end && frames.len() < MAX_HEATMAP_FRAMES {
+        let frame_end = frame_start + bucket_duration;
+        
+        let frame_points = points.iter().filter(|point| {
+            point.timestamp
+                .map(|time| time >= frame_start && time < frame_end)
+                .unwrap_or(false)
+        });
+        
+        frames.push(HeatmapFrame {
+            start_time: frame_start,
+            end_time: frame_end,
+            cells: build_heatmap_cells(frame_points, request, params),
+        });
+        
+        frame_start = frame_end;
+    }
+    
+    frames
+}
+
+fn bucket_duration(bucket: TimeBucket) -> Duration {
+    match bucket {
+        TimeBucket::Hour => Duration::hours(1),
+        TimeBucket::Day => Duration::days(1),
+        TimeBucket::Week => Duration::weeks(1),
+    }
+}
+
+// Number of frames build_heatmap_frames returns for a time range
+fn frame_count(start: DateTime<Utc>, end: DateTime<Utc>, bucket: TimeBucket) -> usize {
+    if end < start {
+        return 0;
+    }
+    let buckets = (end - start).num_seconds() / bucket_duration(bucket).num_seconds() + 1;
+    (buckets as usize).min(MAX_HEATMAP_FRAMES)
+}
+
+// Apply differential privacy to the grid
+fn apply_differential_privacy(grid: &[Vec<u32>], params: &PrivacyParams) -> Vec<Vec<u32>> {
+    let mut rng = rand::thread_rng();
+    let mut dp_grid = grid.to_vec();
     
     // Add noise to each cell
-    for i in 0..dp_grid.len() {
-        for j in 0..dp_grid[i].len() {
-            // Add Gaussian noise scaled by privacy level
-            let noise = normal.sample(&mut rng).round() as i32;
-            let new_value = dp_grid[i][j] as i32 + noise;
+    for row in dp_grid.iter_mut() {
+        for cell in row.iter_mut() {
+            // Add noise calibrated to (epsilon, delta, sensitivity); the float cast saturates
+            let noise = params.sample_noise(&mut rng).round() as i64;
+            let new_value = (*cell as i64).saturating_add(noise);
             
-            // Ensure we don't go below zero (cell counts can't be negative)
-            dp_grid[i][j] = if new_value < 0 { 0 } else { new_value as u32 };
+            // Cell counts can't be negative
+            *cell = new_value.clamp(0, u32::MAX as i64) as u32;
         }
     }
     
@@ -105,7 +428,7 @@ fn apply_differential_privacy(grid: &Vec<Vec<u32>>, privacy_level: f64) -> Vec<V
 }
 
 // Convert grid to heatmap cells
-fn grid_to_heatmap_cells(grid: &Vec<Vec<u32>>, min_lat: f64, min_lon: f64) -> Vec<HeatmapCell> {
+fn grid_to_heatmap_cells(grid: &[Vec<u32>], min_lat: Latitude, min_lon: Longitude) -> Vec<HeatmapCell> {
     let mut cells = Vec::new();
     
     // Find the max value for normalization
@@ -123,8 +446,8 @@ fn grid_to_heatmap_cells(grid: &Vec<Vec<u32>>, min_lat: f64, min_lon: f64) -> Ve
         for (j, &count) in row.iter().enumerate() {
             if count > 0 {
                 // Calculate actual lat/lon coordinates for this cell
-                let lat = min_lat + (i as f64 * GRID_SIZE);
-                let lon = min_lon + (j as f64 * GRID_SIZE);
+                let lat = Latitude::clamped(min_lat.degrees() + (i as f64 * GRID_SIZE));
+                let lon = Longitude::clamped(min_lon.degrees() + (j as f64 * GRID_SIZE));
                 
                 // Normalize intensity between 0.0 and 1.0
                 let intensity = if max_value > 0 {
@@ -148,7 +471,37 @@ fn grid_to_heatmap_cells(grid: &Vec<Vec<u32>>, min_lat: f64, min_lon: f64) -> Ve
 
 // Generate synthetic data for testing or demonstration
 pub fn generate_synthetic_heatmap(request: &HeatmapRequest) -> HeatmapResponse {
-    let mut rng = rand::thread_rng();
+    if let Err(error) = validate_bounds(request) {
+        return empty_heatmap_response(request, Some(error));
+    }
+    
+    // A seed makes the output reproducible (demos, golden files)
+    let mut rng = match request.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    
+    let mut cells = Vec::new();
+    for part in split_request(request) {
+        cells.extend(generate_synthetic_cells(&mut rng, &part));
+    }
+    
+    HeatmapResponse {
+        cells,
+        frames: Vec::new(),
+        privacy_level: request.privacy_level,
+        min_lat: request.min_lat,
+        max_lat: request.max_lat,
+        min_lon: request.min_lon,
+        max_lon: request.max_lon,
+        epsilon_spent: 0.0,
+        privacy_budget_remaining: None,
+        error: None,
+    }
+}
+
+// Synthetic cells for bounds that do not cross the antimeridian
+fn generate_synthetic_cells(rng: &mut StdRng, request: &HeatmapRequest) -> Vec<HeatmapCell> {
     let mut cells = Vec::new();
     
     // Number of "hot spots" to generate
@@ -156,21 +509,20 @@ pub fn generate_synthetic_heatmap(request: &HeatmapRequest) -> HeatmapResponse {
     
     // Generate hotspots
     let hotspots = generate_random_hotspots(
-        &mut rng, 
+        rng, 
         hotspot_count, 
-        request.min_lat, request.max_lat,
-        request.min_lon, request.max_lon
+        request.min_lat.degrees(), request.max_lat.degrees(),
+        request.min_lon.degrees(), request.max_lon.degrees()
     );
     
     // Calculate grid boundaries
-    let lat_cells = ((request.max_lat - request.min_lat) / GRID_SIZE).ceil() as usize;
-    let lon_cells = ((request.max_lon - request.min_lon) / GRID_SIZE).ceil() as usize;
+    let (lat_cells, lon_cells) = grid_dimensions(request);
     
     // Generate grid cells influenced by hotspots
     for lat_idx in 0..lat_cells {
         for lon_idx in 0..lon_cells {
-            let lat = request.min_lat + (lat_idx as f64 * GRID_SIZE);
-            let lon = request.min_lon + (lon_idx as f64 * GRID_SIZE);
+            let lat = request.min_lat.degrees() + (lat_idx as f64 * GRID_SIZE);
+            let lon = request.min_lon.degrees() + (lon_idx as f64 * GRID_SIZE);
             
             // Calculate cell intensity based on distance to hotspots
             let intensity = calculate_intensity(&hotspots, lat, lon);
@@ -181,8 +533,8 @@ pub fn generate_synthetic_heatmap(request: &HeatmapRequest) -> HeatmapResponse {
                 let count = (intensity * 100.0).round() as u32;
                 
                 cells.push(HeatmapCell {
-                    lat,
-                    lon,
+                    lat: Latitude::clamped(lat),
+                    lon: Longitude::clamped(lon),
                     intensity,
                     count,
                 });
@@ -190,14 +542,7 @@ pub fn generate_synthetic_heatmap(request: &HeatmapRequest) -> HeatmapResponse {
         }
     }
     
-    HeatmapResponse {
-        cells,
-        privacy_level: request.privacy_level,
-        min_lat: request.min_lat,
-        max_lat: request.max_lat,
-        min_lon: request.min_lon,
-        max_lon: request.max_lon,
-    }
+    cells
 }
 
 // Helper struct for hotspot generation
@@ -210,7 +555,7 @@ struct Hotspot {
 
 // Generate random hotspots within the map bounds
 fn generate_random_hotspots(
-    rng: &mut ThreadRng, 
+    rng: &mut StdRng, 
     count: usize,
     min_lat: f64, max_lat: f64,
     min_lon: f64, max_lon: f64
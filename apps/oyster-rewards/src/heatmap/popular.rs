@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+use crate::models::HeatmapRequest;
+use super::{cache_key, compute_heatmap, HEATMAP_CACHE};
+
+// Heatmap queries seen recently, keyed like the heatmap cache, so the popular ones can be
+// recomputed before their cache entry expires
+static REQUEST_COUNTS: Lazy<Mutex<HashMap<String, TrackedRequest>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Distinct queries tracked at once; the least requested one makes room for a new one
+const MAX_TRACKED_REQUESTS: usize = 1000;
+
+// Queries asked for fewer times than this since the last decay are not worth precomputing
+pub const MIN_POPULAR_COUNT: u32 = 2;
+
+// Requester charged for precomputed heatmaps, so they draw on their own privacy budget
+pub const PRECOMPUTE_REQUESTER: &str = "precompute";
+
+struct TrackedRequest {
+    request: HeatmapRequest,
+    count: u32,
+}
+
+// Outcome of one precomputation run
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PrecomputeOutcome {
+    pub popular: usize,
+    // Already cached and still fresh
+    pub fresh: usize,
+    pub computed: usize,
+    // Rejected, e.g. because the precompute budget is exhausted
+    pub failed: usize,
+}
+
+// Count a heatmap query towards its popularity
+pub fn record_request(request: &HeatmapRequest) {
+    let key = cache_key(request);
+    let mut counts = REQUEST_COUNTS.lock().unwrap();
+
+    if !counts.contains_key(&key) && counts.len() >= MAX_TRACKED_REQUESTS {
+        let least = counts.iter()
+            .min_by_key(|(_, tracked)| tracked.count)
+            .map(|(key, _)| key.clone());
+        if let Some(least) = least {
+            counts.remove(&least);
+        }
+    }
+
+    let tracked = counts.entry(key).or_insert_with(|| TrackedRequest {
+        request: HeatmapRequest { requester_id: None, seed: None, ..request.clone() },
+        count: 0,
+    });
+    tracked.count += 1;
+}
+
+// The most requested queries, most popular first
+pub fn popular_requests(limit: usize) -> Vec<HeatmapRequest> {
+    let counts = REQUEST_COUNTS.lock().unwrap();
+    let mut popular: Vec<&TrackedRequest> = counts.values()
+        .filter(|tracked| tracked.count >= MIN_POPULAR_COUNT)
+        .collect();
+    popular.sort_by_key(|tracked| std::cmp::Reverse(tracked.count));
+    popular.into_iter()
+        .take(limit)
+        .map(|tracked| tracked.request.clone())
+        .collect()
+}
+
+// Halve every count so popularity reflects recent traffic, forgetting queries that died out
+pub fn decay_counts() {
+    let mut counts = REQUEST_COUNTS.lock().unwrap();
+    counts.values_mut().for_each(|tracked| tracked.count /= 2);
+    counts.retain(|_, tracked| tracked.count > 0);
+}
+
+// Compute the most popular heatmaps that are not cached, so their next request is a cache hit
+pub fn precompute_popular(limit: usize) -> PrecomputeOutcome {
+    let popular = popular_requests(limit);
+    decay_counts();
+
+    let mut outcome = PrecomputeOutcome { popular: popular.len(), ..Default::default() };
+    for request in popular {
+        if HEATMAP_CACHE.contains_fresh(&cache_key(&request)) {
+            outcome.fresh += 1;
+            continue;
+        }
+
+        let request = HeatmapRequest { requester_id: Some(PRECOMPUTE_REQUESTER.to_string()), ..request };
+        if compute_heatmap(&request).error.is_some() {
+            outcome.failed += 1;
+        } else {
+            outcome.computed += 1;
+        }
+    }
+    outcome
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use rand::Rng;
+use rand_distr::{Normal, Distribution};
+use crate::models::HeatmapRequest;
+
+// Sensitivity of a cell count: one point changes one cell by one
+pub const DEFAULT_SENSITIVITY: f64 = 1.0;
+
+// Smallest epsilon and largest sensitivity accepted. Below or above them the noise is so large
+// that it swamps every count (and can overflow), while the query costs next to no budget
+pub const MIN_EPSILON: f64 = 0.01;
+pub const MAX_SENSITIVITY: f64 = 1000.0;
+
+// Epsilon each requester may spend per budget window. Kept in single digits, where the combined
+// releases still bound what can be learned about one user; deployments that accept a weaker
+// guarantee raise it with PRIVACY_EPSILON_BUDGET
+pub const DEFAULT_EPSILON_BUDGET: f64 = 5.0;
+pub const DEFAULT_BUDGET_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Ledger key for requests that do not identify the requester
+pub const ANONYMOUS_REQUESTER: &str = "anonymous";
+
+// Ledger keys of the identities the server vouches for. Clients never name their own, or a
+// new name would come with a fresh budget
+pub fn user_requester(user_id: &str) -> String {
+    format!("user:{}", user_id)
+}
+
+pub fn address_requester(address: Option<IpAddr>) -> String {
+    match address {
+        Some(address) => format!("address:{}", address),
+        None => ANONYMOUS_REQUESTER.to_string(),
+    }
+}
+
+// Error returned once a requester has spent their budget
+pub const BUDGET_EXHAUSTED_ERROR: &str = "Privacy budget exhausted";
+
+// Parameters of the differential privacy mechanism
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PrivacyParams {
+    pub epsilon: f64,
+    pub delta: f64,
+    pub sensitivity: f64,
+}
+
+impl PrivacyParams {
+    // Resolve the mechanism parameters of a heatmap request.
+    // Without an explicit epsilon, privacy_level is read as 1 / epsilon.
+    pub fn from_request(request: &HeatmapRequest) -> Result<Self, String> {
+        let epsilon = match request.epsilon {
+            Some(epsilon) => epsilon,
+            None if request.privacy_level > 0.0 => 1.0 / request.privacy_level,
+            None => return Err("privacy_level must be positive".to_string()),
+        };
+        let delta = request.delta.unwrap_or(0.0);
+        let sensitivity = request.sensitivity.unwrap_or(DEFAULT_SENSITIVITY);
+        
+        if !(epsilon > 0.0 && epsilon.is_finite()) {
+            return Err("epsilon must be a positive number".to_string());
+        }
+        if epsilon < MIN_EPSILON {
+            return Err(format!("epsilon must be at least {} (privacy_level at most {})", MIN_EPSILON, 1.0 / MIN_EPSILON));
+        }
+        if !(0.0..1.0).contains(&delta) {
+            return Err("delta must be in [0, 1)".to_string());
+        }
+        if !(sensitivity > 0.0 && sensitivity.is_finite()) {
+            return Err("sensitivity must be a positive number".to_string());
+        }
+        if sensitivity > MAX_SENSITIVITY {
+            return Err(format!("sensitivity must be at most {}", MAX_SENSITIVITY));
+        }
+        
+        Ok(PrivacyParams { epsilon, delta, sensitivity })
+    }
+
+    // Draw one noise sample: Laplace for pure epsilon-DP, Gaussian when delta > 0
+    pub fn sample_noise(&self, rng: &mut impl Rng) -> f64 {
+        if self.delta > 0.0 {
+            let sigma = self.sensitivity * (2.0 * (1.25 / self.delta).ln()).sqrt() / self.epsilon;
+            Normal::new(0.0, sigma).unwrap().sample(rng)
+        } else {
+            sample_laplace(rng, self.sensitivity / self.epsilon)
+        }
+    }
+}
+
+// Sample from Laplace(0, scale) by inverting its CDF
+fn sample_laplace(rng: &mut impl Rng, scale: f64) -> f64 {
+    let u: f64 = rng.gen_range(-0.5..0.5);
+    -scale * u.signum() * (1.0 - 2.0 * u.abs()).ln()
+}
+
+// Epsilon spent by one requester in the current window
+struct BudgetEntry {
+    spent: f64,
+    window_start: Instant,
+}
+
+// Per-requester epsilon accounting under sequential composition
+pub struct PrivacyBudgetLedger {
+    entries: HashMap<String, BudgetEntry>,
+    limit: f64,
+    window: Duration,
+}
+
+impl PrivacyBudgetLedger {
+    pub fn new(limit: f64, window: Duration) -> Self {
+        PrivacyBudgetLedger {
+            entries: HashMap::new(),
+            limit,
+            window,
+        }
+    }
+
+    pub fn set_limit(&mut self, limit: f64) {
+        self.limit = limit;
+    }
+
+    // Spend epsilon for a requester, refusing if it would exceed the budget.
+    // Returns the remaining budget.
+    pub fn charge(&mut self, requester: &str, epsilon: f64) -> Result<f64, String> {
+        let limit = self.limit;
+        let entry = self.current_entry(requester);
+        if entry.spent + epsilon > limit {
+            return Err(format!("{}: {:.3} of {:.3} epsilon remaining",
+                BUDGET_EXHAUSTED_ERROR, (limit - entry.spent).max(0.0), limit));
+        }
+        
+        entry.spent += epsilon;
+        Ok(limit - entry.spent)
+    }
+
+    // Budget left for a requester in the current window
+    pub fn remaining(&mut self, requester: &str) -> f64 {
+        let limit = self.limit;
+        (limit - self.current_entry(requester).spent).max(0.0)
+    }
+
+    // Entry for the requester, starting a new window if the previous one elapsed
+    fn current_entry(&mut self, requester: &str) -> &mut BudgetEntry {
+        let window = self.window;
+        let entry = self.entries.entry(requester.to_string())
+            .or_insert_with(|| BudgetEntry { spent: 0.0, window_start: Instant::now() });
+        
+        if entry.window_start.elapsed() >= window {
+            entry.spent = 0.0;
+            entry.window_start = Instant::now();
+        }
+        entry
+    }
+}
+
+impl Default for PrivacyBudgetLedger {
+    fn default() -> Self {
+        PrivacyBudgetLedger::new(DEFAULT_EPSILON_BUDGET, DEFAULT_BUDGET_WINDOW)
+    }
+}
@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+use crate::models::{BoundingBox, HeatmapResponse};
+use crate::location::GRID_SIZE;
+
+// Largest image dimension we are willing to render
+pub const MAX_RENDER_DIMENSION: u32 = 2048;
+
+// Color palettes for rendered heatmaps
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Palette {
+    #[default]
+    Heat,
+    Viridis,
+    Grayscale,
+}
+
+// Output settings for a rendered heatmap
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RenderOptions {
+    pub width: u32,
+    pub height: u32,
+    pub palette: Palette,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            width: 512,
+            height: 512,
+            palette: Palette::Heat,
+        }
+    }
+}
+
+impl Palette {
+    // Gradient stops from low to high intensity
+    fn stops(&self) -> &'static [[u8; 3]] {
+        match self {
+            Palette::Heat => &[[0, 0, 255], [0, 255, 255], [0, 255, 0], [255, 255, 0], [255, 0, 0]],
+            Palette::Viridis => &[[68, 1, 84], [59, 82, 139], [33, 145, 140], [94, 201, 98], [253, 231, 37]],
+            Palette::Grayscale => &[[255, 255, 255], [0, 0, 0]],
+        }
+    }
+
+    // Interpolate the color for an intensity between 0.0 and 1.0
+    pub fn color(&self, intensity: f64) -> [u8; 3] {
+        let stops = self.stops();
+        let position = intensity.clamp(0.0, 1.0) * (stops.len() - 1) as f64;
+        let index = (position.floor() as usize).min(stops.len() - 2);
+        let fraction = position - index as f64;
+        
+        let (from, to) = (stops[index], stops[index + 1]);
+        let mut color = [0u8; 3];
+        for channel in 0..3 {
+            let value = from[channel] as f64 + (to[channel] as f64 - from[channel] as f64) * fraction;
+            color[channel] = value.round() as u8;
+        }
+        color
+    }
+}
+
+// Rasterize a heatmap into a transparent RGBA PNG covering its bounding box
+pub fn render_png(heatmap: &HeatmapResponse, options: &RenderOptions) -> Result<Vec<u8>, String> {
+    if options.width == 0 || options.height == 0 ||
+       options.width > MAX_RENDER_DIMENSION || options.height > MAX_RENDER_DIMENSION {
+        return Err(format!("Image dimensions must be between 1 and {}", MAX_RENDER_DIMENSION));
+    }
+    
+    let bounds = BoundingBox {
+        min_lat: heatmap.min_lat,
+        min_lon: heatmap.min_lon,
+        max_lat: heatmap.max_lat,
+        max_lon: heatmap.max_lon,
+    };
+    let lat_span = bounds.lat_span();
+    let lon_span = bounds.lon_span();
+    if lat_span <= 0.0 || lon_span <= 0.0 {
+        return Err("Heatmap bounding box is empty".to_string());
+    }
+    
+    let width = options.width as usize;
+    let height = options.height as usize;
+    let mut pixels = vec![0u8; width * height * 4];
+    
+    for cell in &heatmap.cells {
+        // Longitude east of min_lon, wrapping across the antimeridian
+        let mut lon_offset = cell.lon.degrees() - heatmap.min_lon.degrees();
+        if lon_offset < -GRID_SIZE / 2.0 {
+            lon_offset += 360.0;
+        }
+        
+        // Pixel rectangle covered by this cell (image rows go from north to south)
+        let x0 = (lon_offset / lon_span * width as f64).floor().max(0.0) as usize;
+        let x1 = ((lon_offset + GRID_SIZE) / lon_span * width as f64).ceil() as usize;
+        let lat_offset = heatmap.max_lat.degrees() - cell.lat.degrees();
+        let y0 = ((lat_offset - GRID_SIZE) / lat_span * height as f64).floor().max(0.0) as usize;
+        let y1 = (lat_offset / lat_span * height as f64).ceil() as usize;
+        
+        let [r, g, b] = options.palette.color(cell.intensity);
+        let alpha = (64.0 + cell.intensity.clamp(0.0, 1.0) * 191.0).round() as u8;
+        
+        for y in y0..y1.min(height) {
+            for x in x0..x1.min(width) {
+                let offset = (y * width + x) * 4;
+                pixels[offset..offset + 4].copy_from_slice(&[r, g, b, alpha]);
+            }
+        }
+    }
+    
+    // Encode as PNG
+    let mut output = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut output, options.width, options.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()
+            .map_err(|e| format!("PNG encoding error: {}", e))?;
+        writer.write_image_data(&pixels)
+            .map_err(|e| format!("PNG encoding error: {}", e))?;
+    }
+    
+    Ok(output)
+}
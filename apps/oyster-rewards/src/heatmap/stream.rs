@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use base64::{Engine as _, engine::general_purpose};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use crate::models::{BoundingBox, HeatmapRequest, HeatmapCell, Latitude, Longitude};
+use crate::location::GRID_SIZE;
+use super::privacy::{PrivacyParams, ANONYMOUS_REQUESTER};
+use super::{collect_points, floor_to_hour, grid_dimensions, check_time_range, PRIVACY_BUDGET};
+
+// Cells sent per streamed batch
+pub const STREAM_BATCH_SIZE: usize = 1000;
+
+// Server-side caps per streamed response; the rest is reached with the continuation token
+pub const MAX_STREAM_CELLS: usize = 100_000;
+pub const MAX_STREAM_SCAN: u64 = 4_000_000;
+
+// Noisy cells of a heatmap, produced row by row in batches instead of as one vector.
+// Every response (first page or continuation) is charged the request epsilon.
+pub struct HeatmapStream {
+    request: HeatmapRequest,
+    params: PrivacyParams,
+    counts: HashMap<u64, u32>,
+    lat_cells: u64,
+    lon_cells: u64,
+    total_positions: u64,
+    position: u64,
+    scan_end: u64,
+    emitted: usize,
+    max_count: f64,
+    rng: StdRng,
+    pub epsilon_spent: f64,
+    pub privacy_budget_remaining: f64,
+}
+
+// Start (or resume, given a continuation token) streaming a heatmap
+pub fn stream_heatmap(request: &HeatmapRequest, token: Option<&str>) -> Result<HeatmapStream, String> {
+    if request.bucket.is_some() {
+        return Err("Time-bucketed heatmaps cannot be streamed".to_string());
+    }
+    
+    let bounds = BoundingBox::from_request(request);
+    bounds.validate()?;
+    if bounds.crosses_antimeridian() {
+        return Err("Streamed heatmaps cannot cross the antimeridian; request each side separately".to_string());
+    }
+    
+    let params = PrivacyParams::from_request(request)?;
+    let time_range = match &request.time_range {
+        Some(range) => {
+            let (start, end) = check_time_range(range)?;
+            Some((floor_to_hour(start), end))
+        },
+        None => None,
+    };
+    
+    let (lat_cells, lon_cells) = grid_dimensions(request);
+    let (lat_cells, lon_cells) = (lat_cells as u64, lon_cells as u64);
+    let total_positions = lat_cells * lon_cells;
+    let position = match token {
+        Some(token) => decode_token(token, lat_cells, lon_cells)?,
+        None => 0,
+    };
+    
+    // Charge the budget before any cell is released
+    let requester = request.requester_id.as_deref().unwrap_or(ANONYMOUS_REQUESTER);
+    let privacy_budget_remaining = PRIVACY_BUDGET.lock().unwrap().charge(requester, params.epsilon)?;
+    
+    // Only cells that hold data are kept in memory; empty cells get noise on the fly
+    let mut counts = HashMap::new();
+    for point in collect_points(request, time_range) {
+        let lat_idx = ((point.lat.degrees() - request.min_lat.degrees()) / GRID_SIZE).floor() as u64;
+        let lon_idx = ((point.lon.degrees() - request.min_lon.degrees()) / GRID_SIZE).floor() as u64;
+        if lat_idx < lat_cells && lon_idx < lon_cells {
+            *counts.entry(lat_idx * lon_cells + lon_idx).or_insert(0) += point.weight;
+        }
+    }
+    
+    // Intensities are normalized against a noisy maximum so the true maximum is not revealed
+    let mut rng = StdRng::from_entropy();
+    let true_max = counts.values().copied().max().unwrap_or(0) as f64;
+    let max_count = (true_max + params.sample_noise(&mut rng)).round().max(1.0);
+    
+    Ok(HeatmapStream {
+        request: request.clone(),
+        params,
+        counts,
+        lat_cells,
+        lon_cells,
+        total_positions,
+        position,
+        scan_end: total_positions.min(position + MAX_STREAM_SCAN),
+        emitted: 0,
+        max_count,
+        rng,
+        epsilon_spent: params.epsilon,
+        privacy_budget_remaining,
+    })
+}
+
+impl HeatmapStream {
+    // Token to resume from once the stream is exhausted, if cells remain
+    pub fn next_token(&self) -> Option<String> {
+        if self.position < self.total_positions {
+            Some(encode_token(self.position, self.lat_cells, self.lon_cells))
+        } else {
+            None
+        }
+    }
+}
+
+impl Iterator for HeatmapStream {
+    type Item = Vec<HeatmapCell>;
+    
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut batch = Vec::new();
+        while self.position < self.scan_end
+            && self.emitted < MAX_STREAM_CELLS
+            && batch.len() < STREAM_BATCH_SIZE
+        {
+            let true_count = self.counts.get(&self.position).copied().unwrap_or(0);
+            let noise = self.params.sample_noise(&mut self.rng).round() as i64;
+            let count = (true_count as i64 + noise).max(0) as u32;
+            
+            if count > 0 {
+                let lat_idx = self.position / self.lon_cells;
+                let lon_idx = self.position % self.lon_cells;
+                batch.push(HeatmapCell {
+                    lat: Latitude::clamped(self.request.min_lat.degrees() + (lat_idx as f64 * GRID_SIZE)),
+                    lon: Longitude::clamped(self.request.min_lon.degrees() + (lon_idx as f64 * GRID_SIZE)),
+                    intensity: (count as f64 / self.max_count).min(1.0),
+                    count,
+                });
+                self.emitted += 1;
+            }
+            self.position += 1;
+        }
+        
+        if batch.is_empty() {
+            None
+        } else {
+            Some(batch)
+        }
+    }
+}
+
+// Continuation tokens carry the next grid position and the grid shape they belong to
+fn encode_token(position: u64, lat_cells: u64, lon_cells: u64) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(format!("{}:{}:{}", position, lat_cells, lon_cells))
+}
+
+fn decode_token(token: &str, lat_cells: u64, lon_cells: u64) -> Result<u64, String> {
+    let invalid = || "Invalid continuation token".to_string();
+    let decoded = general_purpose::URL_SAFE_NO_PAD.decode(token).map_err(|_| invalid())?;
+    let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+    
+    let parts: Vec<u64> = decoded.split(':')
+        .map(|part| part.parse::<u64>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid())?;
+    match parts.as_slice() {
+        [position, lat, lon] if *lat == lat_cells && *lon == lon_cells && *position < lat_cells * lon_cells => {
+            Ok(*position)
+        },
+        _ => Err("Continuation token does not match this bounding box".to_string()),
+    }
+}
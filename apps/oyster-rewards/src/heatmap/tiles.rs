@@ -0,0 +1,196 @@
+use std::f64::consts::PI;
+use crate::models::{HeatmapRequest, HeatmapResponse, Latitude, Longitude};
+use crate::location::GRID_SIZE;
+use super::generate_heatmap;
+
+// Tiles below this zoom would cover too many grid cells to aggregate per request
+pub const MIN_TILE_ZOOM: u32 = 10;
+pub const MAX_TILE_ZOOM: u32 = 22;
+
+// Coordinate space of a vector tile
+const TILE_EXTENT: u32 = 4096;
+
+// Name of the layer holding heatmap cells
+const LAYER_NAME: &str = "heatmap";
+
+// Generate a Mapbox Vector Tile containing the heatmap cells of tile z/x/y
+pub fn generate_tile(z: u32, x: u32, y: u32, privacy_level: f64) -> Result<Vec<u8>, String> {
+    if !(MIN_TILE_ZOOM..=MAX_TILE_ZOOM).contains(&z) {
+        return Err(format!("Zoom must be between {} and {}", MIN_TILE_ZOOM, MAX_TILE_ZOOM));
+    }
+    let tiles_per_axis = 1u32 << z;
+    if x >= tiles_per_axis || y >= tiles_per_axis {
+        return Err("Tile coordinates out of range for zoom level".to_string());
+    }
+    
+    let (min_lat, min_lon, max_lat, max_lon) = tile_bounds(z, x, y);
+    let request = HeatmapRequest {
+        min_lat,
+        min_lon,
+        max_lat,
+        max_lon,
+        privacy_level,
+        time_range: None,
+        bucket: None,
+        epsilon: None,
+        delta: None,
+        sensitivity: None,
+        requester_id: None,
+        seed: None,
+    };
+    
+    let heatmap = generate_heatmap(&request);
+    if let Some(error) = heatmap.error {
+        return Err(error);
+    }
+    
+    let n = (1u32 << z) as f64;
+    let frame = TileFrame { min_x: x as f64 / n, min_y: y as f64 / n, size_x: 1.0 / n, size_y: 1.0 / n };
+    Ok(encode_tile(&heatmap, &frame))
+}
+
+// Encode a heatmap as one vector tile whose extent spans the heatmap's bounding box, for clients
+// that asked for MVT over an arbitrary area rather than a z/x/y tile
+pub fn encode_heatmap_tile(heatmap: &HeatmapResponse) -> Vec<u8> {
+    let (min_x, max_y) = mercator(heatmap.min_lat.degrees(), heatmap.min_lon.degrees());
+    let (max_x, min_y) = mercator(heatmap.max_lat.degrees(), heatmap.max_lon.degrees());
+    let frame = TileFrame { min_x, min_y, size_x: max_x - min_x, size_y: max_y - min_y };
+    encode_tile(heatmap, &frame)
+}
+
+// Area a tile's extent covers, in Web Mercator units (0 to 1 across the world, y growing south)
+struct TileFrame {
+    min_x: f64,
+    min_y: f64,
+    size_x: f64,
+    size_y: f64,
+}
+
+// Geographic bounds (min_lat, min_lon, max_lat, max_lon) of a Web Mercator tile
+pub fn tile_bounds(z: u32, x: u32, y: u32) -> (Latitude, Longitude, Latitude, Longitude) {
+    let n = (1u32 << z) as f64;
+    let lon_of = |x: f64| Longitude::clamped(x / n * 360.0 - 180.0);
+    let lat_of = |y: f64| Latitude::clamped((PI * (1.0 - 2.0 * y / n)).sinh().atan().to_degrees());
+    
+    (lat_of(y as f64 + 1.0), lon_of(x as f64), lat_of(y as f64), lon_of(x as f64 + 1.0))
+}
+
+// Web Mercator position of a coordinate
+fn mercator(lat: f64, lon: f64) -> (f64, f64) {
+    let lat_rad = lat.to_radians();
+    (
+        (lon + 180.0) / 360.0,
+        (1.0 - (lat_rad.tan() + 1.0 / lat_rad.cos()).ln() / PI) / 2.0,
+    )
+}
+
+// Project a coordinate into the pixel space of a tile
+fn project(lat: f64, lon: f64, frame: &TileFrame) -> (i64, i64) {
+    let (global_x, global_y) = mercator(lat, lon);
+    (
+        ((global_x - frame.min_x) / frame.size_x * TILE_EXTENT as f64).round() as i64,
+        ((global_y - frame.min_y) / frame.size_y * TILE_EXTENT as f64).round() as i64,
+    )
+}
+
+// Encode heatmap cells as polygons in a single-layer vector tile
+fn encode_tile(heatmap: &HeatmapResponse, frame: &TileFrame) -> Vec<u8> {
+    // Attribute keys are shared; every feature carries its own values
+    let mut layer = Vec::new();
+    write_varint_field(&mut layer, 15, 2); // version
+    write_bytes_field(&mut layer, 1, LAYER_NAME.as_bytes());
+    
+    let mut values = Vec::new();
+    for (index, cell) in heatmap.cells.iter().enumerate() {
+        // Cell corners, north-west first so the ring is clockwise in tile space
+        let (lat, lon) = (cell.lat.degrees(), cell.lon.degrees());
+        let (x0, y0) = project(lat + GRID_SIZE, lon, frame);
+        let (x1, y1) = project(lat, lon + GRID_SIZE, frame);
+        
+        let geometry = [
+            command(1, 1), zigzag(x0), zigzag(y0), // MoveTo
+            command(2, 3), // LineTo
+            zigzag(x1 - x0), zigzag(0),
+            zigzag(0), zigzag(y1 - y0),
+            zigzag(x0 - x1), zigzag(0),
+            command(7, 1), // ClosePath
+        ];
+        
+        let intensity_index = values.len() as u64;
+        values.push(encode_double_value(cell.intensity));
+        values.push(encode_uint_value(cell.count as u64));
+        
+        let mut feature = Vec::new();
+        write_varint_field(&mut feature, 1, index as u64 + 1); // id
+        write_packed_field(&mut feature, 2, &[0, intensity_index, 1, intensity_index + 1]); // tags
+        write_varint_field(&mut feature, 3, 3); // type = POLYGON
+        write_packed_field(&mut feature, 4, &geometry);
+        write_bytes_field(&mut layer, 2, &feature);
+    }
+    
+    write_bytes_field(&mut layer, 3, b"intensity");
+    write_bytes_field(&mut layer, 3, b"count");
+    for value in &values {
+        write_bytes_field(&mut layer, 4, value);
+    }
+    write_varint_field(&mut layer, 5, TILE_EXTENT as u64);
+    
+    let mut tile = Vec::new();
+    write_bytes_field(&mut tile, 3, &layer);
+    tile
+}
+
+// Geometry command integer
+fn command(id: u64, count: u64) -> u64 {
+    (id & 0x7) | (count << 3)
+}
+
+// Zigzag-encode a signed geometry parameter
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn encode_double_value(value: f64) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    write_key(&mut buffer, 3, 1); // double_value, 64-bit
+    buffer.extend_from_slice(&value.to_le_bytes());
+    buffer
+}
+
+fn encode_uint_value(value: u64) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    write_varint_field(&mut buffer, 5, value); // uint_value
+    buffer
+}
+
+// Minimal protobuf writers
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn write_key(buffer: &mut Vec<u8>, field: u64, wire_type: u64) {
+    write_varint(buffer, (field << 3) | wire_type);
+}
+
+fn write_varint_field(buffer: &mut Vec<u8>, field: u64, value: u64) {
+    write_key(buffer, field, 0);
+    write_varint(buffer, value);
+}
+
+fn write_bytes_field(buffer: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_key(buffer, field, 2);
+    write_varint(buffer, bytes.len() as u64);
+    buffer.extend_from_slice(bytes);
+}
+
+fn write_packed_field(buffer: &mut Vec<u8>, field: u64, values: &[u64]) {
+    let mut packed = Vec::new();
+    for &value in values {
+        write_varint(&mut packed, value);
+    }
+    write_bytes_field(buffer, field, &packed);
+}
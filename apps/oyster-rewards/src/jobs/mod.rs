@@ -0,0 +1,264 @@
+use std::collections::BTreeMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::Instant;
+use once_cell::sync::Lazy;
+use chrono::{DateTime, Duration, Utc};
+use crate::models::{JobStatus, RetentionRequest};
+use crate::admin::{apply_retention, validate_retention_days};
+use crate::analytics::{self, ANALYTICS_CACHE};
+use crate::heatmap::{self, HEATMAP_CACHE};
+
+// Background jobs run periodically by the API's scheduler or on demand through the admin API.
+// The scheduler itself lives in the binary; this module only knows what each job does and
+// keeps the status of their runs.
+
+pub const HEATMAP_SNAPSHOTS: &str = "heatmap_snapshots";
+pub const RETENTION_CLEANUP: &str = "retention_cleanup";
+pub const CACHE_EVICTION: &str = "cache_eviction";
+pub const HEATMAP_PRECOMPUTE: &str = "heatmap_precompute";
+pub const DAILY_SUMMARIES: &str = "daily_summaries";
+
+// Popular heatmaps kept warm by default
+pub const DEFAULT_PRECOMPUTE_LIMIT: usize = 10;
+
+// A job and its default schedule
+pub struct JobDefinition {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub default_interval_seconds: u64,
+}
+
+pub const JOBS: &[JobDefinition] = &[
+    JobDefinition {
+        name: HEATMAP_SNAPSHOTS,
+        description: "Persist the heatmap snapshots of changed days to the store",
+        default_interval_seconds: 300,
+    },
+    JobDefinition {
+        name: RETENTION_CLEANUP,
+        description: "Remove stored locations older than the retention period",
+        default_interval_seconds: 3600,
+    },
+    JobDefinition {
+        name: CACHE_EVICTION,
+        description: "Drop expired heatmap and analytics cache entries",
+        default_interval_seconds: 60,
+    },
+    JobDefinition {
+        name: HEATMAP_PRECOMPUTE,
+        description: "Recompute the most requested heatmaps before their cache entry expires",
+        // Shorter than the heatmap cache TTL so popular entries never go cold
+        default_interval_seconds: 45,
+    },
+    JobDefinition {
+        name: DAILY_SUMMARIES,
+        description: "Materialize yesterday's daily summaries of every active user",
+        default_interval_seconds: 3600,
+    },
+];
+
+static JOB_STATES: Lazy<Mutex<BTreeMap<&'static str, JobState>>> = Lazy::new(|| {
+    Mutex::new(JOBS.iter()
+        .map(|job| (job.name, JobState::new(job.default_interval_seconds)))
+        .collect())
+});
+
+// Retention period applied by the cleanup job; without one the job has nothing to do
+static RETENTION_DAYS: Lazy<Mutex<Option<u32>>> = Lazy::new(|| Mutex::new(None));
+
+static PRECOMPUTE_LIMIT: Lazy<Mutex<usize>> = Lazy::new(|| Mutex::new(DEFAULT_PRECOMPUTE_LIMIT));
+
+struct JobState {
+    interval_seconds: u64,
+    running: bool,
+    runs: u64,
+    failures: u64,
+    last_started_at: Option<DateTime<Utc>>,
+    last_finished_at: Option<DateTime<Utc>>,
+    last_duration_ms: Option<u64>,
+    last_result: Option<String>,
+    last_error: Option<String>,
+    next_run_at: Option<DateTime<Utc>>,
+}
+
+impl JobState {
+    fn new(interval_seconds: u64) -> Self {
+        JobState {
+            interval_seconds,
+            running: false,
+            runs: 0,
+            failures: 0,
+            last_started_at: None,
+            last_finished_at: None,
+            last_duration_ms: None,
+            last_result: None,
+            last_error: None,
+            next_run_at: None,
+        }
+    }
+}
+
+fn definition(name: &str) -> Result<&'static JobDefinition, String> {
+    JOBS.iter()
+        .find(|job| job.name == name)
+        .ok_or_else(|| format!("Job {} not found", name))
+}
+
+// Set how often a job runs; 0 disables its scheduled runs
+pub fn set_interval(name: &str, interval_seconds: u64) -> Result<(), String> {
+    let job = definition(name)?;
+    JOB_STATES.lock().unwrap().get_mut(job.name).unwrap().interval_seconds = interval_seconds;
+    Ok(())
+}
+
+// Seconds between scheduled runs of a job, None when it is disabled
+pub fn scheduled_interval(name: &str) -> Option<u64> {
+    let interval = JOB_STATES.lock().unwrap().get(name)?.interval_seconds;
+    let configured = name != RETENTION_CLEANUP || RETENTION_DAYS.lock().unwrap().is_some();
+    (interval > 0 && configured).then_some(interval)
+}
+
+pub fn set_retention_days(days: Option<u32>) -> Result<(), String> {
+    if let Some(days) = days {
+        // Validated like a manual cleanup, so a bad setting fails at startup rather than every hour
+        validate_retention_days(days)?;
+    }
+    *RETENTION_DAYS.lock().unwrap() = days;
+    Ok(())
+}
+
+pub fn set_precompute_limit(limit: usize) {
+    *PRECOMPUTE_LIMIT.lock().unwrap() = limit;
+}
+
+// Record when the scheduler will next run a job
+pub fn set_next_run(name: &str, at: DateTime<Utc>) {
+    if let Some(state) = JOB_STATES.lock().unwrap().get_mut(name) {
+        state.next_run_at = Some(at);
+    }
+}
+
+// Run a job now, returning its status afterwards. A failing job is not an error here;
+// its failure is recorded in the status. Runs of the same job never overlap.
+pub fn run_job(name: &str, now: DateTime<Utc>) -> Result<JobStatus, String> {
+    let job = definition(name)?;
+    {
+        let mut states = JOB_STATES.lock().unwrap();
+        let state = states.get_mut(job.name).unwrap();
+        if state.running {
+            return Err(format!("Job {} is already running", name));
+        }
+        state.running = true;
+        state.last_started_at = Some(now);
+    }
+    let mut running = RunningJob { name: job.name, finished: false };
+
+    let started = Instant::now();
+    let outcome = execute(job.name, now);
+    let elapsed = started.elapsed();
+
+    let mut states = JOB_STATES.lock().unwrap();
+    let state = states.get_mut(job.name).unwrap();
+    state.runs += 1;
+    state.last_finished_at = Some(now + Duration::from_std(elapsed).unwrap_or_default());
+    state.last_duration_ms = Some(elapsed.as_millis() as u64);
+    match outcome {
+        Ok(result) => {
+            state.last_result = Some(result);
+            state.last_error = None;
+        },
+        Err(error) => {
+            state.failures += 1;
+            state.last_error = Some(error);
+        },
+    }
+    drop(states);
+    running.finished = true;
+    drop(running);
+
+    job_status(name)
+}
+
+// Clears a job's running flag when its run ends, also when it panics, which counts as a failure
+struct RunningJob {
+    name: &'static str,
+    finished: bool,
+}
+
+impl Drop for RunningJob {
+    fn drop(&mut self) {
+        let mut states = JOB_STATES.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(state) = states.get_mut(self.name) else { return };
+        state.running = false;
+        if !self.finished {
+            state.runs += 1;
+            state.failures += 1;
+            state.last_error = Some("Job panicked".to_string());
+        }
+    }
+}
+
+pub fn job_status(name: &str) -> Result<JobStatus, String> {
+    let job = definition(name)?;
+    let interval = scheduled_interval(job.name);
+    let states = JOB_STATES.lock().unwrap();
+    let state = &states[job.name];
+    Ok(JobStatus {
+        name: job.name.to_string(),
+        description: job.description.to_string(),
+        interval_seconds: state.interval_seconds,
+        enabled: interval.is_some(),
+        running: state.running,
+        runs: state.runs,
+        failures: state.failures,
+        last_started_at: state.last_started_at,
+        last_finished_at: state.last_finished_at,
+        last_duration_ms: state.last_duration_ms,
+        last_result: state.last_result.clone(),
+        last_error: state.last_error.clone(),
+        next_run_at: interval.and(state.next_run_at),
+    })
+}
+
+// Status of every job, in the order they are defined
+pub fn job_statuses() -> Vec<JobStatus> {
+    JOBS.iter()
+        .filter_map(|job| job_status(job.name).ok())
+        .collect()
+}
+
+fn execute(name: &str, now: DateTime<Utc>) -> Result<String, String> {
+    match name {
+        HEATMAP_SNAPSHOTS => {
+            let days = heatmap::history::persist_snapshots()?;
+            Ok(format!("Persisted heatmap snapshots for {} day(s)", days))
+        },
+        RETENTION_CLEANUP => {
+            let retention_days = RETENTION_DAYS.lock().unwrap()
+                .ok_or_else(|| "No retention period configured".to_string())?;
+            let response = apply_retention(&RetentionRequest { retention_days }, now)?;
+            Ok(format!("Removed {} location(s) registered before {}", response.removed_locations, response.cutoff.to_rfc3339()))
+        },
+        CACHE_EVICTION => {
+            let heatmap_entries = HEATMAP_CACHE.evict_expired();
+            let analytics_entries = ANALYTICS_CACHE.lock().unwrap().evict_expired();
+            Ok(format!("Evicted {} heatmap and {} analytics cache entries", heatmap_entries, analytics_entries))
+        },
+        HEATMAP_PRECOMPUTE => {
+            let limit = *PRECOMPUTE_LIMIT.lock().unwrap();
+            let outcome = heatmap::popular::precompute_popular(limit);
+            let summary = format!("Computed {} of {} popular heatmaps ({} already fresh)",
+                outcome.computed, outcome.popular, outcome.fresh);
+            if outcome.failed > 0 {
+                return Err(format!("{}; {} rejected, the precompute privacy budget may be exhausted", summary, outcome.failed));
+            }
+            Ok(summary)
+        },
+        DAILY_SUMMARIES => {
+            let yesterday = (now - Duration::days(1)).date_naive();
+            let added = analytics::daily::materialize(yesterday);
+            Ok(format!("Materialized {} daily summaries for {}", added, yesterday.format("%Y-%m-%d")))
+        },
+        _ => Err(format!("Job {} not found", name)),
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use chrono::{Datelike, Duration, NaiveDate, Utc};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use crate::models::{Coordinate, GridCell, LeaderboardEntry, LeaderboardPeriod, LeaderboardResponse, LeaderboardStanding};
+use crate::location::GRID_SIZE;
+use crate::rewards::ledger::REWARD_LEDGER;
+
+// Entries per board when none (or too many) are requested
+pub const DEFAULT_LEADERBOARD_SIZE: usize = 10;
+pub const MAX_LEADERBOARD_SIZE: usize = 100;
+
+// Secret mixed into handles so they cannot be matched to user IDs by hashing guesses. It is
+// random per process and never persisted, so handles also change whenever the server restarts
+static HANDLE_SALT: Lazy<[u8; 32]> = Lazy::new(|| rand::thread_rng().gen());
+
+// Distinct cells each user registered a location in during the current week
+static WEEKLY_CELLS: Lazy<Mutex<WeekCells>> = Lazy::new(|| Mutex::new(WeekCells::default()));
+
+#[derive(Default)]
+struct WeekCells {
+    // Monday (UTC) of the week being counted
+    start: Option<NaiveDate>,
+    users: HashMap<String, HashSet<GridCell>>,
+}
+
+// Count the cell of a newly registered location towards the user's week
+pub fn record_visit(user_id: &str, point: Coordinate, today: NaiveDate) {
+    let week = week_start(today);
+    let mut weekly = WEEKLY_CELLS.lock().unwrap();
+    // Only the current week is ever ranked
+    if weekly.start != Some(week) {
+        weekly.start = Some(week);
+        weekly.users.clear();
+    }
+    weekly.users.entry(user_id.to_string())
+        .or_default()
+        .insert(GridCell::from_location(point.lat, point.lon, GRID_SIZE));
+}
+
+// Top `limit` users of the period by points earned and by distinct cells visited, under
+// pseudonymous handles; with a user, also where that user stands
+pub fn leaderboard(period: LeaderboardPeriod, limit: usize, user_id: Option<&str>) -> LeaderboardResponse {
+    let today = Utc::now().date_naive();
+    let (start, end) = match period {
+        LeaderboardPeriod::Week => (week_start(today), week_start(today) + Duration::days(7)),
+    };
+    let since = start.and_hms_opt(0, 0, 0).unwrap().and_utc();
+
+    let rewards = REWARD_LEDGER.lock().unwrap().earned_since(since);
+    let weekly = WEEKLY_CELLS.lock().unwrap();
+    let cells: HashMap<String, u64> = if weekly.start == Some(start) {
+        weekly.users.iter().map(|(user, cells)| (user.clone(), cells.len() as u64)).collect()
+    } else {
+        HashMap::new()
+    };
+    drop(weekly);
+
+    let reward_board = rank(&rewards, start);
+    let cell_board = rank(&cells, start);
+    let participants = rewards.keys().chain(cells.keys()).collect::<HashSet<_>>().len();
+    let you = user_id.map(|user_id| {
+        let handle = handle(user_id, start);
+        let find = |board: &[LeaderboardEntry]| board.iter().find(|entry| entry.handle == handle).cloned();
+        LeaderboardStanding {
+            rewards: find(&reward_board),
+            cells: find(&cell_board),
+            handle,
+        }
+    });
+
+    let limit = limit.clamp(1, MAX_LEADERBOARD_SIZE);
+    LeaderboardResponse {
+        period,
+        start: start.to_string(),
+        end: end.to_string(),
+        participants,
+        rewards: reward_board.into_iter().take(limit).collect(),
+        cells: cell_board.into_iter().take(limit).collect(),
+        you,
+    }
+}
+
+// Rank users with a positive score, highest first; ties share a rank and are ordered by handle
+// so the order reveals nothing about the users behind them
+fn rank(scores: &HashMap<String, u64>, period_start: NaiveDate) -> Vec<LeaderboardEntry> {
+    let mut board: Vec<LeaderboardEntry> = scores.iter()
+        .filter(|(_, &score)| score > 0)
+        .map(|(user_id, &score)| LeaderboardEntry { rank: 0, handle: handle(user_id, period_start), score })
+        .collect();
+    board.sort_by(|a, b| b.score.cmp(&a.score).then_with(|| a.handle.cmp(&b.handle)));
+
+    for i in 0..board.len() {
+        board[i].rank = if i > 0 && board[i].score == board[i - 1].score { board[i - 1].rank } else { i + 1 };
+    }
+    board
+}
+
+// Pseudonym of a user for one period; it changes every period so boards cannot be linked
+fn handle(user_id: &str, period_start: NaiveDate) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(HANDLE_SALT.as_slice());
+    hasher.update(period_start.to_string().as_bytes());
+    hasher.update(user_id.as_bytes());
+    let digest = hasher.finalize();
+    let suffix: String = digest[..4].iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("explorer-{}", suffix)
+}
+
+// Monday of the day's ISO week
+fn week_start(day: NaiveDate) -> NaiveDate {
+    day - Duration::days(day.weekday().num_days_from_monday() as i64)
+}
@@ -1,18 +1,69 @@
 pub mod models;
+pub mod auth;
 pub mod crypto;
 pub mod location;
 pub mod heatmap;
 pub mod analytics;
+pub mod store;
+pub mod rewards;
+pub mod quests;
+pub mod leaderboard;
+pub mod fraud;
+pub mod webhooks;
+pub mod metrics;
+pub mod admin;
+pub mod jobs;
+pub mod v2;
+#[cfg(feature = "server")]
+pub mod grpc;
+#[cfg(feature = "server")]
+pub mod graphql;
 
 // Re-export key types and functions
 pub use models::{
-    Location, EncryptedLocation, SensorData, WifiNetwork, CellTower,
-    Station, StationType, GridCell, HeatmapResponse, HeatmapRequest,
-    VisitAnalyticsRequest, VisitAnalyticsResponse, LocationRegistrationRequest,
-    LocationRegistrationResponse, LocationVisit
+    Coordinate, CoordinateError, Latitude, Longitude,
+    Meters, Kilometers, Seconds,
+    Location, LocationBuilder, LocationError, EncryptedLocation, SensorData, WifiNetwork, CellTower, BleBeacon,
+    Station, StationType, GridCell, HeatmapResponse, HeatmapRequest, HeatmapCell,
+    HeatmapFrame, TimeRange, TimeBucket, HeatmapDiffRequest, HeatmapDiffResponse, HeatmapDiffCell,
+    HotspotsResponse, HeatmapHotspot, BoundingBox, AdaptiveHeatmapResponse, AdaptiveHeatmapCell,
+    HeatmapSnapshot, HeatmapSnapshotCell,
+    UserLocationsRequest, UserLocationsResponse, UserLocation, UserTokenClaims, LocationImportResponse, ImportRejection,
+    VisitAnalyticsRequest, VisitAnalyticsResponse, AnalyticsWarning, AnalyticsWarningCode, LocationRegistrationRequest,
+    LocationRegistrationResponse, LocationVisit, Poi, Trip, TransportMode, UnitSystem, Measurement,
+    DailySummaryRequest, DailySummaryResponse, AnonymousContributionRequest, ApiResponse,
+    RoutinesRequest, RoutinesResponse, RoutineSummary, RoutineLabel, CommuteSummary,
+    AnomaliesRequest, AnomaliesResponse, AnomalousVisit, AnomalyReason, AggregateAnalyticsResponse,
+    PopularRoutesResponse, PopularRoute, PredictionsRequest, PredictionsResponse, PredictedPlace,
+    RewardTier, RewardQuote, RewardDraw, RewardVrfKey, RewardDrawVerification, LedgerEntry, LedgerEntryKind, RewardBalance, RewardHistoryResponse, DiscoveryBadge, DiscoveriesResponse,
+    RedeemRequest, RedeemResponse, Redemption, RedemptionStatus, RedemptionClaim, RedemptionKey,
+    QuestObjective, QuestDefinition, QuestStatus, QuestsResponse, StreakStatus,
+    LeaderboardPeriod, LeaderboardEntry, LeaderboardStanding, LeaderboardResponse,
+    FraudAssessment, FraudSignal, FraudSignalKind, BoostEvent, BoostEventRequest, RewardClaimResponse,
+    WebhookEventKind, WebhookEvent, WebhookEndpointRequest, WebhookEndpoint, WebhookRegistration, VisitCompletion,
+    AdminStats, RetentionRequest, RetentionResponse, CacheInvalidationResponse, JobStatus
 };
 
 pub use crypto::{encrypt_location, decrypt_location};
-pub use location::{register_location, get_location, verify_location};
+pub use auth::{issue_user_token, verify_user_token};
+pub use location::{register_location, register_location_from, get_location, list_user_locations, verify_location, verify_location_with, contribute_anonymous, VerificationThresholds};
 pub use heatmap::{generate_heatmap, generate_synthetic_heatmap};
-pub use analytics::{generate_visit_analytics, generate_daily_summary}; 
\ No newline at end of file
+pub use heatmap::diff::generate_heatmap_diff;
+pub use heatmap::hotspots::top_hotspots;
+pub use heatmap::adaptive::generate_adaptive_heatmap;
+pub use heatmap::history::heatmap_history;
+pub use analytics::{generate_visit_analytics, generate_daily_summary, generate_daily_report};
+pub use analytics::routines::generate_routines;
+pub use analytics::anomalies::detect_anomalies;
+pub use analytics::poi::register_poi;
+pub use analytics::aggregate::aggregate;
+pub use analytics::routes::popular_routes;
+pub use analytics::predictions::predict_next_visits;
+pub use rewards::quote_reward;
+pub use rewards::claim::claim_reward;
+pub use rewards::vrf::verify_draw;
+pub use rewards::discovery::discoveries;
+pub use rewards::events::{active_events, list_events, create_event, update_event, delete_event};
+pub use rewards::redemption::{redeem, fulfill_redemption, verify_redemption_token};
+pub use quests::quest_statuses;
+pub use leaderboard::leaderboard; 
\ No newline at end of file
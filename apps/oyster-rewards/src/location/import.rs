@@ -0,0 +1,223 @@
+use std::collections::{HashMap, HashSet};
+use chrono::{DateTime, Duration, Utc};
+use crate::models::{Location, SensorData, LocationImportResponse, ImportRejection};
+use crate::models::interop::{parse_time, GpxPoint};
+use crate::crypto;
+use crate::analytics;
+use super::LOCATION_HISTORY;
+
+// Import of historical traces exported by other tracking apps. Imported points have no sensor
+// data to verify, so they only seed the user's own history and analytics: they are flagged as
+// imported and never count towards heatmaps, rewards, quests, streaks or the leaderboard.
+
+// Points accepted in one upload
+pub const MAX_IMPORT_POINTS: usize = 50_000;
+
+// Rejections listed in the response; the count covers all of them
+pub const MAX_IMPORT_REJECTIONS: usize = 100;
+
+// Clock skew tolerated before a point counts as being in the future
+const MAX_FUTURE_SKEW_MINUTES: i64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    Gpx,
+    Csv,
+}
+
+impl ImportFormat {
+    // Parse an explicit `format` parameter
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "gpx" => Ok(ImportFormat::Gpx),
+            "csv" => Ok(ImportFormat::Csv),
+            _ => Err(format!("Unknown import format: {} (expected gpx or csv)", name)),
+        }
+    }
+
+    // Format of an upload's Content-Type, if it names one
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        match media_type.as_str() {
+            "application/gpx+xml" | "application/xml" | "text/xml" => Some(ImportFormat::Gpx),
+            "text/csv" | "application/csv" => Some(ImportFormat::Csv),
+            _ => None,
+        }
+    }
+
+    // Guess the format of an upload from its content: GPX is XML, anything else is read as CSV
+    pub fn sniff(data: &str) -> Self {
+        if data.trim_start_matches('\u{feff}').trim_start().starts_with('<') {
+            ImportFormat::Gpx
+        } else {
+            ImportFormat::Csv
+        }
+    }
+}
+
+// A point read from an upload, before it is checked
+struct ImportedPoint {
+    row: usize,
+    lat: f64,
+    lon: f64,
+    time: DateTime<Utc>,
+}
+
+// Parse an upload and add its points to the user's history
+pub fn import_locations(user_id: &str, device_id: &str, format: ImportFormat, data: &str, now: DateTime<Utc>) -> Result<LocationImportResponse, String> {
+    let rows = match format {
+        ImportFormat::Gpx => parse_gpx(data)?,
+        ImportFormat::Csv => parse_csv(data)?,
+    };
+    if rows.len() > MAX_IMPORT_POINTS {
+        return Err(format!("Uploads are limited to {} points, got {}", MAX_IMPORT_POINTS, rows.len()));
+    }
+
+    // Points at a time the user already has a location for are taken to be the same point,
+    // so uploading the same export twice adds nothing
+    let mut known_times: HashSet<DateTime<Utc>> = LOCATION_HISTORY
+        .get(user_id)
+        .map(|records| records.iter().map(|record| record.timestamp).collect())
+        .unwrap_or_default();
+
+    let mut response = LocationImportResponse {
+        imported: 0,
+        duplicates: 0,
+        rejected: 0,
+        rejections: Vec::new(),
+    };
+    let reject = |response: &mut LocationImportResponse, row: usize, reason: String| {
+        response.rejected += 1;
+        if response.rejections.len() < MAX_IMPORT_REJECTIONS {
+            response.rejections.push(ImportRejection { row, reason });
+        }
+    };
+
+    let latest = now + Duration::minutes(MAX_FUTURE_SKEW_MINUTES);
+    let mut records = Vec::new();
+    for row in rows {
+        let point = match row {
+            Ok(point) => point,
+            Err(rejection) => {
+                reject(&mut response, rejection.row, rejection.reason);
+                continue;
+            }
+        };
+        let location = Location::builder()
+            .position(point.lat, point.lon)
+            .timestamp(point.time)
+            .user_id(user_id)
+            .device_id(device_id)
+            .sensors(imported_sensors(format))
+            .build();
+        let location = match location {
+            Ok(location) => location,
+            Err(error) => {
+                reject(&mut response, point.row, error.to_string());
+                continue;
+            }
+        };
+        if point.time > latest {
+            reject(&mut response, point.row, "Timestamp is in the future".to_string());
+            continue;
+        }
+        if !known_times.insert(point.time) {
+            response.duplicates += 1;
+            continue;
+        }
+
+        let mut record = crypto::encrypt_location(&location)?;
+        record.imported = true;
+        records.push(record);
+    }
+    response.imported = records.len();
+    if records.is_empty() {
+        return Ok(response);
+    }
+
+    // Keep the history in time order, so imported points land before later registrations
+    {
+        let mut user_history = LOCATION_HISTORY.entry(user_id.to_string()).or_default();
+        user_history.extend(records);
+        user_history.sort_by_key(|record| record.timestamp);
+    }
+    analytics::rolling::rebuild_user(user_id);
+
+    analytics::ANALYTICS_CACHE.lock().unwrap().invalidate_user(user_id);
+    analytics::daily::invalidate_user(user_id);
+
+    Ok(response)
+}
+
+// Sensor data of an imported point: none, with the source noted
+fn imported_sensors(format: ImportFormat) -> SensorData {
+    let source = match format {
+        ImportFormat::Gpx => "gpx",
+        ImportFormat::Csv => "csv",
+    };
+    SensorData {
+        wifi_networks: Vec::new(),
+        cell_towers: Vec::new(),
+        ble_beacons: Vec::new(),
+        accelerometer: None,
+        gyroscope: None,
+        pressure_hpa: None,
+        is_mock_location: false,
+        additional_data: HashMap::from([("import_source".to_string(), source.to_string())]),
+    }
+}
+
+// Track, route and waypoints of a GPX file, in document order
+fn parse_gpx(data: &str) -> Result<Vec<Result<ImportedPoint, ImportRejection>>, String> {
+    let document = roxmltree::Document::parse(data.trim_start_matches('\u{feff}'))
+        .map_err(|e| format!("Invalid GPX: {}", e))?;
+    if !document.root_element().has_tag_name("gpx") {
+        return Err("Invalid GPX: the root element must be <gpx>".to_string());
+    }
+
+    let points = document.descendants()
+        .filter(|node| ["trkpt", "rtept", "wpt"].iter().any(|name| node.has_tag_name(*name)))
+        .enumerate()
+        .map(|(index, node)| {
+            let row = index + 1;
+            let point = GpxPoint::try_from(node).map_err(|reason| ImportRejection { row, reason })?;
+            Ok(ImportedPoint { row, lat: point.lat.degrees(), lon: point.lon.degrees(), time: point.time })
+        })
+        .collect();
+    Ok(points)
+}
+
+// Rows of a CSV file with a header naming its latitude, longitude and time columns
+fn parse_csv(data: &str) -> Result<Vec<Result<ImportedPoint, ImportRejection>>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(data.trim_start_matches('\u{feff}').as_bytes());
+
+    let headers = reader.headers().map_err(|e| format!("Invalid CSV: {}", e))?.clone();
+    let column = |names: &[&str]| headers.iter().position(|header| names.iter().any(|name| header.eq_ignore_ascii_case(name)));
+    let lat_column = column(&["lat", "latitude"]).ok_or("CSV header has no lat or latitude column")?;
+    let lon_column = column(&["lon", "lng", "long", "longitude"]).ok_or("CSV header has no lon, lng or longitude column")?;
+    let time_column = column(&["timestamp", "time", "datetime", "date"]).ok_or("CSV header has no timestamp or time column")?;
+
+    let points = reader.records()
+        .map(|record| {
+            let record = record.map_err(|e| ImportRejection {
+                row: e.position().map(|position| position.line() as usize).unwrap_or(0),
+                reason: format!("Invalid CSV row: {}", e),
+            })?;
+            let row = record.position().map(|position| position.line() as usize).unwrap_or(0);
+            let rejection = |reason: &str| ImportRejection { row, reason: reason.to_string() };
+            let coordinate = |index: usize| record.get(index).and_then(|value| value.parse::<f64>().ok());
+            let (Some(lat), Some(lon)) = (coordinate(lat_column), coordinate(lon_column)) else {
+                return Err(rejection("Missing or invalid lat/lon"));
+            };
+            let time = record.get(time_column)
+                .filter(|value| !value.is_empty())
+                .ok_or_else(|| rejection("Missing time"))?;
+            let time = parse_time(time).ok_or_else(|| rejection("Invalid time"))?;
+            Ok(ImportedPoint { row, lat, lon, time })
+        })
+        .collect();
+    Ok(points)
+}
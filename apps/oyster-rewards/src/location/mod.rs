@@ -1,40 +1,148 @@
-use std::collections::{HashMap, HashSet};
+pub mod import;
+
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Mutex;
 use once_cell::sync::Lazy;
-use rand::Rng;
-use crate::models::{Location, EncryptedLocation, Station, StationType, GridCell, LocationRegistrationResponse};
+use dashmap::DashMap;
+use base64::{Engine as _, engine::general_purpose};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::models::{
+    Latitude, Longitude, Location, EncryptedLocation, Station, StationType, GridCell, SensorData,
+    LocationRegistrationResponse, AnonymousContributionRequest, UserLocationsRequest, UserLocationsResponse, UserLocation, ApiResponse, WebhookEventKind
+};
 use crate::crypto;
+use crate::heatmap;
+use crate::analytics;
+use crate::quests;
+use crate::leaderboard;
+use crate::fraud;
+use crate::webhooks;
+use crate::rewards::{discovery, events, streaks};
+use crate::rewards::ledger::REWARD_LEDGER;
 
 // Grid size for heatmap (0.001 degrees is roughly 100m)
 pub const GRID_SIZE: f64 = 0.001;
 
-// In-memory storage for location data (in a real app, this would be persisted securely)
-pub static LOCATION_HISTORY: Lazy<Mutex<HashMap<String, Vec<EncryptedLocation>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+// Page sizes of a user's location listing
+pub const DEFAULT_LOCATIONS_PAGE: usize = 50;
+pub const MAX_LOCATIONS_PAGE: usize = 200;
+
+// Beacons heard at least this strongly (dBm) are close enough to place the device at their venue
+pub const MIN_BEACON_RSSI: i32 = -90;
+
+// Barometer readings outside this range (hPa) are not from anywhere people register from
+pub const MIN_PRESSURE_HPA: f64 = 300.0;
+pub const MAX_PRESSURE_HPA: f64 = 1100.0;
+
+// Height of a floor of a multi-story venue, and how far above a cell's ground level a device
+// can be (the tallest buildings are about 830m)
+pub const FLOOR_HEIGHT_METERS: f64 = 4.0;
+pub const MAX_HEIGHT_ABOVE_GROUND_METERS: f64 = 850.0;
+
+// A reading counts towards its cell's ground pressure this long before the weather makes it stale
+pub const GROUND_PRESSURE_TTL_SECONDS: i64 = 3600;
+
+// A cell has a ground level once this many devices read a fresh pressure in it; the latest
+// reading of up to MAX_GROUND_READINGS devices is kept per cell
+pub const MIN_GROUND_DEVICES: usize = 5;
+const MAX_GROUND_READINGS: usize = 64;
+
+// Where in a cell's readings, lowest pressure first, its ground pressure is taken. High, since
+// the ground is the lowest place people read from, but short of the top: one outlying reading
+// only moves it to the neighbouring one
+const GROUND_PRESSURE_PERCENTILE: f64 = 0.75;
+
+// In-memory storage for location data (in a real app, this would be persisted securely).
+// Sharded by user, so registrations of different users do not wait on each other; nothing is
+// decrypted while a shard is locked, and no entry is looked up while another is held
+pub static LOCATION_HISTORY: Lazy<DashMap<String, Vec<EncryptedLocation>>> = Lazy::new(DashMap::new);
+
+// Store nearby stations for location verification, sharded by cell
+pub static NEARBY_STATIONS: Lazy<DashMap<GridCell, Vec<Station>>> = Lazy::new(DashMap::new);
+
+// The latest barometric pressure each device registered in a cell read, from which the cell's
+// ground level is derived
+pub static GROUND_PRESSURE: Lazy<Mutex<HashMap<GridCell, HashMap<String, GroundPressure>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct GroundPressure {
+    pub pressure_hpa: f64,
+    pub observed_at: DateTime<Utc>,
+}
+
+// How much of what is known about a cell the sensor readings of a location in it must match
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct VerificationThresholds {
+    // Known stations that must be seen, once the cell has any
+    pub min_matching_stations: usize,
+    // Share (0 to 1) of the cell's known stations that must be seen
+    pub min_matching_ratio: f64,
+}
 
-// Store nearby stations for location verification
-pub static NEARBY_STATIONS: Lazy<Mutex<HashMap<GridCell, Vec<Station>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+impl Default for VerificationThresholds {
+    // Any one known station
+    fn default() -> Self {
+        VerificationThresholds {
+            min_matching_stations: 1,
+            min_matching_ratio: 0.0,
+        }
+    }
+}
 
 // Verify the legitimacy of a location based on sensor data
 pub fn verify_location(location: &Location) -> bool {
+    verify_location_with(location, &VerificationThresholds::default(), Utc::now())
+}
+
+// Verify a location against custom thresholds at the given time. Callers replaying logged
+// registrations pass the logged time, so the ground pressure resolves as it did originally
+pub fn verify_location_with(location: &Location, thresholds: &VerificationThresholds, now: DateTime<Utc>) -> bool {
+    verify_sensors(location.lat, location.lon, &location.sensors, Some(&location.device_id), thresholds, now)
+}
+
+// Verify sensor data observed at a position and learn the stations seen there. Only readings
+// of a known device feed the cell's ground pressure
+fn verify_sensors(
+    lat: Latitude,
+    lon: Longitude,
+    sensors: &SensorData,
+    device_id: Option<&str>,
+    thresholds: &VerificationThresholds,
+    now: DateTime<Utc>,
+) -> bool {
     // Check for mock location flag from the device
-    if location.sensors.is_mock_location {
+    if sensors.is_mock_location {
         return false;
     }
 
     // Check for sensor presence (a real device should have these sensors)
-    if location.sensors.accelerometer.is_none() || location.sensors.gyroscope.is_none() {
+    if sensors.accelerometer.is_none() || sensors.gyroscope.is_none() {
         return false;
     }
 
-    // If we have previously observed WiFi networks or cell towers in this area,
-    // check that at least some of them match
-    let grid_cell = GridCell::from_location(location.lat, location.lon, GRID_SIZE);
-    let stations = NEARBY_STATIONS.lock().unwrap();
+    // The barometer must read a pressure a device at this position can be at
+    let grid_cell = GridCell::from_location(lat, lon, GRID_SIZE);
+    if let Some(pressure) = sensors.pressure_hpa {
+        if !(MIN_PRESSURE_HPA..=MAX_PRESSURE_HPA).contains(&pressure) {
+            return false;
+        }
+        let height = height_above_ground(&grid_cell, pressure, now);
+        if height.is_some_and(|height| height > MAX_HEIGHT_ABOVE_GROUND_METERS) {
+            return false;
+        }
+    }
+
+    // If we have previously observed WiFi networks, cell towers or beacons in this area,
+    // check that at least some of them match. The cell stays locked until it has learned this
+    // location's stations, so registrations in the cell are checked one after the other
+    let mut stations_in_cell = NEARBY_STATIONS.entry(grid_cell.clone()).or_default();
     
-    if let Some(expected_stations) = stations.get(&grid_cell) {
+    {
+        let expected_stations = &*stations_in_cell;
         if !expected_stations.is_empty() {
             // Count how many WiFi networks match
-            let wifi_matches = location.sensors.wifi_networks.iter()
+            let wifi_matches = sensors.wifi_networks.iter()
                 .filter(|network| {
                     expected_stations.iter()
                         .filter(|station| station.station_type == StationType::Wifi)
@@ -43,32 +151,44 @@ pub fn verify_location(location: &Location) -> bool {
                 .count();
                 
             // Count how many cell towers match
-            let cell_matches = location.sensors.cell_towers.iter()
+            let cell_matches = sensors.cell_towers.iter()
                 .filter(|tower| {
                     expected_stations.iter()
                         .filter(|station| station.station_type == StationType::CellTower)
                         .any(|station| station.id == tower.cell_id)
                 })
                 .count();
-                
-            // If we have at least one match in either WiFi or cell towers, consider it verified
-            if wifi_matches == 0 && cell_matches == 0 && !expected_stations.is_empty() {
+
+            // Count how many beacons match, and whether one of them is heard up close
+            let beacon_matches: Vec<_> = sensors.ble_beacons.iter()
+                .filter(|beacon| {
+                    let id = beacon.station_id();
+                    expected_stations.iter()
+                        .filter(|station| station.station_type == StationType::BleBeacon)
+                        .any(|station| station.id == id)
+                })
+                .collect();
+            let near_beacon = beacon_matches.iter().any(|beacon| beacon.rssi >= MIN_BEACON_RSSI);
+
+            // Indoors the WiFi networks and towers known across a cell say little about where in
+            // it the device is, so a known beacon heard up close is enough. Otherwise a cell that
+            // knows fewer stations than the minimum needs all of them
+            let matches = wifi_matches + cell_matches + beacon_matches.len();
+            if !near_beacon && (matches < thresholds.min_matching_stations.min(expected_stations.len())
+                || (matches as f64) < thresholds.min_matching_ratio * expected_stations.len() as f64) {
                 return false;
             }
         }
     }
     
-    // Update our knowledge about nearby stations for future verifications
-    let mut stations = NEARBY_STATIONS.lock().unwrap();
-    let mut stations_in_cell = stations.entry(grid_cell).or_insert(Vec::new());
-    
+    // Update our knowledge about nearby stations for future verifications (reusing the lock held above)
     // Add any WiFi networks we haven't seen before
-    for network in &location.sensors.wifi_networks {
+    for network in &sensors.wifi_networks {
         if !stations_in_cell.iter().any(|s| s.id == network.bssid) {
             stations_in_cell.push(Station {
                 id: network.bssid.clone(),
-                lat: location.lat,
-                lon: location.lon,
+                lat,
+                lon,
                 station_type: StationType::Wifi,
                 signal_strength: network.signal_strength,
             });
@@ -76,46 +196,161 @@ pub fn verify_location(location: &Location) -> bool {
     }
     
     // Add any cell towers we haven't seen before
-    for tower in &location.sensors.cell_towers {
+    for tower in &sensors.cell_towers {
         if !stations_in_cell.iter().any(|s| s.id == tower.cell_id) {
             stations_in_cell.push(Station {
                 id: tower.cell_id.clone(),
-                lat: location.lat,
-                lon: location.lon,
+                lat,
+                lon,
                 station_type: StationType::CellTower,
                 signal_strength: tower.signal_strength,
             });
         }
     }
-    
+
+    // Add any beacons we haven't seen before
+    for beacon in &sensors.ble_beacons {
+        let id = beacon.station_id();
+        if !stations_in_cell.iter().any(|s| s.id == id) {
+            stations_in_cell.push(Station {
+                id,
+                lat,
+                lon,
+                station_type: StationType::BleBeacon,
+                signal_strength: beacon.rssi,
+            });
+        }
+    }
+    drop(stations_in_cell);
+
+    if let (Some(pressure), Some(device_id)) = (sensors.pressure_hpa, device_id) {
+        record_ground_pressure(grid_cell, device_id, pressure, now);
+    }
+
     true
 }
 
+// Meters above the cell's ground level a pressure is read at, while the cell has one
+fn height_above_ground(grid_cell: &GridCell, pressure_hpa: f64, now: DateTime<Utc>) -> Option<f64> {
+    let ground_hpa = ground_pressure(grid_cell, now)?;
+    // The international barometric formula
+    Some(44_330.0 * (1.0 - (pressure_hpa / ground_hpa).powf(1.0 / 5.255)))
+}
+
+// Ground pressure of a cell from its fresh readings, if enough devices read one
+fn ground_pressure(grid_cell: &GridCell, now: DateTime<Utc>) -> Option<f64> {
+    let ground_pressure = GROUND_PRESSURE.lock().unwrap();
+    let mut pressures: Vec<f64> = ground_pressure.get(grid_cell)?.values()
+        .filter(|reading| (now - reading.observed_at).num_seconds() <= GROUND_PRESSURE_TTL_SECONDS)
+        .map(|reading| reading.pressure_hpa)
+        .collect();
+    if pressures.len() < MIN_GROUND_DEVICES {
+        return None;
+    }
+    pressures.sort_by(f64::total_cmp);
+    Some(pressures[((pressures.len() - 1) as f64 * GROUND_PRESSURE_PERCENTILE).round() as usize])
+}
+
+// Replace the device's reading in the cell, dropping stale ones and the oldest past the limit
+fn record_ground_pressure(grid_cell: GridCell, device_id: &str, pressure_hpa: f64, now: DateTime<Utc>) {
+    let mut ground_pressure = GROUND_PRESSURE.lock().unwrap();
+    let readings = ground_pressure.entry(grid_cell).or_default();
+    readings.insert(device_id.to_string(), GroundPressure { pressure_hpa, observed_at: now });
+    readings.retain(|_, reading| (now - reading.observed_at).num_seconds() <= GROUND_PRESSURE_TTL_SECONDS);
+    while readings.len() > MAX_GROUND_READINGS {
+        let oldest = readings.iter()
+            .min_by_key(|(_, reading)| reading.observed_at)
+            .map(|(device_id, _)| device_id.clone());
+        match oldest {
+            Some(device_id) => readings.remove(&device_id),
+            None => break,
+        };
+    }
+}
+
+// The floor a location was registered on: the one the client reported, or else counted up
+// from its cell's ground level by its barometer at the given time
+pub fn floor_level(location: &Location, now: DateTime<Utc>) -> Option<i32> {
+    if location.floor_level.is_some() {
+        return location.floor_level;
+    }
+    let grid_cell = GridCell::from_location(location.lat, location.lon, GRID_SIZE);
+    let height = height_above_ground(&grid_cell, location.sensors.pressure_hpa?, now)?;
+    Some((height / FLOOR_HEIGHT_METERS).round() as i32)
+}
+
 // Register a location
 pub fn register_location(location: Location) -> LocationRegistrationResponse {
+    register_location_from(location, None)
+}
+
+// Register a location submitted from a client address, which feeds the Sybil checks
+pub fn register_location_from(location: Location, client_ip: Option<&str>) -> LocationRegistrationResponse {
     // Verify the location first
-    if !verify_location(&location) {
+    let now = Utc::now();
+    if !verify_location_with(&location, &VerificationThresholds::default(), now) {
         return LocationRegistrationResponse {
             encrypted_location_id: String::new(),
             success: false,
             message: "Location verification failed. It appears to be a mock location.".to_string(),
+            completed_quests: Vec::new(),
+            streak: None,
+            discovery: None,
+            fraud: None,
+            boost: None,
+            daily_remaining: None,
         };
     }
     
+    // Stored with its floor, so visits on different floors of a venue are told apart
+    let location = Location { floor_level: floor_level(&location, now), ..location };
+
     // Encrypt the location
     match crypto::encrypt_location(&location) {
         Ok(encrypted) => {
             // Store the encrypted location in our history
-            let mut history = LOCATION_HISTORY.lock().unwrap();
-            history.entry(location.user_id.clone())
-                .or_insert(Vec::new())
-                .push(encrypted.clone());
+            analytics::rolling::record_location(&location, || {
+                LOCATION_HISTORY.entry(location.user_id.clone()).or_default().push(encrypted.clone());
+            });
+            
+            // Cached analytics of this user are now stale
+            analytics::ANALYTICS_CACHE.lock().unwrap().invalidate_user(&location.user_id);
+            analytics::daily::invalidate_user(&location.user_id);
+            
+            // Update the heatmap aggregates
+            heatmap::record_point(location.lat, location.lon, Some(location.timestamp));
+            
+            // Extend the user's streak (by the server's day, not the client's timestamp)
+            // and advance their daily quests, whose rewards the streak multiplies
+            let today = now.date_naive();
+            let streak = streaks::record_activity(&location.user_id, today);
+            // Rewards of likely multi-account clusters are down-weighted, those in boost events raised
+            let fraud = fraud::assess(&location, client_ip);
+            let boost = events::boost_at(location.coordinate(), now);
+            for event in events::entered_events(&location.user_id, location.coordinate(), now) {
+                webhooks::emit(WebhookEventKind::GeofenceEntered, &location.user_id, serde_json::json!({
+                    "event_id": event.id,
+                    "name": event.name,
+                    "multiplier": event.multiplier,
+                }));
+            }
+            let boost_multiplier = boost.as_ref().map(|event| event.multiplier).unwrap_or(1.0);
+            let multiplier = streak.multiplier * fraud.reward_weight * boost_multiplier;
+            let completed_quests = quests::record_registration(&location, multiplier);
+            let discovery = discovery::record_discovery(&location, multiplier);
+            leaderboard::record_visit(&location.user_id, location.coordinate(), today);
                 
             // Return the encrypted location ID
             LocationRegistrationResponse {
                 encrypted_location_id: encrypted.enc_data.clone(),
                 success: true,
                 message: "Location registered successfully.".to_string(),
+                completed_quests,
+                streak: Some(streak),
+                discovery,
+                fraud: Some(fraud),
+                boost,
+                daily_remaining: REWARD_LEDGER.lock().unwrap().daily_remaining(&location.user_id),
             }
         },
         Err(err) => {
@@ -123,6 +358,12 @@ pub fn register_location(location: Location) -> LocationRegistrationResponse {
                 encrypted_location_id: String::new(),
                 success: false,
                 message: format!("Failed to encrypt location: {}", err),
+                completed_quests: Vec::new(),
+                streak: None,
+                discovery: None,
+                fraud: None,
+                boost: None,
+                daily_remaining: None,
             }
         }
     }
@@ -130,17 +371,136 @@ pub fn register_location(location: Location) -> LocationRegistrationResponse {
 
 // Get a location by its encrypted ID
 pub fn get_location(encrypted_id: &str) -> Result<Location, String> {
-    let history = LOCATION_HISTORY.lock().unwrap();
+    // Search through all users, decrypting once the record is copied out
+    let found = LOCATION_HISTORY.iter()
+        .find_map(|locations| locations.iter().find(|encrypted_loc| encrypted_loc.enc_data == encrypted_id).cloned());
     
-    // Search through all users
-    for (_, locations) in history.iter() {
-        // Search through all locations for this user
-        for encrypted_loc in locations {
-            if encrypted_loc.enc_data == encrypted_id {
-                return crypto::decrypt_location(encrypted_loc);
+    match found {
+        Some(encrypted_loc) => crypto::decrypt_location(&encrypted_loc),
+        None => Err("Location not found".to_string()),
+    }
+}
+
+// List a user's locations, decrypted, oldest first (by timestamp, then id). The cursor is the
+// last location of the previous page, so records inserted or purged meanwhile neither repeat
+// nor skip any. Records that fail to decrypt are left out.
+pub fn list_user_locations(request: &UserLocationsRequest) -> Result<UserLocationsResponse, String> {
+    let (from, to) = (request.from, request.to);
+    if let (Some(from), Some(to)) = (from, to) {
+        if from > to {
+            return Err("from must not be after to".to_string());
+        }
+    }
+    let after = request.cursor.as_deref().map(decode_locations_cursor).transpose()?;
+    let limit = request.limit.unwrap_or(DEFAULT_LOCATIONS_PAGE).clamp(1, MAX_LOCATIONS_PAGE);
+
+    // Copy out the first limit + 1 matches only, so registrations are not blocked while decrypting.
+    // One match past the page tells there is another page.
+    let mut matches: BTreeMap<(DateTime<Utc>, String), EncryptedLocation> = BTreeMap::new();
+    if let Some(records) = LOCATION_HISTORY.get(&request.user_id) {
+        for record in records.iter() {
+            let key = (record.timestamp, record.enc_data.as_str());
+            if from.is_some_and(|from| record.timestamp < from) || to.is_some_and(|to| record.timestamp > to) {
+                continue;
+            }
+            if after.as_ref().is_some_and(|(time, id)| key <= (*time, id.as_str())) {
+                continue;
+            }
+            if matches.len() > limit && matches.last_key_value().is_some_and(|((time, id), _)| key >= (*time, id.as_str())) {
+                continue;
+            }
+            matches.insert((record.timestamp, record.enc_data.clone()), record.clone());
+            if matches.len() > limit + 1 {
+                matches.pop_last();
             }
         }
     }
+
+    let mut next_cursor = None;
+    if matches.len() > limit {
+        matches.pop_last();
+        next_cursor = matches.last_key_value().map(|((time, id), _)| encode_locations_cursor(*time, id));
+    }
+
+    let locations = matches.into_values()
+        .filter_map(|record| {
+            let location = crypto::decrypt_location(&record).ok()?;
+            Some(UserLocation {
+                id: record.enc_data,
+                lat: location.lat,
+                lon: location.lon,
+                timestamp: record.timestamp,
+                imported: record.imported,
+            })
+        })
+        .collect();
+
+    Ok(UserLocationsResponse {
+        user_id: request.user_id.clone(),
+        locations,
+        next_cursor,
+        limit,
+    })
+}
+
+fn encode_locations_cursor(time: DateTime<Utc>, id: &str) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(format!("{}:{}:{}", time.timestamp(), time.timestamp_subsec_nanos(), id))
+}
+
+fn decode_locations_cursor(cursor: &str) -> Result<(DateTime<Utc>, String), String> {
+    let invalid = || "Invalid cursor".to_string();
+    let decoded = general_purpose::URL_SAFE_NO_PAD.decode(cursor).map_err(|_| invalid())?;
+    let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+
+    let mut parts = decoded.splitn(3, ':');
+    let (Some(seconds), Some(nanos), Some(id)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+    let seconds = seconds.parse::<i64>().map_err(|_| invalid())?;
+    let nanos = nanos.parse::<u32>().map_err(|_| invalid())?;
+    let time = DateTime::from_timestamp(seconds, nanos).ok_or_else(invalid)?;
+    Ok((time, id.to_string()))
+}
+
+// Remove stored locations registered (by their timestamp) before the cutoff, returning how many
+// were removed. Heatmap counts and snapshots are aggregates and stay.
+pub fn purge_locations_before(cutoff: DateTime<Utc>) -> usize {
+    let mut removed = 0;
+    let mut purged_users = Vec::new();
+    LOCATION_HISTORY.retain(|user_id, records| {
+        let count = records.len();
+        records.retain(|record| record.timestamp >= cutoff);
+        if records.len() != count {
+            removed += count - records.len();
+            purged_users.push(user_id.clone());
+        }
+        !records.is_empty()
+    });
+    
+    // Rebuilt once the shards are released, since rebuilding decrypts
+    for user_id in purged_users {
+        analytics::rolling::rebuild_user(&user_id);
+        analytics::ANALYTICS_CACHE.lock().unwrap().invalidate_user(&user_id);
+        analytics::daily::invalidate_user(&user_id);
+    }
+    removed
+}
+
+// Contribute an anonymous point to the heatmap and station map.
+// The point is verified like a regular registration but is never stored individually.
+pub fn contribute_anonymous(contribution: &AnonymousContributionRequest) -> ApiResponse {
+    if !verify_sensors(contribution.lat, contribution.lon, &contribution.sensors, None, &VerificationThresholds::default(), Utc::now()) {
+        return ApiResponse {
+            success: false,
+            message: "Contribution verification failed. It appears to be a mock location.".to_string(),
+        };
+    }
     
-    Err("Location not found".to_string())
-} 
\ No newline at end of file
+    // Only the aggregate is kept, never the point; it carries no timestamp
+    heatmap::record_point(contribution.lat, contribution.lon, None);
+    
+    ApiResponse {
+        success: true,
+        message: "Anonymous contribution accepted.".to_string(),
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+use once_cell::sync::Lazy;
+use crate::analytics::ANALYTICS_CACHE;
+use crate::heatmap::HEATMAP_CACHE;
+use crate::webhooks;
+
+// Upper bounds (seconds) of the request latency histogram buckets
+pub const LATENCY_BUCKETS: [f64; 11] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+// Route label of requests that matched no route, so scanners cannot blow up the label set
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+// Request counts and latencies since startup
+static REQUEST_METRICS: Lazy<Mutex<RequestMetrics>> = Lazy::new(|| Mutex::new(RequestMetrics::default()));
+
+#[derive(Default)]
+struct RequestMetrics {
+    // Requests by method, route pattern and status code
+    requests: BTreeMap<(String, String, u16), u64>,
+    // Latencies by method and route pattern
+    latencies: BTreeMap<(String, String), Histogram>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    // Cumulative counts per entry of LATENCY_BUCKETS
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+// Record a served request under its route pattern (e.g. `/api/v1/locations/{id}`)
+pub fn record_request(method: &str, route: &str, status: u16, elapsed: Duration) {
+    let mut metrics = REQUEST_METRICS.lock().unwrap();
+    *metrics.requests.entry((method.to_string(), route.to_string(), status)).or_default() += 1;
+
+    let seconds = elapsed.as_secs_f64();
+    let histogram = metrics.latencies.entry((method.to_string(), route.to_string())).or_default();
+    for (bucket, bound) in histogram.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+        if seconds <= bound {
+            *bucket += 1;
+        }
+    }
+    histogram.count += 1;
+    histogram.sum += seconds;
+}
+
+// All metrics in the Prometheus text exposition format
+pub fn render() -> String {
+    let mut out = String::new();
+    {
+        let metrics = REQUEST_METRICS.lock().unwrap();
+        out.push_str("# HELP orbitalmap_http_requests_total HTTP requests by method, route and status.\n");
+        out.push_str("# TYPE orbitalmap_http_requests_total counter\n");
+        for ((method, route, status), count) in &metrics.requests {
+            let _ = writeln!(out, "orbitalmap_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}", method, escape(route), status, count);
+        }
+
+        out.push_str("# HELP orbitalmap_http_request_duration_seconds HTTP request latency by method and route.\n");
+        out.push_str("# TYPE orbitalmap_http_request_duration_seconds histogram\n");
+        for ((method, route), histogram) in &metrics.latencies {
+            let labels = format!("method=\"{}\",route=\"{}\"", method, escape(route));
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                let _ = writeln!(out, "orbitalmap_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, count);
+            }
+            let _ = writeln!(out, "orbitalmap_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, histogram.count);
+            let _ = writeln!(out, "orbitalmap_http_request_duration_seconds_sum{{{}}} {}", labels, histogram.sum);
+            let _ = writeln!(out, "orbitalmap_http_request_duration_seconds_count{{{}}} {}", labels, histogram.count);
+        }
+    }
+
+    let caches = [
+        ("heatmap", HEATMAP_CACHE.stats()),
+        ("analytics", ANALYTICS_CACHE.lock().unwrap().stats()),
+    ];
+    out.push_str("# HELP orbitalmap_cache_lookups_total Cache lookups by cache and result.\n");
+    out.push_str("# TYPE orbitalmap_cache_lookups_total counter\n");
+    for (cache, (hits, misses)) in caches {
+        let _ = writeln!(out, "orbitalmap_cache_lookups_total{{cache=\"{}\",result=\"hit\"}} {}", cache, hits);
+        let _ = writeln!(out, "orbitalmap_cache_lookups_total{{cache=\"{}\",result=\"miss\"}} {}", cache, misses);
+    }
+
+    out.push_str("# HELP orbitalmap_webhook_queue_depth Webhook events waiting for the dispatcher.\n");
+    out.push_str("# TYPE orbitalmap_webhook_queue_depth gauge\n");
+    let _ = writeln!(out, "orbitalmap_webhook_queue_depth {}", webhooks::queue_depth());
+    out
+}
+
+// Escape a label value
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -0,0 +1,53 @@
+use std::fmt;
+use base64::{Engine as _, engine::general_purpose};
+use serde::de::{self, Visitor};
+use serde::{Deserializer, Serializer};
+
+// Serde for binary data the models hold as base64 text, used with `#[serde(with = "base64_bytes")]`.
+// JSON and other human-readable formats carry the text; binary formats (CBOR) carry the bytes
+// themselves, a quarter smaller than their base64
+
+pub fn serialize<S: Serializer>(text: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+        return serializer.serialize_str(text);
+    }
+    match general_purpose::STANDARD.decode(text) {
+        Ok(bytes) => serializer.serialize_bytes(&bytes),
+        // Not base64 after all; kept as it is
+        Err(_) => serializer.serialize_str(text),
+    }
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_string(Base64Visitor)
+    } else {
+        deserializer.deserialize_any(Base64Visitor)
+    }
+}
+
+struct Base64Visitor;
+
+impl<'de> Visitor<'de> for Base64Visitor {
+    type Value = String;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("base64 text or bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        Ok(value.to_string())
+    }
+
+    fn visit_string<E: de::Error>(self, value: String) -> Result<Self::Value, E> {
+        Ok(value)
+    }
+
+    fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<Self::Value, E> {
+        Ok(general_purpose::STANDARD.encode(value))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, value: Vec<u8>) -> Result<Self::Value, E> {
+        self.visit_bytes(&value)
+    }
+}
@@ -0,0 +1,16 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+// CBOR encoding of the models (feature "cbor"): a compact alternative to JSON for payloads that
+// are encrypted, stored or framed rather than read by people. Fields marked `base64_bytes` are
+// written as raw bytes
+
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    ciborium::ser::into_writer(value, &mut bytes).map_err(|e| format!("CBOR serialization error: {}", e))?;
+    Ok(bytes)
+}
+
+pub fn from_slice<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+    ciborium::de::from_reader(bytes).map_err(|e| format!("CBOR deserialization error: {}", e))
+}
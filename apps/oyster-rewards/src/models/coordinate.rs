@@ -0,0 +1,198 @@
+use std::borrow::Cow;
+use std::fmt;
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
+use serde::{Deserialize, Serialize};
+use super::Meters;
+
+// Latitudes, longitudes and points built from them. Both are checked when they are made,
+// including when they are deserialized, so a model holding one never needs a range check,
+// and a latitude cannot be passed where a longitude is expected.
+
+// Mean Earth radius used for great-circle distances
+pub const EARTH_RADIUS_METERS: f64 = 6_371_000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoordinateError {
+    LatitudeOutOfRange(f64),
+    LongitudeOutOfRange(f64),
+}
+
+impl fmt::Display for CoordinateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoordinateError::LatitudeOutOfRange(degrees) => write!(f, "Latitude {} is outside [-90, 90]", degrees),
+            CoordinateError::LongitudeOutOfRange(degrees) => write!(f, "Longitude {} is outside [-180, 180]", degrees),
+        }
+    }
+}
+
+impl std::error::Error for CoordinateError {}
+
+// Degrees north of the equator, in [-90, 90]
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default, Serialize, Deserialize)]
+#[serde(try_from = "f64", into = "f64")]
+pub struct Latitude(f64);
+
+// Degrees east of Greenwich, in [-180, 180]
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default, Serialize, Deserialize)]
+#[serde(try_from = "f64", into = "f64")]
+pub struct Longitude(f64);
+
+impl Latitude {
+    pub const MIN: Latitude = Latitude(-90.0);
+    pub const MAX: Latitude = Latitude(90.0);
+
+    pub fn new(degrees: f64) -> Result<Self, CoordinateError> {
+        if (-90.0..=90.0).contains(&degrees) {
+            Ok(Latitude(degrees))
+        } else {
+            Err(CoordinateError::LatitudeOutOfRange(degrees))
+        }
+    }
+
+    // For values computed from valid latitudes, such as cell centres, that rounding or a cell
+    // size may push past a pole
+    pub fn clamped(degrees: f64) -> Self {
+        Latitude(if degrees.is_nan() { 0.0 } else { degrees.clamp(-90.0, 90.0) })
+    }
+
+    pub fn degrees(self) -> f64 {
+        self.0
+    }
+}
+
+impl Longitude {
+    pub const MIN: Longitude = Longitude(-180.0);
+    pub const MAX: Longitude = Longitude(180.0);
+
+    pub fn new(degrees: f64) -> Result<Self, CoordinateError> {
+        if (-180.0..=180.0).contains(&degrees) {
+            Ok(Longitude(degrees))
+        } else {
+            Err(CoordinateError::LongitudeOutOfRange(degrees))
+        }
+    }
+
+    // For values computed from valid longitudes that may land past the antimeridian
+    pub fn clamped(degrees: f64) -> Self {
+        Longitude(if degrees.is_nan() { 0.0 } else { degrees.clamp(-180.0, 180.0) })
+    }
+
+    pub fn degrees(self) -> f64 {
+        self.0
+    }
+}
+
+impl TryFrom<f64> for Latitude {
+    type Error = CoordinateError;
+
+    fn try_from(degrees: f64) -> Result<Self, CoordinateError> {
+        Latitude::new(degrees)
+    }
+}
+
+impl TryFrom<f64> for Longitude {
+    type Error = CoordinateError;
+
+    fn try_from(degrees: f64) -> Result<Self, CoordinateError> {
+        Longitude::new(degrees)
+    }
+}
+
+impl From<Latitude> for f64 {
+    fn from(latitude: Latitude) -> Self {
+        latitude.0
+    }
+}
+
+impl From<Longitude> for f64 {
+    fn from(longitude: Longitude) -> Self {
+        longitude.0
+    }
+}
+
+impl JsonSchema for Latitude {
+    fn schema_name() -> Cow<'static, str> {
+        "Latitude".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "type": "number",
+            "minimum": -90.0,
+            "maximum": 90.0,
+            "description": "Degrees north of the equator",
+        })
+    }
+}
+
+impl JsonSchema for Longitude {
+    fn schema_name() -> Cow<'static, str> {
+        "Longitude".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "type": "number",
+            "minimum": -180.0,
+            "maximum": 180.0,
+            "description": "Degrees east of Greenwich",
+        })
+    }
+}
+
+impl fmt::Display for Latitude {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for Longitude {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+// A point on the Earth's surface
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize, JsonSchema)]
+pub struct Coordinate {
+    pub lat: Latitude,
+    pub lon: Longitude,
+}
+
+impl Coordinate {
+    pub fn new(lat: Latitude, lon: Longitude) -> Self {
+        Coordinate { lat, lon }
+    }
+
+    // Check raw degrees, e.g. from a client or an import
+    pub fn from_degrees(lat: f64, lon: f64) -> Result<Self, CoordinateError> {
+        Ok(Coordinate { lat: Latitude::new(lat)?, lon: Longitude::new(lon)? })
+    }
+
+    // Great-circle (haversine) distance
+    pub fn distance(self, other: Coordinate) -> Meters {
+        let (phi1, phi2) = (self.lat.0.to_radians(), other.lat.0.to_radians());
+        let dphi = (other.lat.0 - self.lat.0).to_radians();
+        let dlambda = (other.lon.0 - self.lon.0).to_radians();
+
+        let a = (dphi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (dlambda / 2.0).sin().powi(2);
+        Meters(2.0 * EARTH_RADIUS_METERS * a.sqrt().asin())
+    }
+
+    // Initial bearing of the great circle towards `other`, in degrees clockwise from north in [0, 360)
+    pub fn bearing_degrees(self, other: Coordinate) -> f64 {
+        let (phi1, phi2) = (self.lat.0.to_radians(), other.lat.0.to_radians());
+        let dlambda = (other.lon.0 - self.lon.0).to_radians();
+
+        let y = dlambda.sin() * phi2.cos();
+        let x = phi1.cos() * phi2.sin() - phi1.sin() * phi2.cos() * dlambda.cos();
+        y.atan2(x).to_degrees().rem_euclid(360.0)
+    }
+}
+
+impl fmt::Display for Coordinate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{}", self.lat, self.lon)
+    }
+}
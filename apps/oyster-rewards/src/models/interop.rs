@@ -0,0 +1,414 @@
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use super::{timestamp, Coordinate, Latitude, Location, LocationVisit, Longitude, Poi, Seconds, SensorData};
+
+// The mapping of locations and visits to GPX points and GeoJSON features, shared by the import
+// and export code and documented for the frontend. GeoJSON properties carry the model's own
+// field names, and a feature converts back to the value it was made from.
+
+// A GPX point: a <trkpt> of a track or a <wpt> waypoint
+#[derive(Debug, Clone, PartialEq)]
+pub struct GpxPoint {
+    pub lat: Latitude,
+    pub lon: Longitude,
+    pub time: DateTime<Utc>,
+    // <name>; a visit's waypoint is named after its place
+    pub name: Option<String>,
+}
+
+impl GpxPoint {
+    // The location this point was recorded at; GPX carries no owner or sensor data
+    pub fn into_location(self, user_id: &str, device_id: &str, sensors: SensorData) -> Location {
+        Location {
+            lat: self.lat,
+            lon: self.lon,
+            timestamp: self.time,
+            user_id: user_id.to_string(),
+            device_id: device_id.to_string(),
+            sensors,
+            floor_level: None,
+        }
+    }
+
+    // The point as a GPX element, e.g. "trkpt" or "wpt"
+    pub fn to_xml(&self, element: &str) -> String {
+        let name = self.name.as_deref()
+            .map(|name| format!("<name>{}</name>", escape_xml(name)))
+            .unwrap_or_default();
+        format!(
+            r#"<{element} lat="{}" lon="{}"><time>{}</time>{name}</{element}>"#,
+            self.lat.degrees(),
+            self.lon.degrees(),
+            self.time.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        )
+    }
+}
+
+impl From<&Location> for GpxPoint {
+    fn from(location: &Location) -> Self {
+        GpxPoint {
+            lat: location.lat,
+            lon: location.lon,
+            time: location.timestamp,
+            name: None,
+        }
+    }
+}
+
+// A visit is a waypoint at its arrival
+impl From<&LocationVisit> for GpxPoint {
+    fn from(visit: &LocationVisit) -> Self {
+        GpxPoint {
+            lat: visit.lat,
+            lon: visit.lon,
+            time: visit.arrival_time,
+            name: visit.poi.as_ref().map(|poi| poi.name.clone()),
+        }
+    }
+}
+
+// Read a <trkpt>, <rtept> or <wpt> element
+impl TryFrom<roxmltree::Node<'_, '_>> for GpxPoint {
+    type Error = String;
+
+    fn try_from(node: roxmltree::Node<'_, '_>) -> Result<Self, String> {
+        let degrees = |name: &str| node.attribute(name).and_then(|value| value.trim().parse::<f64>().ok());
+        let (Some(lat), Some(lon)) = (degrees("lat"), degrees("lon")) else {
+            return Err("Missing or invalid lat/lon".to_string());
+        };
+        let coordinate = Coordinate::from_degrees(lat, lon).map_err(|e| e.to_string())?;
+        let child_text = |name: &str| node.children()
+            .find(|child| child.has_tag_name(name))
+            .and_then(|child| child.text());
+        let time = child_text("time").ok_or("Missing time")?;
+
+        Ok(GpxPoint {
+            lat: coordinate.lat,
+            lon: coordinate.lon,
+            time: parse_time(time).ok_or("Invalid time")?,
+            name: child_text("name").map(str::to_string),
+        })
+    }
+}
+
+// A GPX document with one track of the locations, in order, and a waypoint per visit
+pub fn gpx_document(locations: &[Location], visits: &[LocationVisit]) -> String {
+    let track: String = locations.iter().map(|location| GpxPoint::from(location).to_xml("trkpt")).collect();
+    let waypoints: String = visits.iter().map(|visit| GpxPoint::from(visit).to_xml("wpt")).collect();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?><gpx version="1.1" creator="orbitalmap" xmlns="http://www.topografix.com/GPX/1/1">{waypoints}<trk><trkseg>{track}</trkseg></trk></gpx>"#,
+    )
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// Times of GPX and CSV traces: RFC 3339, a date and time without zone (taken as UTC), or Unix seconds
+pub fn parse_time(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Some(time.with_timezone(&Utc));
+    }
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(value, format) {
+            return Some(time.and_utc());
+        }
+    }
+    timestamp::parse(value)
+}
+
+// A GeoJSON feature of a point
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Feature {
+    #[serde(rename = "type")]
+    pub kind: FeatureKind,
+    pub geometry: Geometry,
+    pub properties: Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FeatureKind {
+    Feature,
+}
+
+// Other geometries are refused when read
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Geometry {
+    // [lon, lat], optionally followed by the elevation
+    Point { coordinates: Vec<f64> },
+}
+
+impl Feature {
+    fn new(lat: Latitude, lon: Longitude, properties: Value) -> Self {
+        Feature {
+            kind: FeatureKind::Feature,
+            geometry: Geometry::Point { coordinates: vec![lon.degrees(), lat.degrees()] },
+            properties: match properties {
+                Value::Object(properties) => properties,
+                _ => Map::new(),
+            },
+        }
+    }
+
+    fn coordinate(&self) -> Result<Coordinate, String> {
+        let Geometry::Point { coordinates } = &self.geometry;
+        let [lon, lat, ..] = coordinates[..] else {
+            return Err("A Point needs [lon, lat] coordinates".to_string());
+        };
+        Coordinate::from_degrees(lat, lon).map_err(|e| e.to_string())
+    }
+
+    // Read the properties as the rest of a model's fields
+    fn properties<T: for<'de> Deserialize<'de>>(&self) -> Result<T, String> {
+        serde_json::from_value(Value::Object(self.properties.clone()))
+            .map_err(|e| format!("Invalid feature properties: {}", e))
+    }
+}
+
+// A FeatureCollection of features, e.g. a user's locations for the map
+pub fn feature_collection(features: impl IntoIterator<Item = Feature>) -> Value {
+    json!({
+        "type": "FeatureCollection",
+        "features": features.into_iter().collect::<Vec<_>>(),
+    })
+}
+
+impl From<&Location> for Feature {
+    fn from(location: &Location) -> Self {
+        Feature::new(location.lat, location.lon, json!({
+            "timestamp": location.timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            "user_id": location.user_id,
+            "device_id": location.device_id,
+            "sensors": location.sensors,
+            "floor_level": location.floor_level,
+        }))
+    }
+}
+
+// A feature without sensors (e.g. drawn on the map) gets none
+impl TryFrom<&Feature> for Location {
+    type Error = String;
+
+    fn try_from(feature: &Feature) -> Result<Self, String> {
+        #[derive(Deserialize)]
+        struct Properties {
+            #[serde(with = "timestamp")]
+            timestamp: DateTime<Utc>,
+            user_id: String,
+            device_id: String,
+            #[serde(default)]
+            sensors: SensorData,
+            #[serde(default)]
+            floor_level: Option<i32>,
+        }
+
+        let coordinate = feature.coordinate()?;
+        let properties: Properties = feature.properties()?;
+        Ok(Location {
+            lat: coordinate.lat,
+            lon: coordinate.lon,
+            timestamp: properties.timestamp,
+            user_id: properties.user_id,
+            device_id: properties.device_id,
+            sensors: properties.sensors,
+            floor_level: properties.floor_level,
+        })
+    }
+}
+
+impl From<&LocationVisit> for Feature {
+    fn from(visit: &LocationVisit) -> Self {
+        Feature::new(visit.lat, visit.lon, json!({
+            "arrival_time": visit.arrival_time.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            "departure_time": visit.departure_time.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            "duration_seconds": visit.duration_seconds,
+            "point_count": visit.point_count,
+            "poi": visit.poi,
+            "floor_level": visit.floor_level,
+        }))
+    }
+}
+
+impl TryFrom<&Feature> for LocationVisit {
+    type Error = String;
+
+    fn try_from(feature: &Feature) -> Result<Self, String> {
+        #[derive(Deserialize)]
+        struct Properties {
+            #[serde(with = "timestamp")]
+            arrival_time: DateTime<Utc>,
+            #[serde(with = "timestamp")]
+            departure_time: DateTime<Utc>,
+            duration_seconds: Seconds,
+            point_count: u32,
+            #[serde(default)]
+            poi: Option<Poi>,
+            #[serde(default)]
+            floor_level: Option<i32>,
+        }
+
+        let coordinate = feature.coordinate()?;
+        let properties: Properties = feature.properties()?;
+        Ok(LocationVisit {
+            lat: coordinate.lat,
+            lon: coordinate.lon,
+            arrival_time: properties.arrival_time,
+            departure_time: properties.departure_time,
+            duration_seconds: properties.duration_seconds,
+            point_count: properties.point_count,
+            poi: properties.poi,
+            floor_level: properties.floor_level,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+
+    fn location(time: DateTime<Utc>) -> Location {
+        Location {
+            lat: Latitude::clamped(25.033964),
+            lon: Longitude::clamped(121.564468),
+            timestamp: time,
+            user_id: "user-1".to_string(),
+            device_id: "device-1".to_string(),
+            sensors: SensorData {
+                accelerometer: Some(vec![0.1, 0.2, 9.8]),
+                additional_data: HashMap::from([("source".to_string(), "test".to_string())]),
+                ..SensorData::default()
+            },
+            floor_level: Some(3),
+        }
+    }
+
+    fn visit() -> LocationVisit {
+        LocationVisit {
+            lat: Latitude::clamped(-33.856784),
+            lon: Longitude::clamped(151.215297),
+            arrival_time: "2026-10-16T08:00:00.250Z".parse().unwrap(),
+            departure_time: "2026-10-16T08:45:30Z".parse().unwrap(),
+            duration_seconds: Seconds(2730),
+            point_count: 42,
+            poi: Some(Poi {
+                id: "poi-1".to_string(),
+                name: "Opera House & <Forecourt>".to_string(),
+                category: "landmark".to_string(),
+                lat: Latitude::clamped(-33.8568),
+                lon: Longitude::clamped(151.2153),
+                radius_meters: 150.0,
+            }),
+            floor_level: None,
+        }
+    }
+
+    // The models have no PartialEq; their JSON covers every field
+    fn assert_same<T: Serialize>(left: &T, right: &T) {
+        assert_eq!(serde_json::to_value(left).unwrap(), serde_json::to_value(right).unwrap());
+    }
+
+    // The points of a GPX document's elements with the given tag
+    fn gpx_points(document: &str, element: &str) -> Vec<Result<GpxPoint, String>> {
+        let document = roxmltree::Document::parse(document).unwrap();
+        document.descendants()
+            .filter(|node| node.has_tag_name(element))
+            .map(GpxPoint::try_from)
+            .collect()
+    }
+
+    // Through the JSON a client would send back
+    fn feature_json(feature: Feature) -> Feature {
+        serde_json::from_str(&serde_json::to_string(&feature).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn location_round_trips_through_gpx() {
+        let original = location("2026-10-16T08:00:00Z".parse().unwrap());
+        let document = gpx_document(std::slice::from_ref(&original), &[]);
+
+        let points = gpx_points(&document, "trkpt");
+        assert_eq!(points.len(), 1);
+        let point = points.into_iter().next().unwrap().unwrap();
+        let restored = point.into_location(&original.user_id, &original.device_id, original.sensors.clone());
+
+        // GPX carries no floor
+        assert_same(&restored, &Location { floor_level: None, ..original });
+    }
+
+    #[test]
+    fn visit_waypoint_keeps_position_arrival_and_name() {
+        let original = visit();
+        let document = gpx_document(&[], std::slice::from_ref(&original));
+
+        let point = gpx_points(&document, "wpt").into_iter().next().unwrap().unwrap();
+        assert_eq!(point, GpxPoint::from(&original));
+        assert_eq!(point.name.as_deref(), Some("Opera House & <Forecourt>"));
+    }
+
+    #[test]
+    fn location_round_trips_through_feature() {
+        let original = location("2026-10-16T08:00:00Z".parse().unwrap());
+        let restored = Location::try_from(&feature_json(Feature::from(&original))).unwrap();
+        assert_same(&restored, &original);
+    }
+
+    #[test]
+    fn visit_round_trips_through_feature() {
+        let original = visit();
+        let restored = LocationVisit::try_from(&feature_json(Feature::from(&original))).unwrap();
+        assert_same(&restored, &original);
+    }
+
+    #[test]
+    fn sub_second_timestamps_survive_both_formats() {
+        for time in ["2026-10-16T08:00:00.123Z", "2026-10-16T08:00:00.123456Z", "2026-10-16T08:00:00.123456789Z"] {
+            let time: DateTime<Utc> = time.parse().unwrap();
+            let original = location(time);
+
+            let document = gpx_document(std::slice::from_ref(&original), &[]);
+            let point = gpx_points(&document, "trkpt").into_iter().next().unwrap().unwrap();
+            assert_eq!(point.time, time);
+
+            let restored = Location::try_from(&feature_json(Feature::from(&original))).unwrap();
+            assert_eq!(restored.timestamp, time);
+        }
+    }
+
+    #[test]
+    fn gpx_point_without_time_is_rejected() {
+        let document = r#"<gpx><trk><trkseg><trkpt lat="25.03" lon="121.56"></trkpt><trkpt lat="25.03" lon="121.56"><time>not a time</time></trkpt></trkseg></trk></gpx>"#;
+        let points = gpx_points(document, "trkpt");
+        assert_eq!(points, vec![Err("Missing time".to_string()), Err("Invalid time".to_string())]);
+    }
+
+    #[test]
+    fn feature_without_required_properties_is_rejected() {
+        let mut feature = Feature::from(&visit());
+        feature.properties.remove("arrival_time");
+        let error = LocationVisit::try_from(&feature).unwrap_err();
+        assert!(error.contains("arrival_time"), "{}", error);
+
+        let mut feature = Feature::from(&location("2026-10-16T08:00:00Z".parse().unwrap()));
+        feature.properties.clear();
+        assert!(Location::try_from(&feature).is_err());
+    }
+
+    #[test]
+    fn feature_without_sensors_or_floor_gets_none() {
+        let mut feature = Feature::from(&location("2026-10-16T08:00:00Z".parse().unwrap()));
+        feature.properties.remove("sensors");
+        feature.properties.remove("floor_level");
+
+        let restored = Location::try_from(&feature).unwrap();
+        assert_same(&restored.sensors, &SensorData::default());
+        assert_eq!(restored.floor_level, None);
+    }
+}
@@ -0,0 +1,195 @@
+use std::fmt;
+use chrono::{DateTime, Duration, Utc};
+use crate::location::{MAX_PRESSURE_HPA, MIN_PRESSURE_HPA};
+use super::{BleBeacon, CellTower, Coordinate, CoordinateError, Location, SensorData, WifiNetwork};
+
+// Assembling a location one field at a time. The position, user and device are required;
+// the time defaults to now and the sensor data to none. `build` checks what deserializing
+// cannot: that the ids are set, the time is not ahead of the clock, and the readings are
+// numbers a sensor can report.
+
+// How far ahead of the clock a device's time may be
+pub const MAX_CLOCK_SKEW_SECONDS: i64 = 300;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LocationError {
+    Missing(&'static str),
+    Empty(&'static str),
+    Coordinate(CoordinateError),
+    FutureTimestamp(DateTime<Utc>),
+    InvalidReading(&'static str),
+}
+
+impl fmt::Display for LocationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocationError::Missing(field) => write!(f, "{} is required", field),
+            LocationError::Empty(field) => write!(f, "{} must not be empty", field),
+            LocationError::Coordinate(error) => write!(f, "{}", error),
+            LocationError::FutureTimestamp(time) => write!(f, "Timestamp {} is in the future", time.to_rfc3339()),
+            LocationError::InvalidReading(sensor) => write!(f, "{} reading is not a plausible sensor value", sensor),
+        }
+    }
+}
+
+impl std::error::Error for LocationError {}
+
+impl From<CoordinateError> for LocationError {
+    fn from(error: CoordinateError) -> Self {
+        LocationError::Coordinate(error)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LocationBuilder {
+    position: Option<(f64, f64)>,
+    timestamp: Option<DateTime<Utc>>,
+    user_id: Option<String>,
+    device_id: Option<String>,
+    sensors: SensorData,
+    floor_level: Option<i32>,
+}
+
+impl Location {
+    pub fn builder() -> LocationBuilder {
+        LocationBuilder::default()
+    }
+
+    // The checks `LocationBuilder::build` runs, for locations that were deserialized
+    pub fn validate(&self) -> Result<(), LocationError> {
+        if self.user_id.is_empty() {
+            return Err(LocationError::Empty("user_id"));
+        }
+        if self.device_id.is_empty() {
+            return Err(LocationError::Empty("device_id"));
+        }
+        if self.timestamp > Utc::now() + Duration::seconds(MAX_CLOCK_SKEW_SECONDS) {
+            return Err(LocationError::FutureTimestamp(self.timestamp));
+        }
+        self.sensors.validate()
+    }
+}
+
+impl SensorData {
+    fn validate(&self) -> Result<(), LocationError> {
+        if self.wifi_networks.iter().any(|network| network.bssid.is_empty()) {
+            return Err(LocationError::Empty("wifi_networks.bssid"));
+        }
+        if self.cell_towers.iter().any(|tower| tower.cell_id.is_empty()) {
+            return Err(LocationError::Empty("cell_towers.cell_id"));
+        }
+        if self.ble_beacons.iter().any(|beacon| beacon.uuid.is_empty()) {
+            return Err(LocationError::Empty("ble_beacons.uuid"));
+        }
+        // Motion sensors report at least one finite axis
+        let motion = |reading: &Option<Vec<f64>>| reading.as_ref()
+            .is_none_or(|values| !values.is_empty() && values.iter().all(|value| value.is_finite()));
+        if !motion(&self.accelerometer) {
+            return Err(LocationError::InvalidReading("accelerometer"));
+        }
+        if !motion(&self.gyroscope) {
+            return Err(LocationError::InvalidReading("gyroscope"));
+        }
+        if self.pressure_hpa.is_some_and(|pressure| !(MIN_PRESSURE_HPA..=MAX_PRESSURE_HPA).contains(&pressure)) {
+            return Err(LocationError::InvalidReading("pressure_hpa"));
+        }
+        Ok(())
+    }
+}
+
+impl LocationBuilder {
+    // Required: where, in degrees
+    pub fn position(mut self, lat: f64, lon: f64) -> Self {
+        self.position = Some((lat, lon));
+        self
+    }
+
+    pub fn coordinate(self, coordinate: Coordinate) -> Self {
+        self.position(coordinate.lat.degrees(), coordinate.lon.degrees())
+    }
+
+    // Required
+    pub fn user_id(mut self, user_id: impl Into<String>) -> Self {
+        self.user_id = Some(user_id.into());
+        self
+    }
+
+    // Required
+    pub fn device_id(mut self, device_id: impl Into<String>) -> Self {
+        self.device_id = Some(device_id.into());
+        self
+    }
+
+    // When the device was there (default: when built)
+    pub fn timestamp(mut self, timestamp: DateTime<Utc>) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    // All of the sensor data at once, replacing what was set before
+    pub fn sensors(mut self, sensors: SensorData) -> Self {
+        self.sensors = sensors;
+        self
+    }
+
+    pub fn wifi_network(mut self, network: WifiNetwork) -> Self {
+        self.sensors.wifi_networks.push(network);
+        self
+    }
+
+    pub fn cell_tower(mut self, tower: CellTower) -> Self {
+        self.sensors.cell_towers.push(tower);
+        self
+    }
+
+    pub fn ble_beacon(mut self, beacon: BleBeacon) -> Self {
+        self.sensors.ble_beacons.push(beacon);
+        self
+    }
+
+    pub fn accelerometer(mut self, reading: Vec<f64>) -> Self {
+        self.sensors.accelerometer = Some(reading);
+        self
+    }
+
+    pub fn gyroscope(mut self, reading: Vec<f64>) -> Self {
+        self.sensors.gyroscope = Some(reading);
+        self
+    }
+
+    pub fn pressure_hpa(mut self, pressure_hpa: f64) -> Self {
+        self.sensors.pressure_hpa = Some(pressure_hpa);
+        self
+    }
+
+    pub fn mock_location(mut self, is_mock_location: bool) -> Self {
+        self.sensors.is_mock_location = is_mock_location;
+        self
+    }
+
+    pub fn additional_data(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.sensors.additional_data.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn floor_level(mut self, floor_level: i32) -> Self {
+        self.floor_level = Some(floor_level);
+        self
+    }
+
+    pub fn build(self) -> Result<Location, LocationError> {
+        let (lat, lon) = self.position.ok_or(LocationError::Missing("position"))?;
+        let coordinate = Coordinate::from_degrees(lat, lon)?;
+        let location = Location {
+            lat: coordinate.lat,
+            lon: coordinate.lon,
+            timestamp: self.timestamp.unwrap_or_else(Utc::now),
+            user_id: self.user_id.ok_or(LocationError::Missing("user_id"))?,
+            device_id: self.device_id.ok_or(LocationError::Missing("device_id"))?,
+            sensors: self.sensors,
+            floor_level: self.floor_level,
+        };
+        location.validate()?;
+        Ok(location)
+    }
+}
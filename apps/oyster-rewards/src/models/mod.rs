@@ -1,35 +1,83 @@
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+mod coordinate;
+mod location_builder;
+mod units;
+pub mod timestamp;
+pub mod base64_bytes;
+pub mod interop;
+pub mod schema;
+#[cfg(feature = "cbor")]
+pub mod cbor;
+pub use coordinate::{Coordinate, CoordinateError, Latitude, Longitude, EARTH_RADIUS_METERS};
+pub use location_builder::{LocationBuilder, LocationError, MAX_CLOCK_SKEW_SECONDS};
+pub use units::{Kilometers, Meters, Seconds, METERS_PER_KILOMETER};
+
 // Location Structs
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Location {
-    pub lat: f64,
-    pub lon: f64,
-    pub timestamp: String,
+    pub lat: Latitude,
+    pub lon: Longitude,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub timestamp: DateTime<Utc>,
     pub user_id: String,
     pub device_id: String,
     pub sensors: SensorData,
+    // Floor of a multi-story venue, 0 at its ground level: reported by the client (e.g. from
+    // the venue's indoor positioning) or derived from the barometer when registered
+    #[serde(default)]
+    pub floor_level: Option<i32>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// Version of the EncryptedLocation format written by this build. Records are read whatever
+// fields they carry beyond the ones known here, so those of newer builds read as long as the
+// version allows; records from before the tag was added read as version 1
+pub const ENCRYPTED_LOCATION_VERSION: u32 = 2;
+
+fn untagged_version() -> u32 {
+    1
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct EncryptedLocation {
+    #[serde(default = "untagged_version")]
+    pub version: u32,
+    // Base64 of the ciphertext, which is also the location's id
+    #[serde(with = "base64_bytes")]
+    #[schemars(with = "String")]
     pub enc_data: String,
-    pub timestamp: String,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub timestamp: DateTime<Utc>,
+    #[serde(with = "base64_bytes")]
+    #[schemars(with = "String")]
     pub nonce: String,
+    // Imported from another app's export rather than registered with verified sensor data
+    #[serde(default)]
+    pub imported: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default, JsonSchema)]
 pub struct SensorData {
     pub wifi_networks: Vec<WifiNetwork>,
     pub cell_towers: Vec<CellTower>,
+    // Bluetooth beacons of indoor venues; absent from readings of older clients
+    #[serde(default)]
+    pub ble_beacons: Vec<BleBeacon>,
     pub accelerometer: Option<Vec<f64>>,
     pub gyroscope: Option<Vec<f64>>,
+    // Barometric pressure, which tells the floors of a venue apart
+    #[serde(default)]
+    pub pressure_hpa: Option<f64>,
     pub is_mock_location: bool,
     pub additional_data: HashMap<String, String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct WifiNetwork {
     pub ssid: String,
     pub bssid: String,
@@ -37,7 +85,7 @@ pub struct WifiNetwork {
     pub frequency: u32,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct CellTower {
     pub cell_id: String,
     pub signal_strength: i32,
@@ -46,135 +94,1305 @@ pub struct CellTower {
     pub lac: u32,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// An iBeacon-style advertisement, heard only within tens of meters of the beacon
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct BleBeacon {
+    pub uuid: String,
+    pub major: u16,
+    pub minor: u16,
+    pub rssi: i32,
+}
+
+impl BleBeacon {
+    // The beacon's id among the stations of a cell, e.g. "f7826da6-...:100:7"
+    pub fn station_id(&self) -> String {
+        format!("{}:{}:{}", self.uuid.to_lowercase(), self.major, self.minor)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Station {
     pub id: String,
-    pub lat: f64,
-    pub lon: f64,
+    pub lat: Latitude,
+    pub lon: Longitude,
     pub station_type: StationType,
     pub signal_strength: i32,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
 pub enum StationType {
     Wifi,
     CellTower,
+    BleBeacon,
 }
 
 // Grid Cell for Heatmap
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq, Eq, Hash, JsonSchema)]
 pub struct GridCell {
     pub lat_grid: i32,
     pub lon_grid: i32,
 }
 
 // API Models
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct HeatmapResponse {
     pub cells: Vec<HeatmapCell>,
+    pub frames: Vec<HeatmapFrame>,
     pub privacy_level: f64,
-    pub min_lat: f64,
-    pub max_lat: f64,
-    pub min_lon: f64,
-    pub max_lon: f64,
+    pub min_lat: Latitude,
+    pub max_lat: Latitude,
+    pub min_lon: Longitude,
+    pub max_lon: Longitude,
+    pub epsilon_spent: f64,
+    pub privacy_budget_remaining: Option<f64>,
+    pub error: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// One time bucket of a temporal heatmap
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct HeatmapFrame {
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub start_time: DateTime<Utc>,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub end_time: DateTime<Utc>,
+    pub cells: Vec<HeatmapCell>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct HeatmapCell {
-    pub lat: f64,
-    pub lon: f64,
+    pub lat: Latitude,
+    pub lon: Longitude,
     pub intensity: f64,
     pub count: u32,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// Change in density between two time windows
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct HeatmapDiffResponse {
+    pub cells: Vec<HeatmapDiffCell>,
+    pub period_a: TimeRange,
+    pub period_b: TimeRange,
+    pub min_lat: Latitude,
+    pub max_lat: Latitude,
+    pub min_lon: Longitude,
+    pub max_lon: Longitude,
+    pub epsilon_spent: f64,
+    pub privacy_budget_remaining: Option<f64>,
+    pub error: Option<String>,
+}
+
+// Per-cell counts of both windows; percent_change is None when period A was empty
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct HeatmapDiffCell {
+    pub lat: Latitude,
+    pub lon: Longitude,
+    pub count_a: u32,
+    pub count_b: u32,
+    pub delta: i64,
+    pub percent_change: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct VisitAnalyticsResponse {
     pub visits: Vec<LocationVisit>,
+    // Movement between consecutive visits
+    pub trips: Vec<Trip>,
+    // Locations per hour of day (UTC) and per day of week (Monday first) over the range
+    pub hourly_counts: Vec<usize>,
+    pub weekday_counts: Vec<usize>,
+    pub units: UnitSystem,
+    pub distance_traveled: Measurement,
+    pub average_speed: Measurement,
+    // Stored records that had to be left out of the analytics
+    #[serde(default)]
+    pub warnings: Vec<AnalyticsWarning>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AnalyticsWarningCode {
+    UndecryptableRecord,
+    // The requested range reached past the kept history and was shortened
+    RangeTruncated,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct AnalyticsWarning {
+    pub code: AnalyticsWarningCode,
+    pub skipped_records: usize,
+    pub message: String,
+}
+
+// Recurring places of a user and their home-work commute
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct RoutinesResponse {
+    pub routines: Vec<RoutineSummary>,
+    pub commute: Option<CommuteSummary>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RoutineLabel {
+    Home,
+    Work,
+    Frequent,
+}
+
+// A place the user keeps coming back to (hours are UTC)
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct RoutineSummary {
+    pub label: RoutineLabel,
+    pub lat: Latitude,
+    pub lon: Longitude,
+    pub visit_count: usize,
+    pub distinct_days: usize,
+    pub days_of_week: Vec<String>,
+    pub typical_arrival_hour: Option<u32>,
+    pub typical_departure_hour: Option<u32>,
+    pub total_duration_seconds: Seconds,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct CommuteSummary {
+    pub days: usize,
+    pub typical_departure_hour: Option<u32>,
+    pub typical_arrival_hour: Option<u32>,
+    pub average_duration_seconds: Seconds,
+}
+
+// Visits in a time window that fall outside the user's historical activity envelope
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct AnomaliesResponse {
+    pub anomalies: Vec<AnomalousVisit>,
+    // Visits in the history window the envelope was built from
+    pub baseline_visits: usize,
     pub error: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyReason {
+    // Far from every place visited before
+    NewArea,
+    // At an hour the user is rarely seen arriving anywhere (UTC)
+    UnusualHour,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct AnomalousVisit {
+    pub visit: LocationVisit,
+    // Between 0 (routine) and 1 (never seen anything like it)
+    pub anomaly_score: f64,
+    pub reasons: Vec<AnomalyReason>,
+    // Distance to the nearest previously visited place, if there is any history
+    pub nearest_known_place_meters: Option<Meters>,
+}
+
+// Likely next destinations of a user, learned from the transitions between their past visits
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct PredictionsResponse {
+    // The place of the user's latest visit, which the predictions start from
+    pub current_place: Option<PredictedPlace>,
+    // Most likely first, excluding the current place
+    pub predictions: Vec<PredictedPlace>,
+    // Share of the user's visits starting in each hour of day (UTC)
+    pub hourly_visit_likelihood: Vec<f64>,
+    // Visits the model was built from
+    pub history_visits: usize,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct PredictedPlace {
+    pub lat: Latitude,
+    pub lon: Longitude,
+    // Probability that this is the next place visited (1 for the current place)
+    pub probability: f64,
+    pub visit_count: usize,
+    pub typical_arrival_hour: Option<u32>,
+    pub poi: Option<Poi>,
+}
+
+// Differentially private visit statistics across all users of an area.
+// Every statistic is None when too few visitors were seen to release anything.
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct AggregateAnalyticsResponse {
+    pub total_visits: Option<u64>,
+    pub unique_visitors: Option<u64>,
+    pub median_dwell_seconds: Option<i64>,
+    pub suppressed: bool,
+    pub epsilon_spent: f64,
+    pub privacy_budget_remaining: Option<f64>,
+    pub error: Option<String>,
+}
+
+// Unit system used for display values in analytics responses
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum UnitSystem {
+    #[default]
+    Metric,
+    Imperial,
+}
+
+// A value reported both in canonical SI units and in the requested display units
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct Measurement {
+    pub value: f64,
+    pub unit: String,
+    pub display_value: f64,
+    pub display_unit: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct LocationVisit {
-    pub lat: f64,
-    pub lon: f64,
-    pub arrival_time: String,
-    pub departure_time: String,
-    pub duration_seconds: i64,
+    pub lat: Latitude,
+    pub lon: Longitude,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub arrival_time: DateTime<Utc>,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub departure_time: DateTime<Utc>,
+    pub duration_seconds: Seconds,
+    pub point_count: u32,
+    // Named place the visit took place at, when a POI provider knows one
+    #[serde(default)]
+    pub poi: Option<Poi>,
+    // Floor most of the visit's locations were on, when they have one
+    #[serde(default)]
+    pub floor_level: Option<i32>,
+}
+
+// Reward tiers, from the busiest cells to the rarest
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RewardTier {
+    Common,
+    Uncommon,
+    Rare,
+    Epic,
+    Legendary,
+}
+
+// Reward offered for visiting a point, priced from how often its cell was visited
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct RewardQuote {
+    pub lat: Latitude,
+    pub lon: Longitude,
+    pub tier: RewardTier,
+    pub points: u64,
+    pub cell_visits: u32,
+    // Event whose multiplier is included in the points
+    #[serde(default)]
+    pub boost: Option<BoostEvent>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventKind {
+    // Points were credited to a user's ledger
+    RewardGranted,
+    // A user registered a location inside a boost event's area they were not in before
+    GeofenceEntered,
+    QuestCompleted,
+    // A user left a place they had stayed at long enough for it to count as a visit
+    VisitCompleted,
+}
+
+// Data of a visit_completed event
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct VisitCompletion {
+    pub visit: LocationVisit,
+    // Trip from the user's previous visit, if there was one
+    pub trip: Option<Trip>,
+}
+
+// Body POSTed to webhook endpoints
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct WebhookEvent {
+    pub id: String,
+    pub kind: WebhookEventKind,
+    pub user_id: String,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub created_at: DateTime<Utc>,
+    pub data: serde_json::Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct WebhookEndpointRequest {
+    pub url: String,
+    // Kinds to deliver; all when empty
+    #[serde(default)]
+    pub events: Vec<WebhookEventKind>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct WebhookEndpoint {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<WebhookEventKind>,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub created_at: DateTime<Utc>,
+    // User whose own events the endpoint receives; operator endpoints receive everyone's
+    #[serde(default)]
+    pub user_id: Option<String>,
+}
+
+// A new endpoint with the secret its deliveries are signed with, returned only once
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct WebhookRegistration {
+    pub endpoint: WebhookEndpoint,
+    pub secret: String,
+}
+
+// Sizes of the server's in-memory state, for operators
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct AdminStats {
+    // Users with stored locations
+    pub users: usize,
+    pub stored_locations: usize,
+    pub heatmap_cells: usize,
+    // Grid cells with known WiFi networks or cell towers
+    pub station_cells: usize,
+    pub reward_accounts: usize,
+    pub pending_redemptions: usize,
+    // Boost events that have not ended, upcoming ones included
+    pub boost_events: usize,
+    pub webhooks: usize,
+    pub webhook_queue_depth: usize,
+    pub heatmap_cache_entries: usize,
+    pub analytics_cache_entries: usize,
+}
+
+// Stored locations older than `retention_days` are removed
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct RetentionRequest {
+    pub retention_days: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct RetentionResponse {
+    // Locations registered before this time were removed
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub cutoff: DateTime<Utc>,
+    pub removed_locations: usize,
+    pub remaining_locations: usize,
+}
+
+// Entries dropped from each cache
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct CacheInvalidationResponse {
+    pub heatmap_entries: usize,
+    pub analytics_entries: usize,
+}
+
+// State of a background job run by the API's scheduler
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct JobStatus {
+    pub name: String,
+    pub description: String,
+    // Seconds between scheduled runs; disabled jobs only run when triggered
+    pub interval_seconds: u64,
+    pub enabled: bool,
+    pub running: bool,
+    pub runs: u64,
+    pub failures: u64,
+    #[serde(default, with = "timestamp::option")]
+    #[schemars(with = "Option<DateTime<Utc>>")]
+    pub last_started_at: Option<DateTime<Utc>>,
+    #[serde(default, with = "timestamp::option")]
+    #[schemars(with = "Option<DateTime<Utc>>")]
+    pub last_finished_at: Option<DateTime<Utc>>,
+    pub last_duration_ms: Option<u64>,
+    // Summary of the last successful run, or why it failed
+    pub last_result: Option<String>,
+    pub last_error: Option<String>,
+    #[serde(default, with = "timestamp::option")]
+    #[schemars(with = "Option<DateTime<Utc>>")]
+    pub next_run_at: Option<DateTime<Utc>>,
+}
+
+// Outcome of claiming the reward of a verified location
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct RewardClaimResponse {
+    pub success: bool,
+    pub message: String,
+    pub tier: Option<RewardTier>,
+    // What was credited, after the multiplier and the daily cap
+    pub reward: Option<LedgerEntry>,
+    // Proof of the random bonus included in the tier's points
+    pub draw: Option<RewardDraw>,
+    // Streak multiplier x Sybil weight x boost applied to the engine's points
+    pub multiplier: f64,
+    pub streak: Option<StreakStatus>,
+    pub fraud: Option<FraudAssessment>,
+    pub boost: Option<BoostEvent>,
+    pub daily_remaining: Option<u64>,
+    // Seconds until the user can claim this cell again
+    pub cooldown_seconds: u64,
+}
+
+// Admin-defined period in which rewards earned inside an area are multiplied
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct BoostEventRequest {
+    pub name: String,
+    pub bounds: BoundingBox,
+    // RFC3339 time window
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub start: DateTime<Utc>,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub end: DateTime<Utc>,
+    pub multiplier: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct BoostEvent {
+    pub id: String,
+    pub name: String,
+    pub bounds: BoundingBox,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub start: DateTime<Utc>,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub end: DateTime<Utc>,
+    pub multiplier: f64,
+}
+
+// A verifiable random draw: the sr25519 VRF output and proof over (user_id, nonce), with the
+// roll in [0, 1) derived from the output; all binary fields are base64
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct RewardDraw {
+    pub user_id: String,
+    pub nonce: u64,
+    pub roll: f64,
+    pub public_key: String,
+    pub output: String,
+    pub proof: String,
+}
+
+// Public key and context to verify reward draws with
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct RewardVrfKey {
+    pub public_key: String,
+    pub context: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct RewardDrawVerification {
+    pub valid: bool,
+    pub roll: Option<f64>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LedgerEntryKind {
+    Earned,
+    Spent,
+    // Spent points returned, e.g. when a redemption expires
+    Refunded,
+}
+
+// One change to a user's reward balance
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct LedgerEntry {
+    pub id: u64,
+    pub kind: LedgerEntryKind,
+    pub points: u64,
+    pub reason: String,
+    pub balance_after: u64,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct RewardBalance {
+    pub user_id: String,
+    pub earned: u64,
+    pub spent: u64,
+    pub balance: u64,
+    // Points earned today (UTC) and what the daily cap still allows; None when uncapped
+    pub earned_today: u64,
+    pub daily_remaining: Option<u64>,
+}
+
+// A page of a user's ledger entries, newest first
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct RewardHistoryResponse {
+    pub user_id: String,
+    pub entries: Vec<LedgerEntry>,
+    // Entries across all pages
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+// Badge for being the first to register a verified location in a grid cell
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct DiscoveryBadge {
+    // Center of the discovered cell
+    pub lat: Latitude,
+    pub lon: Longitude,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub discovered_at: DateTime<Utc>,
+    pub bonus_points: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct RedeemRequest {
+    pub user_id: String,
+    pub points: u64,
+    // What the points are exchanged for
+    pub item: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RedemptionStatus {
+    // Points held in escrow until the item is handed over
+    Pending,
+    Fulfilled,
+    // Not fulfilled in time; the points were refunded
+    Expired,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct Redemption {
+    pub id: String,
+    pub user_id: String,
+    pub item: String,
+    pub points: u64,
+    pub status: RedemptionStatus,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub created_at: DateTime<Utc>,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub expires_at: DateTime<Utc>,
+    #[serde(default, with = "timestamp::option")]
+    #[schemars(with = "Option<DateTime<Utc>>")]
+    pub fulfilled_at: Option<DateTime<Utc>>,
+}
+
+// What a redemption token attests to, signed by the enclave
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct RedemptionClaim {
+    pub redemption_id: String,
+    pub user_id: String,
+    pub item: String,
+    pub points: u64,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct RedeemResponse {
+    pub redemption: Redemption,
+    // base64url(JSON claim) "." base64url(sr25519 signature)
+    pub token: String,
+}
+
+// Public key and context to verify redemption tokens with
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct RedemptionKey {
+    pub public_key: String,
+    pub context: String,
+}
+
+// What a user token attests to, signed with the secret the API shares with the identity service
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct UserTokenClaims {
+    pub user_id: String,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub expires_at: DateTime<Utc>,
+}
+
+// A page of the cells a user discovered, newest first
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct DiscoveriesResponse {
+    pub user_id: String,
+    pub discoveries: Vec<DiscoveryBadge>,
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+// What a quest asks of a user within one (UTC) day
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum QuestObjective {
+    // Register locations in this many grid cells the user has never been to
+    NewCells { count: u32 },
+    // Register this many locations
+    Registrations { count: u32 },
+    // Check in at a POI of this category, e.g. "transit_station"
+    NearPoiCategory { category: String },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct QuestDefinition {
+    pub id: String,
+    pub title: String,
+    pub objective: QuestObjective,
+    pub reward_points: u64,
+}
+
+// A quest with a user's progress on it today
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct QuestStatus {
+    pub id: String,
+    pub title: String,
+    pub objective: QuestObjective,
+    pub reward_points: u64,
+    pub progress: u32,
+    pub target: u32,
+    pub completed: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct QuestsResponse {
+    // The (UTC) day the progress belongs to; quests reset at midnight
+    pub date: String,
+    pub quests: Vec<QuestStatus>,
+}
+
+// A named place such as a park or a station, covering a circle around its center
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct Poi {
+    pub id: String,
+    pub name: String,
+    pub category: String,
+    pub lat: Latitude,
+    pub lon: Longitude,
+    #[serde(default = "default_poi_radius")]
+    pub radius_meters: f64,
+}
+
+fn default_poi_radius() -> f64 {
+    100.0
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportMode {
+    Walking,
+    Cycling,
+    Driving,
+    Transit,
+    // Too few points to tell
+    Unknown,
+}
+
+// Travel from one visit to the next
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct Trip {
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub start_time: DateTime<Utc>,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub end_time: DateTime<Utc>,
+    pub duration_seconds: Seconds,
     pub point_count: u32,
+    pub distance: Measurement,
+    pub average_speed: Measurement,
+    pub mode: TransportMode,
 }
 
 // Request Models
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct LocationRegistrationRequest {
-    pub lat: f64,
-    pub lon: f64,
+    pub lat: Latitude,
+    pub lon: Longitude,
     pub user_id: String,
     pub device_id: String,
     pub wifi_networks: Vec<WifiNetwork>,
     pub cell_towers: Vec<CellTower>,
+    #[serde(default)]
+    pub ble_beacons: Vec<BleBeacon>,
     pub accelerometer: Option<Vec<f64>>,
     pub gyroscope: Option<Vec<f64>>,
     pub is_mock_location: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// Anonymous heatmap-only contribution (no user or device identity, earns no rewards)
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct AnonymousContributionRequest {
+    pub lat: Latitude,
+    pub lon: Longitude,
+    pub sensors: SensorData,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct LocationLookupRequest {
     pub encrypted_location_id: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct HeatmapRequest {
-    pub min_lat: f64,
-    pub min_lon: f64,
-    pub max_lat: f64,
-    pub max_lon: f64,
+    pub min_lat: Latitude,
+    pub min_lon: Longitude,
+    pub max_lat: Latitude,
+    pub max_lon: Longitude,
     pub privacy_level: f64,
+    #[serde(default)]
+    pub time_range: Option<TimeRange>,
+    #[serde(default)]
+    pub bucket: Option<TimeBucket>,
+    // Differential privacy parameters; epsilon defaults to 1 / privacy_level
+    #[serde(default)]
+    pub epsilon: Option<f64>,
+    #[serde(default)]
+    pub delta: Option<f64>,
+    #[serde(default)]
+    pub sensitivity: Option<f64>,
+    // Whose privacy budget this query is charged to (see heatmap::privacy), set by the server
+    // from the request's credentials and never read from the body
+    #[serde(skip)]
+    pub requester_id: Option<String>,
+    // Seed for synthetic heatmaps; real heatmaps always draw fresh noise
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+// Per-cell counts of one UTC day, persisted so history survives purged location data
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct HeatmapSnapshot {
+    pub date: String,
+    pub cells: Vec<HeatmapSnapshotCell>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct HeatmapSnapshotCell {
+    pub lat_grid: i32,
+    pub lon_grid: i32,
+    pub count: u32,
+}
+
+// Heatmap whose cells are finer where points are dense
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct AdaptiveHeatmapResponse {
+    pub cells: Vec<AdaptiveHeatmapCell>,
+    pub privacy_level: f64,
+    pub min_lat: Latitude,
+    pub max_lat: Latitude,
+    pub min_lon: Longitude,
+    pub max_lon: Longitude,
+    pub epsilon_spent: f64,
+    pub privacy_budget_remaining: Option<f64>,
+    pub error: Option<String>,
+}
+
+// A cell of its own size; lat/lon is its south-west corner
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct AdaptiveHeatmapCell {
+    pub lat: Latitude,
+    pub lon: Longitude,
+    pub lat_size: f64,
+    pub lon_size: f64,
+    pub depth: u32,
+    pub count: u32,
+    pub intensity: f64,
+}
+
+// Busiest areas of a heatmap, ranked by visits
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct HotspotsResponse {
+    pub hotspots: Vec<HeatmapHotspot>,
+    pub epsilon_spent: f64,
+    pub privacy_budget_remaining: Option<f64>,
+    pub error: Option<String>,
+}
+
+// A cluster of adjacent high-density cells
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct HeatmapHotspot {
+    pub rank: usize,
+    pub name: String,
+    pub lat: Latitude,
+    pub lon: Longitude,
+    pub radius_meters: f64,
+    pub cell_count: usize,
+    pub count: u32,
+}
+
+// Most traveled corridors between places, ranked by (noisy) number of travelers
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct PopularRoutesResponse {
+    pub routes: Vec<PopularRoute>,
+    pub epsilon_spent: f64,
+    pub privacy_budget_remaining: Option<f64>,
+    pub error: Option<String>,
+}
+
+// A corridor between two places, counted in both directions
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct PopularRoute {
+    pub rank: usize,
+    pub from_lat: Latitude,
+    pub from_lon: Longitude,
+    pub to_lat: Latitude,
+    pub to_lon: Longitude,
+    pub distance_meters: Meters,
+    pub count: u32,
+}
+
+// Geographic bounds; min_lon > max_lon means the box crosses the antimeridian (±180°)
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+pub struct BoundingBox {
+    pub min_lat: Latitude,
+    pub min_lon: Longitude,
+    pub max_lat: Latitude,
+    pub max_lon: Longitude,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// Compare period B against period A over the same bounds
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct HeatmapDiffRequest {
+    #[serde(flatten)]
+    pub heatmap: HeatmapRequest,
+    pub period_a: TimeRange,
+    pub period_b: TimeRange,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct TimeRange {
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub start: DateTime<Utc>,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub end: DateTime<Utc>,
+}
+
+// Frame size for time-bucketed heatmaps
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeBucket {
+    Hour,
+    Day,
+    Week,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct VisitAnalyticsRequest {
     pub user_id: String,
-    pub start_time: String,
-    pub end_time: String,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub start_time: DateTime<Utc>,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub end_time: DateTime<Utc>,
+    #[serde(default)]
+    pub units: UnitSystem,
+    // Visit detection thresholds; omitted fields use the defaults
+    #[serde(default)]
+    pub min_stay_seconds: Option<i64>,
+    #[serde(default)]
+    pub cluster_radius_m: Option<f64>,
+    #[serde(default)]
+    pub max_gap_seconds: Option<i64>,
+}
+
+// A page of a user's stored locations, oldest first, optionally within [from, to]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct UserLocationsRequest {
+    pub user_id: String,
+    #[serde(default, with = "timestamp::option")]
+    #[schemars(with = "Option<DateTime<Utc>>")]
+    pub from: Option<DateTime<Utc>>,
+    #[serde(default, with = "timestamp::option")]
+    #[schemars(with = "Option<DateTime<Utc>>")]
+    pub to: Option<DateTime<Utc>>,
+    // Opaque position returned as `next_cursor` by the previous page
+    #[serde(default)]
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+// One decrypted location of a user
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct UserLocation {
+    // Encrypted ID the location is looked up by
+    pub id: String,
+    pub lat: Latitude,
+    pub lon: Longitude,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub timestamp: DateTime<Utc>,
+    pub imported: bool,
+}
+
+// Outcome of importing a GPX or CSV trace into a user's history
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct LocationImportResponse {
+    pub imported: usize,
+    // Points at a time the user already has a location for
+    pub duplicates: usize,
+    pub rejected: usize,
+    // Why points were rejected, the first MAX_IMPORT_REJECTIONS of them
+    pub rejections: Vec<ImportRejection>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct ImportRejection {
+    // CSV line number, or position of the point in the GPX file (both from 1)
+    pub row: usize,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct UserLocationsResponse {
+    pub user_id: String,
+    pub locations: Vec<UserLocation>,
+    // Cursor of the next page, absent on the last one
+    pub next_cursor: Option<String>,
+    pub limit: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct RoutinesRequest {
+    pub user_id: String,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub start_time: DateTime<Utc>,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub end_time: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct AnomaliesRequest {
+    pub user_id: String,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub start_time: DateTime<Utc>,
+    #[serde(with = "timestamp")]
+    #[schemars(with = "DateTime<Utc>")]
+    pub end_time: DateTime<Utc>,
+    // Days before start_time that make up the user's history (default 90)
+    #[serde(default)]
+    pub history_days: Option<i64>,
+    // Visits scoring below this are not reported (default 0.5)
+    #[serde(default)]
+    pub min_score: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct PredictionsRequest {
+    pub user_id: String,
+    // Moment to predict from (default now); only visits before it are used
+    #[serde(default, with = "timestamp::option")]
+    #[schemars(with = "Option<DateTime<Utc>>")]
+    pub at: Option<DateTime<Utc>>,
+    // Days of history before `at` the model is built from (default 90)
+    #[serde(default)]
+    pub history_days: Option<i64>,
+    // Number of predictions returned (default 5)
+    #[serde(default)]
+    pub n: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct DailySummaryRequest {
+    pub user_id: String,
+    pub date: String,
+    #[serde(default)]
+    pub units: UnitSystem,
 }
 
 // Response Models
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct LocationRegistrationResponse {
     pub encrypted_location_id: String,
     pub success: bool,
     pub message: String,
+    // Quests this registration completed
+    #[serde(default)]
+    pub completed_quests: Vec<QuestStatus>,
+    // The user's run of consecutive active days, after this registration
+    #[serde(default)]
+    pub streak: Option<StreakStatus>,
+    // Set when this registration was the first ever in its cell
+    #[serde(default)]
+    pub discovery: Option<DiscoveryBadge>,
+    // Sybil risk of this registration; rewards above were weighted by it
+    #[serde(default)]
+    pub fraud: Option<FraudAssessment>,
+    // Event whose multiplier applied to the rewards above
+    #[serde(default)]
+    pub boost: Option<BoostEvent>,
+    // Points the user may still earn today; None when earnings are uncapped
+    #[serde(default)]
+    pub daily_remaining: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FraudSignalKind {
+    // The device was used by other accounts
+    SharedDevice,
+    // Accelerometer and gyroscope readings identical to other accounts'
+    ClonedMotion,
+    // The same set of Wi-Fi networks as other accounts
+    SharedWifiEnvironment,
+    // More accounts from the client's IP address than a shared network explains
+    CrowdedIp,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct FraudSignal {
+    pub kind: FraudSignalKind,
+    pub other_accounts: usize,
+    // Risk this signal contributes, between 0 and 1
+    pub score: f64,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// How likely a user is one of several accounts run by the same person
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct FraudAssessment {
+    // Between 0 (no evidence) and 1
+    pub score: f64,
+    // Factor applied to the registration's rewards (1 - score)
+    pub reward_weight: f64,
+    pub signals: Vec<FraudSignal>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LeaderboardPeriod {
+    // The current ISO week (Monday to Sunday, UTC)
+    Week,
+}
+
+// One place on a leaderboard; the handle is a per-period pseudonym, not the user ID
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct LeaderboardEntry {
+    pub rank: usize,
+    pub handle: String,
+    pub score: u64,
+}
+
+// Where the requesting user stands; None on a board they have not scored on
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct LeaderboardStanding {
+    pub handle: String,
+    pub rewards: Option<LeaderboardEntry>,
+    pub cells: Option<LeaderboardEntry>,
+}
+
+// Top users of a period by reward points earned and by distinct cells visited
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct LeaderboardResponse {
+    pub period: LeaderboardPeriod,
+    // Dates (UTC) of the period, end exclusive
+    pub start: String,
+    pub end: String,
+    pub participants: usize,
+    pub rewards: Vec<LeaderboardEntry>,
+    pub cells: Vec<LeaderboardEntry>,
+    pub you: Option<LeaderboardStanding>,
+}
+
+// Consecutive (UTC) days on which a user registered a location
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct StreakStatus {
+    pub current_days: u32,
+    pub longest_days: u32,
+    // Factor applied to rewards earned today
+    pub multiplier: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct LocationResponse {
-    pub lat: Option<f64>,
-    pub lon: Option<f64>,
-    pub timestamp: Option<String>,
+    pub lat: Option<Latitude>,
+    pub lon: Option<Longitude>,
+    #[serde(default, with = "timestamp::option")]
+    #[schemars(with = "Option<DateTime<Utc>>")]
+    pub timestamp: Option<DateTime<Utc>>,
     pub success: bool,
     pub message: String,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct DailySummaryResponse {
+    pub date: String,
+    pub hourly_counts: Vec<usize>,
+    pub unique_places: usize,
+    pub units: UnitSystem,
+    pub distance_traveled: Measurement,
+    pub error: Option<String>,
+}
+
 // General response for errors
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct ApiResponse {
     pub success: bool,
     pub message: String,
 }
 
+// Points of the models that have a position
+impl Location {
+    pub fn coordinate(&self) -> Coordinate {
+        Coordinate::new(self.lat, self.lon)
+    }
+}
+
+impl LocationVisit {
+    pub fn coordinate(&self) -> Coordinate {
+        Coordinate::new(self.lat, self.lon)
+    }
+}
+
+impl Poi {
+    pub fn coordinate(&self) -> Coordinate {
+        Coordinate::new(self.lat, self.lon)
+    }
+}
+
 // Grid Cell implementation
 impl GridCell {
-    pub fn from_location(lat: f64, lon: f64, grid_size: f64) -> Self {
+    pub fn from_location(lat: Latitude, lon: Longitude, grid_size: f64) -> Self {
         GridCell {
-            lat_grid: (lat / grid_size).floor() as i32,
-            lon_grid: (lon / grid_size).floor() as i32,
+            lat_grid: (lat.degrees() / grid_size).floor() as i32,
+            lon_grid: (lon.degrees() / grid_size).floor() as i32,
         }
     }
 
-    pub fn to_coordinates(&self, grid_size: f64) -> (f64, f64) {
+    // Centre of the cell; cells on a pole or the antimeridian are cut at the edge of the globe
+    pub fn to_coordinates(&self, grid_size: f64) -> (Latitude, Longitude) {
         (
-            (self.lat_grid as f64) * grid_size + (grid_size / 2.0),
-            (self.lon_grid as f64) * grid_size + (grid_size / 2.0),
+            Latitude::clamped((self.lat_grid as f64) * grid_size + (grid_size / 2.0)),
+            Longitude::clamped((self.lon_grid as f64) * grid_size + (grid_size / 2.0)),
         )
     }
-} 
\ No newline at end of file
+}
+
+// Bounding box implementation
+impl BoundingBox {
+    pub fn from_request(request: &HeatmapRequest) -> Self {
+        BoundingBox {
+            min_lat: request.min_lat,
+            min_lon: request.min_lon,
+            max_lat: request.max_lat,
+            max_lon: request.max_lon,
+        }
+    }
+
+    // Check that the box is not inverted in latitude; its corners are on the globe by type
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_lat > self.max_lat {
+            return Err("min_lat must not be greater than max_lat".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn crosses_antimeridian(&self) -> bool {
+        self.min_lon > self.max_lon
+    }
+
+    pub fn lat_span(&self) -> f64 {
+        self.max_lat.degrees() - self.min_lat.degrees()
+    }
+
+    // Longitude extent, measured eastwards from min_lon to max_lon
+    pub fn lon_span(&self) -> f64 {
+        if self.crosses_antimeridian() {
+            360.0 - (self.min_lon.degrees() - self.max_lon.degrees())
+        } else {
+            self.max_lon.degrees() - self.min_lon.degrees()
+        }
+    }
+
+    pub fn contains(&self, lat: Latitude, lon: Longitude) -> bool {
+        let lon_inside = if self.crosses_antimeridian() {
+            lon >= self.min_lon || lon <= self.max_lon
+        } else {
+            lon >= self.min_lon && lon <= self.max_lon
+        };
+        lat >= self.min_lat && lat <= self.max_lat && lon_inside
+    }
+
+    // Split a box crossing the antimeridian into its eastern and western parts
+    pub fn split_antimeridian(&self) -> Vec<BoundingBox> {
+        if !self.crosses_antimeridian() {
+            return vec![*self];
+        }
+        vec![
+            BoundingBox { max_lon: Longitude::MAX, ..*self },
+            BoundingBox { min_lon: Longitude::MIN, ..*self },
+        ]
+    }
+
+    // Number of grid cells needed to cover the box
+    pub fn grid_cells(&self, grid_size: f64) -> u64 {
+        let lat_cells = (self.lat_span() / grid_size).ceil() as u64;
+        let lon_cells = (self.lon_span() / grid_size).ceil() as u64;
+        lat_cells.saturating_mul(lon_cells)
+    }
+}
+
+impl HeatmapRequest {
+    // Copy of the request restricted to other bounds
+    pub fn with_bounds(&self, bounds: &BoundingBox) -> HeatmapRequest {
+        HeatmapRequest {
+            min_lat: bounds.min_lat,
+            min_lon: bounds.min_lon,
+            max_lat: bounds.max_lat,
+            max_lon: bounds.max_lon,
+            ..self.clone()
+        }
+    }
+}
+
+// Unit conversion implementation
+const METERS_PER_MILE: f64 = 1609.344;
+
+impl UnitSystem {
+    // Express a distance in this unit system
+    pub fn distance(&self, meters: Meters) -> Measurement {
+        let (display_value, display_unit) = match self {
+            UnitSystem::Metric => (Kilometers::from(meters).0, "km"),
+            UnitSystem::Imperial => (meters.0 / METERS_PER_MILE, "mi"),
+        };
+
+        Measurement {
+            value: meters.0,
+            unit: "m".to_string(),
+            display_value,
+            display_unit: display_unit.to_string(),
+        }
+    }
+
+    // Express a speed given in meters per second in this unit system
+    pub fn speed(&self, meters_per_second: f64) -> Measurement {
+        let meters_per_hour = meters_per_second * 3600.0;
+        let (display_value, display_unit) = match self {
+            UnitSystem::Metric => (meters_per_hour / METERS_PER_KILOMETER, "km/h"),
+            UnitSystem::Imperial => (meters_per_hour / METERS_PER_MILE, "mph"),
+        };
+
+        Measurement {
+            value: meters_per_second,
+            unit: "m/s".to_string(),
+            display_value,
+            display_unit: display_unit.to_string(),
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use schemars::generate::SchemaSettings;
+use serde_json::{json, Value};
+use super::*;
+
+// The JSON Schema (draft 2020-12) of every model, served at /api/v1/schema so clients can
+// generate their types instead of mirroring the structs. Each model is a definition under
+// "$defs" named as in Rust; times are RFC 3339 strings, though the API also reads Unix seconds.
+
+pub fn document() -> Value {
+    let mut generator = SchemaSettings::draft2020_12().into_generator();
+    macro_rules! define {
+        ($($model:ty),* $(,)?) => { $( generator.subschema_for::<$model>(); )* };
+    }
+    define!(
+        Location, EncryptedLocation, SensorData, WifiNetwork, CellTower, BleBeacon, Station, StationType,
+        GridCell, HeatmapResponse, HeatmapFrame, HeatmapCell, HeatmapDiffResponse, HeatmapDiffCell,
+        VisitAnalyticsResponse, AnalyticsWarningCode, AnalyticsWarning, RoutinesResponse, RoutineLabel,
+        RoutineSummary, CommuteSummary, AnomaliesResponse, AnomalyReason, AnomalousVisit,
+        PredictionsResponse, PredictedPlace, AggregateAnalyticsResponse, UnitSystem, Measurement,
+        LocationVisit, RewardTier, RewardQuote, WebhookEventKind, VisitCompletion, WebhookEvent,
+        WebhookEndpointRequest, WebhookEndpoint, WebhookRegistration, AdminStats, RetentionRequest,
+        RetentionResponse, CacheInvalidationResponse, JobStatus, RewardClaimResponse, BoostEventRequest,
+        BoostEvent, RewardDraw, RewardVrfKey, RewardDrawVerification, LedgerEntryKind, LedgerEntry,
+        RewardBalance, RewardHistoryResponse, DiscoveryBadge, RedeemRequest, RedemptionStatus, Redemption,
+        RedemptionClaim, RedeemResponse, RedemptionKey, UserTokenClaims, DiscoveriesResponse, QuestObjective,
+        QuestDefinition, QuestStatus, QuestsResponse, Poi, TransportMode, Trip, LocationRegistrationRequest,
+        AnonymousContributionRequest, LocationLookupRequest, HeatmapRequest, HeatmapSnapshot,
+        HeatmapSnapshotCell, AdaptiveHeatmapResponse, AdaptiveHeatmapCell, HotspotsResponse, HeatmapHotspot,
+        PopularRoutesResponse, PopularRoute, BoundingBox, HeatmapDiffRequest, TimeRange, TimeBucket,
+        VisitAnalyticsRequest, UserLocationsRequest, UserLocation, LocationImportResponse, ImportRejection,
+        UserLocationsResponse, RoutinesRequest, AnomaliesRequest, PredictionsRequest, DailySummaryRequest,
+        LocationRegistrationResponse, FraudSignalKind, FraudSignal, FraudAssessment, LeaderboardPeriod,
+        LeaderboardEntry, LeaderboardStanding, LeaderboardResponse, StreakStatus, LocationResponse,
+        DailySummaryResponse, ApiResponse, Coordinate
+    );
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "Oyster Rewards API models",
+        "$defs": generator.take_definitions(true),
+    })
+}
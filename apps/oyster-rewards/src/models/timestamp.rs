@@ -0,0 +1,77 @@
+use std::fmt;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::de::{self, Visitor};
+use serde::{Deserializer, Serializer};
+
+// Serde for the instants in the models, used with `#[serde(with = "timestamp")]`. They are
+// written as RFC 3339 in UTC, and read from RFC 3339 or from Unix seconds, which the enclave
+// and older clients send.
+
+pub fn serialize<S: Serializer>(time: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+    deserializer.deserialize_any(TimestampVisitor)
+}
+
+// Parse RFC 3339 or Unix seconds given as text, e.g. in a query string
+pub fn parse(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    DateTime::parse_from_rfc3339(value)
+        .map(|time| time.with_timezone(&Utc))
+        .ok()
+        .or_else(|| value.parse::<i64>().ok().and_then(|seconds| DateTime::from_timestamp(seconds, 0)))
+}
+
+struct TimestampVisitor;
+
+impl<'de> Visitor<'de> for TimestampVisitor {
+    type Value = DateTime<Utc>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an RFC 3339 time or Unix seconds")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        parse(value).ok_or_else(|| E::custom(format!("invalid time {:?}: expected RFC 3339 or Unix seconds", value)))
+    }
+
+    fn visit_i64<E: de::Error>(self, seconds: i64) -> Result<Self::Value, E> {
+        DateTime::from_timestamp(seconds, 0).ok_or_else(|| E::custom(format!("Unix time {} is out of range", seconds)))
+    }
+
+    fn visit_u64<E: de::Error>(self, seconds: u64) -> Result<Self::Value, E> {
+        let seconds = i64::try_from(seconds).map_err(|_| E::custom(format!("Unix time {} is out of range", seconds)))?;
+        self.visit_i64(seconds)
+    }
+
+    fn visit_f64<E: de::Error>(self, seconds: f64) -> Result<Self::Value, E> {
+        let (whole, fraction) = (seconds.floor(), seconds - seconds.floor());
+        if !whole.is_finite() || whole.abs() > i64::MAX as f64 {
+            return Err(E::custom(format!("Unix time {} is out of range", seconds)));
+        }
+        DateTime::from_timestamp(whole as i64, (fraction * 1e9) as u32)
+            .ok_or_else(|| E::custom(format!("Unix time {} is out of range", seconds)))
+    }
+}
+
+// The same for optional instants; fields using it also need `#[serde(default)]`
+pub mod option {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(time: &Option<DateTime<Utc>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(time) => super::serialize(time, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+        #[derive(Deserialize)]
+        struct Timestamp(#[serde(with = "super")] DateTime<Utc>);
+
+        Ok(Option::<Timestamp>::deserialize(deserializer)?.map(|Timestamp(time)| time))
+    }
+}
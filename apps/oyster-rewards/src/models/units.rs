@@ -0,0 +1,129 @@
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Sub};
+use chrono::Duration;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// Distances and durations. On the wire they are the plain numbers they always were, named by
+// their field (`distance_meters`, `duration_seconds`); in code the unit is part of the type,
+// so a distance cannot be passed where a duration, or kilometers where meters, are expected.
+
+pub const METERS_PER_KILOMETER: f64 = 1000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(transparent)]
+pub struct Meters(pub f64);
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(transparent)]
+pub struct Kilometers(pub f64);
+
+// Whole seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(transparent)]
+pub struct Seconds(pub i64);
+
+impl Meters {
+    // Average speed over the time, in meters per second; 0 if no time passed
+    pub fn per_second(self, elapsed: Seconds) -> f64 {
+        if elapsed.0 > 0 {
+            self.0 / elapsed.0 as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Seconds {
+    pub fn duration(self) -> Duration {
+        Duration::seconds(self.0)
+    }
+}
+
+impl From<Kilometers> for Meters {
+    fn from(kilometers: Kilometers) -> Self {
+        Meters(kilometers.0 * METERS_PER_KILOMETER)
+    }
+}
+
+impl From<Meters> for Kilometers {
+    fn from(meters: Meters) -> Self {
+        Kilometers(meters.0 / METERS_PER_KILOMETER)
+    }
+}
+
+// Sub-second parts are dropped
+impl From<Duration> for Seconds {
+    fn from(duration: Duration) -> Self {
+        Seconds(duration.num_seconds())
+    }
+}
+
+impl Add for Meters {
+    type Output = Meters;
+
+    fn add(self, other: Meters) -> Meters {
+        Meters(self.0 + other.0)
+    }
+}
+
+impl AddAssign for Meters {
+    fn add_assign(&mut self, other: Meters) {
+        self.0 += other.0;
+    }
+}
+
+impl Sub for Meters {
+    type Output = Meters;
+
+    fn sub(self, other: Meters) -> Meters {
+        Meters(self.0 - other.0)
+    }
+}
+
+impl Sum for Meters {
+    fn sum<I: Iterator<Item = Meters>>(iter: I) -> Self {
+        Meters(iter.map(|meters| meters.0).sum())
+    }
+}
+
+impl Add for Seconds {
+    type Output = Seconds;
+
+    fn add(self, other: Seconds) -> Seconds {
+        Seconds(self.0 + other.0)
+    }
+}
+
+impl Sub for Seconds {
+    type Output = Seconds;
+
+    fn sub(self, other: Seconds) -> Seconds {
+        Seconds(self.0 - other.0)
+    }
+}
+
+impl Sum for Seconds {
+    fn sum<I: Iterator<Item = Seconds>>(iter: I) -> Self {
+        Seconds(iter.map(|seconds| seconds.0).sum())
+    }
+}
+
+impl fmt::Display for Meters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} m", self.0)
+    }
+}
+
+impl fmt::Display for Kilometers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} km", self.0)
+    }
+}
+
+impl fmt::Display for Seconds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} s", self.0)
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use chrono::{NaiveDate, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use crate::models::{GridCell, Location, QuestDefinition, QuestObjective, QuestStatus, QuestsResponse, WebhookEventKind};
+use crate::webhooks;
+use crate::analytics::poi::POI_PROVIDER;
+use crate::location::GRID_SIZE;
+use crate::rewards::apply_multiplier;
+use crate::rewards::ledger::REWARD_LEDGER;
+
+// Active quests; the built-in set unless the API server loads a configuration
+pub static QUEST_CONFIG: Lazy<Mutex<QuestConfig>> = Lazy::new(|| Mutex::new(QuestConfig::default()));
+
+// Progress of every user, evaluated as their locations are registered
+static QUEST_PROGRESS: Lazy<Mutex<HashMap<String, UserQuests>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// The daily quests
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuestConfig {
+    pub quests: Vec<QuestDefinition>,
+}
+
+impl Default for QuestConfig {
+    fn default() -> Self {
+        let quest = |id: &str, title: &str, objective, reward_points| QuestDefinition {
+            id: id.to_string(),
+            title: title.to_string(),
+            objective,
+            reward_points,
+        };
+        QuestConfig {
+            quests: vec![
+                quest("explorer", "Visit 3 new cells today", QuestObjective::NewCells { count: 3 }, 150),
+                quest("regular", "Check in 10 times today", QuestObjective::Registrations { count: 10 }, 50),
+                quest("commuter", "Check in near a transit station", QuestObjective::NearPoiCategory { category: "transit_station".to_string() }, 75),
+            ],
+        }
+    }
+}
+
+impl QuestConfig {
+    // Load the quests from a JSON file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, String> {
+        let json = fs::read(path.as_ref()).map_err(|e| format!("Failed to read quests: {}", e))?;
+        let config: QuestConfig = serde_json::from_slice(&json).map_err(|e| format!("Invalid quests: {}", e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let mut ids = HashSet::new();
+        for quest in &self.quests {
+            if !ids.insert(quest.id.as_str()) {
+                return Err(format!("Quest {} is defined twice", quest.id));
+            }
+            if target(&quest.objective) == 0 {
+                return Err(format!("Quest {} has a zero count", quest.id));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Replace the active quests; progress made today is kept for quests that keep their id
+pub fn set_quest_config(config: QuestConfig) {
+    *QUEST_CONFIG.lock().unwrap() = config;
+}
+
+// One user's quest state
+#[derive(Default)]
+struct UserQuests {
+    // Every cell the user has registered a location in
+    visited_cells: HashSet<GridCell>,
+    // Progress and completions of the current day, reset when the day changes
+    day: Option<NaiveDate>,
+    progress: HashMap<String, u32>,
+    completed: HashSet<String>,
+}
+
+// Advance the user's quests with a newly registered location, crediting completed ones
+// (scaled by the user's streak multiplier).
+// Days follow the server clock so backdated timestamps cannot replay a day's quests.
+pub fn record_registration(location: &Location, multiplier: f64) -> Vec<QuestStatus> {
+    let today = Utc::now().date_naive();
+    let grid_cell = GridCell::from_location(location.lat, location.lon, GRID_SIZE);
+    let poi_category = POI_PROVIDER.lock().unwrap().lookup(location.coordinate()).map(|poi| poi.category);
+    let quests = QUEST_CONFIG.lock().unwrap().quests.clone();
+
+    let mut progress = QUEST_PROGRESS.lock().unwrap();
+    let user = progress.entry(location.user_id.clone()).or_default();
+    if user.day != Some(today) {
+        user.day = Some(today);
+        user.progress.clear();
+        user.completed.clear();
+    }
+    let new_cell = user.visited_cells.insert(grid_cell);
+
+    let mut completed = Vec::new();
+    for quest in &quests {
+        if user.completed.contains(&quest.id) {
+            continue;
+        }
+        let advances = match &quest.objective {
+            QuestObjective::NewCells { .. } => new_cell,
+            QuestObjective::Registrations { .. } => true,
+            QuestObjective::NearPoiCategory { category } => poi_category.as_ref() == Some(category),
+        };
+        if !advances {
+            continue;
+        }
+
+        let count = user.progress.entry(quest.id.clone()).or_insert(0);
+        *count += 1;
+        if *count >= target(&quest.objective) {
+            user.completed.insert(quest.id.clone());
+            let points = apply_multiplier(quest.reward_points, multiplier);
+            REWARD_LEDGER.lock().unwrap().credit(&location.user_id, points, &format!("quest: {}", quest.title));
+            let status = status(quest, user);
+            webhooks::emit(WebhookEventKind::QuestCompleted, &location.user_id, serde_json::to_value(&status).unwrap_or_default());
+            completed.push(status);
+        }
+    }
+
+    completed
+}
+
+// Today's quests with the user's progress, if a user is given
+pub fn quest_statuses(user_id: Option<&str>) -> QuestsResponse {
+    let today = Utc::now().date_naive();
+    let quests = QUEST_CONFIG.lock().unwrap().quests.clone();
+    let progress = QUEST_PROGRESS.lock().unwrap();
+    let empty = UserQuests::default();
+    let user = user_id
+        .and_then(|user_id| progress.get(user_id))
+        .filter(|user| user.day == Some(today))
+        .unwrap_or(&empty);
+
+    QuestsResponse {
+        date: today.to_string(),
+        quests: quests.iter().map(|quest| status(quest, user)).collect(),
+    }
+}
+
+fn status(quest: &QuestDefinition, user: &UserQuests) -> QuestStatus {
+    let target = target(&quest.objective);
+    QuestStatus {
+        id: quest.id.clone(),
+        title: quest.title.clone(),
+        objective: quest.objective.clone(),
+        reward_points: quest.reward_points,
+        progress: user.progress.get(&quest.id).copied().unwrap_or(0).min(target),
+        target,
+        completed: user.completed.contains(&quest.id),
+    }
+}
+
+// Number of qualifying registrations that complete the objective
+fn target(objective: &QuestObjective) -> u32 {
+    match objective {
+        QuestObjective::NewCells { count } | QuestObjective::Registrations { count } => *count,
+        QuestObjective::NearPoiCategory { .. } => 1,
+    }
+}
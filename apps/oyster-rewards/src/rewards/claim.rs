@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use crate::models::{GridCell, Location, RewardClaimResponse};
+use crate::location::{verify_location, GRID_SIZE};
+use crate::fraud;
+use super::ledger::REWARD_LEDGER;
+use super::{apply_multiplier, cell_visits, events, streaks, REWARD_ENGINE};
+
+// A user can claim the reward of a cell once per this many seconds
+pub const CLAIM_COOLDOWN_SECONDS: i64 = 6 * 3600;
+
+// A user and a cell they claimed
+type ClaimKey = (String, GridCell);
+
+// Last claim of each user in each cell
+static LAST_CLAIMS: Lazy<Mutex<HashMap<ClaimKey, DateTime<Utc>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Pay the reward of a verified location: priced by the reward engine, then scaled by the user's
+// streak, Sybil risk and any boost event, and credited up to the daily cap
+pub fn claim_reward(location: &Location, client_ip: Option<&str>) -> RewardClaimResponse {
+    if !verify_location(location) {
+        return rejected("Location verification failed. It appears to be a mock location.", 0);
+    }
+
+    let now = Utc::now();
+    let grid_cell = GridCell::from_location(location.lat, location.lon, GRID_SIZE);
+    {
+        let mut last_claims = LAST_CLAIMS.lock().unwrap();
+        let key = (location.user_id.clone(), grid_cell);
+        if let Some(last_claim) = last_claims.get(&key) {
+            let remaining = CLAIM_COOLDOWN_SECONDS - (now - *last_claim).num_seconds();
+            if remaining > 0 {
+                return rejected("The reward of this cell was already claimed recently", remaining as u64);
+            }
+        }
+        last_claims.retain(|_, last_claim| (now - *last_claim).num_seconds() < CLAIM_COOLDOWN_SECONDS);
+        last_claims.insert(key, now);
+    }
+
+    let (tier, points, draw) = REWARD_ENGINE.lock().unwrap().price(cell_visits(location.coordinate()), &location.user_id);
+    let streak = streaks::record_activity(&location.user_id, now.date_naive());
+    let fraud = fraud::assess(location, client_ip);
+    let boost = events::boost_at(location.coordinate(), now);
+    let multiplier = streak.multiplier * fraud.reward_weight * boost.as_ref().map(|event| event.multiplier).unwrap_or(1.0);
+
+    let mut ledger = REWARD_LEDGER.lock().unwrap();
+    let reward = ledger.credit(&location.user_id, apply_multiplier(points, multiplier), &format!("{:?} location reward", tier).to_lowercase());
+    let daily_remaining = ledger.daily_remaining(&location.user_id);
+    RewardClaimResponse {
+        success: true,
+        message: match reward {
+            Some(_) => "Reward claimed.".to_string(),
+            None => "Nothing was credited; the daily reward cap is reached or the reward was weighted to zero.".to_string(),
+        },
+        tier: Some(tier),
+        reward,
+        draw,
+        multiplier,
+        streak: Some(streak),
+        fraud: Some(fraud),
+        boost,
+        daily_remaining,
+        cooldown_seconds: CLAIM_COOLDOWN_SECONDS as u64,
+    }
+}
+
+fn rejected(message: &str, cooldown_seconds: u64) -> RewardClaimResponse {
+    RewardClaimResponse {
+        success: false,
+        message: message.to_string(),
+        tier: None,
+        reward: None,
+        draw: None,
+        multiplier: 0.0,
+        streak: None,
+        fraud: None,
+        boost: None,
+        daily_remaining: None,
+        cooldown_seconds,
+    }
+}
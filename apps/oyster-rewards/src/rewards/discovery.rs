@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::Mutex;
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use crate::models::{DiscoveriesResponse, DiscoveryBadge, GridCell, Location};
+use crate::location::GRID_SIZE;
+use super::ledger::{REWARD_LEDGER, MAX_HISTORY_PAGE};
+use super::{apply_multiplier, REWARD_ENGINE};
+
+// First discoverer of every cell a verified location was registered in
+static DISCOVERIES: Lazy<Mutex<Discoveries>> = Lazy::new(|| Mutex::new(Discoveries::default()));
+
+#[derive(Default)]
+struct Discoveries {
+    discovered_by: HashMap<GridCell, String>,
+    // Badges of each user, oldest first
+    badges: HashMap<String, Vec<DiscoveryBadge>>,
+}
+
+// Claim the cell of a verified location for the user if nobody registered there before,
+// crediting the explorer bonus (scaled by the user's streak multiplier)
+pub fn record_discovery(location: &Location, multiplier: f64) -> Option<DiscoveryBadge> {
+    let grid_cell = GridCell::from_location(location.lat, location.lon, GRID_SIZE);
+    let mut discoveries = DISCOVERIES.lock().unwrap();
+    match discoveries.discovered_by.entry(grid_cell.clone()) {
+        Entry::Occupied(_) => return None,
+        Entry::Vacant(entry) => entry.insert(location.user_id.clone()),
+    };
+
+    let bonus_points = apply_multiplier(REWARD_ENGINE.lock().unwrap().config().explorer_bonus, multiplier);
+    let (lat, lon) = grid_cell.to_coordinates(GRID_SIZE);
+    // What the daily cap let through
+    let bonus_points = REWARD_LEDGER.lock().unwrap()
+        .credit(&location.user_id, bonus_points, &format!("explorer bonus: first to reach {:.4}, {:.4}", lat, lon))
+        .map(|entry| entry.points)
+        .unwrap_or(0);
+    let badge = DiscoveryBadge {
+        lat,
+        lon,
+        discovered_at: Utc::now(),
+        bonus_points,
+    };
+    discoveries.badges.entry(location.user_id.clone()).or_default().push(badge.clone());
+    Some(badge)
+}
+
+// One page of the cells a user discovered first, newest first
+pub fn discoveries(user_id: &str, offset: usize, limit: usize) -> DiscoveriesResponse {
+    let discoveries = DISCOVERIES.lock().unwrap();
+    let badges = discoveries.badges.get(user_id).map(|badges| badges.as_slice()).unwrap_or(&[]);
+    let limit = limit.clamp(1, MAX_HISTORY_PAGE);
+    DiscoveriesResponse {
+        user_id: user_id.to_string(),
+        discoveries: badges.iter().rev().skip(offset).take(limit).cloned().collect(),
+        total: badges.len(),
+        offset,
+        limit,
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use crate::models::{BoostEvent, BoostEventRequest, Coordinate};
+
+// Largest multiplier an event may set
+pub const MAX_BOOST_MULTIPLIER: f64 = 10.0;
+
+// Boost events defined by admins, including upcoming ones
+static BOOST_EVENTS: Lazy<Mutex<Vec<BoostEvent>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+// Events each user's last registration fell inside
+static USERS_INSIDE: Lazy<Mutex<HashMap<String, HashSet<String>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Validate and store a new event
+pub fn create_event(request: &BoostEventRequest) -> Result<BoostEvent, String> {
+    let id = rand::thread_rng().gen::<[u8; 8]>().iter().map(|byte| format!("{:02x}", byte)).collect();
+    let event = validate_event(id, request)?;
+    BOOST_EVENTS.lock().unwrap().push(event.clone());
+    Ok(event)
+}
+
+// Replace an event's name, area, window and multiplier, keeping its ID
+pub fn update_event(id: &str, request: &BoostEventRequest) -> Result<BoostEvent, String> {
+    let updated = validate_event(id.to_string(), request)?;
+    let mut events = BOOST_EVENTS.lock().unwrap();
+    let event = events.iter_mut()
+        .find(|event| event.id == id)
+        .ok_or_else(|| format!("Event {} not found", id))?;
+    *event = updated.clone();
+    Ok(updated)
+}
+
+pub fn delete_event(id: &str) -> Result<(), String> {
+    let mut events = BOOST_EVENTS.lock().unwrap();
+    let count = events.len();
+    events.retain(|event| event.id != id);
+    if events.len() == count {
+        return Err(format!("Event {} not found", id));
+    }
+    Ok(())
+}
+
+// Every event that has not ended yet, upcoming ones included
+pub fn list_events(now: DateTime<Utc>) -> Vec<BoostEvent> {
+    let mut events = BOOST_EVENTS.lock().unwrap();
+    events.retain(|event| event.end > now);
+    events.clone()
+}
+
+// Events running at `now`; ended ones are dropped
+pub fn active_events(now: DateTime<Utc>) -> Vec<BoostEvent> {
+    let mut events = BOOST_EVENTS.lock().unwrap();
+    events.retain(|event| event.end > now);
+    events.iter()
+        .filter(|event| event.start <= now)
+        .cloned()
+        .collect()
+}
+
+// The strongest event boosting a point at `now`; overlapping events do not stack
+pub fn boost_at(point: Coordinate, now: DateTime<Utc>) -> Option<BoostEvent> {
+    active_events(now).into_iter()
+        .filter(|event| event.bounds.contains(point.lat, point.lon))
+        .max_by(|a, b| a.multiplier.total_cmp(&b.multiplier))
+}
+
+// Active events whose area the user's registration at a point entered (the user's previous
+// registration was outside it)
+pub fn entered_events(user_id: &str, point: Coordinate, now: DateTime<Utc>) -> Vec<BoostEvent> {
+    let inside: Vec<BoostEvent> = active_events(now).into_iter()
+        .filter(|event| event.bounds.contains(point.lat, point.lon))
+        .collect();
+    let mut users_inside = USERS_INSIDE.lock().unwrap();
+    let previous = users_inside.insert(user_id.to_string(), inside.iter().map(|event| event.id.clone()).collect());
+    inside.into_iter()
+        .filter(|event| !previous.as_ref().is_some_and(|previous| previous.contains(&event.id)))
+        .collect()
+}
+
+fn validate_event(id: String, request: &BoostEventRequest) -> Result<BoostEvent, String> {
+    request.bounds.validate()?;
+    let (start, end) = (request.start, request.end);
+    if start >= end {
+        return Err("start must be before end".to_string());
+    }
+    if !(request.multiplier > 1.0 && request.multiplier <= MAX_BOOST_MULTIPLIER) {
+        return Err(format!("multiplier must be above 1 and at most {}", MAX_BOOST_MULTIPLIER));
+    }
+    if end <= Utc::now() {
+        return Err("The event has already ended".to_string());
+    }
+
+    Ok(BoostEvent {
+        id,
+        name: request.name.clone(),
+        bounds: request.bounds,
+        start,
+        end,
+        multiplier: request.multiplier,
+    })
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use chrono::{DateTime, NaiveDate, Utc};
+use once_cell::sync::Lazy;
+use crate::models::{LedgerEntry, LedgerEntryKind, RewardBalance, RewardHistoryResponse, WebhookEventKind};
+use crate::webhooks;
+
+// Page size of the reward history when none (or too large a one) is requested
+pub const DEFAULT_HISTORY_PAGE: usize = 50;
+pub const MAX_HISTORY_PAGE: usize = 200;
+
+// Balances and entries of every user, capped at the default daily limit until the API server
+// configures the reward engine
+pub static REWARD_LEDGER: Lazy<Mutex<RewardLedger>> = Lazy::new(|| Mutex::new(RewardLedger {
+    daily_cap: Some(super::DEFAULT_DAILY_CAP),
+    ..RewardLedger::default()
+}));
+
+// Append-only record of the points each user earned and spent
+#[derive(Default)]
+pub struct RewardLedger {
+    accounts: HashMap<String, Account>,
+    next_id: u64,
+    // Most points a user may earn per (UTC) day; no limit when None
+    daily_cap: Option<u64>,
+}
+
+#[derive(Default)]
+struct Account {
+    earned: u64,
+    spent: u64,
+    // Oldest first
+    entries: Vec<LedgerEntry>,
+    // Points earned on `earned_day`, counted against the daily cap
+    earned_day: Option<NaiveDate>,
+    earned_today: u64,
+}
+
+impl Account {
+    fn earned_on(&self, day: NaiveDate) -> u64 {
+        if self.earned_day == Some(day) { self.earned_today } else { 0 }
+    }
+}
+
+impl RewardLedger {
+    pub fn set_daily_cap(&mut self, daily_cap: Option<u64>) {
+        self.daily_cap = daily_cap;
+    }
+
+    // Users with at least one ledger entry
+    pub fn account_count(&self) -> usize {
+        self.accounts.len()
+    }
+
+    // Add earned points to a user's balance, up to what is left of the daily cap; returns
+    // None when nothing could be credited
+    pub fn credit(&mut self, user_id: &str, points: u64, reason: &str) -> Option<LedgerEntry> {
+        let today = Utc::now().date_naive();
+        let remaining = self.daily_remaining(user_id);
+        let credited = remaining.map_or(points, |remaining| points.min(remaining));
+        if credited == 0 {
+            return None;
+        }
+
+        let account = self.accounts.entry(user_id.to_string()).or_default();
+        account.earned_today = account.earned_on(today) + credited;
+        account.earned_day = Some(today);
+        account.earned += credited;
+        let balance_after = account.earned - account.spent;
+        let reason = if credited < points {
+            format!("{} (capped at the daily limit)", reason)
+        } else {
+            reason.to_string()
+        };
+        let entry = self.append(user_id, LedgerEntryKind::Earned, credited, &reason, balance_after);
+        webhooks::emit(WebhookEventKind::RewardGranted, user_id, serde_json::to_value(&entry).unwrap_or_default());
+        Some(entry)
+    }
+
+    // Points the user may still earn today; None without a daily cap
+    pub fn daily_remaining(&self, user_id: &str) -> Option<u64> {
+        let earned_today = self.accounts.get(user_id)
+            .map(|account| account.earned_on(Utc::now().date_naive()))
+            .unwrap_or(0);
+        self.daily_cap.map(|cap| cap.saturating_sub(earned_today))
+    }
+
+    // Spend points, refusing to take the balance below zero
+    pub fn debit(&mut self, user_id: &str, points: u64, reason: &str) -> Result<LedgerEntry, String> {
+        let account = self.accounts.entry(user_id.to_string()).or_default();
+        let balance = account.earned - account.spent;
+        if points > balance {
+            return Err(format!("Insufficient balance: {} points available, {} requested", balance, points));
+        }
+        account.spent += points;
+        Ok(self.append(user_id, LedgerEntryKind::Spent, points, reason, balance - points))
+    }
+
+    // Return spent points to a user's balance
+    pub fn refund(&mut self, user_id: &str, points: u64, reason: &str) -> LedgerEntry {
+        let account = self.accounts.entry(user_id.to_string()).or_default();
+        account.spent = account.spent.saturating_sub(points);
+        let balance_after = account.earned - account.spent;
+        self.append(user_id, LedgerEntryKind::Refunded, points, reason, balance_after)
+    }
+
+    pub fn balance(&self, user_id: &str) -> RewardBalance {
+        let today = Utc::now().date_naive();
+        let (earned, spent, earned_today) = self.accounts.get(user_id)
+            .map(|account| (account.earned, account.spent, account.earned_on(today)))
+            .unwrap_or((0, 0, 0));
+        RewardBalance {
+            user_id: user_id.to_string(),
+            earned,
+            spent,
+            balance: earned - spent,
+            earned_today,
+            daily_remaining: self.daily_remaining(user_id),
+        }
+    }
+
+    // One page of a user's entries, newest first
+    pub fn history(&self, user_id: &str, offset: usize, limit: usize) -> RewardHistoryResponse {
+        let entries = self.accounts.get(user_id).map(|account| account.entries.as_slice()).unwrap_or(&[]);
+        let limit = limit.clamp(1, MAX_HISTORY_PAGE);
+        RewardHistoryResponse {
+            user_id: user_id.to_string(),
+            entries: entries.iter().rev().skip(offset).take(limit).cloned().collect(),
+            total: entries.len(),
+            offset,
+            limit,
+        }
+    }
+
+    // A user's entries timestamped within [start, end], oldest first
+    pub fn entries_between(&self, user_id: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Vec<LedgerEntry> {
+        let entries = self.accounts.get(user_id).map(|account| account.entries.as_slice()).unwrap_or(&[]);
+        entries.iter()
+            .filter(|entry| entry.timestamp >= start && entry.timestamp <= end)
+            .cloned()
+            .collect()
+    }
+
+    // Points each user earned from `since` on
+    pub fn earned_since(&self, since: DateTime<Utc>) -> HashMap<String, u64> {
+        self.accounts.iter()
+            .map(|(user_id, account)| {
+                let earned = account.entries.iter()
+                    .rev()
+                    .take_while(|entry| entry.timestamp >= since)
+                    .filter(|entry| entry.kind == LedgerEntryKind::Earned)
+                    .map(|entry| entry.points)
+                    .sum();
+                (user_id.clone(), earned)
+            })
+            .filter(|(_, earned)| *earned > 0)
+            .collect()
+    }
+
+    fn append(&mut self, user_id: &str, kind: LedgerEntryKind, points: u64, reason: &str, balance_after: u64) -> LedgerEntry {
+        self.next_id += 1;
+        let entry = LedgerEntry {
+            id: self.next_id,
+            kind,
+            points,
+            reason: reason.to_string(),
+            balance_after,
+            timestamp: Utc::now(),
+        };
+        self.accounts.entry(user_id.to_string()).or_default().entries.push(entry.clone());
+        entry
+    }
+}
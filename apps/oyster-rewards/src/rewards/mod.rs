@@ -0,0 +1,247 @@
+pub mod claim;
+pub mod discovery;
+pub mod events;
+pub mod ledger;
+pub mod redemption;
+pub mod streaks;
+pub mod vrf;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use crate::models::{Coordinate, GridCell, RewardDraw, RewardQuote, RewardTier};
+use crate::heatmap::HEATMAP_DATA;
+use crate::location::GRID_SIZE;
+
+// Bonus on top of a tier's points, as a fraction of them, drawn uniformly per reward
+pub const DEFAULT_BONUS_FRACTION: f64 = 0.2;
+
+// Points for being the first to register a verified location in a cell
+pub const DEFAULT_EXPLORER_BONUS: u64 = 250;
+
+// Points a user may earn per day unless configured otherwise
+pub const DEFAULT_DAILY_CAP: u64 = 2000;
+
+// Shared engine; default tier curve with a fresh VRF key unless the API server configures one
+pub static REWARD_ENGINE: Lazy<Mutex<RewardEngine>> = Lazy::new(|| Mutex::new(RewardEngine::new(RewardConfig::default(), None)));
+
+// One step of the tier curve: cells with at most `max_cell_visits` recorded visits
+// (no bound on the last step) pay out this tier
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TierRule {
+    pub tier: RewardTier,
+    pub max_cell_visits: Option<u32>,
+    pub points: u64,
+}
+
+// One step of the streak curve: from `min_days` consecutive active days on, rewards are multiplied
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct StreakMultiplier {
+    pub min_days: u32,
+    pub multiplier: f64,
+}
+
+// Tier curve from the rarest cells to the busiest, the random bonus range, the streak curve
+// and the bonus for discovering a cell
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RewardConfig {
+    pub tiers: Vec<TierRule>,
+    #[serde(default = "default_bonus_fraction")]
+    pub bonus_fraction: f64,
+    #[serde(default = "default_streak_multipliers")]
+    pub streak_multipliers: Vec<StreakMultiplier>,
+    #[serde(default = "default_explorer_bonus")]
+    pub explorer_bonus: u64,
+    // Most points a user may earn per (UTC) day, bounding what undetected spoofing can gain
+    #[serde(default = "default_daily_cap")]
+    pub daily_cap: Option<u64>,
+}
+
+fn default_bonus_fraction() -> f64 {
+    DEFAULT_BONUS_FRACTION
+}
+
+fn default_explorer_bonus() -> u64 {
+    DEFAULT_EXPLORER_BONUS
+}
+
+fn default_daily_cap() -> Option<u64> {
+    Some(DEFAULT_DAILY_CAP)
+}
+
+fn default_streak_multipliers() -> Vec<StreakMultiplier> {
+    [(3, 1.25), (7, 1.5), (14, 1.75), (30, 2.0)].iter()
+        .map(|&(min_days, multiplier)| StreakMultiplier { min_days, multiplier })
+        .collect()
+}
+
+impl Default for RewardConfig {
+    fn default() -> Self {
+        let rule = |tier, max_cell_visits, points| TierRule { tier, max_cell_visits, points };
+        RewardConfig {
+            tiers: vec![
+                rule(RewardTier::Legendary, Some(0), 500),
+                rule(RewardTier::Epic, Some(5), 200),
+                rule(RewardTier::Rare, Some(25), 100),
+                rule(RewardTier::Uncommon, Some(100), 40),
+                rule(RewardTier::Common, None, 10),
+            ],
+            bonus_fraction: DEFAULT_BONUS_FRACTION,
+            streak_multipliers: default_streak_multipliers(),
+            explorer_bonus: DEFAULT_EXPLORER_BONUS,
+            daily_cap: default_daily_cap(),
+        }
+    }
+}
+
+impl RewardConfig {
+    // Load a tier curve from a JSON file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, String> {
+        let json = fs::read(path.as_ref()).map_err(|e| format!("Failed to read reward tiers: {}", e))?;
+        let config: RewardConfig = serde_json::from_slice(&json).map_err(|e| format!("Invalid reward tiers: {}", e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Steps must be ordered by strictly increasing visit bound, with only the last one unbounded
+    pub fn validate(&self) -> Result<(), String> {
+        let (last, bounded) = match self.tiers.split_last() {
+            Some(split) => split,
+            None => return Err("The tier curve needs at least one tier".to_string()),
+        };
+        if last.max_cell_visits.is_some() {
+            return Err("The last tier must not have a max_cell_visits bound".to_string());
+        }
+        let mut previous: Option<u32> = None;
+        for rule in bounded {
+            match rule.max_cell_visits {
+                Some(bound) if previous.is_none_or(|previous| bound > previous) => previous = Some(bound),
+                Some(_) => return Err("max_cell_visits must increase from one tier to the next".to_string()),
+                None => return Err("Only the last tier may omit max_cell_visits".to_string()),
+            }
+        }
+        if !(0.0..=10.0).contains(&self.bonus_fraction) {
+            return Err("bonus_fraction must be between 0 and 10".to_string());
+        }
+        for pair in self.streak_multipliers.windows(2) {
+            if pair[1].min_days <= pair[0].min_days {
+                return Err("streak_multipliers must be ordered by increasing min_days".to_string());
+            }
+        }
+        if self.streak_multipliers.iter().any(|step| !(1.0..=10.0).contains(&step.multiplier)) {
+            return Err("Streak multipliers must be between 1 and 10".to_string());
+        }
+        Ok(())
+    }
+
+    // Multiplier earned by a streak of this many days (1 below the first step)
+    pub fn streak_multiplier(&self, streak_days: u32) -> f64 {
+        self.streak_multipliers.iter()
+            .rev()
+            .find(|step| streak_days >= step.min_days)
+            .map(|step| step.multiplier)
+            .unwrap_or(1.0)
+    }
+
+    // The step a cell with this many visits falls on
+    pub fn tier_for(&self, cell_visits: u32) -> &TierRule {
+        self.tiers.iter()
+            .find(|rule| rule.max_cell_visits.is_none_or(|bound| cell_visits <= bound))
+            .unwrap_or(&self.tiers[self.tiers.len() - 1])
+    }
+}
+
+// Prices rewards from how rarely a cell is visited; the tier is fixed by the cell's density,
+// only the bonus is random. Each bonus is drawn from a VRF over (user_id, nonce) so users can
+// verify the draw, and a secret seed fixes the VRF key to make the whole sequence reproducible
+pub struct RewardEngine {
+    config: RewardConfig,
+    vrf: vrf::RewardVrf,
+    // Next draw nonce of each user; a nonce is never reused, so nobody can redraw a bonus
+    nonces: HashMap<String, u64>,
+}
+
+impl RewardEngine {
+    pub fn new(config: RewardConfig, seed: Option<[u8; 32]>) -> Self {
+        RewardEngine {
+            config,
+            vrf: vrf::RewardVrf::new(seed),
+            nonces: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &RewardConfig {
+        &self.config
+    }
+
+    // Swap the tier curve, keeping the VRF key and draw nonces so earlier draws stay verifiable
+    pub fn set_config(&mut self, config: RewardConfig) {
+        self.config = config;
+    }
+
+    // Public key verifying the engine's draws
+    pub fn vrf_public_key(&self) -> String {
+        self.vrf.public_key()
+    }
+
+    // Tier and base points of a cell that has seen `cell_visits` visits, without any bonus
+    pub fn quote(&self, cell_visits: u32) -> (RewardTier, u64) {
+        let rule = self.config.tier_for(cell_visits);
+        (rule.tier, rule.points)
+    }
+
+    // Reward paid to a user for a cell, drawing their bonus. Only claims price rewards: every
+    // call uses up one of the user's draws
+    pub fn price(&mut self, cell_visits: u32, user_id: &str) -> (RewardTier, u64, Option<RewardDraw>) {
+        let (tier, points) = self.quote(cell_visits);
+        let bonus_fraction = self.config.bonus_fraction;
+        if bonus_fraction <= 0.0 {
+            return (tier, points, None);
+        }
+
+        let nonce = self.nonces.entry(user_id.to_string()).or_insert(0);
+        let draw = self.vrf.draw(user_id, *nonce);
+        *nonce += 1;
+        let bonus = (points as f64 * draw.roll * bonus_fraction).round() as u64;
+        (tier, points + bonus, Some(draw))
+    }
+}
+
+// Points after applying a streak multiplier
+pub fn apply_multiplier(points: u64, multiplier: f64) -> u64 {
+    (points as f64 * multiplier).round() as u64
+}
+
+// Replace the shared engine, and the ledger's daily cap with the one it configures
+pub fn set_reward_engine(engine: RewardEngine) {
+    ledger::REWARD_LEDGER.lock().unwrap().set_daily_cap(engine.config().daily_cap);
+    *REWARD_ENGINE.lock().unwrap() = engine;
+}
+
+// Validate and apply a new reward config to the running engine and the ledger's daily cap
+pub fn set_reward_config(config: RewardConfig) -> Result<RewardConfig, String> {
+    config.validate()?;
+    ledger::REWARD_LEDGER.lock().unwrap().set_daily_cap(config.daily_cap);
+    REWARD_ENGINE.lock().unwrap().set_config(config.clone());
+    Ok(config)
+}
+
+// Visits recorded so far in the heatmap cell of a point
+pub fn cell_visits(point: Coordinate) -> u32 {
+    let grid_cell = GridCell::from_location(point.lat, point.lon, GRID_SIZE);
+    HEATMAP_DATA.lock().unwrap().get(&grid_cell).map(|aggregate| aggregate.total).unwrap_or(0)
+}
+
+// Quote the reward for visiting a point with the shared engine: the tier's points under the
+// strongest boost event running there. No bonus is drawn; that happens only when claiming
+pub fn quote_reward(point: Coordinate) -> RewardQuote {
+    let cell_visits = cell_visits(point);
+    let (tier, points) = REWARD_ENGINE.lock().unwrap().quote(cell_visits);
+    let boost = events::boost_at(point, Utc::now());
+    let points = apply_multiplier(points, boost.as_ref().map(|event| event.multiplier).unwrap_or(1.0));
+    RewardQuote { lat: point.lat, lon: point.lon, tier, points, cell_visits, boost }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use base64::{Engine as _, engine::general_purpose};
+use chrono::{DateTime, Duration, Utc};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use schnorrkel::{signing_context, Keypair, Signature};
+use crate::models::{RedeemRequest, RedeemResponse, Redemption, RedemptionClaim, RedemptionStatus};
+use super::ledger::REWARD_LEDGER;
+
+// Redemptions left unfulfilled this long expire and their points are refunded
+pub const REDEMPTION_TTL_DAYS: i64 = 7;
+
+// Domain separation of redemption tokens; verifiers must use the same context
+pub const REDEMPTION_CONTEXT: &[u8] = b"orbitalmap-redemption";
+
+// Signs redemption tokens. Lives only in this process and is replaced on every restart, so
+// tokens issued before one stop verifying
+static REDEMPTION_KEY: Lazy<Keypair> = Lazy::new(Keypair::generate);
+
+// Every redemption by ID; the points of pending ones are held in escrow
+static REDEMPTIONS: Lazy<Mutex<HashMap<String, Redemption>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn redemption_public_key() -> String {
+    general_purpose::STANDARD.encode(REDEMPTION_KEY.public.to_bytes())
+}
+
+// Debit the points and open a pending redemption in one step, returning it with its signed token
+pub fn redeem(request: &RedeemRequest) -> Result<RedeemResponse, String> {
+    if request.points == 0 {
+        return Err("points must be positive".to_string());
+    }
+    if request.item.trim().is_empty() {
+        return Err("item must not be empty".to_string());
+    }
+
+    let now = Utc::now();
+    let mut redemptions = REDEMPTIONS.lock().unwrap();
+    expire(&mut redemptions, now);
+
+    let id: String = rand::thread_rng().gen::<[u8; 16]>().iter().map(|byte| format!("{:02x}", byte)).collect();
+    REWARD_LEDGER.lock().unwrap().debit(&request.user_id, request.points, &format!("redemption {}: {}", id, request.item))?;
+
+    let redemption = Redemption {
+        id: id.clone(),
+        user_id: request.user_id.clone(),
+        item: request.item.clone(),
+        points: request.points,
+        status: RedemptionStatus::Pending,
+        created_at: now,
+        expires_at: now + Duration::days(REDEMPTION_TTL_DAYS),
+        fulfilled_at: None,
+    };
+    redemptions.insert(id, redemption.clone());
+    let token = sign_token(&redemption);
+    Ok(RedeemResponse { redemption, token })
+}
+
+// Close a pending redemption once the item was handed over; its escrowed points are spent for good
+pub fn fulfill_redemption(id: &str) -> Result<Redemption, String> {
+    let now = Utc::now();
+    let mut redemptions = REDEMPTIONS.lock().unwrap();
+    expire(&mut redemptions, now);
+
+    let redemption = redemptions.get_mut(id).ok_or_else(|| format!("Redemption {} not found", id))?;
+    match redemption.status {
+        RedemptionStatus::Pending => {
+            redemption.status = RedemptionStatus::Fulfilled;
+            redemption.fulfilled_at = Some(now);
+            Ok(redemption.clone())
+        },
+        RedemptionStatus::Fulfilled => Err(format!("Redemption {} was already fulfilled", id)),
+        RedemptionStatus::Expired => Err(format!("Redemption {} has expired and was refunded", id)),
+    }
+}
+
+// A user's redemptions, newest first
+pub fn user_redemptions(user_id: &str) -> Vec<Redemption> {
+    let mut redemptions = REDEMPTIONS.lock().unwrap();
+    expire(&mut redemptions, Utc::now());
+
+    let mut found: Vec<Redemption> = redemptions.values()
+        .filter(|redemption| redemption.user_id == user_id)
+        .cloned()
+        .collect();
+    found.sort_by_key(|redemption| std::cmp::Reverse(redemption.created_at));
+    found
+}
+
+// Redemptions whose points are still held in escrow
+pub fn pending_redemptions() -> usize {
+    let mut redemptions = REDEMPTIONS.lock().unwrap();
+    expire(&mut redemptions, Utc::now());
+    redemptions.values().filter(|redemption| redemption.status == RedemptionStatus::Pending).count()
+}
+
+// Check a redemption token's signature, returning the claim it carries
+pub fn verify_redemption_token(token: &str) -> Result<RedemptionClaim, String> {
+    let (payload, signature) = token.split_once('.').ok_or("Malformed redemption token")?;
+    let payload = general_purpose::URL_SAFE_NO_PAD.decode(payload).map_err(|e| format!("Invalid token payload: {}", e))?;
+    let signature = general_purpose::URL_SAFE_NO_PAD.decode(signature).map_err(|e| format!("Invalid token signature: {}", e))?;
+    let signature = Signature::from_bytes(&signature).map_err(|e| format!("Invalid token signature: {}", e))?;
+
+    REDEMPTION_KEY.public.verify(signing_context(REDEMPTION_CONTEXT).bytes(&payload), &signature)
+        .map_err(|_| "The redemption token was not signed by this server's current key".to_string())?;
+    serde_json::from_slice(&payload).map_err(|e| format!("Invalid token payload: {}", e))
+}
+
+// Token format: base64url(JSON claim) "." base64url(sr25519 signature over the JSON bytes)
+fn sign_token(redemption: &Redemption) -> String {
+    let claim = RedemptionClaim {
+        redemption_id: redemption.id.clone(),
+        user_id: redemption.user_id.clone(),
+        item: redemption.item.clone(),
+        points: redemption.points,
+        expires_at: redemption.expires_at,
+    };
+    let payload = serde_json::to_vec(&claim).expect("claims serialize");
+    let signature = REDEMPTION_KEY.sign(signing_context(REDEMPTION_CONTEXT).bytes(&payload));
+    format!(
+        "{}.{}",
+        general_purpose::URL_SAFE_NO_PAD.encode(&payload),
+        general_purpose::URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+    )
+}
+
+// Expire pending redemptions past their deadline, refunding the escrowed points
+fn expire(redemptions: &mut HashMap<String, Redemption>, now: DateTime<Utc>) {
+    for redemption in redemptions.values_mut() {
+        if redemption.status == RedemptionStatus::Pending && redemption.expires_at <= now {
+            redemption.status = RedemptionStatus::Expired;
+            REWARD_LEDGER.lock().unwrap().refund(&redemption.user_id, redemption.points, &format!("refund: redemption {} expired", redemption.id));
+        }
+    }
+}
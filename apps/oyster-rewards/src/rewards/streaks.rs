@@ -0,0 +1,43 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use chrono::{Duration, NaiveDate};
+use once_cell::sync::Lazy;
+use crate::models::StreakStatus;
+use super::REWARD_ENGINE;
+
+// Streak of every user, advanced as their locations are registered
+static STREAKS: Lazy<Mutex<HashMap<String, Streak>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct Streak {
+    last_active: NaiveDate,
+    current_days: u32,
+    longest_days: u32,
+}
+
+// Count the user as active on `today`, extending the streak if they were active yesterday
+pub fn record_activity(user_id: &str, today: NaiveDate) -> StreakStatus {
+    let mut streaks = STREAKS.lock().unwrap();
+    let streak = streaks.entry(user_id.to_string()).or_insert(Streak {
+        last_active: today,
+        current_days: 1,
+        longest_days: 1,
+    });
+
+    if streak.last_active == today - Duration::days(1) {
+        streak.current_days += 1;
+    } else if streak.last_active != today {
+        streak.current_days = 1;
+    }
+    streak.last_active = today;
+    streak.longest_days = streak.longest_days.max(streak.current_days);
+
+    status(streak.current_days, streak.longest_days)
+}
+
+fn status(current_days: u32, longest_days: u32) -> StreakStatus {
+    StreakStatus {
+        current_days,
+        longest_days,
+        multiplier: REWARD_ENGINE.lock().unwrap().config().streak_multiplier(current_days),
+    }
+}
@@ -0,0 +1,92 @@
+use base64::{Engine as _, engine::general_purpose};
+use rand::Rng;
+use schnorrkel::vrf::{VRFPreOut, VRFProof};
+use schnorrkel::{signing_context, ExpansionMode, Keypair, MiniSecretKey, PublicKey};
+use crate::models::RewardDraw;
+
+// Domain separation of reward draws; verifiers must use the same context
+pub const VRF_CONTEXT: &[u8] = b"orbitalmap-reward-draw";
+// Label the draw's randomness is extracted under
+const VRF_OUTPUT_LABEL: &[u8] = b"reward-roll";
+
+// sr25519 VRF key held in this process's memory only. Without a seed a new one is made on
+// every start, so draws from before a restart verify only against the old public key
+pub struct RewardVrf {
+    keypair: Keypair,
+}
+
+impl RewardVrf {
+    // A fresh key, or the one a secret seed makes so draws are reproducible
+    pub fn new(seed: Option<[u8; 32]>) -> Self {
+        let secret = seed.unwrap_or_else(|| rand::thread_rng().gen());
+        let keypair = MiniSecretKey::from_bytes(&secret)
+            .expect("32 bytes are a valid mini secret key")
+            .expand_to_keypair(ExpansionMode::Ed25519);
+        RewardVrf { keypair }
+    }
+
+    pub fn public_key(&self) -> String {
+        general_purpose::STANDARD.encode(self.keypair.public.to_bytes())
+    }
+
+    // Evaluate the VRF over (user_id, nonce), returning a roll in [0, 1) with its proof
+    pub fn draw(&self, user_id: &str, nonce: u64) -> RewardDraw {
+        let (inout, proof, _) = self.keypair.vrf_sign(signing_context(VRF_CONTEXT).bytes(&vrf_input(user_id, nonce)));
+        RewardDraw {
+            user_id: user_id.to_string(),
+            nonce,
+            roll: roll(inout.make_bytes::<[u8; 8]>(VRF_OUTPUT_LABEL)),
+            public_key: self.public_key(),
+            output: general_purpose::STANDARD.encode(inout.to_preout().to_bytes()),
+            proof: general_purpose::STANDARD.encode(proof.to_bytes()),
+        }
+    }
+}
+
+// A VRF seed given as 64 hex digits, e.g. from `openssl rand -hex 32`; it is the secret key
+// itself, so it has to be as hard to guess as one
+pub fn parse_seed(seed: &str) -> Result<[u8; 32], String> {
+    let seed = seed.trim();
+    if seed.len() != 64 || !seed.is_ascii() {
+        return Err("expected 64 hex digits (32 bytes)".to_string());
+    }
+    let mut secret = [0u8; 32];
+    for (byte, digits) in secret.iter_mut().zip(seed.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).map_err(|e| e.to_string())?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| "expected 64 hex digits (32 bytes)".to_string())?;
+    }
+    Ok(secret)
+}
+
+// Check a draw against its public key, returning the roll it proves
+pub fn verify_draw(draw: &RewardDraw) -> Result<f64, String> {
+    let decode = |field: &str, value: &str| general_purpose::STANDARD.decode(value)
+        .map_err(|e| format!("Invalid {}: {}", field, e));
+    let public_key = PublicKey::from_bytes(&decode("public_key", &draw.public_key)?)
+        .map_err(|e| format!("Invalid public_key: {}", e))?;
+    let output = VRFPreOut::from_bytes(&decode("output", &draw.output)?)
+        .map_err(|e| format!("Invalid output: {}", e))?;
+    let proof = VRFProof::from_bytes(&decode("proof", &draw.proof)?)
+        .map_err(|e| format!("Invalid proof: {}", e))?;
+
+    let (inout, _) = public_key
+        .vrf_verify(signing_context(VRF_CONTEXT).bytes(&vrf_input(&draw.user_id, draw.nonce)), &output, &proof)
+        .map_err(|_| "The VRF proof does not match the public key, user and nonce".to_string())?;
+    let roll = roll(inout.make_bytes::<[u8; 8]>(VRF_OUTPUT_LABEL));
+    if roll != draw.roll {
+        return Err("The roll does not match the VRF output".to_string());
+    }
+    Ok(roll)
+}
+
+// The nonce comes first as a fixed-width prefix, so no two (user_id, nonce) pairs share an input
+fn vrf_input(user_id: &str, nonce: u64) -> Vec<u8> {
+    let mut input = nonce.to_be_bytes().to_vec();
+    input.extend_from_slice(user_id.as_bytes());
+    input
+}
+
+// Top 53 bits of the output as a uniform float in [0, 1)
+fn roll(bytes: [u8; 8]) -> f64 {
+    (u64::from_be_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+use crate::models::HeatmapSnapshot;
+
+// Storage backend for data that has to outlive the in-memory state
+pub trait Store: Send {
+    fn save_heatmap_snapshot(&mut self, snapshot: &HeatmapSnapshot) -> Result<(), String>;
+    fn load_heatmap_snapshot(&self, date: &str) -> Result<Option<HeatmapSnapshot>, String>;
+}
+
+// Active store; in memory unless the API server configures another backend
+pub static STORE: Lazy<Mutex<Box<dyn Store>>> = Lazy::new(|| Mutex::new(Box::new(MemoryStore::default())));
+
+// Replace the active store
+pub fn set_store(store: Box<dyn Store>) {
+    *STORE.lock().unwrap() = store;
+}
+
+// Store kept in process memory (lost on restart)
+#[derive(Default)]
+pub struct MemoryStore {
+    snapshots: HashMap<String, HeatmapSnapshot>,
+}
+
+impl Store for MemoryStore {
+    fn save_heatmap_snapshot(&mut self, snapshot: &HeatmapSnapshot) -> Result<(), String> {
+        self.snapshots.insert(snapshot.date.clone(), snapshot.clone());
+        Ok(())
+    }
+
+    fn load_heatmap_snapshot(&self, date: &str) -> Result<Option<HeatmapSnapshot>, String> {
+        Ok(self.snapshots.get(date).cloned())
+    }
+}
+
+// Store writing one JSON file per record under a directory
+pub struct FileStore {
+    root: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self, String> {
+        let root = root.into();
+        fs::create_dir_all(root.join("heatmap"))
+            .map_err(|e| format!("Failed to create store directory: {}", e))?;
+        Ok(FileStore { root })
+    }
+
+    fn snapshot_path(&self, date: &str) -> PathBuf {
+        self.root.join("heatmap").join(format!("{}.json", date))
+    }
+}
+
+impl Store for FileStore {
+    fn save_heatmap_snapshot(&mut self, snapshot: &HeatmapSnapshot) -> Result<(), String> {
+        let json = serde_json::to_vec(snapshot)
+            .map_err(|e| format!("Serialization error: {}", e))?;
+
+        // Write to a temporary file first so a crash never leaves a truncated snapshot
+        let path = self.snapshot_path(&snapshot.date);
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, json).map_err(|e| format!("Failed to write snapshot: {}", e))?;
+        fs::rename(&temp_path, &path).map_err(|e| format!("Failed to write snapshot: {}", e))
+    }
+
+    fn load_heatmap_snapshot(&self, date: &str) -> Result<Option<HeatmapSnapshot>, String> {
+        let path = self.snapshot_path(date);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let json = fs::read(&path).map_err(|e| format!("Failed to read snapshot: {}", e))?;
+        serde_json::from_slice(&json)
+            .map(Some)
+            .map_err(|e| format!("Corrupt snapshot {}: {}", date, e))
+    }
+}
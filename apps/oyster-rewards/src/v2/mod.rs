@@ -0,0 +1,326 @@
+use std::fmt;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::models::{
+    self, ApiResponse, BoostEvent, BoundingBox, DiscoveryBadge, FraudAssessment, HeatmapCell, HeatmapResponse,
+    Latitude, LedgerEntryKind, LocationRegistrationResponse, Longitude, QuestStatus, RewardHistoryResponse, SensorData, StreakStatus,
+    UserLocationsResponse
+};
+use crate::heatmap::privacy::BUDGET_EXHAUSTED_ERROR;
+
+// Models of the v2 API: typed timestamps, trust scores, cursor pages and typed errors.
+// v1 keeps its models; its handlers for routes that have a v2 successor share the same
+// code paths and convert with the From impls here.
+
+// When v1 routes with a v2 successor were deprecated (2026-10-16T00:00:00Z), in Unix seconds
+pub const V1_DEPRECATED_AT: i64 = 1_792_108_800;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    // The location's sensor data did not check out
+    VerificationFailed,
+    PrivacyBudgetExhausted,
+    Internal,
+}
+
+impl ErrorCode {
+    // HTTP status the error is returned with
+    pub fn status(self) -> u16 {
+        match self {
+            ErrorCode::InvalidRequest => 400,
+            ErrorCode::Unauthorized => 401,
+            ErrorCode::Forbidden => 403,
+            ErrorCode::NotFound => 404,
+            ErrorCode::VerificationFailed => 422,
+            ErrorCode::PrivacyBudgetExhausted => 429,
+            ErrorCode::Internal => 500,
+        }
+    }
+}
+
+// Error body of every v2 endpoint
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ApiError { code, message: message.into() }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[cfg(feature = "server")]
+impl actix_web::ResponseError for ApiError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        actix_web::http::StatusCode::from_u16(self.code.status())
+            .unwrap_or(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse {
+        actix_web::HttpResponse::build(self.status_code()).json(self)
+    }
+}
+
+// The v1 error body
+impl From<ApiError> for ApiResponse {
+    fn from(error: ApiError) -> Self {
+        ApiResponse {
+            success: false,
+            message: error.message,
+        }
+    }
+}
+
+// A page of results; pass `next_cursor` back as `cursor` for the next one
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    // None on the last page
+    pub next_cursor: Option<String>,
+    pub limit: usize,
+}
+
+// A location to register
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Location {
+    pub lat: Latitude,
+    pub lon: Longitude,
+    pub timestamp: DateTime<Utc>,
+    pub user_id: String,
+    pub device_id: String,
+    pub sensors: SensorData,
+    #[serde(default)]
+    pub floor_level: Option<i32>,
+}
+
+impl From<Location> for models::Location {
+    fn from(location: Location) -> Self {
+        models::Location {
+            lat: location.lat,
+            lon: location.lon,
+            timestamp: location.timestamp,
+            user_id: location.user_id,
+            device_id: location.device_id,
+            sensors: location.sensors,
+            floor_level: location.floor_level,
+        }
+    }
+}
+
+// A registered location and what it earned
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LocationRegistration {
+    // Encrypted ID the location is looked up by
+    pub id: String,
+    // How much the registration is trusted, between 0 and 1: its sensor data checked out,
+    // so this is what is left after the Sybil risk, and the factor its rewards were weighted by
+    pub trust_score: f64,
+    pub fraud: FraudAssessment,
+    pub completed_quests: Vec<QuestStatus>,
+    pub streak: StreakStatus,
+    pub discovery: Option<DiscoveryBadge>,
+    pub boost: Option<BoostEvent>,
+    pub daily_remaining: Option<u64>,
+}
+
+impl TryFrom<LocationRegistrationResponse> for LocationRegistration {
+    type Error = ApiError;
+
+    fn try_from(response: LocationRegistrationResponse) -> Result<Self, ApiError> {
+        let (true, Some(fraud), Some(streak)) = (response.success, response.fraud, response.streak) else {
+            // A registration is either rejected for its sensor data or failed to be stored
+            let code = if response.message.starts_with("Location verification failed") {
+                ErrorCode::VerificationFailed
+            } else {
+                ErrorCode::Internal
+            };
+            return Err(ApiError::new(code, response.message));
+        };
+
+        Ok(LocationRegistration {
+            id: response.encrypted_location_id,
+            trust_score: fraud.reward_weight,
+            fraud,
+            completed_quests: response.completed_quests,
+            streak,
+            discovery: response.discovery,
+            boost: response.boost,
+            daily_remaining: response.daily_remaining,
+        })
+    }
+}
+
+// The v1 registration response
+impl From<LocationRegistration> for LocationRegistrationResponse {
+    fn from(registration: LocationRegistration) -> Self {
+        LocationRegistrationResponse {
+            encrypted_location_id: registration.id,
+            success: true,
+            message: "Location registered successfully.".to_string(),
+            completed_quests: registration.completed_quests,
+            streak: Some(registration.streak),
+            discovery: registration.discovery,
+            fraud: Some(registration.fraud),
+            boost: registration.boost,
+            daily_remaining: registration.daily_remaining,
+        }
+    }
+}
+
+// The v1 registration response of a failed registration
+impl From<ApiError> for LocationRegistrationResponse {
+    fn from(error: ApiError) -> Self {
+        LocationRegistrationResponse {
+            encrypted_location_id: String::new(),
+            success: false,
+            message: error.message,
+            completed_quests: Vec::new(),
+            streak: None,
+            discovery: None,
+            fraud: None,
+            boost: None,
+            daily_remaining: None,
+        }
+    }
+}
+
+// A stored location of the user
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StoredLocation {
+    pub id: String,
+    pub lat: Latitude,
+    pub lon: Longitude,
+    pub timestamp: DateTime<Utc>,
+    pub imported: bool,
+}
+
+impl From<UserLocationsResponse> for Page<StoredLocation> {
+    fn from(page: UserLocationsResponse) -> Self {
+        Page {
+            items: page.locations.into_iter()
+                .map(|location| StoredLocation {
+                    id: location.id,
+                    lat: location.lat,
+                    lon: location.lon,
+                    timestamp: location.timestamp,
+                    imported: location.imported,
+                })
+                .collect(),
+            next_cursor: page.next_cursor,
+            limit: page.limit,
+        }
+    }
+}
+
+// A heatmap with its privacy accounting kept apart from the data
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Heatmap {
+    pub bounds: BoundingBox,
+    pub cells: Vec<HeatmapCell>,
+    pub frames: Vec<HeatmapFrame>,
+    pub privacy: PrivacyAccounting,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HeatmapFrame {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub cells: Vec<HeatmapCell>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PrivacyAccounting {
+    pub privacy_level: f64,
+    pub epsilon_spent: f64,
+    pub budget_remaining: Option<f64>,
+}
+
+impl TryFrom<HeatmapResponse> for Heatmap {
+    type Error = ApiError;
+
+    fn try_from(response: HeatmapResponse) -> Result<Self, ApiError> {
+        if let Some(error) = response.error {
+            let code = if error.starts_with(BUDGET_EXHAUSTED_ERROR) {
+                ErrorCode::PrivacyBudgetExhausted
+            } else {
+                ErrorCode::InvalidRequest
+            };
+            return Err(ApiError::new(code, error));
+        }
+
+        let frames = response.frames.into_iter()
+            .map(|frame| HeatmapFrame {
+                start: frame.start_time,
+                end: frame.end_time,
+                cells: frame.cells,
+            })
+            .collect();
+
+        Ok(Heatmap {
+            bounds: BoundingBox {
+                min_lat: response.min_lat,
+                min_lon: response.min_lon,
+                max_lat: response.max_lat,
+                max_lon: response.max_lon,
+            },
+            cells: response.cells,
+            frames,
+            privacy: PrivacyAccounting {
+                privacy_level: response.privacy_level,
+                epsilon_spent: response.epsilon_spent,
+                budget_remaining: response.privacy_budget_remaining,
+            },
+        })
+    }
+}
+
+// One change to the user's reward balance
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LedgerEntry {
+    pub id: u64,
+    pub kind: LedgerEntryKind,
+    pub points: u64,
+    pub reason: String,
+    pub balance_after: u64,
+    pub timestamp: DateTime<Utc>,
+}
+
+// Ledger pages are offsets into the newest-first history; the cursor is the next offset
+impl From<RewardHistoryResponse> for Page<LedgerEntry> {
+    fn from(history: RewardHistoryResponse) -> Self {
+        let end = history.offset + history.entries.len();
+        Page {
+            items: history.entries.into_iter()
+                .map(|entry| LedgerEntry {
+                    id: entry.id,
+                    kind: entry.kind,
+                    points: entry.points,
+                    reason: entry.reason,
+                    balance_after: entry.balance_after,
+                    timestamp: entry.timestamp,
+                })
+                .collect(),
+            next_cursor: (end < history.total).then(|| end.to_string()),
+            limit: history.limit,
+        }
+    }
+}
+
+// Parse a cursor handed out as an offset
+pub fn offset_cursor(cursor: Option<&str>) -> Result<usize, ApiError> {
+    cursor.map_or(Ok(0), |cursor| cursor.parse::<usize>()
+        .map_err(|_| ApiError::new(ErrorCode::InvalidRequest, "Invalid cursor")))
+}
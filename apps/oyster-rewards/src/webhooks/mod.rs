@@ -0,0 +1,272 @@
+use std::sync::Mutex;
+#[cfg(feature = "server")]
+use std::net::{IpAddr, SocketAddr};
+#[cfg(feature = "server")]
+use std::time::Duration;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use sha2::Sha256;
+use tokio::sync::broadcast;
+#[cfg(feature = "server")]
+use tokio::sync::broadcast::error::RecvError;
+use crate::models::{WebhookEndpoint, WebhookEvent, WebhookEventKind};
+#[cfg(feature = "server")]
+use crate::models::{WebhookEndpointRequest, WebhookRegistration};
+
+// Events buffered for the dispatcher; older ones are dropped if it falls this far behind
+const WEBHOOK_CHANNEL_CAPACITY: usize = 4096;
+
+// A delivery is retried with exponential backoff (1s, 2s, 4s, ...) until it succeeds or runs out
+pub const MAX_DELIVERY_ATTEMPTS: u32 = 5;
+#[cfg(feature = "server")]
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+#[cfg(feature = "server")]
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Callback URLs one user can register for their own events
+pub const MAX_USER_ENDPOINTS: usize = 5;
+
+// Header carrying `t=<unix seconds>,v1=<hex HMAC-SHA256 of "<t>.<body>">`
+pub const SIGNATURE_HEADER: &str = "X-Orbitalmap-Signature";
+
+// Events waiting to be delivered
+static WEBHOOK_EVENTS: Lazy<broadcast::Sender<WebhookEvent>> = Lazy::new(|| broadcast::channel(WEBHOOK_CHANNEL_CAPACITY).0);
+
+// Registered endpoints with their signing secrets
+static WEBHOOK_ENDPOINTS: Lazy<Mutex<Vec<(WebhookEndpoint, String)>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+// Queue an event for every endpoint subscribed to its kind
+pub fn emit(kind: WebhookEventKind, user_id: &str, data: serde_json::Value) {
+    let event = WebhookEvent {
+        id: random_id(),
+        kind,
+        user_id: user_id.to_string(),
+        created_at: Utc::now(),
+        data,
+    };
+    // Sending only fails when no dispatcher is running
+    let _ = WEBHOOK_EVENTS.send(event);
+}
+
+// Events emitted and not yet taken by the dispatcher
+pub fn queue_depth() -> usize {
+    WEBHOOK_EVENTS.len()
+}
+
+// Register an endpoint, receiving only the events of `user_id` when given;
+// the returned secret is shown only once
+#[cfg(feature = "server")]
+pub async fn register_endpoint(request: &WebhookEndpointRequest, user_id: Option<&str>) -> Result<WebhookRegistration, String> {
+    let url = reqwest::Url::parse(&request.url).map_err(|e| format!("Invalid webhook URL: {}", e))?;
+    match user_id {
+        // Users may only call out to the public internet, never to the server's own network
+        Some(_) => {
+            if url.scheme() != "https" {
+                return Err("Webhook URLs must use https".to_string());
+            }
+            resolve_public(&url).await?;
+        },
+        // Admins may also use plain HTTP for local development receivers
+        None => {
+            let loopback = matches!(url.host_str(), Some("localhost") | Some("127.0.0.1") | Some("[::1]"));
+            if url.scheme() != "https" && !(url.scheme() == "http" && loopback) {
+                return Err("Webhook URLs must use https".to_string());
+            }
+        },
+    }
+
+    let endpoint = WebhookEndpoint {
+        id: random_id(),
+        url: url.to_string(),
+        events: request.events.clone(),
+        created_at: Utc::now(),
+        user_id: user_id.map(str::to_string),
+    };
+    let secret = random_id() + &random_id();
+    let mut endpoints = WEBHOOK_ENDPOINTS.lock().unwrap();
+    if let Some(user_id) = user_id {
+        let owned = endpoints.iter().filter(|(endpoint, _)| endpoint.user_id.as_deref() == Some(user_id)).count();
+        if owned >= MAX_USER_ENDPOINTS {
+            return Err(format!("Users can register at most {} webhooks", MAX_USER_ENDPOINTS));
+        }
+    }
+    endpoints.push((endpoint.clone(), secret.clone()));
+    Ok(WebhookRegistration { endpoint, secret })
+}
+
+pub fn list_endpoints() -> Vec<WebhookEndpoint> {
+    WEBHOOK_ENDPOINTS.lock().unwrap().iter().map(|(endpoint, _)| endpoint.clone()).collect()
+}
+
+// Endpoints a user registered for their own events
+pub fn list_user_endpoints(user_id: &str) -> Vec<WebhookEndpoint> {
+    WEBHOOK_ENDPOINTS.lock().unwrap().iter()
+        .filter(|(endpoint, _)| endpoint.user_id.as_deref() == Some(user_id))
+        .map(|(endpoint, _)| endpoint.clone())
+        .collect()
+}
+
+// Delete any endpoint, or with `user_id` only one that user registered
+pub fn delete_endpoint(id: &str, user_id: Option<&str>) -> Result<(), String> {
+    let mut endpoints = WEBHOOK_ENDPOINTS.lock().unwrap();
+    let count = endpoints.len();
+    endpoints.retain(|(endpoint, _)| {
+        let owned = user_id.is_none_or(|user_id| endpoint.user_id.as_deref() == Some(user_id));
+        !(endpoint.id == id && owned)
+    });
+    if endpoints.len() == count {
+        return Err(format!("Webhook {} not found", id));
+    }
+    Ok(())
+}
+
+// Deliver queued events until the process exits; each delivery runs on its own task so a slow
+// endpoint does not hold up the others
+#[cfg(feature = "server")]
+pub async fn run_dispatcher() {
+    let mut receiver = WEBHOOK_EVENTS.subscribe();
+    let client = reqwest::Client::builder()
+        .timeout(DELIVERY_TIMEOUT)
+        .build()
+        .expect("the webhook HTTP client builds");
+
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                log::warn!("Webhook dispatcher fell behind; {} event(s) were dropped", skipped);
+                continue;
+            },
+            Err(RecvError::Closed) => return,
+        };
+
+        let body = serde_json::to_string(&event).expect("webhook events serialize");
+        let targets: Vec<(WebhookEndpoint, String)> = WEBHOOK_ENDPOINTS.lock().unwrap().iter()
+            .filter(|(endpoint, _)| endpoint.events.is_empty() || endpoint.events.contains(&event.kind))
+            .filter(|(endpoint, _)| endpoint.user_id.as_ref().is_none_or(|user_id| *user_id == event.user_id))
+            .cloned()
+            .collect();
+        for (endpoint, secret) in targets {
+            tokio::spawn(deliver(client.clone(), endpoint, secret, event.id.clone(), event.kind, body.clone()));
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+async fn deliver(client: reqwest::Client, endpoint: WebhookEndpoint, secret: String, event_id: String, kind: WebhookEventKind, body: String) {
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1..=MAX_DELIVERY_ATTEMPTS {
+        // A user's host is resolved again for every attempt, as it may have been repointed at a
+        // private address since registration, and the request is pinned to the checked addresses
+        let client = match endpoint.user_id {
+            Some(_) => match pinned_client(&endpoint.url).await {
+                Ok(client) => client,
+                Err(error) => {
+                    log::error!("Not delivering event {} to webhook {}: {}", event_id, endpoint.id, error);
+                    return;
+                },
+            },
+            None => client.clone(),
+        };
+        // Signed per attempt so receivers can reject stale replays by the timestamp
+        let timestamp = Utc::now().timestamp();
+        let result = client.post(&endpoint.url)
+            .header("Content-Type", "application/json")
+            .header("X-Orbitalmap-Event", kind_name(kind))
+            .header("X-Orbitalmap-Delivery", &event_id)
+            .header(SIGNATURE_HEADER, format!("t={},v1={}", timestamp, sign(&secret, timestamp, &body)))
+            .body(body.clone())
+            .send()
+            .await;
+        match result {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) => log::warn!("Webhook {} answered {} to event {} (attempt {})", endpoint.id, response.status(), event_id, attempt),
+            Err(error) => log::warn!("Webhook {} failed for event {} (attempt {}): {}", endpoint.id, event_id, attempt, error),
+        }
+        if attempt < MAX_DELIVERY_ATTEMPTS {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+    log::error!("Giving up on event {} for webhook {} after {} attempts", event_id, endpoint.id, MAX_DELIVERY_ATTEMPTS);
+}
+
+// A client that connects to a user endpoint's host only at the public addresses it resolves to now,
+// and follows no redirects, which could lead anywhere
+#[cfg(feature = "server")]
+async fn pinned_client(url: &str) -> Result<reqwest::Client, String> {
+    let url = reqwest::Url::parse(url).map_err(|e| format!("Invalid webhook URL: {}", e))?;
+    let addresses = resolve_public(&url).await?;
+    let mut builder = reqwest::Client::builder()
+        .timeout(DELIVERY_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none());
+    if let Some(domain) = url.domain() {
+        builder = builder.resolve_to_addrs(domain, &addresses);
+    }
+    builder.build().map_err(|e| format!("Cannot build the webhook client: {}", e))
+}
+
+// Every address a webhook URL's host resolves to, refusing it if any is not publicly routable
+#[cfg(feature = "server")]
+async fn resolve_public(url: &reqwest::Url) -> Result<Vec<SocketAddr>, String> {
+    let host = url.host_str().ok_or("Webhook URLs need a host")?;
+    let port = url.port_or_known_default().ok_or("Webhook URLs need a port")?;
+    let addresses: Vec<SocketAddr> = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host, port)).await
+            .map_err(|e| format!("Cannot resolve webhook host {}: {}", host, e))?
+            .collect(),
+    };
+    if addresses.is_empty() {
+        return Err(format!("Webhook host {} has no addresses", host));
+    }
+    if let Some(address) = addresses.iter().find(|address| !is_public(address.ip())) {
+        return Err(format!("Webhook host {} resolves to the non-public address {}", host, address.ip()));
+    }
+    Ok(addresses)
+}
+
+// Loopback, private, link-local, shared (CGNAT), multicast and other special-use addresses are
+// all reachable from the server but not from the internet
+#[cfg(feature = "server")]
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+                || ip.is_broadcast() || ip.is_documentation() || ip.is_multicast()
+                || a == 0 || a >= 240 || (a == 100 && (64..128).contains(&b)) || (a == 198 && (18..20).contains(&b)))
+        },
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+                || ip.is_unique_local() || ip.is_unicast_link_local()
+                // Site-local (fec0::/10) and NAT64 (64:ff9b::/96), which can reach IPv4 private space
+                || (ip.segments()[0] & 0xffc0) == 0xfec0
+                || ip.segments()[..6] == [0x64, 0xff9b, 0, 0, 0, 0]),
+        },
+    }
+}
+
+// Hex HMAC-SHA256 of "<timestamp>.<body>" under the endpoint's secret
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(feature = "server")]
+fn kind_name(kind: WebhookEventKind) -> &'static str {
+    match kind {
+        WebhookEventKind::RewardGranted => "reward_granted",
+        WebhookEventKind::GeofenceEntered => "geofence_entered",
+        WebhookEventKind::QuestCompleted => "quest_completed",
+        WebhookEventKind::VisitCompleted => "visit_completed",
+    }
+}
+
+fn random_id() -> String {
+    rand::thread_rng().gen::<[u8; 16]>().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
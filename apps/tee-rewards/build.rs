@@ -0,0 +1,13 @@
+// Generates the protobuf messages of the command protocol; the core models come from the
+// oyster-rewards schema, so both speak the same Location
+fn main() {
+    println!("cargo:rerun-if-changed=proto/tee.proto");
+    println!("cargo:rerun-if-changed=../oyster-rewards/proto/models.proto");
+    // A vendored protoc, so building does not depend on one being installed
+    if std::env::var_os("PROTOC").is_none() {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("No vendored protoc for this platform");
+        std::env::set_var("PROTOC", protoc);
+    }
+    prost_build::compile_protos(&["proto/tee.proto"], &["proto", "../oyster-rewards/proto"])
+        .expect("Failed to compile proto/tee.proto");
+}
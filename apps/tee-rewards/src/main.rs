@@ -1,99 +1,515 @@
 use std::io::{self, BufRead, Write};
 use std::process::exit;
-use std::collections::{HashMap, HashSet};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use serde::{Deserialize, Serialize};
-use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
-use chacha20poly1305::aead::Aead;
-use chacha20poly1305::KeyInit;
-use x25519_dalek::{EphemeralSecret, PublicKey};
-use rand::rngs::OsRng;
-use rand::Rng;
+use serde::de::DeserializeOwned;
 use sha2::{Sha256, Digest};
 use base64::{Engine as _, engine::general_purpose};
 use std::sync::Mutex;
-use once_cell::sync::Lazy;
-
-// TEE key pair (never leaves the TEE)
-static PRIVATE_KEY_BYTES: Lazy<[u8; 32]> = Lazy::new(|| {
-    let mut bytes = [0u8; 32];
-    OsRng.fill(&mut bytes);
-    bytes
-});
-static PRIVATE_KEY: Lazy<EphemeralSecret> = Lazy::new(|| {
-    // 使用 OsRng 创建 EphemeralSecret (不能从已有字节创建)
-    let secret = EphemeralSecret::random_from_rng(OsRng);
-    secret
-});
-static PUBLIC_KEY: Lazy<PublicKey> = Lazy::new(|| PublicKey::from(&*PRIVATE_KEY));
-
-// In-memory storage for location data (in a real app, this would be persisted securely)
-static LOCATION_HISTORY: Lazy<Mutex<HashMap<String, Vec<EncryptedLocation>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
-static HEATMAP_DATA: Lazy<Mutex<HashMap<GridCell, u32>>> = Lazy::new(|| Mutex::new(HashMap::new()));
-static LOCATION_VISITS: Lazy<Mutex<HashMap<GridCell, Vec<u64>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
-static NEARBY_STATIONS: Lazy<Mutex<HashMap<GridCell, Vec<Station>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
-
-// Grid size for heatmap (0.001 degrees is roughly 100m)
-const GRID_SIZE: f64 = 0.001;
+use once_cell::sync::{Lazy, OnceCell};
+use std::collections::VecDeque;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use chrono::{DateTime, TimeZone, Utc};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, KeyInit};
+use chacha20poly1305::aead::Aead;
+use rand::{rngs::OsRng, Rng};
+use schnorrkel::{signing_context, Keypair};
+// Models, encryption and sensor verification are shared with the oyster-rewards API; the key pair,
+// the stored encrypted locations and the known stations live in its statics inside the TEE
+use oyster_rewards::{Location, EncryptedLocation, Station, GridCell, BoundingBox, VerificationThresholds, encrypt_location, verify_location_with};
+use oyster_rewards::{AnalyticsWarning, AnalyticsWarningCode, TimeRange, Coordinate, Latitude, Longitude};
+use oyster_rewards::crypto::{decrypt_location, get_derived_key, PUBLIC_KEY};
+use oyster_rewards::models::timestamp;
+use oyster_rewards::location::{floor_level, GRID_SIZE, LOCATION_HISTORY, NEARBY_STATIONS};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct Location {
-    lat: f64,
-    lon: f64,
-    timestamp: u64,
-    user_id: String,
-    device_id: String,
-    sensors: SensorData,
+mod wire;
+
+// Apps sharing the enclave are kept apart by a tenant id sent with each command: users are
+// stored as "<tenant>/<user_id>" and cells are keyed by tenant, so one tenant's locations,
+// heatmap, analytics and rewards are invisible to the others. The station map used for
+// verification describes the physical world and is shared
+type TenantCell = (String, GridCell);
+
+// Memory bounds, read from the environment at startup
+static MEMORY_LIMITS: OnceCell<MemoryLimits> = OnceCell::new();
+// Owners of the stored encrypted locations, oldest first, so the oldest can be evicted
+static LOCATION_ORDER: Lazy<Mutex<VecDeque<String>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+static EVICTED_LOCATIONS: AtomicU64 = AtomicU64::new(0);
+// Per-cell data is kept apart per tenant; see TenantCell
+static HEATMAP_DATA: Lazy<Mutex<LruMap<TenantCell, u32>>> = Lazy::new(|| Mutex::new(LruMap::new(memory_limits().max_heatmap_cells)));
+// Last nonce accepted from each device, keyed by tenant_user(tenant, device_id)
+static DEVICE_NONCES: Lazy<Mutex<NonceRegistry>> = Lazy::new(|| Mutex::new(NonceRegistry::new()));
+static LOCATION_VISITS: Lazy<Mutex<LruMap<TenantCell, VisitCounts>>> = Lazy::new(|| Mutex::new(LruMap::new(memory_limits().max_visit_cells)));
+// Hour (since the epoch) of the last compaction pass
+static LAST_COMPACTION_HOUR: AtomicU64 = AtomicU64::new(0);
+// Per-cell sketches of hashed visitor ids
+static VISITOR_SKETCHES: Lazy<Mutex<HashMap<TenantCell, VisitorSketches>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+// Per-cell first and last day (since the epoch) each hashed visitor was seen
+type VisitorDays = HashMap<u64, (u64, u64)>;
+static VISITOR_DAYS: Lazy<Mutex<HashMap<TenantCell, VisitorDays>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+// Reward balances and entries per user
+static REWARD_LEDGER: Lazy<Mutex<HashMap<String, RewardAccount>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_REWARD_ID: Lazy<Mutex<u64>> = Lazy::new(|| Mutex::new(0));
+// Registrations whose reward is yet to be claimed, by claim id
+static REWARD_CLAIMS: Lazy<Mutex<LruMap<u64, RewardClaim>>> = Lazy::new(|| Mutex::new(LruMap::new(memory_limits().max_locations)));
+static NEXT_CLAIM_ID: Lazy<Mutex<u64>> = Lazy::new(|| Mutex::new(0));
+// Signs claimed rewards; generated inside the enclave, only the public half ever leaves it
+static REWARD_SIGNING_KEY: Lazy<Keypair> = Lazy::new(Keypair::generate);
+// Last time (TEE clock) each hashed user was rewarded in each cell
+static REWARD_COOLDOWNS: Lazy<Mutex<HashMap<(u64, GridCell), u64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+// Active reward campaign, and the TOML file named by REWARD_CONFIG at startup, the only one
+// ReloadConfig reads
+static REWARD_CONFIG: Lazy<Mutex<RewardConfig>> = Lazy::new(|| Mutex::new(RewardConfig::default()));
+static REWARD_CONFIG_PATH: OnceCell<String> = OnceCell::new();
+// Responses waiting to be written; flushed whenever no further command is waiting in the input
+static OUTPUT: Lazy<Mutex<io::BufWriter<io::Stdout>>> = Lazy::new(|| Mutex::new(io::BufWriter::new(io::stdout())));
+// When the enclave started, for its uptime
+static STARTED_AT: Lazy<Instant> = Lazy::new(Instant::now);
+// Operational parameters, changed at runtime by Configure
+static TUNING: Lazy<Mutex<Tuning>> = Lazy::new(|| Mutex::new(Tuning::default()));
+// SHA-256 of the token Configure and ReloadConfig require, and the file tuning is sealed to, both read
+// from the environment at startup
+static ADMIN_TOKEN_HASH: OnceCell<[u8; 32]> = OnceCell::new();
+static SEALED_TUNING: OnceCell<SealedFile> = OnceCell::new();
+// Registrations refused since startup, by reason
+static REJECTED_REGISTRATIONS: Lazy<Mutex<RejectionCounts>> = Lazy::new(|| Mutex::new(RejectionCounts::default()));
+// Registrations per hashed user in the current hour: (hour since the epoch, count)
+static REGISTRATION_COUNTS: Lazy<Mutex<HashMap<u64, (u64, u32)>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+// Geofences of each tenant, by id
+static GEOFENCES: Lazy<Mutex<HashMap<String, BTreeMap<String, Geofence>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+// Ids of the geofences each user (by tenant_user) was inside at their last registration; users
+// outside all geofences are not kept
+static GEOFENCE_PRESENCE: Lazy<Mutex<LruMap<String, Vec<String>>>> = Lazy::new(|| Mutex::new(LruMap::new(memory_limits().max_devices)));
+// Sealed log of the commands that changed state, written before they run (TEE_COMMAND_LOG)
+static COMMAND_LOG: OnceCell<Mutex<CommandLog>> = OnceCell::new();
+// The time a logged command acts at, so replaying it acts at the same time; 0 outside one
+static COMMAND_TIME: AtomicU64 = AtomicU64::new(0);
+// Key of the user id hashes; derived from TEE_SEALING_KEY when the state is logged, so hashes in
+// a snapshot still match after a restart
+static USER_HASH_KEY: OnceCell<[u8; 32]> = OnceCell::new();
+
+// Events raised by the command being processed, with their tenant; the framed protocol writes
+// them after its response
+static PENDING_EVENTS: Lazy<Mutex<Vec<(String, Event)>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+// Largest number of grid cells scanned for one heatmap
+const MAX_HEATMAP_GRID_CELLS: u64 = 4_000_000;
+
+// HyperLogLog registers are addressed by this many hash bits (1024 registers, ~3% error)
+const HLL_PRECISION: u32 = 10;
+
+// Visitor sketches kept per cell: hourly ones for the last day and daily ones for the last week
+const VISITOR_SKETCH_HOURS: u64 = 24;
+const VISITOR_SKETCH_DAYS: u64 = 7;
+
+// Hourly visit counts kept per cell, which bounds the analytics range; compaction folds older
+// ones into the cell's weekday totals
+const VISIT_HISTORY_HOURS: u64 = 7 * 24;
+
+// Analytics range when a request names no start: the last 24 hours, the current one included
+const DEFAULT_ANALYTICS_HOURS: u64 = 24;
+
+// Retention windows (days) reported when a request does not name any, and the most it may name
+const DEFAULT_RETENTION_WINDOWS: [u64; 2] = [7, 30];
+const MAX_RETENTION_WINDOWS: usize = 8;
+
+// Reward history page size when none (or too large a one) is requested
+const DEFAULT_REWARD_HISTORY_PAGE: usize = 50;
+const MAX_REWARD_HISTORY_PAGE: usize = 200;
+
+// How long after a registration its reward may be claimed
+const REWARD_CLAIM_WINDOW_SECONDS: u64 = 24 * 3600;
+
+// Domain separation of reward tokens; verifiers must use the same context
+const REWARD_CONTEXT: &[u8] = b"orbitalmap-reward";
+
+// Version of the command protocol, sent with every tagged reply; raised whenever a command or
+// response changes shape, so a web interface built for another version can tell
+const PROTOCOL_VERSION: u32 = 7;
+
+// How commands and responses are written on stdin and stdout, chosen by TEE_PROTOCOL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    // A banner, a prompt and pretty-printed JSON, for people at the console
+    Console,
+    // One compact JSON command or response per line (TEE_PROTOCOL=ndjson)
+    Ndjson,
+    // Length-prefixed messages of proto/tee.proto (TEE_PROTOCOL=protobuf)
+    Protobuf,
+    // Length-prefixed CBOR of the JSON protocol's messages (TEE_PROTOCOL=cbor)
+    #[cfg(feature = "cbor")]
+    Cbor,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct EncryptedLocation {
-    enc_data: String,
-    timestamp: u64,
+// Tenant of commands that name none
+const DEFAULT_TENANT: &str = "default";
+const MAX_TENANT_ID_LENGTH: usize = 64;
+
+// Input read ahead at once, so commands written together are read with a single read
+const INPUT_BUFFER_BYTES: usize = 64 * 1024;
+
+// Most commands a single Batch command may carry
+const MAX_BATCH_COMMANDS: usize = 64;
+
+// Commands read ahead of the one being run; further input waits in the pipe
+const MAX_QUEUED_COMMANDS: usize = 256;
+
+// Default memory bounds; each can be overridden by the environment variable named in
+// MemoryLimits::from_env
+const DEFAULT_MAX_LOCATIONS: usize = 100_000;
+const DEFAULT_MAX_HEATMAP_CELLS: usize = 100_000;
+const DEFAULT_MAX_VISIT_CELLS: usize = 50_000;
+const DEFAULT_MAX_DEVICES: usize = 100_000;
+
+// Grid sizes (degrees) Configure accepts: about 11 m to 111 km at the equator
+const MIN_GRID_SIZE: f64 = 0.0001;
+const MAX_GRID_SIZE: f64 = 1.0;
+
+// Shortest TEE_ADMIN_TOKEN accepted
+const MIN_ADMIN_TOKEN_LENGTH: usize = 16;
+
+// Log entries after which a snapshot replaces the log (TEE_SNAPSHOT_ENTRIES); one is also
+// taken every hour while the log is not empty
+const DEFAULT_SNAPSHOT_ENTRIES: u64 = 10_000;
+
+// Geofences a tenant may define, and the radius they may have
+const MAX_GEOFENCES: usize = 1_000;
+const MIN_GEOFENCE_RADIUS_METERS: f64 = 10.0;
+const MAX_GEOFENCE_RADIUS_METERS: f64 = 100_000.0;
+const MAX_GEOFENCE_NAME_LENGTH: usize = 128;
+
+// Bounds on the stores that grow with traffic, so a keep with little memory evicts old data
+// instead of aborting
+#[derive(Debug, Clone)]
+struct MemoryLimits {
+    // Encrypted locations kept over all users; the oldest are evicted first
+    max_locations: usize,
+    // Heatmap cells; the least recently visited are evicted first
+    max_heatmap_cells: usize,
+    // Cells with visit counts; the least recently visited are evicted first
+    max_visit_cells: usize,
+    // Devices whose last nonce is kept; the least recently registering are evicted first
+    max_devices: usize,
+}
+
+// Entries evicted from each bounded store since startup
+#[derive(Debug, Serialize, Deserialize)]
+struct EvictionCounts {
+    locations: u64,
+    heatmap_cells: u64,
+    visit_cells: u64,
+    devices: u64,
+}
+
+// Parameters an operator can change at runtime with Configure
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Tuning {
+    // Heatmap and visit analytics cell size, in degrees
+    grid_size: f64,
+    verification: VerificationThresholds,
+    // Default windows (days) of the new vs. returning visitor metrics
+    retention_windows: Vec<u64>,
+    // Registrations accepted per user per hour; 0 for no limit
+    registrations_per_hour: u32,
+}
+
+// The fields of a Configure command; those left out keep their value
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TuningChanges {
+    #[serde(default)]
+    grid_size: Option<f64>,
+    #[serde(default)]
+    min_matching_stations: Option<usize>,
+    #[serde(default)]
+    min_matching_ratio: Option<f64>,
+    #[serde(default)]
+    retention_windows: Option<Vec<u64>>,
+    #[serde(default)]
+    registrations_per_hour: Option<u32>,
+}
+
+// A file holding the tuning encrypted under the sealing key
+struct SealedFile {
+    path: String,
+    key: Key,
+}
+
+// On-disk form of sealed data: a file, or a line of the command log
+#[derive(Debug, Serialize, Deserialize)]
+struct SealedData {
     nonce: String,
+    ciphertext: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct SensorData {
-    wifi_networks: Vec<WifiNetwork>,
-    cell_towers: Vec<CellTower>,
-    accelerometer: Option<[f32; 3]>,
-    gyroscope: Option<[f32; 3]>,
-    is_mock_location: bool,
+// Append-only log of the commands that changed state, one sealed entry per line, next to a
+// sealed snapshot of the whole state (the same path with ".snapshot"). Restoring the snapshot and
+// replaying the log after it rebuilds the state of an enclave that crashed
+struct CommandLog {
+    path: String,
+    key: Key,
+    file: std::fs::File,
+    // Sequence number of the last entry, in the log or covered by the snapshot
+    seq: u64,
+    // Entries in the log, i.e. written since the last snapshot
+    entries: u64,
+    snapshot_entries: u64,
+    snapshot_hour: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct WifiNetwork {
-    ssid: String,
-    bssid: String,
-    signal_strength: i32,
+// Log entries and snapshots carry the protocol version they were sealed by, 0 for those sealed
+// before they were tagged. They are read whatever fields they carry beyond the ones known here
+// (fields added later are defaulted), so state sealed by older builds restores after the models
+// change; one that does not decode names the version that sealed it
+#[derive(Debug, Serialize, Deserialize)]
+struct LogEntry {
+    #[serde(default)]
+    version: u32,
+    seq: u64,
+    // The time the entry took effect at, which its replay acts at too
+    time: u64,
+    record: LogRecord,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct CellTower {
-    cell_id: String,
-    signal_strength: i32,
+// The form an entry is written in
+#[derive(Serialize)]
+struct LogEntryRef<'a> {
+    version: u32,
+    seq: u64,
+    time: u64,
+    record: &'a LogRecord,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct Station {
+#[derive(Debug, Serialize, Deserialize)]
+enum LogRecord {
+    // A RegisterLocation or RegisterGeofence command of the tenant
+    Command { tenant: String, command: Box<Command> },
+    // A ClaimReward command, by the claim it resolved to: location ids change with every start
+    RewardClaim { tenant: String, claim_id: u64 },
+    // Settings as they were changed by Configure and ReloadConfig, which are not replayed
+    // themselves: they need the admin token and files of the time
+    Tuning(Tuning),
+    RewardConfig(RewardConfig),
+}
+
+// A snapshot's plaintext. Locations are kept decrypted, since a restarted enclave encrypts
+// them under a new key; location ids handed out before the restart cannot be looked up
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    #[serde(default)]
+    version: u32,
+    // The last log entry the snapshot covers
+    seq: u64,
+    locations: Vec<(String, Vec<Location>)>,
+    state: EnclaveState,
+}
+
+// Everything else the enclave holds, moved out of the stores while a snapshot is written
+#[derive(Serialize, Deserialize)]
+struct EnclaveState {
+    tuning: Tuning,
+    reward_config: RewardConfig,
+    location_order: VecDeque<String>,
+    evicted_locations: u64,
+    heatmap: LruMap<TenantCell, u32>,
+    device_nonces: NonceRegistry,
+    visits: LruMap<TenantCell, VisitCounts>,
+    last_compaction_hour: u64,
+    #[serde(with = "pairs")]
+    visitor_sketches: HashMap<TenantCell, VisitorSketches>,
+    #[serde(with = "pairs")]
+    visitor_days: HashMap<TenantCell, VisitorDays>,
+    reward_ledger: HashMap<String, RewardAccount>,
+    next_reward_id: u64,
+    reward_claims: LruMap<u64, RewardClaim>,
+    next_claim_id: u64,
+    #[serde(with = "pairs")]
+    reward_cooldowns: HashMap<(u64, GridCell), u64>,
+    registration_counts: HashMap<u64, (u64, u32)>,
+    geofences: HashMap<String, BTreeMap<String, Geofence>>,
+    geofence_presence: LruMap<String, Vec<String>>,
+    #[serde(with = "pairs")]
+    nearby_stations: HashMap<GridCell, Vec<Station>>,
+    rejected_registrations: RejectionCounts,
+}
+
+// Serde for maps whose keys are not strings, as a list of [key, value] pairs
+mod pairs {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<'a, M, K, V, S>(map: &'a M, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        &'a M: IntoIterator<Item = (&'a K, &'a V)>,
+        K: Serialize + 'a,
+        V: Serialize + 'a,
+        S: Serializer,
+    {
+        serializer.collect_seq(map)
+    }
+
+    pub fn deserialize<'de, M, K, V, D>(deserializer: D) -> Result<M, D::Error>
+    where
+        M: FromIterator<(K, V)>,
+        K: Deserialize<'de>,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Ok(Vec::<(K, V)>::deserialize(deserializer)?.into_iter().collect())
+    }
+}
+
+// Serde for byte strings as base64 in JSON, and as they are in CBOR
+mod base64_bytes {
+    use base64::{Engine as _, engine::general_purpose};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&general_purpose::STANDARD.encode(bytes))
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            general_purpose::STANDARD.decode(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+        } else {
+            byte_buf(deserializer)
+        }
+    }
+
+    fn byte_buf<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        struct BytesVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for BytesVisitor {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("bytes")
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, value: &[u8]) -> Result<Vec<u8>, E> {
+                Ok(value.to_vec())
+            }
+
+            fn visit_byte_buf<E: serde::de::Error>(self, value: Vec<u8>) -> Result<Vec<u8>, E> {
+                Ok(value)
+            }
+        }
+
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+// Registrations refused since startup, by reason
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct RejectionCounts {
+    rate_limited: u64,
+    // Nonce not greater than the device's last one
+    replayed: u64,
+    verification_failed: u64,
+    encryption_failed: u64,
+}
+
+// Anti-replay state: every registration carries a nonce greater than the last one its device
+// sent. A device not (or no longer) tracked must exceed the highest nonce ever evicted, so
+// pushing a device out of the bounded map does not reopen its old nonces to replay
+#[derive(Debug, Serialize, Deserialize)]
+struct NonceRegistry {
+    last: LruMap<String, u64>,
+    evicted_floor: u64,
+}
+
+// A location to register, with its device's anti-replay nonce alongside the location fields
+#[derive(Debug, Serialize, Deserialize)]
+struct Registration {
+    #[serde(flatten)]
+    location: Location,
+    nonce: u64,
+}
+
+// A circle of a tenant's; a user whose registered location enters or leaves it raises a
+// GeofenceEvent. Ids are chosen by the client, so registering an id again replaces the geofence
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Geofence {
     id: String,
-    lat: f64,
-    lon: f64,
-    station_type: StationType,
-    signal_strength: i32,
+    name: String,
+    lat: Latitude,
+    lon: Longitude,
+    radius_meters: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum GeofenceTransition {
+    Enter,
+    Exit,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GeofenceEvent {
+    geofence_id: String,
+    geofence_name: String,
+    transition: GeofenceTransition,
+    user_id: String,
+    timestamp: u64,
+}
+
+// Messages the framed protocol writes on their own lines, between responses
+#[derive(Debug, Serialize)]
+enum Event {
+    Geofence(GeofenceEvent),
+}
+
+// An event line: {"tenant_id": "default", "protocol_version": 7, "event": {"Geofence": {...}}};
+// having no id tells it apart from the responses
+#[derive(Debug, Serialize)]
+struct EventLine<'a> {
+    tenant_id: &'a str,
+    protocol_version: u32,
+    event: &'a Event,
+}
+
+// A map holding at most `capacity` entries; writing a new key into a full one evicts the entry
+// written least recently
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound(serialize = "K: Serialize, V: Serialize", deserialize = "K: Deserialize<'de> + Eq + Hash, V: Deserialize<'de>"))]
+struct LruMap<K, V> {
+    #[serde(with = "pairs")]
+    entries: HashMap<K, (V, u64)>,
+    // Set from the memory limits when a snapshot is restored
+    #[serde(skip)]
+    capacity: usize,
+    clock: u64,
+    evicted: u64,
+}
+
+// Visits to a cell: counts per hour for the last week, and older visits folded into totals by
+// day of week, so a cell takes the same memory however often it is visited
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct VisitCounts {
+    // (hours since the epoch, visits), oldest first
+    hourly: VecDeque<(u64, u32)>,
+    // Visits folded out of `hourly`, by day of week (Monday first)
+    weekday_totals: [u32; 7],
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
-enum StationType {
-    Wifi,
-    CellTower,
+// Sketches of the hashed ids of a cell's visitors, oldest first
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct VisitorSketches {
+    // (hours since the epoch, sketch) for the last day
+    hourly: Vec<(u64, HyperLogLog)>,
+    // (days since the epoch, sketch) for the last week
+    daily: Vec<(u64, HyperLogLog)>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
-struct GridCell {
-    lat_grid: i32,
-    lon_grid: i32,
+// Cardinality estimator with a fixed memory footprint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HyperLogLog {
+    #[serde(with = "base64_bytes")]
+    registers: Vec<u8>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -104,312 +520,1738 @@ struct HeatmapResponse {
 
 #[derive(Debug, Serialize, Deserialize)]
 struct HeatmapCell {
-    lat: f64,
-    lon: f64,
+    lat: Latitude,
+    lon: Longitude,
     value: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct VisitAnalyticsResponse {
     location: Location,
-    visits_24h: u32,
-    unique_visitors_24h: u32,
+    // The whole hours covered, once shortened to the kept history
+    range: TimeRange,
+    visits: u32,
+    // Estimated distinct visitors (hashed user ids) in the range; before the last 24 hours they
+    // are counted by whole UTC day
+    unique_visitors: u32,
     peak_hour: u32,
+    // Visits per hour of day (UTC) and per day of week (Monday first) over the range, named as
+    // in the oyster-rewards analytics
+    hourly_counts: Vec<u32>,
+    weekday_counts: Vec<u32>,
+    // Visits per day of week over all recorded visits
+    weekday_totals: Vec<u32>,
+    retention: Vec<RetentionMetrics>,
+    warnings: Vec<AnalyticsWarning>,
+}
+
+// New vs. returning visitors of a cell among those seen in the last `window_days` days.
+// A visitor is returning once they have been seen on more than one day.
+#[derive(Debug, Serialize, Deserialize)]
+struct RetentionMetrics {
+    window_days: u64,
+    visitors: u32,
+    new_visitors: u32,
+    returning_visitors: u32,
+    return_rate: f64,
+}
+
+// Reward campaign settings, loaded from TOML so campaigns change without rebuilding the enclave
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RewardConfig {
+    // Rarest cells first; only the last tier may omit max_cell_visits
+    tiers: Vec<RewardTierConfig>,
+    // Registering the same cell again within this many seconds earns nothing
+    cooldown_seconds: u64,
+    // Factor applied to every reward, e.g. for a double-points weekend
+    #[serde(default = "default_reward_multiplier")]
+    multiplier: f64,
+    // Most points a user may earn per (UTC) day, bounding what undetected spoofing can gain
+    #[serde(default = "default_daily_cap")]
+    daily_cap: u64,
+}
+
+// Cells visited at most `max_cell_visits` times before pay out this tier
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RewardTierConfig {
+    name: String,
+    emoji: String,
+    max_cell_visits: Option<u32>,
+    points: u64,
+}
+
+fn default_reward_multiplier() -> f64 {
+    1.0
+}
+
+fn default_daily_cap() -> u64 {
+    2000
+}
+
+// Earned and spent points of a user, with every change (oldest first)
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RewardAccount {
+    earned: u64,
+    spent: u64,
+    entries: Vec<RewardEntry>,
+    // Points earned on `earned_day` (days since the epoch), counted against the daily cap
+    earned_day: u64,
+    earned_today: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct RewardEntry {
+    id: u64,
+    // "earned" or "spent"
+    kind: String,
+    points: u64,
+    reason: String,
+    balance_after: u64,
+    timestamp: u64,
+}
+
+// A registration's reward, priced when it is claimed: the cell's visits before the
+// registration pick the tier, the campaign at claim time the multiplier, cooldown and cap
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RewardClaim {
+    tenant: String,
+    // Keyed by tenant_user
+    user: String,
+    location_id: String,
+    grid_cell: GridCell,
+    earlier_visits: u32,
+    registered_at: u64,
+}
+
+// What a reward token vouches for
+#[derive(Debug, Serialize, Deserialize)]
+struct RewardGrant {
+    reward_id: u64,
+    tenant_id: String,
+    user_id: String,
+    location_id: String,
+    points: u64,
+    timestamp: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SignedReward {
+    user_id: String,
+    entry: RewardEntry,
+    // base64url(JSON RewardGrant) "." base64url(sr25519 signature), under REWARD_CONTEXT
+    token: String,
+}
+
+// What the enclave holds and how long it has run
+#[derive(Debug, Serialize, Deserialize)]
+struct TeeInfo {
+    protocol_version: u32,
+    build_version: String,
+    uptime_seconds: u64,
+    users: usize,
+    locations: usize,
+    heatmap_cells: usize,
+    visits: usize,
+    known_stations: usize,
+    reward_accounts: usize,
+    // Rough size of the data above; allocator overhead and map capacity are not counted
+    memory_estimate_bytes: usize,
+    evicted: EvictionCounts,
+}
+
+// A page of a user's reward entries, newest first
+#[derive(Debug, Serialize, Deserialize)]
+struct RewardHistoryResponse {
+    user_id: String,
+    earned: u64,
+    spent: u64,
+    balance: u64,
+    // Points the daily cap still allows today
+    daily_remaining: u64,
+    entries: Vec<RewardEntry>,
+    total: usize,
+    offset: usize,
+    limit: usize,
+}
+
+// Entries held by each store, cheap enough to collect on every metrics scrape
+#[derive(Debug, Serialize, Deserialize)]
+struct TeeStats {
+    uptime_seconds: u64,
+    users: usize,
+    locations: usize,
+    heatmap_cells: usize,
+    visit_cells: usize,
+    known_stations: usize,
+    reward_accounts: usize,
+    // Entries of the internal per-cell and per-user maps
+    caches: CacheSizes,
+    rejected_registrations: RejectionCounts,
+    evicted: EvictionCounts,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheSizes {
+    visitor_sketches: usize,
+    visitor_days: usize,
+    device_nonces: usize,
+    reward_cooldowns: usize,
+    registration_counts: usize,
+    geofences: usize,
+    geofence_presence: usize,
+    reward_claims: usize,
 }
 
 // Commands
 #[derive(Debug, Serialize, Deserialize)]
 enum Command {
-    RegisterLocation(Location),
+    RegisterLocation(Registration),
     GetLocation(String),
     GenerateHeatmap { min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64 },
-    GetVisitAnalytics { lat: f64, lon: f64 },
+    GetVisitAnalytics {
+        lat: f64,
+        lon: f64,
+        #[serde(default)]
+        window_days: Option<Vec<u64>>,
+        // Bounds of the range, RFC 3339 or Unix seconds (default: the last 24 hours)
+        #[serde(default)]
+        start_time: Option<String>,
+        #[serde(default)]
+        end_time: Option<String>,
+    },
+    GetRewardHistory {
+        user_id: String,
+        #[serde(default)]
+        offset: usize,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    // Credit the reward of a registration, by the location id it returned
+    ClaimReward { location_id: String },
+    // Add or replace a geofence of the tenant
+    RegisterGeofence(Geofence),
+    ListGeofences,
+    // Reload the reward campaign from the TOML file loaded at startup (admin); a path, if
+    // given, must name that file
+    ReloadConfig {
+        admin_token: String,
+        #[serde(default)]
+        path: Option<String>,
+    },
+    // Several commands answered together, in order, by a single Batch response
+    Batch(Vec<serde_json::Value>),
+    // Liveness check; answered without touching any state
+    Ping,
+    Version,
+    // The enclave's public key, base64 encoded
+    GetPublicKey,
+    GetInfo,
+    // Store sizes and rejection counters, for monitoring
+    GetStats,
+    // Fold old visit data into compact totals; also run automatically once an hour
+    Compact,
+    // Change operational parameters (admin); without changes the current ones are returned
+    Configure {
+        admin_token: String,
+        #[serde(flatten)]
+        changes: TuningChanges,
+    },
     Help,
     Exit,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 enum Response {
-    LocationRegistered { enc_location: String, success: bool, message: String },
+    LocationRegistered {
+        enc_location: String,
+        success: bool,
+        message: String,
+        // Seconds until a reward in this cell can be claimed, 0 if it can be now
+        reward_cooldown_seconds: u64,
+        // Points the daily cap still allows today
+        daily_remaining: u64,
+        // Geofences of the tenant the user entered or left with this location
+        geofence_events: Vec<GeofenceEvent>,
+    },
     LocationData { location: Option<Location>, success: bool, message: String },
     Heatmap(HeatmapResponse),
     VisitAnalytics(VisitAnalyticsResponse),
+    RewardHistory(RewardHistoryResponse),
+    RewardClaimed {
+        success: bool,
+        message: String,
+        reward: Option<SignedReward>,
+        // Seconds until the claim could succeed, when a cooldown refused it
+        reward_cooldown_seconds: u64,
+        daily_remaining: u64,
+    },
+    Batch(Vec<Response>),
+    Pong { timestamp: u64 },
+    Version { protocol_version: u32, build_version: String },
+    PublicKey { public_key: String, reward_public_key: String, reward_context: String },
+    Info(TeeInfo),
+    Stats(TeeStats),
+    Configured { success: bool, message: String, tuning: Option<Tuning> },
+    GeofenceRegistered { success: bool, message: String, geofence: Option<Geofence> },
+    Geofences(Vec<Geofence>),
     Message { success: bool, message: String },
 }
 
-impl GridCell {
-    fn from_location(lat: f64, lon: f64) -> Self {
-        GridCell {
-            lat_grid: (lat / GRID_SIZE).floor() as i32,
-            lon_grid: (lon / GRID_SIZE).floor() as i32,
+// A command tagged with an id that its response echoes, so a client can keep several commands
+// in flight and match the responses: {"id": 7, "command": {"Help": null}}. An optional
+// request_id names the API request the command serves; it is echoed too and logged to stderr
+// with the command's processing time, so a request can be traced into the enclave. An optional
+// tenant_id names the tenant whose data the command reads and writes (default: "default"). An
+// optional protocol_version names the version the client speaks, so a command this enclave
+// cannot parse is refused as one of a newer protocol rather than as malformed
+#[derive(Debug, Deserialize)]
+struct Request {
+    id: serde_json::Value,
+    #[serde(default)]
+    request_id: Option<String>,
+    #[serde(default)]
+    tenant_id: Option<String>,
+    #[serde(default)]
+    protocol_version: Option<u32>,
+    command: serde_json::Value,
+}
+
+// The response to a tagged command: {"id": 7, "protocol_version": 7, "response": {"Message": {...}}}
+#[derive(Debug, Serialize)]
+struct Reply<'a> {
+    id: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    protocol_version: u32,
+    response: &'a Response,
+}
+
+impl Default for RewardConfig {
+    fn default() -> Self {
+        let tier = |name: &str, emoji: &str, max_cell_visits, points| RewardTierConfig {
+            name: name.to_string(),
+            emoji: emoji.to_string(),
+            max_cell_visits,
+            points,
+        };
+        RewardConfig {
+            tiers: vec![
+                tier("legendary", "🌟", Some(0), 500),
+                tier("epic", "💎", Some(5), 200),
+                tier("rare", "🔷", Some(25), 100),
+                tier("uncommon", "🟢", Some(100), 40),
+                tier("common", "⚪", None, 10),
+            ],
+            cooldown_seconds: 6 * 3600,
+            multiplier: 1.0,
+            daily_cap: default_daily_cap(),
+        }
+    }
+}
+
+impl RewardConfig {
+    fn from_toml_file(path: &str) -> Result<Self, String> {
+        let toml = std::fs::read_to_string(path).map_err(|e| format!("Failed to read reward config {}: {}", path, e))?;
+        // The message and position only; the error's own display quotes the file
+        let config: RewardConfig = toml::from_str(&toml)
+            .map_err(|e| format!("Invalid reward config {}: {} (at bytes {:?})", path, e.message().trim().replace('\n', ", "), e.span()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let (last, bounded) = match self.tiers.split_last() {
+            Some(split) => split,
+            None => return Err("The reward config needs at least one tier".to_string()),
+        };
+        if last.max_cell_visits.is_some() {
+            return Err("The last tier must not have a max_cell_visits bound".to_string());
         }
+        for pair in bounded.windows(2) {
+            if pair[0].max_cell_visits >= pair[1].max_cell_visits {
+                return Err("max_cell_visits must increase from one tier to the next".to_string());
+            }
+        }
+        if bounded.iter().any(|tier| tier.max_cell_visits.is_none()) {
+            return Err("Only the last tier may omit max_cell_visits".to_string());
+        }
+        if !(0.0..=10.0).contains(&self.multiplier) {
+            return Err("multiplier must be between 0 and 10".to_string());
+        }
+        Ok(())
     }
 
-    fn to_coordinates(&self) -> (f64, f64) {
-        (
-            (self.lat_grid as f64) * GRID_SIZE + (GRID_SIZE / 2.0),
-            (self.lon_grid as f64) * GRID_SIZE + (GRID_SIZE / 2.0),
-        )
+    // Tier of a cell that has been visited this many times before
+    fn tier_for(&self, earlier_visits: u32) -> &RewardTierConfig {
+        self.tiers.iter()
+            .find(|tier| tier.max_cell_visits.is_none_or(|bound| earlier_visits <= bound))
+            .unwrap_or(&self.tiers[self.tiers.len() - 1])
     }
 }
 
-// Get a derived key for encryption/decryption
-fn get_derived_key() -> Key {
-    let mut hasher = Sha256::new();
-    // 为了保持一致性，我们仍然使用 PRIVATE_KEY_BYTES
-    hasher.update(&*PRIVATE_KEY_BYTES);
-    let hashed_key = hasher.finalize();
-    *Key::from_slice(&hashed_key[0..32])
-}
-
-// Function to encrypt location data
-fn encrypt_location(location: &Location) -> Result<EncryptedLocation, String> {
-    // Generate a random nonce
-    let mut rng = OsRng;
-    let mut nonce_bytes = [0u8; 12];
-    rng.fill(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes);
-
-    // Serialize location data
-    let location_json = serde_json::to_string(location)
-        .map_err(|e| format!("Serialization error: {}", e))?;
-
-    // Get the derived key
-    let key = get_derived_key();
-
-    // Create cipher and encrypt
-    let cipher = ChaCha20Poly1305::new(&key);
-    let encrypted = cipher
-        .encrypt(nonce, location_json.as_bytes())
-        .map_err(|e| format!("Encryption error: {}", e))?;
+impl MemoryLimits {
+    fn from_env() -> Result<Self, String> {
+        let limit = |name: &str, default: usize| match std::env::var(name) {
+            Ok(value) => match value.parse() {
+                Ok(0) | Err(_) => Err(format!("{} must be a positive number, got {}", name, value)),
+                Ok(limit) => Ok(limit),
+            },
+            Err(_) => Ok(default),
+        };
+        Ok(MemoryLimits {
+            max_locations: limit("TEE_MAX_LOCATIONS", DEFAULT_MAX_LOCATIONS)?,
+            max_heatmap_cells: limit("TEE_MAX_HEATMAP_CELLS", DEFAULT_MAX_HEATMAP_CELLS)?,
+            max_visit_cells: limit("TEE_MAX_VISIT_CELLS", DEFAULT_MAX_VISIT_CELLS)?,
+            max_devices: limit("TEE_MAX_DEVICES", DEFAULT_MAX_DEVICES)?,
+        })
+    }
+}
+
+impl Default for MemoryLimits {
+    fn default() -> Self {
+        MemoryLimits {
+            max_locations: DEFAULT_MAX_LOCATIONS,
+            max_heatmap_cells: DEFAULT_MAX_HEATMAP_CELLS,
+            max_visit_cells: DEFAULT_MAX_VISIT_CELLS,
+            max_devices: DEFAULT_MAX_DEVICES,
+        }
+    }
+}
+
+fn memory_limits() -> &'static MemoryLimits {
+    MEMORY_LIMITS.get_or_init(MemoryLimits::default)
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Tuning {
+            grid_size: GRID_SIZE,
+            verification: VerificationThresholds::default(),
+            retention_windows: DEFAULT_RETENTION_WINDOWS.to_vec(),
+            registrations_per_hour: 0,
+        }
+    }
+}
 
-    Ok(EncryptedLocation {
-        enc_data: general_purpose::STANDARD.encode(encrypted),
-        timestamp: location.timestamp,
+impl Tuning {
+    fn validate(&self) -> Result<(), String> {
+        if !self.grid_size.is_finite() || self.grid_size < MIN_GRID_SIZE || self.grid_size > MAX_GRID_SIZE {
+            return Err(format!("grid_size must be between {} and {} degrees", MIN_GRID_SIZE, MAX_GRID_SIZE));
+        }
+        let ratio = self.verification.min_matching_ratio;
+        if !ratio.is_finite() || !(0.0..=1.0).contains(&ratio) {
+            return Err("min_matching_ratio must be between 0 and 1".to_string());
+        }
+        check_retention_windows(&self.retention_windows)
+    }
+
+    // The tuning with the given changes applied, if the result is valid
+    fn with_changes(&self, changes: TuningChanges) -> Result<Tuning, String> {
+        let mut tuning = self.clone();
+        if let Some(grid_size) = changes.grid_size {
+            tuning.grid_size = grid_size;
+        }
+        if let Some(min_matching_stations) = changes.min_matching_stations {
+            tuning.verification.min_matching_stations = min_matching_stations;
+        }
+        if let Some(min_matching_ratio) = changes.min_matching_ratio {
+            tuning.verification.min_matching_ratio = min_matching_ratio;
+        }
+        if let Some(retention_windows) = changes.retention_windows {
+            tuning.retention_windows = retention_windows;
+        }
+        if let Some(registrations_per_hour) = changes.registrations_per_hour {
+            tuning.registrations_per_hour = registrations_per_hour;
+        }
+        tuning.validate()?;
+        Ok(tuning)
+    }
+}
+
+impl TuningChanges {
+    fn is_empty(&self) -> bool {
+        self.grid_size.is_none()
+            && self.min_matching_stations.is_none()
+            && self.min_matching_ratio.is_none()
+            && self.retention_windows.is_none()
+            && self.registrations_per_hour.is_none()
+    }
+}
+
+impl NonceRegistry {
+    fn new() -> Self {
+        NonceRegistry {
+            last: LruMap::new(memory_limits().max_devices),
+            evicted_floor: 0,
+        }
+    }
+
+    // Accept a device's nonce if it is greater than any it sent before
+    fn accept(&mut self, device: String, nonce: u64) -> Result<(), String> {
+        let last = self.last.get(&device).copied().unwrap_or(self.evicted_floor);
+        if nonce <= last {
+            return Err(format!("Stale or replayed registration: the nonce must be greater than {}", last));
+        }
+        if let Some(evicted) = self.last.insert(device, nonce) {
+            self.evicted_floor = self.evicted_floor.max(evicted);
+        }
+        Ok(())
+    }
+}
+
+fn tuning() -> Tuning {
+    TUNING.lock().unwrap().clone()
+}
+
+// TEE_SEALING_KEY: base64 of the 32-byte key sealing the tuning file and the command log
+fn sealing_key() -> Result<Option<Key>, String> {
+    let Ok(key) = std::env::var("TEE_SEALING_KEY") else { return Ok(None) };
+    let key = general_purpose::STANDARD.decode(key.trim())
+        .map_err(|e| format!("TEE_SEALING_KEY is not valid base64: {}", e))?;
+    if key.len() != 32 {
+        return Err("TEE_SEALING_KEY must hold 32 bytes".to_string());
+    }
+    Ok(Some(*Key::from_slice(&key)))
+}
+
+// Encrypt under the sealing key with a fresh nonce
+fn seal(key: &Key, plaintext: &[u8]) -> Result<SealedData, String> {
+    let nonce: [u8; 12] = OsRng.gen();
+    let ciphertext = ChaCha20Poly1305::new(key)
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|e| format!("Encryption error: {}", e))?;
+    Ok(SealedData {
         nonce: general_purpose::STANDARD.encode(nonce),
+        ciphertext: general_purpose::STANDARD.encode(ciphertext),
+    })
+}
+
+// Decrypt sealed data, failing if it was sealed with another key or modified
+fn unseal(key: &Key, sealed: &SealedData) -> Result<Vec<u8>, String> {
+    let nonce = general_purpose::STANDARD.decode(&sealed.nonce)
+        .map_err(|e| format!("invalid nonce: {}", e))?;
+    let ciphertext = general_purpose::STANDARD.decode(&sealed.ciphertext)
+        .map_err(|e| format!("invalid ciphertext: {}", e))?;
+    if nonce.len() != 12 {
+        return Err("invalid nonce".to_string());
+    }
+    ChaCha20Poly1305::new(key)
+        .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+        .map_err(|_| "not sealed with TEE_SEALING_KEY, or modified".to_string())
+}
+
+// What is sealed: CBOR with the "cbor" feature, JSON otherwise
+fn encode_sealed<T: Serialize>(value: &T) -> Result<Vec<u8>, String> {
+    #[cfg(feature = "cbor")]
+    let plaintext = oyster_rewards::models::cbor::to_vec(value)?;
+    #[cfg(not(feature = "cbor"))]
+    let plaintext = serde_json::to_vec(value).map_err(|e| format!("Serialization error: {}", e))?;
+    Ok(plaintext)
+}
+
+// JSON plaintexts are objects, so state sealed before the "cbor" feature was turned on still
+// restores; a CBOR map never starts with '{'
+fn decode_sealed<T: DeserializeOwned>(plaintext: &[u8]) -> Result<T, String> {
+    #[cfg(feature = "cbor")]
+    if plaintext.first() != Some(&b'{') {
+        return oyster_rewards::models::cbor::from_slice(plaintext);
+    }
+    serde_json::from_slice(plaintext).map_err(|e| e.to_string())
+}
+
+// The version tag of a sealed log entry or snapshot, read on its own when the rest does not decode
+#[derive(Deserialize)]
+struct VersionTag {
+    #[serde(default)]
+    version: u32,
+}
+
+// Decode a log entry or snapshot, naming the version that sealed it if a newer build did
+fn decode_versioned<T: DeserializeOwned>(plaintext: &[u8]) -> Result<T, String> {
+    decode_sealed(plaintext).map_err(|e| match decode_sealed::<VersionTag>(plaintext) {
+        Ok(VersionTag { version }) if version > PROTOCOL_VERSION => {
+            format!("sealed by protocol version {}, newer than this build's {}: {}", version, PROTOCOL_VERSION, e)
+        },
+        _ => e,
     })
 }
 
-// Function to decrypt location data
-fn decrypt_location(encrypted: &EncryptedLocation) -> Result<Location, String> {
-    // Get the same derived key
-    let key = get_derived_key();
+// Replace a file through a temporary one, so a crash leaves the old or the new contents
+fn replace_file(path: &str, contents: &[u8]) -> Result<(), String> {
+    let temporary = format!("{}.tmp", path);
+    std::fs::write(&temporary, contents)
+        .map_err(|e| format!("Failed to write {}: {}", temporary, e))?;
+    std::fs::rename(&temporary, path)
+        .map_err(|e| format!("Failed to replace {}: {}", path, e))
+}
 
-    // Decode base64 nonce and ciphertext
-    let nonce_bytes = general_purpose::STANDARD.decode(&encrypted.nonce)
-        .map_err(|e| format!("Nonce decoding error: {}", e))?;
-    let ciphertext = general_purpose::STANDARD.decode(&encrypted.enc_data)
-        .map_err(|e| format!("Ciphertext decoding error: {}", e))?;
+impl SealedFile {
+    // TEE_TUNING_FILE needs TEE_SEALING_KEY
+    fn from_env() -> Result<Option<SealedFile>, String> {
+        let Ok(path) = std::env::var("TEE_TUNING_FILE") else { return Ok(None) };
+        match sealing_key()? {
+            Some(key) => Ok(Some(SealedFile { path, key })),
+            None => Err("TEE_TUNING_FILE needs TEE_SEALING_KEY".to_string()),
+        }
+    }
 
-    let nonce = Nonce::from_slice(&nonce_bytes);
+    // The sealed tuning, or None if the file does not exist yet
+    fn read(&self) -> Result<Option<Tuning>, String> {
+        let contents = match std::fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("Failed to read {}: {}", self.path, e)),
+        };
+        let sealed: SealedData = serde_json::from_str(&contents)
+            .map_err(|e| format!("{} is not a sealed tuning file: {}", self.path, e))?;
+        let plaintext = unseal(&self.key, &sealed).map_err(|e| format!("{}: {}", self.path, e))?;
+        let tuning: Tuning = decode_sealed(&plaintext)
+            .map_err(|e| format!("Invalid tuning in {}: {}", self.path, e))?;
+        tuning.validate().map_err(|e| format!("Invalid tuning in {}: {}", self.path, e))?;
+        Ok(Some(tuning))
+    }
 
-    // Create cipher and decrypt
-    let cipher = ChaCha20Poly1305::new(&key);
-    let decrypted = cipher
-        .decrypt(nonce, ciphertext.as_ref())
-        .map_err(|e| format!("Decryption error: {}", e))?;
+    fn write(&self, tuning: &Tuning) -> Result<(), String> {
+        let plaintext = encode_sealed(tuning)?;
+        let sealed = seal(&self.key, &plaintext)?;
+        replace_file(&self.path, &serde_json::to_vec(&sealed).expect("sealed data serializes"))
+    }
+}
 
-    // Deserialize back to Location
-    let location: Location = serde_json::from_slice(&decrypted)
-        .map_err(|e| format!("Deserialization error: {}", e))?;
+impl CommandLog {
+    // Open TEE_COMMAND_LOG, if set. With TEE_STARTUP=replay the snapshot and log found there are
+    // restored first; with TEE_STARTUP=fresh they are discarded. Left unset, the enclave only
+    // starts if there is nothing to restore
+    fn open_from_env() -> Result<Option<String>, String> {
+        let Ok(path) = std::env::var("TEE_COMMAND_LOG") else { return Ok(None) };
+        let key = sealing_key()?.ok_or("TEE_COMMAND_LOG needs TEE_SEALING_KEY")?;
+        let snapshot_entries = match std::env::var("TEE_SNAPSHOT_ENTRIES") {
+            Ok(value) => match value.parse() {
+                Ok(0) | Err(_) => return Err(format!("TEE_SNAPSHOT_ENTRIES must be a positive number, got {}", value)),
+                Ok(entries) => entries,
+            },
+            Err(_) => DEFAULT_SNAPSHOT_ENTRIES,
+        };
+        let _ = USER_HASH_KEY.set(Sha256::new().chain_update(b"tee-rewards user id hash").chain_update(key).finalize().into());
+        
+        let snapshot_path = format!("{}.snapshot", path);
+        let exists = std::path::Path::new(&path).exists() || std::path::Path::new(&snapshot_path).exists();
+        let (seq, entries, message) = match std::env::var("TEE_STARTUP").as_deref() {
+            Ok("replay") => replay(&path, &snapshot_path, &key)?,
+            Ok("fresh") | Err(_) if !exists => (0, 0, format!("Logging state changes to {}", path)),
+            Ok("fresh") => {
+                for file in [&path, &snapshot_path] {
+                    if let Err(e) = std::fs::remove_file(file) {
+                        if e.kind() != io::ErrorKind::NotFound {
+                            return Err(format!("Failed to remove {}: {}", file, e));
+                        }
+                    }
+                }
+                (0, 0, format!("Discarded the earlier state; logging state changes to {}", path))
+            },
+            Err(_) => return Err(format!("{} holds the state of an earlier run; start with TEE_STARTUP=replay to restore it or TEE_STARTUP=fresh to discard it", path)),
+            Ok(mode) => return Err(format!("TEE_STARTUP must be replay or fresh, got {}", mode)),
+        };
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(&path)
+            .map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        let log = CommandLog { path, key, file, seq, entries, snapshot_entries, snapshot_hour: now() / 3600 };
+        let _ = COMMAND_LOG.set(Mutex::new(log));
+        Ok(Some(message))
+    }
+
+    fn append(&mut self, time: u64, record: &LogRecord) -> Result<(), String> {
+        let entry = LogEntryRef { version: PROTOCOL_VERSION, seq: self.seq + 1, time, record };
+        let plaintext = encode_sealed(&entry)?;
+        let line = serde_json::to_string(&seal(&self.key, &plaintext)?).expect("sealed data serializes");
+        self.file.write_all(format!("{}\n", line).as_bytes())
+            .and_then(|_| self.file.flush())
+            .map_err(|e| format!("Failed to write {}: {}", self.path, e))?;
+        self.seq += 1;
+        self.entries += 1;
+        Ok(())
+    }
+
+    // Seal the whole state into the snapshot, then empty the log it replaces. A crash in
+    // between leaves entries the snapshot covers, which replay skips by their sequence number
+    fn snapshot(&mut self) -> Result<(), String> {
+        let locations = LOCATION_HISTORY.iter()
+            .map(|entry| (entry.key().clone(), entry.iter().filter_map(|location| decrypt_location(location).ok()).collect()))
+            .collect();
+        let snapshot = Snapshot { version: PROTOCOL_VERSION, seq: self.seq, locations, state: EnclaveState::take() };
+        let plaintext = encode_sealed(&snapshot);
+        snapshot.state.restore();
+        let plaintext = plaintext?;
+        let sealed = seal(&self.key, &plaintext)?;
+        replace_file(&format!("{}.snapshot", self.path), &serde_json::to_vec(&sealed).expect("sealed data serializes"))?;
+        self.file.set_len(0).map_err(|e| format!("Failed to truncate {}: {}", self.path, e))?;
+        self.entries = 0;
+        self.snapshot_hour = now() / 3600;
+        Ok(())
+    }
+}
+
+// Restore the snapshot, if any, and replay the log entries after it, returning the last
+// sequence number, the entries in the log and a summary. A line cut short by a crash ends the
+// log and is cut off; any other entry that fails to unseal, or a gap in the sequence, stops the
+// enclave, since the state could not be rebuilt faithfully
+fn replay(path: &str, snapshot_path: &str, key: &Key) -> Result<(u64, u64, String), String> {
+    let mut seq = 0;
+    match std::fs::read_to_string(snapshot_path) {
+        Ok(contents) => {
+            let sealed: SealedData = serde_json::from_str(&contents)
+                .map_err(|e| format!("{} is not a sealed snapshot: {}", snapshot_path, e))?;
+            let plaintext = unseal(key, &sealed).map_err(|e| format!("{}: {}", snapshot_path, e))?;
+            let snapshot: Snapshot = decode_versioned(&plaintext)
+                .map_err(|e| format!("Invalid snapshot in {}: {}", snapshot_path, e))?;
+            seq = snapshot.seq;
+            snapshot.state.restore();
+            LOCATION_HISTORY.clear();
+            for (user, locations) in snapshot.locations {
+                let encrypted = locations.iter()
+                    .map(encrypt_location)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| format!("Failed to restore the locations of {}: {}", snapshot_path, e))?;
+                LOCATION_HISTORY.insert(user, encrypted);
+            }
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => return Err(format!("Failed to read {}: {}", snapshot_path, e)),
+    }
+    let snapshot_seq = seq;
+    
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("Failed to read {}: {}", path, e)),
+    };
+    let (mut replayed, mut entries, mut valid_length) = (0, 0, 0);
+    for line in contents.split_inclusive('\n') {
+        let entry = serde_json::from_str::<SealedData>(line.trim_end())
+            .map_err(|e| e.to_string())
+            .and_then(|sealed| unseal(key, &sealed))
+            .and_then(|plaintext| decode_versioned::<LogEntry>(&plaintext));
+        let entry = match entry {
+            Ok(entry) if line.ends_with('\n') => entry,
+            Err(e) if line.ends_with('\n') => return Err(format!("Entry {} of {} cannot be replayed: {}", seq + 1, path, e)),
+            _ => {
+                eprintln!("Cut off an incomplete last entry of {}", path);
+                break;
+            },
+        };
+        valid_length += line.len();
+        entries += 1;
+        if entry.seq <= snapshot_seq {
+            continue;
+        }
+        if entry.seq != seq + 1 {
+            return Err(format!("{} lacks entries {} to {}", path, seq + 1, entry.seq - 1));
+        }
+        seq = entry.seq;
+        compact_if_due(entry.time);
+        match entry.record {
+            LogRecord::Command { tenant, command } => {
+                at_time(entry.time, || process_command(Ok(*command), &tenant));
+            },
+            LogRecord::RewardClaim { tenant, claim_id } => {
+                at_time(entry.time, || settle_reward_claim(claim_id, &tenant));
+            },
+            LogRecord::Tuning(tuning) => apply_tuning(tuning),
+            LogRecord::RewardConfig(config) => *REWARD_CONFIG.lock().unwrap() = config,
+        }
+        replayed += 1;
+    }
+    if valid_length < contents.len() {
+        std::fs::OpenOptions::new().write(true).open(path)
+            .and_then(|file| file.set_len(valid_length as u64))
+            .map_err(|e| format!("Failed to cut off the incomplete entry of {}: {}", path, e))?;
+    }
+    // Clients saw the events when the commands first ran
+    PENDING_EVENTS.lock().unwrap().clear();
+    Ok((seq, entries, format!("Restored {} entries of {} after a snapshot of {} entries", replayed, path, snapshot_seq)))
+}
+
+// Write an entry ahead of the change it records; without a log there is nothing to do
+fn append_log(time: u64, record: &LogRecord) -> Result<(), String> {
+    match COMMAND_LOG.get() {
+        Some(log) => log.lock().unwrap().append(time, record),
+        None => Ok(()),
+    }
+}
+
+// Snapshot the state once the log is long or an hour old
+fn snapshot_if_due() {
+    let Some(log) = COMMAND_LOG.get() else { return };
+    let mut log = log.lock().unwrap();
+    if log.entries >= log.snapshot_entries || (log.entries > 0 && now() / 3600 > log.snapshot_hour) {
+        if let Err(message) = log.snapshot() {
+            eprintln!("Snapshot failed, the log keeps growing: {}", message);
+        }
+    }
+}
+
+// Seconds since the epoch, or the time of the logged command being run
+fn now() -> u64 {
+    match COMMAND_TIME.load(Ordering::Relaxed) {
+        0 => SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        time => time,
+    }
+}
+
+fn at_time<T>(time: u64, run: impl FnOnce() -> T) -> T {
+    COMMAND_TIME.store(time, Ordering::Relaxed);
+    let result = run();
+    COMMAND_TIME.store(0, Ordering::Relaxed);
+    result
+}
+
+// Log a state-changing command, then run it at the logged time
+fn run_logged(command: Command, tenant: &str) -> Response {
+    let time = now();
+    let record = LogRecord::Command { tenant: tenant.to_string(), command: Box::new(command) };
+    let logged = append_log(time, &record);
+    let LogRecord::Command { command, .. } = record else { unreachable!() };
+    if let Err(message) = logged {
+        let message = format!("The change was not made, as it could not be logged: {}", message);
+        return match *command {
+            Command::RegisterGeofence(_) => Response::GeofenceRegistered { success: false, message, geofence: None },
+            _ => registration_failed(message),
+        };
+    }
+    at_time(time, || process_command(Ok(*command), tenant))
+}
+
+impl EnclaveState {
+    // Move the state out of the stores, leaving them empty
+    fn take() -> Self {
+        let limits = memory_limits();
+        EnclaveState {
+            tuning: tuning(),
+            reward_config: REWARD_CONFIG.lock().unwrap().clone(),
+            location_order: std::mem::take(&mut *LOCATION_ORDER.lock().unwrap()),
+            evicted_locations: EVICTED_LOCATIONS.load(Ordering::Relaxed),
+            heatmap: std::mem::replace(&mut *HEATMAP_DATA.lock().unwrap(), LruMap::new(limits.max_heatmap_cells)),
+            device_nonces: std::mem::replace(&mut *DEVICE_NONCES.lock().unwrap(), NonceRegistry::new()),
+            visits: std::mem::replace(&mut *LOCATION_VISITS.lock().unwrap(), LruMap::new(limits.max_visit_cells)),
+            last_compaction_hour: LAST_COMPACTION_HOUR.load(Ordering::Relaxed),
+            visitor_sketches: std::mem::take(&mut *VISITOR_SKETCHES.lock().unwrap()),
+            visitor_days: std::mem::take(&mut *VISITOR_DAYS.lock().unwrap()),
+            reward_ledger: std::mem::take(&mut *REWARD_LEDGER.lock().unwrap()),
+            next_reward_id: *NEXT_REWARD_ID.lock().unwrap(),
+            reward_claims: std::mem::replace(&mut *REWARD_CLAIMS.lock().unwrap(), LruMap::new(limits.max_locations)),
+            next_claim_id: *NEXT_CLAIM_ID.lock().unwrap(),
+            reward_cooldowns: std::mem::take(&mut *REWARD_COOLDOWNS.lock().unwrap()),
+            registration_counts: std::mem::take(&mut *REGISTRATION_COUNTS.lock().unwrap()),
+            geofences: std::mem::take(&mut *GEOFENCES.lock().unwrap()),
+            geofence_presence: std::mem::replace(&mut *GEOFENCE_PRESENCE.lock().unwrap(), LruMap::new(limits.max_devices)),
+            nearby_stations: {
+                let cells: Vec<GridCell> = NEARBY_STATIONS.iter().map(|entry| entry.key().clone()).collect();
+                cells.into_iter().filter_map(|cell| NEARBY_STATIONS.remove(&cell)).collect()
+            },
+            rejected_registrations: REJECTED_REGISTRATIONS.lock().unwrap().clone(),
+        }
+    }
+
+    // Put the state into the stores, under the current memory limits
+    fn restore(self) {
+        let limits = memory_limits();
+        *TUNING.lock().unwrap() = self.tuning;
+        *REWARD_CONFIG.lock().unwrap() = self.reward_config;
+        *LOCATION_ORDER.lock().unwrap() = self.location_order;
+        EVICTED_LOCATIONS.store(self.evicted_locations, Ordering::Relaxed);
+        *HEATMAP_DATA.lock().unwrap() = self.heatmap.with_capacity(limits.max_heatmap_cells);
+        let mut device_nonces = self.device_nonces;
+        device_nonces.last = device_nonces.last.with_capacity(limits.max_devices);
+        *DEVICE_NONCES.lock().unwrap() = device_nonces;
+        *LOCATION_VISITS.lock().unwrap() = self.visits.with_capacity(limits.max_visit_cells);
+        LAST_COMPACTION_HOUR.store(self.last_compaction_hour, Ordering::Relaxed);
+        *VISITOR_SKETCHES.lock().unwrap() = self.visitor_sketches;
+        *VISITOR_DAYS.lock().unwrap() = self.visitor_days;
+        *REWARD_LEDGER.lock().unwrap() = self.reward_ledger;
+        *NEXT_REWARD_ID.lock().unwrap() = self.next_reward_id;
+        *REWARD_CLAIMS.lock().unwrap() = self.reward_claims.with_capacity(limits.max_locations);
+        *NEXT_CLAIM_ID.lock().unwrap() = self.next_claim_id;
+        *REWARD_COOLDOWNS.lock().unwrap() = self.reward_cooldowns;
+        *REGISTRATION_COUNTS.lock().unwrap() = self.registration_counts;
+        *GEOFENCES.lock().unwrap() = self.geofences;
+        *GEOFENCE_PRESENCE.lock().unwrap() = self.geofence_presence.with_capacity(limits.max_devices);
+        NEARBY_STATIONS.clear();
+        for (cell, stations) in self.nearby_stations {
+            NEARBY_STATIONS.insert(cell, stations);
+        }
+        *REJECTED_REGISTRATIONS.lock().unwrap() = self.rejected_registrations;
+    }
+}
+
+// Read the admin token and the sealed tuning file from the environment, loading the tuning
+// sealed by an earlier run
+fn load_admin_settings() -> Result<Option<String>, String> {
+    if let Ok(token) = std::env::var("TEE_ADMIN_TOKEN") {
+        if token.len() < MIN_ADMIN_TOKEN_LENGTH {
+            return Err(format!("TEE_ADMIN_TOKEN must be at least {} characters", MIN_ADMIN_TOKEN_LENGTH));
+        }
+        let _ = ADMIN_TOKEN_HASH.set(Sha256::digest(token.as_bytes()).into());
+    }
+    
+    let Some(sealed) = SealedFile::from_env()? else { return Ok(None) };
+    let message = match sealed.read()? {
+        Some(tuning) => {
+            *TUNING.lock().unwrap() = tuning;
+            Some(format!("Loaded the tuning sealed in {}", sealed.path))
+        },
+        None => None,
+    };
+    let _ = SEALED_TUNING.set(sealed);
+    Ok(message)
+}
+
+// Compare digests, so the comparison takes the same time wherever the tokens differ
+fn admin_token_matches(token: &str) -> bool {
+    let digest: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+    ADMIN_TOKEN_HASH.get().is_some_and(|expected| *expected == digest)
+}
+
+// Change the tuning; the change is sealed before it takes effect, so a restart keeps it
+fn configure(admin_token: String, changes: TuningChanges) -> Response {
+    let rejected = |message: String| Response::Configured { success: false, message, tuning: None };
+    if ADMIN_TOKEN_HASH.get().is_none() {
+        return rejected("Configure is disabled; start the enclave with TEE_ADMIN_TOKEN".to_string());
+    }
+    if !admin_token_matches(&admin_token) {
+        return rejected("Invalid admin token".to_string());
+    }
+    
+    let current = tuning();
+    if changes.is_empty() {
+        return Response::Configured { success: true, message: "Current tuning".to_string(), tuning: Some(current) };
+    }
+    let tuning = match current.with_changes(changes) {
+        Ok(tuning) => tuning,
+        Err(message) => return rejected(message),
+    };
+    if let Err(message) = append_log(now(), &LogRecord::Tuning(tuning.clone())) {
+        return rejected(format!("The tuning was not changed: {}", message));
+    }
+    if let Some(sealed) = SEALED_TUNING.get() {
+        if let Err(message) = sealed.write(&tuning) {
+            return rejected(format!("The tuning was not changed: {}", message));
+        }
+    }
+    apply_tuning(tuning.clone());
+    
+    let mut message = "Tuning updated".to_string();
+    if tuning.grid_size != current.grid_size {
+        message.push_str("; the grid size changed, so heatmap, visit and cooldown data was cleared");
+    }
+    if SEALED_TUNING.get().is_none() {
+        message.push_str("; it is kept in memory only, as no TEE_TUNING_FILE is set");
+    }
+    Response::Configured { success: true, message, tuning: Some(tuning) }
+}
+
+// Cells of different sizes cannot be merged, so the per-cell data starts over with a new grid size
+fn apply_tuning(tuning: Tuning) {
+    let grid_changed = tuning.grid_size != TUNING.lock().unwrap().grid_size;
+    *TUNING.lock().unwrap() = tuning;
+    if grid_changed {
+        clear_cell_data();
+    }
+}
+
+fn clear_cell_data() {
+    HEATMAP_DATA.lock().unwrap().clear();
+    LOCATION_VISITS.lock().unwrap().clear();
+    VISITOR_SKETCHES.lock().unwrap().clear();
+    VISITOR_DAYS.lock().unwrap().clear();
+    REWARD_COOLDOWNS.lock().unwrap().clear();
+}
+
+// Count a registration against the user's hourly limit, failing once it is used up
+fn check_registration_rate(user_id: &str, limit: u32, current_time: u64) -> Result<(), String> {
+    if limit == 0 {
+        return Ok(());
+    }
+    let hour = current_time / 3600;
+    let mut counts = REGISTRATION_COUNTS.lock().unwrap();
+    let (counted_hour, count) = counts.entry(hash_user_id(user_id)).or_insert((hour, 0));
+    if *counted_hour != hour {
+        *counted_hour = hour;
+        *count = 0;
+    }
+    if *count >= limit {
+        return Err(format!("At most {} registrations per hour are accepted; try again later", limit));
+    }
+    *count += 1;
+    Ok(())
+}
+
+// The window_days of an analytics request or of the tuning
+fn check_retention_windows(window_days: &[u64]) -> Result<(), String> {
+    if window_days.is_empty() || window_days.len() > MAX_RETENTION_WINDOWS || window_days.contains(&0) {
+        return Err(format!("window_days must list 1 to {} positive numbers of days", MAX_RETENTION_WINDOWS));
+    }
+    Ok(())
+}
+
+impl VisitCounts {
+    fn record(&mut self, current_time: u64) {
+        let hour = current_time / 3600;
+        match self.hourly.back_mut() {
+            Some((last_hour, visits)) if *last_hour == hour => *visits += 1,
+            _ => self.hourly.push_back((hour, 1)),
+        }
+    }
+
+    // Hourly counts from start_hour up to, not including, end_hour
+    fn in_range(&self, start_hour: u64, end_hour: u64) -> impl Iterator<Item = &(u64, u32)> {
+        self.hourly.iter().filter(move |(hour, _)| (start_hour..end_hour).contains(hour))
+    }
+
+    // Fold the hourly counts older than a week into the weekday totals, returning how many
+    fn compact(&mut self, current_hour: u64) -> usize {
+        let mut folded = 0;
+        while let Some(&(hour, visits)) = self.hourly.front() {
+            if hour + VISIT_HISTORY_HOURS > current_hour {
+                break;
+            }
+            self.weekday_totals[weekday(hour)] += visits;
+            self.hourly.pop_front();
+            folded += 1;
+        }
+        folded
+    }
+}
+
+// Day of week (Monday first) of an hour since the epoch, which fell on a Thursday
+fn weekday(hour: u64) -> usize {
+    ((hour / 24 + 3) % 7) as usize
+}
+
+impl<K: Eq + Hash + Clone, V: Default> LruMap<K, V> {
+    fn new(capacity: usize) -> Self {
+        LruMap {
+            entries: HashMap::new(),
+            capacity,
+            clock: 0,
+            evicted: 0,
+        }
+    }
+
+    // Set the capacity, evicting the entries written least recently that no longer fit
+    fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        let mut excess: Vec<(K, u64)> = self.entries.iter().map(|(key, (_, last_written))| (key.clone(), *last_written)).collect();
+        excess.sort_by_key(|(_, last_written)| *last_written);
+        excess.truncate(self.entries.len().saturating_sub(capacity));
+        for (key, _) in excess {
+            self.entries.remove(&key);
+            self.evicted += 1;
+        }
+        self
+    }
+
+    // The value of a key, created if missing, marked as the most recently written
+    fn entry(&mut self, key: K) -> &mut V {
+        self.make_room(&key);
+        self.clock += 1;
+        let entry = self.entries.entry(key).or_insert_with(|| (V::default(), 0));
+        entry.1 = self.clock;
+        &mut entry.0
+    }
+
+    // Set the value of a key, returning the value evicted to make room for it, if any
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let evicted = self.make_room(&key);
+        self.clock += 1;
+        self.entries.insert(key, (value, self.clock));
+        evicted
+    }
+
+    // Evict the entry written least recently if the key is new and the map is full
+    fn make_room(&mut self, key: &K) -> Option<V> {
+        if self.entries.contains_key(key) || self.entries.len() < self.capacity {
+            return None;
+        }
+        let oldest = self.entries.iter()
+            .min_by_key(|(_, (_, last_written))| *last_written)
+            .map(|(key, _)| key.clone())?;
+        self.evicted += 1;
+        self.entries.remove(&oldest).map(|(value, _)| value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        self.entries.remove(key).map(|(value, _)| value)
+    }
+
+    // Reading does not count as a use
+    fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|(value, _)| value)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // Emptying the map does not count as evicting
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.values().map(|(value, _)| value)
+    }
+
+    // Changing values does not count as a use
+    fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.entries.values_mut().map(|(value, _)| value)
+    }
+}
+
+impl HyperLogLog {
+    fn new() -> Self {
+        HyperLogLog { registers: vec![0; 1 << HLL_PRECISION] }
+    }
+
+    fn insert(&mut self, hash: u64) {
+        let index = (hash >> (64 - HLL_PRECISION)) as usize;
+        // Position of the first set bit after the index bits; the sentinel bit caps the rank
+        let rest = (hash << HLL_PRECISION) | (1 << (HLL_PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        self.registers[index] = self.registers[index].max(rank);
+    }
+
+    fn merge(&mut self, other: &HyperLogLog) {
+        for (register, &value) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(value);
+        }
+    }
+
+    fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&register| 2f64.powi(-(register as i32))).sum();
+        let raw = alpha * m * m / sum;
+
+        // Linear counting is more accurate while many registers are still empty
+        let zeros = self.registers.iter().filter(|&&register| register == 0).count();
+        if raw <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        }
+    }
+}
+
+// Tenant ids are short and cannot contain the '/' separating them from user ids
+fn check_tenant_id(tenant: &str) -> Result<(), String> {
+    check_id("tenant_id", tenant)
+}
+
+fn check_id(name: &str, id: &str) -> Result<(), String> {
+    if id.is_empty() || id.len() > MAX_TENANT_ID_LENGTH
+        || !id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+        return Err(format!("{} must be 1 to {} letters, digits, '-', '_' or '.'", name, MAX_TENANT_ID_LENGTH));
+    }
+    Ok(())
+}
+
+// Key of a user's locations and rewards within a tenant
+fn tenant_user(tenant: &str, user_id: &str) -> String {
+    format!("{}/{}", tenant, user_id)
+}
+
+// Hash a user id with the TEE secret so sketches cannot be matched against known ids
+fn hash_user_id(user_id: &str) -> u64 {
+    let mut hasher = Sha256::new();
+    match USER_HASH_KEY.get() {
+        Some(key) => hasher.update(key),
+        None => hasher.update(get_derived_key()),
+    }
+    hasher.update(user_id.as_bytes());
+    let digest = hasher.finalize();
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+// Add a visitor to the sketches of the current hour and day, dropping expired ones
+fn record_visitor(cell: &TenantCell, user_id: &str, current_time: u64) {
+    let (hour, day) = (current_time / 3600, current_time / (24 * 3600));
+    let mut sketches = VISITOR_SKETCHES.lock().unwrap();
+    let cell_sketches = sketches.entry(cell.clone()).or_default();
+    cell_sketches.prune(current_time);
+    for (sketches, period) in [(&mut cell_sketches.hourly, hour), (&mut cell_sketches.daily, day)] {
+        if sketches.last().map(|(sketch_period, _)| *sketch_period) != Some(period) {
+            sketches.push((period, HyperLogLog::new()));
+        }
+        sketches.last_mut().unwrap().1.insert(hash_user_id(user_id));
+    }
+    drop(sketches);
+
+    let mut days = VISITOR_DAYS.lock().unwrap();
+    let seen = days.entry(cell.clone()).or_default()
+        .entry(hash_user_id(user_id))
+        .or_insert((day, day));
+    seen.1 = seen.1.max(day);
+}
+
+// New and returning visitors of a cell for each window ending today
+fn retention_metrics(cell: &TenantCell, window_days: &[u64], current_time: u64) -> Vec<RetentionMetrics> {
+    let today = current_time / (24 * 3600);
+    let days = VISITOR_DAYS.lock().unwrap();
+    let visitors = days.get(cell);
+
+    window_days.iter()
+        .map(|&window| {
+            let window_start = (today + 1).saturating_sub(window);
+            let (mut new_visitors, mut returning_visitors) = (0, 0);
+            for &(first_day, last_day) in visitors.into_iter().flat_map(|visitors| visitors.values()) {
+                if last_day < window_start {
+                    continue;
+                }
+                if first_day < last_day {
+                    returning_visitors += 1;
+                } else {
+                    new_visitors += 1;
+                }
+            }
+            let total = new_visitors + returning_visitors;
+            RetentionMetrics {
+                window_days: window,
+                visitors: total,
+                new_visitors,
+                returning_visitors,
+                return_rate: if total > 0 { returning_visitors as f64 / total as f64 } else { 0.0 },
+            }
+        })
+        .collect()
+}
+
+// Estimated distinct visitors of a cell over the hourly sketches of the last day
+fn unique_visitors(cell: &TenantCell, start_hour: u64, end_hour: u64, current_time: u64) -> u32 {
+    let sketches = VISITOR_SKETCHES.lock().unwrap();
+    let Some(cell_sketches) = sketches.get(cell) else { return 0 };
+    let hourly_start = (current_time / 3600 + 1).saturating_sub(VISITOR_SKETCH_HOURS);
+    let mut merged = HyperLogLog::new();
+    for (_, sketch) in cell_sketches.hourly.iter()
+        .filter(|(hour, _)| *hour >= start_hour.max(hourly_start) && *hour < end_hour)
+    {
+        merged.merge(sketch);
+    }
+    // The part of the range before the hourly sketches, by the days it touches
+    let daily_end = end_hour.min(hourly_start);
+    if start_hour < daily_end {
+        let days = start_hour / 24..=(daily_end - 1) / 24;
+        for (_, sketch) in cell_sketches.daily.iter().filter(|(day, _)| days.contains(day)) {
+            merged.merge(sketch);
+        }
+    }
+    merged.estimate().round() as u32
+}
+
+impl VisitorSketches {
+    // Drop the sketches older than a day (hourly) or a week (daily)
+    fn prune(&mut self, current_time: u64) -> usize {
+        let (hour, day) = (current_time / 3600, current_time / (24 * 3600));
+        let count = self.hourly.len() + self.daily.len();
+        self.hourly.retain(|(sketch_hour, _)| sketch_hour + VISITOR_SKETCH_HOURS > hour);
+        self.daily.retain(|(sketch_day, _)| sketch_day + VISITOR_SKETCH_DAYS > day);
+        count - self.hourly.len() - self.daily.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.hourly.is_empty() && self.daily.is_empty()
+    }
+}
+
+// Whole hours [start, end) covered by an analytics request, shortened to the kept history
+fn analytics_range(start_time: Option<String>, end_time: Option<String>, current_time: u64) -> Result<(u64, u64, Vec<AnalyticsWarning>), String> {
+    let parse = |field: &str, value: String| timestamp::parse(&value)
+        .map(|time| time.timestamp().max(0) as u64)
+        .ok_or_else(|| format!("{} must be an RFC 3339 time or Unix seconds", field));
+    let end = match end_time {
+        Some(end_time) => parse("end_time", end_time)?.min(current_time),
+        None => current_time,
+    };
+    let start = match start_time {
+        Some(start_time) => parse("start_time", start_time)?,
+        None => (end.div_ceil(3600) * 3600).saturating_sub(DEFAULT_ANALYTICS_HOURS * 3600),
+    };
+    if start >= end {
+        return Err("start_time must be before end_time and the present".to_string());
+    }
+    
+    let mut warnings = Vec::new();
+    let (mut start_hour, end_hour) = (start / 3600, end.div_ceil(3600));
+    let history_start = (current_time / 3600 + 1).saturating_sub(VISIT_HISTORY_HOURS);
+    if start_hour < history_start {
+        start_hour = history_start.min(end_hour);
+        warnings.push(AnalyticsWarning {
+            code: AnalyticsWarningCode::RangeTruncated,
+            skipped_records: 0,
+            message: format!("Visits are kept by hour for {} days; the range starts at the oldest kept hour", VISIT_HISTORY_HOURS / 24),
+        });
+    }
+    Ok((start_hour, end_hour, warnings))
+}
+
+fn hour_start(hour: u64) -> DateTime<Utc> {
+    Utc.timestamp_opt((hour * 3600) as i64, 0).single().unwrap_or_default()
+}
+
+// Check that a heatmap box is valid and does not cover too many grid cells
+fn check_heatmap_bounds(bounds: &BoundingBox) -> Result<(), String> {
+    bounds.validate()?;
+    let grid_size = tuning().grid_size;
+    let lat_cells = (bounds.lat_span() / grid_size).ceil() as u64;
+    let lon_cells = (bounds.lon_span() / grid_size).ceil() as u64;
+    if lat_cells.saturating_mul(lon_cells) > MAX_HEATMAP_GRID_CELLS {
+        return Err(format!("Bounding box covers more than {} grid cells", MAX_HEATMAP_GRID_CELLS));
+    }
+    Ok(())
+}
+
+// Register a new location
+fn register_location(location: Location, nonce: u64, tenant: &str) -> Response {
+    let tuning = tuning();
+    let user = tenant_user(tenant, &location.user_id);
+    let current_time = now();
+    // Before the rate limit, so replaying a user's registrations cannot use up their allowance
+    if let Err(message) = DEVICE_NONCES.lock().unwrap().accept(tenant_user(tenant, &location.device_id), nonce) {
+        REJECTED_REGISTRATIONS.lock().unwrap().replayed += 1;
+        return registration_failed(message);
+    }
+    if let Err(message) = check_registration_rate(&user, tuning.registrations_per_hour, current_time) {
+        REJECTED_REGISTRATIONS.lock().unwrap().rate_limited += 1;
+        return registration_failed(message);
+    }
+    
+    // First, verify the location is legitimate, at the logged time when replaying
+    let verified_at = Utc.timestamp_opt(current_time as i64, 0).single().unwrap_or_else(Utc::now);
+    if !verify_location_with(&location, &tuning.verification, verified_at) {
+        REJECTED_REGISTRATIONS.lock().unwrap().verification_failed += 1;
+        return registration_failed("Location verification failed. Possible spoofing detected.".to_string());
+    }
+    let location = Location { floor_level: floor_level(&location, verified_at), ..location };
+
+    // Encrypt the location
+    match encrypt_location(&location) {
+        Ok(encrypted) => {
+            // Store in our in-memory database
+            store_location(&user, encrypted.clone());
+            
+            // Update heatmap data
+            let grid_cell = GridCell::from_location(location.lat, location.lon, tuning.grid_size);
+            let cell = (tenant.to_string(), grid_cell.clone());
+            let mut heatmap = HEATMAP_DATA.lock().unwrap();
+            let cell_count = heatmap.entry(cell.clone());
+            let earlier_visits = *cell_count;
+            *cell_count += 1;
+            drop(heatmap);
+            
+            // Update visit analytics
+            let mut visits = LOCATION_VISITS.lock().unwrap();
+            visits.entry(cell.clone()).record(current_time);
+            record_visitor(&cell, &user, current_time);
+            
+            // The reward is credited once the client claims it with the location id
+            let config = REWARD_CONFIG.lock().unwrap().clone();
+            let reward_cooldown_seconds = reward_cooldown_remaining(&grid_cell, &user, current_time, config.cooldown_seconds);
+            let mut next_claim_id = NEXT_CLAIM_ID.lock().unwrap();
+            *next_claim_id += 1;
+            REWARD_CLAIMS.lock().unwrap().insert(*next_claim_id, RewardClaim {
+                tenant: tenant.to_string(),
+                user: user.clone(),
+                location_id: encrypted.enc_data.clone(),
+                grid_cell,
+                earlier_visits,
+                registered_at: current_time,
+            });
+            drop(next_claim_id);
+            
+            Response::LocationRegistered {
+                enc_location: encrypted.enc_data,
+                success: true,
+                message: "Location registered successfully.".to_string(),
+                reward_cooldown_seconds,
+                daily_remaining: daily_remaining(&user, current_time, config.daily_cap),
+                geofence_events: update_geofence_presence(tenant, &location, current_time),
+            }
+        },
+        Err(e) => {
+            REJECTED_REGISTRATIONS.lock().unwrap().encryption_failed += 1;
+            registration_failed(format!("Encryption failed: {}", e))
+        },
+    }
+}
+
+fn registration_failed(message: String) -> Response {
+    Response::LocationRegistered {
+        enc_location: String::new(),
+        success: false,
+        message,
+        reward_cooldown_seconds: 0,
+        daily_remaining: 0,
+        geofence_events: Vec::new(),
+    }
+}
+
+fn claim_failed(message: String, reward_cooldown_seconds: u64, daily_remaining: u64) -> Response {
+    Response::RewardClaimed { success: false, message, reward: None, reward_cooldown_seconds, daily_remaining }
+}
+
+// Find the claim of a registration of the tenant and settle it, logging the claim it resolved to
+fn claim_reward(location_id: &str, tenant: &str) -> Response {
+    let claim_id = REWARD_CLAIMS.lock().unwrap().entries.iter()
+        .find(|(_, (claim, _))| claim.location_id == location_id && claim.tenant == tenant)
+        .map(|(&claim_id, _)| claim_id);
+    let Some(claim_id) = claim_id else {
+        return claim_failed("No unclaimed reward for this location".to_string(), 0, 0);
+    };
+    let time = now();
+    if let Err(message) = append_log(time, &LogRecord::RewardClaim { tenant: tenant.to_string(), claim_id }) {
+        return claim_failed(format!("The reward was not claimed, as the claim could not be logged: {}", message), 0, 0);
+    }
+    at_time(time, || settle_reward_claim(claim_id, tenant))
+}
+
+// Credit a claimed reward if the registration still stands, the cell's cooldown has passed and
+// the daily cap allows it; a refused claim may be tried again within the claim window
+fn settle_reward_claim(claim_id: u64, tenant: &str) -> Response {
+    let current_time = now();
+    let config = REWARD_CONFIG.lock().unwrap().clone();
+    let mut claims = REWARD_CLAIMS.lock().unwrap();
+    let Some(claim) = claims.get(&claim_id).filter(|claim| claim.tenant == tenant) else {
+        return claim_failed("No unclaimed reward for this location".to_string(), 0, 0);
+    };
+    let remaining_today = daily_remaining(&claim.user, current_time, config.daily_cap);
+    if current_time.saturating_sub(claim.registered_at) > REWARD_CLAIM_WINDOW_SECONDS {
+        claims.remove(&claim_id);
+        return claim_failed(format!("Rewards must be claimed within {} hours of the registration", REWARD_CLAIM_WINDOW_SECONDS / 3600), 0, remaining_today);
+    }
+    // The location must still be stored, and decrypt to the claiming user
+    let registered = LOCATION_HISTORY.get(&claim.user)
+        .and_then(|locations| locations.iter().find(|location| location.enc_data == claim.location_id).cloned())
+        .and_then(|encrypted| decrypt_location(&encrypted).ok())
+        .is_some_and(|location| tenant_user(tenant, &location.user_id) == claim.user);
+    if !registered {
+        claims.remove(&claim_id);
+        return claim_failed("The registration of this reward is no longer stored".to_string(), 0, remaining_today);
+    }
+    if remaining_today == 0 {
+        return claim_failed("The daily reward cap is reached".to_string(), 0, 0);
+    }
+    if let Err(remaining) = start_reward_cooldown(&claim.grid_cell, &claim.user, current_time, config.cooldown_seconds) {
+        return claim_failed("This cell rewarded the user recently".to_string(), remaining, remaining_today);
+    }
+    
+    // Rarely visited cells earn more
+    let tier = config.tier_for(claim.earlier_visits);
+    let points = (tier.points as f64 * config.multiplier).round() as u64;
+    let reason = format!("{} {} location reward", tier.emoji, tier.name);
+    let Some(entry) = credit_reward(&claim.user, points, &reason, current_time, config.daily_cap) else {
+        return claim_failed("The daily reward cap is reached".to_string(), 0, 0);
+    };
+    let claim = claims.remove(&claim_id).expect("the claim was found above");
+    let grant = RewardGrant {
+        reward_id: entry.id,
+        tenant_id: claim.tenant,
+        user_id: claim.user[tenant.len() + 1..].to_string(),
+        location_id: claim.location_id,
+        points: entry.points,
+        timestamp: entry.timestamp,
+    };
+    Response::RewardClaimed {
+        success: true,
+        message: "Reward claimed.".to_string(),
+        reward: Some(SignedReward { user_id: grant.user_id.clone(), entry, token: sign_reward(&grant) }),
+        reward_cooldown_seconds: config.cooldown_seconds,
+        daily_remaining: daily_remaining(&claim.user, current_time, config.daily_cap),
+    }
+}
 
-    Ok(location)
+fn sign_reward(grant: &RewardGrant) -> String {
+    let payload = serde_json::to_vec(grant).expect("grants serialize");
+    let signature = REWARD_SIGNING_KEY.sign(signing_context(REWARD_CONTEXT).bytes(&payload));
+    format!(
+        "{}.{}",
+        general_purpose::URL_SAFE_NO_PAD.encode(&payload),
+        general_purpose::URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+    )
 }
 
-// Verify the legitimacy of a location based on sensor data
-fn verify_location(location: &Location) -> bool {
-    // Check for mock location flag from the device
-    if location.sensors.is_mock_location {
-        return false;
+fn register_geofence(geofence: Geofence, tenant: &str) -> Response {
+    let failed = |message: String| Response::GeofenceRegistered { success: false, message, geofence: None };
+    if let Err(message) = check_id("geofence id", &geofence.id) {
+        return failed(message);
     }
-
-    // Check for sensor presence (a real device should have these sensors)
-    if location.sensors.accelerometer.is_none() || location.sensors.gyroscope.is_none() {
-        return false;
+    if geofence.name.len() > MAX_GEOFENCE_NAME_LENGTH {
+        return failed(format!("Geofence names are at most {} bytes", MAX_GEOFENCE_NAME_LENGTH));
+    }
+    if !(MIN_GEOFENCE_RADIUS_METERS..=MAX_GEOFENCE_RADIUS_METERS).contains(&geofence.radius_meters) {
+        return failed(format!("Geofence radii must be {} to {} meters", MIN_GEOFENCE_RADIUS_METERS, MAX_GEOFENCE_RADIUS_METERS));
     }
-
-    // If we have previously observed WiFi networks or cell towers in this area,
-    // check that at least some of them match
-    let grid_cell = GridCell::from_location(location.lat, location.lon);
-    let stations = NEARBY_STATIONS.lock().unwrap();
     
-    if let Some(expected_stations) = stations.get(&grid_cell) {
-        if !expected_stations.is_empty() {
-            // Count how many WiFi networks match
-            let wifi_matches = location.sensors.wifi_networks.iter()
-                .filter(|network| {
-                    expected_stations.iter()
-                        .filter(|station| station.station_type == StationType::Wifi)
-                        .any(|station| station.id == network.bssid)
-                })
-                .count();
-                
-            // Count how many cell towers match
-            let cell_matches = location.sensors.cell_towers.iter()
-                .filter(|tower| {
-                    expected_stations.iter()
-                        .filter(|station| station.station_type == StationType::CellTower)
-                        .any(|station| station.id == tower.cell_id)
-                })
-                .count();
-                
-            // Require a minimum percentage of matches to consider it valid
-            let total_expected = expected_stations.len();
-            let total_matched = wifi_matches + cell_matches;
-            
-            if total_expected > 0 && (total_matched as f32 / total_expected as f32) < 0.3 {
-                return false;
-            }
-        }
+    let mut geofences = GEOFENCES.lock().unwrap();
+    let tenant_geofences = geofences.entry(tenant.to_string()).or_default();
+    if tenant_geofences.len() >= MAX_GEOFENCES && !tenant_geofences.contains_key(&geofence.id) {
+        return failed(format!("A tenant may define at most {} geofences", MAX_GEOFENCES));
+    }
+    let replaced = tenant_geofences.insert(geofence.id.clone(), geofence.clone()).is_some();
+    Response::GeofenceRegistered {
+        success: true,
+        message: if replaced { "Geofence replaced." } else { "Geofence registered." }.to_string(),
+        geofence: Some(geofence),
     }
+}
+
+fn list_geofences(tenant: &str) -> Response {
+    let geofences = GEOFENCES.lock().unwrap();
+    Response::Geofences(geofences.get(tenant).map(|tenant_geofences| tenant_geofences.values().cloned().collect()).unwrap_or_default())
+}
+
+// Compare the tenant's geofences containing a user's new location with those containing the
+// last one, and raise an event for every geofence entered or left. The events are also queued
+// for the framed protocol's event stream
+fn update_geofence_presence(tenant: &str, location: &Location, current_time: u64) -> Vec<GeofenceEvent> {
+    let geofences = GEOFENCES.lock().unwrap();
+    let tenant_geofences = geofences.get(tenant);
+    let inside: Vec<&Geofence> = tenant_geofences.into_iter()
+        .flat_map(|tenant_geofences| tenant_geofences.values())
+        .filter(|geofence| Coordinate::new(geofence.lat, geofence.lon).distance(location.coordinate()).0 <= geofence.radius_meters)
+        .collect();
+    let user = tenant_user(tenant, &location.user_id);
+    let mut presence = GEOFENCE_PRESENCE.lock().unwrap();
+    let was_inside = presence.get(&user).cloned().unwrap_or_default();
     
-    // Store observed stations for future verification
-    let mut stations = NEARBY_STATIONS.lock().unwrap();
-    let mut new_stations = Vec::new();
+    let event = |geofence_id: &str, transition| GeofenceEvent {
+        geofence_id: geofence_id.to_string(),
+        geofence_name: tenant_geofences.and_then(|tenant_geofences| tenant_geofences.get(geofence_id))
+            .map(|geofence| geofence.name.clone())
+            .unwrap_or_default(),
+        transition,
+        user_id: location.user_id.clone(),
+        timestamp: current_time,
+    };
+    let mut events: Vec<GeofenceEvent> = was_inside.iter()
+        .filter(|id| !inside.iter().any(|geofence| &geofence.id == *id))
+        .map(|id| event(id, GeofenceTransition::Exit))
+        .collect();
+    events.extend(inside.iter()
+        .filter(|geofence| !was_inside.contains(&geofence.id))
+        .map(|geofence| event(&geofence.id, GeofenceTransition::Enter)));
     
-    // Add WiFi networks
-    for network in &location.sensors.wifi_networks {
-        new_stations.push(Station {
-            id: network.bssid.clone(),
-            lat: location.lat,
-            lon: location.lon,
-            station_type: StationType::Wifi,
-            signal_strength: network.signal_strength,
-        });
+    if inside.is_empty() {
+        presence.remove(&user);
+    } else {
+        presence.insert(user, inside.iter().map(|geofence| geofence.id.clone()).collect());
     }
+    PENDING_EVENTS.lock().unwrap().extend(events.iter().map(|event| (tenant.to_string(), Event::Geofence(event.clone()))));
+    events
+}
+
+// Keep a user's encrypted location (keyed by tenant_user), evicting the oldest stored location of any user once the
+// store is full
+fn store_location(user_id: &str, encrypted: EncryptedLocation) {
+    let mut order = LOCATION_ORDER.lock().unwrap();
+    LOCATION_HISTORY.entry(user_id.to_string()).or_default().push(encrypted);
+    order.push_back(user_id.to_string());
     
-    // Add cell towers
-    for tower in &location.sensors.cell_towers {
-        new_stations.push(Station {
-            id: tower.cell_id.clone(),
-            lat: location.lat,
-            lon: location.lon,
-            station_type: StationType::CellTower,
-            signal_strength: tower.signal_strength,
-        });
+    while order.len() > memory_limits().max_locations {
+        let Some(owner) = order.pop_front() else { break };
+        // Each user's locations are stored oldest first
+        if let Some(mut user_locations) = LOCATION_HISTORY.get_mut(&owner) {
+            user_locations.remove(0);
+        }
+        LOCATION_HISTORY.remove_if(&owner, |_, user_locations| user_locations.is_empty());
+        EVICTED_LOCATIONS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Time left of the user's cooldown in the cell, 0 if none is running
+fn reward_cooldown_remaining(grid_cell: &GridCell, user_id: &str, current_time: u64, cooldown_seconds: u64) -> u64 {
+    REWARD_COOLDOWNS.lock().unwrap().get(&(hash_user_id(user_id), grid_cell.clone()))
+        .map(|&last_rewarded| cooldown_seconds.saturating_sub(current_time.saturating_sub(last_rewarded)))
+        .unwrap_or(0)
+}
+
+// Start the user's cooldown in the cell (the user being keyed by tenant_user), returning its length, or the time left of a running one
+fn start_reward_cooldown(grid_cell: &GridCell, user_id: &str, current_time: u64, cooldown_seconds: u64) -> Result<u64, u64> {
+    let mut cooldowns = REWARD_COOLDOWNS.lock().unwrap();
+    let key = (hash_user_id(user_id), grid_cell.clone());
+    if let Some(&last_rewarded) = cooldowns.get(&key) {
+        let elapsed = current_time.saturating_sub(last_rewarded);
+        if elapsed < cooldown_seconds {
+            return Err(cooldown_seconds - elapsed);
+        }
     }
     
-    // Store the stations
-    stations.insert(grid_cell, new_stations);
-    
-    true
+    // Expired cooldowns no longer matter
+    cooldowns.retain(|_, last_rewarded| current_time.saturating_sub(*last_rewarded) < cooldown_seconds);
+    cooldowns.insert(key, current_time);
+    Ok(cooldown_seconds)
 }
 
-// Register a new location
-fn register_location(location: Location) -> Response {
-    // First, verify the location is legitimate
-    if !verify_location(&location) {
-        return Response::LocationRegistered {
-            enc_location: String::new(),
-            success: false,
-            message: "Location verification failed. Possible spoofing detected.".to_string(),
-        };
+// Read the reward campaign file named at startup again (admin); the active campaign stays on
+// any error. Other paths are refused, so the command reads no file the operator did not name
+fn reload_config(admin_token: String, path: Option<String>) -> Response {
+    let rejected = |message: String| Response::Message { success: false, message };
+    if ADMIN_TOKEN_HASH.get().is_none() {
+        return rejected("ReloadConfig is disabled; start the enclave with TEE_ADMIN_TOKEN".to_string());
     }
+    if !admin_token_matches(&admin_token) {
+        return rejected("Invalid admin token".to_string());
+    }
+    let Some(startup_path) = REWARD_CONFIG_PATH.get() else {
+        return rejected("No reward config file was loaded at startup (REWARD_CONFIG)".to_string());
+    };
+    if path.is_some_and(|path| path != *startup_path) {
+        return rejected(format!("Only the reward config loaded at startup, {}, can be reloaded", startup_path));
+    }
+    load_reward_config(startup_path)
+}
 
-    // Encrypt the location
-    match encrypt_location(&location) {
-        Ok(encrypted) => {
-            // Store in our in-memory database
-            let mut locations = LOCATION_HISTORY.lock().unwrap();
-            let user_locations = locations.entry(location.user_id.clone()).or_insert_with(Vec::new);
-            user_locations.push(encrypted.clone());
-            
-            // Update heatmap data
-            let grid_cell = GridCell::from_location(location.lat, location.lon);
-            let mut heatmap = HEATMAP_DATA.lock().unwrap();
-            *heatmap.entry(grid_cell.clone()).or_insert(0) += 1;
-            
-            // Update visit analytics
-            let current_time = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            
-            let mut visits = LOCATION_VISITS.lock().unwrap();
-            let cell_visits = visits.entry(grid_cell).or_insert_with(Vec::new);
-            cell_visits.push(current_time);
-            
-            Response::LocationRegistered {
-                enc_location: encrypted.enc_data,
+// Replace the reward campaign with the one in a TOML file; the active one stays on any error
+fn load_reward_config(path: &str) -> Response {
+    match RewardConfig::from_toml_file(path) {
+        Ok(config) => {
+            if let Err(message) = append_log(now(), &LogRecord::RewardConfig(config.clone())) {
+                return Response::Message { success: false, message: format!("The reward config was not changed: {}", message) };
+            }
+            let tiers = config.tiers.len();
+            *REWARD_CONFIG.lock().unwrap() = config;
+            Response::Message {
                 success: true,
-                message: "Location registered successfully.".to_string(),
+                message: format!("Loaded {} reward tiers from {}", tiers, path),
             }
         },
-        Err(e) => {
-            Response::LocationRegistered {
-                enc_location: String::new(),
-                success: false,
-                message: format!("Encryption failed: {}", e),
-            }
-        }
+        Err(error) => Response::Message { success: false, message: error },
     }
 }
 
-// Get a decrypted location (only used within the TEE)
-fn get_location(encrypted_data: String) -> Response {
-    // Find the encrypted location entry
-    let locations = LOCATION_HISTORY.lock().unwrap();
+// Add earned points to a user's ledger
+fn credit_reward(user_id: &str, points: u64, reason: &str, current_time: u64, daily_cap: u64) -> Option<RewardEntry> {
+    let mut ledger = REWARD_LEDGER.lock().unwrap();
+    let account = ledger.entry(user_id.to_string()).or_default();
     
-    // Search through all users' locations
-    let mut found_encrypted = None;
-    for user_locations in locations.values() {
-        for encrypted in user_locations {
-            if encrypted.enc_data == encrypted_data {
-                found_encrypted = Some(encrypted.clone());
-                break;
-            }
-        }
-        if found_encrypted.is_some() {
-            break;
-        }
+    // Only what is left of today's cap is paid out
+    let today = current_time / 86400;
+    if account.earned_day != today {
+        account.earned_day = today;
+        account.earned_today = 0;
     }
+    let credited = points.min(daily_cap.saturating_sub(account.earned_today));
+    if credited == 0 {
+        return None;
+    }
+    account.earned_today += credited;
+    account.earned += credited;
     
-    // If found, decrypt it
-    if let Some(encrypted) = found_encrypted {
-        match decrypt_location(&encrypted) {
-            Ok(location) => {
-                Response::LocationData {
-                    location: Some(location),
-                    success: true,
-                    message: "Location retrieved successfully.".to_string(),
-                }
-            },
-            Err(e) => {
-                Response::LocationData {
-                    location: None,
-                    success: false,
-                    message: format!("Decryption failed: {}", e),
-                }
-            }
-        }
-    } else {
-        Response::LocationData {
+    let mut next_id = NEXT_REWARD_ID.lock().unwrap();
+    *next_id += 1;
+    let entry = RewardEntry {
+        id: *next_id,
+        kind: "earned".to_string(),
+        points: credited,
+        reason: if credited < points { format!("{} (capped at the daily limit)", reason) } else { reason.to_string() },
+        balance_after: account.earned - account.spent,
+        timestamp: current_time,
+    };
+    account.entries.push(entry.clone());
+    Some(entry)
+}
+
+// Points the user may still earn on the day of `current_time`
+fn daily_remaining(user_id: &str, current_time: u64, daily_cap: u64) -> u64 {
+    let earned_today = REWARD_LEDGER.lock().unwrap()
+        .get(user_id)
+        .filter(|account| account.earned_day == current_time / 86400)
+        .map(|account| account.earned_today)
+        .unwrap_or(0);
+    daily_cap.saturating_sub(earned_today)
+}
+
+// Balance and one page of a user's reward entries, newest first
+fn get_reward_history(user_id: String, offset: usize, limit: Option<usize>, tenant: &str) -> Response {
+    let limit = limit.unwrap_or(DEFAULT_REWARD_HISTORY_PAGE).clamp(1, MAX_REWARD_HISTORY_PAGE);
+    let current_time = now();
+    let user = tenant_user(tenant, &user_id);
+    let daily_remaining = daily_remaining(&user, current_time, REWARD_CONFIG.lock().unwrap().daily_cap);
+    let ledger = REWARD_LEDGER.lock().unwrap();
+    let (earned, spent, entries) = match ledger.get(&user) {
+        Some(account) => (account.earned, account.spent, account.entries.as_slice()),
+        None => (0, 0, &[][..]),
+    };
+    
+    Response::RewardHistory(RewardHistoryResponse {
+        earned,
+        spent,
+        balance: earned - spent,
+        daily_remaining,
+        entries: entries.iter().rev().skip(offset).take(limit).cloned().collect(),
+        total: entries.len(),
+        offset,
+        limit,
+        user_id,
+    })
+}
+
+// Get a decrypted location (only used within the TEE)
+// Only the tenant's own users' locations are searched
+fn get_location(encrypted_data: String, tenant: &str) -> Response {
+    let prefix = tenant_user(tenant, "");
+    let found = LOCATION_HISTORY.iter()
+        .filter(|entry| entry.key().starts_with(&prefix))
+        .find_map(|entry| entry.iter().find(|location| location.enc_data == encrypted_data).cloned());
+    let result = match found {
+        Some(encrypted) => decrypt_location(&encrypted),
+        None => Err("Location not found".to_string()),
+    };
+    match result {
+        Ok(location) => Response::LocationData {
+            location: Some(location),
+            success: true,
+            message: "Location retrieved successfully.".to_string(),
+        },
+        Err(message) => Response::LocationData {
             location: None,
             success: false,
-            message: "Location not found.".to_string(),
-        }
+            message,
+        },
     }
 }
 
 // Generate a heatmap for a specific area
-fn generate_heatmap(min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64) -> Response {
-    let min_lat_grid = (min_lat / GRID_SIZE).floor() as i32;
-    let min_lon_grid = (min_lon / GRID_SIZE).floor() as i32;
-    let max_lat_grid = (max_lat / GRID_SIZE).ceil() as i32;
-    let max_lon_grid = (max_lon / GRID_SIZE).ceil() as i32;
+fn generate_heatmap(min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64, tenant: &str) -> Response {
+    let (min, max) = match (Coordinate::from_degrees(min_lat, min_lon), Coordinate::from_degrees(max_lat, max_lon)) {
+        (Ok(min), Ok(max)) => (min, max),
+        (Err(error), _) | (_, Err(error)) => return Response::Message { success: false, message: error.to_string() },
+    };
+    let bounds = BoundingBox { min_lat: min.lat, min_lon: min.lon, max_lat: max.lat, max_lon: max.lon };
+    if let Err(message) = check_heatmap_bounds(&bounds) {
+        return Response::Message { success: false, message };
+    }
     
+    let grid_size = tuning().grid_size;
     let heatmap = HEATMAP_DATA.lock().unwrap();
     let mut cells = Vec::new();
     let mut max_value = 0;
+    // One key reused for every lookup of the scan
+    let mut cell = (tenant.to_string(), GridCell { lat_grid: 0, lon_grid: 0 });
     
-    for lat_grid in min_lat_grid..=max_lat_grid {
-        for lon_grid in min_lon_grid..=max_lon_grid {
-            let grid_cell = GridCell { lat_grid, lon_grid };
-            if let Some(&value) = heatmap.get(&grid_cell) {
-                let (lat, lon) = grid_cell.to_coordinates();
-                cells.push(HeatmapCell { lat, lon, value });
-                if value > max_value {
-                    max_value = value;
+    // Scan each side of the antimeridian separately
+    for part in bounds.split_antimeridian() {
+        let min_lat_grid = (part.min_lat.degrees() / grid_size).floor() as i32;
+        let min_lon_grid = (part.min_lon.degrees() / grid_size).floor() as i32;
+        let max_lat_grid = (part.max_lat.degrees() / grid_size).ceil() as i32;
+        let max_lon_grid = (part.max_lon.degrees() / grid_size).ceil() as i32;
+        
+        for lat_grid in min_lat_grid..=max_lat_grid {
+            for lon_grid in min_lon_grid..=max_lon_grid {
+                cell.1 = GridCell { lat_grid, lon_grid };
+                if let Some(&value) = heatmap.get(&cell) {
+                    let (lat, lon) = cell.1.to_coordinates(grid_size);
+                    cells.push(HeatmapCell { lat, lon, value });
+                    if value > max_value {
+                        max_value = value;
+                    }
                 }
             }
         }
@@ -422,101 +2264,243 @@ fn generate_heatmap(min_lat: f64, min_lon: f64, max_lat: f64, max_lon: f64) -> R
 }
 
 // Get visit analytics for a specific location
-fn get_visit_analytics(lat: f64, lon: f64) -> Response {
-    let grid_cell = GridCell::from_location(lat, lon);
-    let current_time = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    
-    // 24 hours in seconds
-    let day_seconds = 24 * 60 * 60;
-    let time_24h_ago = current_time.saturating_sub(day_seconds);
+fn get_visit_analytics(lat: f64, lon: f64, window_days: Option<Vec<u64>>, range: (Option<String>, Option<String>), tenant: &str) -> Response {
+    let tuning = tuning();
+    let window_days = window_days.unwrap_or(tuning.retention_windows);
+    if let Err(message) = check_retention_windows(&window_days) {
+        return Response::Message { success: false, message };
+    }
+    let point = match Coordinate::from_degrees(lat, lon) {
+        Ok(point) => point,
+        Err(error) => return Response::Message { success: false, message: error.to_string() },
+    };
+    let current_time = now();
+    let (start_hour, end_hour, warnings) = match analytics_range(range.0, range.1, current_time) {
+        Ok(range) => range,
+        Err(message) => return Response::Message { success: false, message },
+    };
     
-    let visits = LOCATION_VISITS.lock().unwrap();
+    let cell = (tenant.to_string(), GridCell::from_location(point.lat, point.lon, tuning.grid_size));
+    let retention = retention_metrics(&cell, &window_days, current_time);
+    let unique_visitors = unique_visitors(&cell, start_hour, end_hour, current_time);
     
-    if let Some(timestamps) = visits.get(&grid_cell) {
-        // Count visits in the last 24 hours
-        let recent_visits: Vec<_> = timestamps
-            .iter()
-            .filter(|&&ts| ts >= time_24h_ago)
-            .collect();
-        
-        let visits_24h = recent_visits.len() as u32;
-        
-        // Count unique visitors (use a simple hash of timestamp to simulate user IDs)
-        let mut unique_visitors = HashSet::new();
-        for &ts in &recent_visits {
-            unique_visitors.insert(ts % 100); // This is a simplification for demo purposes
+    // Histograms by hour of day (UTC) and day of week over the range
+    let mut hourly_counts = vec![0; 24];
+    let mut weekday_counts = vec![0; 7];
+    let mut weekday_totals = vec![0; 7];
+    if let Some(counts) = LOCATION_VISITS.lock().unwrap().get(&cell) {
+        for &(hour, visits) in counts.in_range(start_hour, end_hour) {
+            hourly_counts[(hour % 24) as usize] += visits;
+            weekday_counts[weekday(hour)] += visits;
         }
-        
-        let unique_visitors_24h = unique_visitors.len() as u32;
-        
-        // Find peak hour
-        let mut hour_counts = [0; 24];
-        for &ts in &recent_visits {
-            let seconds_since_day_start = (ts - time_24h_ago) % day_seconds;
-            let hour = (seconds_since_day_start / 3600) as usize;
-            if hour < 24 {
-                hour_counts[hour] += 1;
-            }
+        // Folded totals plus the hourly counts not folded yet
+        weekday_totals = counts.weekday_totals.to_vec();
+        for &(hour, visits) in &counts.hourly {
+            weekday_totals[weekday(hour)] += visits;
         }
-        
-        let mut peak_hour = 0;
-        let mut max_count = 0;
-        for (hour, &count) in hour_counts.iter().enumerate() {
-            if count > max_count {
-                max_count = count;
-                peak_hour = hour as u32;
-            }
+    }
+    
+    let mut peak_hour = 0;
+    let mut max_count = 0;
+    for (hour, &count) in hourly_counts.iter().enumerate() {
+        if count > max_count {
+            max_count = count;
+            peak_hour = hour as u32;
         }
-        
-        // Create a dummy location object for the response
-        let location = Location {
-            lat,
-            lon,
-            timestamp: current_time,
-            user_id: "ANONYMOUS".to_string(),
-            device_id: "ANONYMOUS".to_string(),
-            sensors: SensorData {
-                wifi_networks: Vec::new(),
-                cell_towers: Vec::new(),
-                accelerometer: None,
-                gyroscope: None,
-                is_mock_location: false,
-            },
-        };
-        
-        Response::VisitAnalytics(VisitAnalyticsResponse {
-            location,
-            visits_24h,
-            unique_visitors_24h,
-            peak_hour,
-        })
-    } else {
-        // Create a dummy location object for the response
-        let location = Location {
-            lat,
-            lon,
-            timestamp: current_time,
-            user_id: "ANONYMOUS".to_string(),
-            device_id: "ANONYMOUS".to_string(),
-            sensors: SensorData {
-                wifi_networks: Vec::new(),
-                cell_towers: Vec::new(),
-                accelerometer: None,
-                gyroscope: None,
-                is_mock_location: false,
-            },
-        };
-        
-        Response::VisitAnalytics(VisitAnalyticsResponse {
-            location,
-            visits_24h: 0,
-            unique_visitors_24h: 0,
-            peak_hour: 0,
-        })
     }
+    
+    Response::VisitAnalytics(VisitAnalyticsResponse {
+        location: anonymous_location(point, current_time),
+        range: TimeRange { start: hour_start(start_hour), end: hour_start(end_hour) },
+        visits: hourly_counts.iter().sum(),
+        unique_visitors,
+        peak_hour,
+        hourly_counts,
+        weekday_counts,
+        weekday_totals,
+        retention,
+        warnings,
+    })
+}
+
+// Fold hourly visit counts older than a week into weekday totals and drop expired visitor
+// sketches, so idle cells stop holding hourly data
+fn compact(current_time: u64) -> Response {
+    let current_hour = current_time / 3600;
+    LAST_COMPACTION_HOUR.store(current_hour, Ordering::Relaxed);
+    
+    let folded: usize = LOCATION_VISITS.lock().unwrap().values_mut()
+        .map(|counts| counts.compact(current_hour))
+        .sum();
+    let mut dropped = 0;
+    VISITOR_SKETCHES.lock().unwrap().retain(|_, cell_sketches| {
+        dropped += cell_sketches.prune(current_time);
+        !cell_sketches.is_empty()
+    });
+    REGISTRATION_COUNTS.lock().unwrap().retain(|_, (hour, _)| *hour == current_hour);
+    
+    Response::Message {
+        success: true,
+        message: format!("Folded {} hourly visit counts into weekday totals and dropped {} visitor sketches", folded, dropped),
+    }
+}
+
+// Compact once per hour, when a command arrives
+fn compact_if_due(current_time: u64) {
+    if current_time / 3600 > LAST_COMPACTION_HOUR.load(Ordering::Relaxed) {
+        compact(current_time);
+    }
+}
+
+fn public_key_base64() -> String {
+    general_purpose::STANDARD.encode(PUBLIC_KEY.as_bytes())
+}
+
+// Count what the enclave holds; each store is locked in turn, so the counts are not one snapshot
+fn get_info() -> Response {
+    use std::mem::size_of;
+    let mut memory = 0;
+
+    let (users, locations) = {
+        let locations = LOCATION_HISTORY.iter().map(|entry| entry.len()).sum();
+        memory += LOCATION_HISTORY.iter()
+            .map(|entry| entry.key().len() + entry.iter()
+                .map(|location| size_of::<EncryptedLocation>() + location.enc_data.len() + location.nonce.len())
+                .sum::<usize>())
+            .sum::<usize>();
+        (LOCATION_HISTORY.len(), locations)
+    };
+    memory += LOCATION_ORDER.lock().unwrap().iter().map(|owner| size_of::<String>() + owner.len()).sum::<usize>();
+    let (heatmap_cells, evicted_heatmap_cells) = {
+        let heatmap = HEATMAP_DATA.lock().unwrap();
+        memory += heatmap.len() * size_of::<(TenantCell, (u32, u64))>();
+        (heatmap.len(), heatmap.evicted)
+    };
+    let (visits, evicted_visit_cells) = {
+        let cell_visits = LOCATION_VISITS.lock().unwrap();
+        let visits = cell_visits.values()
+            .map(|counts| counts.hourly.iter().map(|(_, visits)| *visits as usize).sum::<usize>() + counts.weekday_totals.iter().map(|&visits| visits as usize).sum::<usize>())
+            .sum();
+        memory += cell_visits.len() * size_of::<(TenantCell, (VisitCounts, u64))>()
+            + cell_visits.values().map(|counts| counts.hourly.len()).sum::<usize>() * size_of::<(u64, u32)>();
+        (visits, cell_visits.evicted)
+    };
+    memory += VISITOR_SKETCHES.lock().unwrap().values()
+        .flat_map(|cell_sketches| cell_sketches.hourly.iter().chain(&cell_sketches.daily))
+        .map(|(_, sketch)| size_of::<(u64, HyperLogLog)>() + sketch.registers.len())
+        .sum::<usize>();
+    memory += VISITOR_DAYS.lock().unwrap().values()
+        .map(|days| size_of::<(TenantCell, VisitorDays)>() + days.len() * size_of::<(u64, (u64, u64))>())
+        .sum::<usize>();
+    let known_stations = {
+        let known_stations = NEARBY_STATIONS.iter().map(|entry| entry.len()).sum();
+        memory += NEARBY_STATIONS.iter()
+            .map(|entry| entry.iter().map(|station| size_of::<Station>() + station.id.len()).sum::<usize>())
+            .sum::<usize>();
+        known_stations
+    };
+    let reward_accounts = {
+        let ledger = REWARD_LEDGER.lock().unwrap();
+        memory += ledger.iter()
+            .map(|(user_id, account)| user_id.len() + size_of::<RewardAccount>() + account.entries.iter()
+                .map(|entry| size_of::<RewardEntry>() + entry.kind.len() + entry.reason.len())
+                .sum::<usize>())
+            .sum::<usize>();
+        ledger.len()
+    };
+    memory += REWARD_COOLDOWNS.lock().unwrap().len() * size_of::<((u64, GridCell), u64)>();
+    memory += REGISTRATION_COUNTS.lock().unwrap().len() * size_of::<(u64, (u64, u32))>();
+    memory += GEOFENCES.lock().unwrap().values().flat_map(BTreeMap::values)
+        .map(|geofence| size_of::<(String, Geofence)>() + 2 * geofence.id.len() + geofence.name.len())
+        .sum::<usize>();
+    memory += GEOFENCE_PRESENCE.lock().unwrap().entries.iter()
+        .map(|(user, (ids, _))| size_of::<(String, (Vec<String>, u64))>() + user.len() + ids.iter().map(|id| size_of::<String>() + id.len()).sum::<usize>())
+        .sum::<usize>();
+    memory += REWARD_CLAIMS.lock().unwrap().values()
+        .map(|claim| size_of::<(u64, (RewardClaim, u64))>() + claim.tenant.len() + claim.user.len() + claim.location_id.len())
+        .sum::<usize>();
+    let evicted_devices = {
+        let nonces = DEVICE_NONCES.lock().unwrap();
+        memory += nonces.last.entries.keys().map(|device| size_of::<(String, (u64, u64))>() + device.len()).sum::<usize>();
+        nonces.last.evicted
+    };
+
+    Response::Info(TeeInfo {
+        protocol_version: PROTOCOL_VERSION,
+        build_version: env!("CARGO_PKG_VERSION").to_string(),
+        uptime_seconds: STARTED_AT.elapsed().as_secs(),
+        users,
+        locations,
+        heatmap_cells,
+        visits,
+        known_stations,
+        reward_accounts,
+        memory_estimate_bytes: memory,
+        evicted: EvictionCounts {
+            locations: EVICTED_LOCATIONS.load(Ordering::Relaxed),
+            heatmap_cells: evicted_heatmap_cells,
+            visit_cells: evicted_visit_cells,
+            devices: evicted_devices,
+        },
+    })
+}
+
+// Count the entries of every store without walking their contents
+fn get_stats() -> Response {
+    let (device_nonces, evicted_devices) = {
+        let nonces = DEVICE_NONCES.lock().unwrap();
+        (nonces.last.len(), nonces.last.evicted)
+    };
+    let (users, locations) = {
+        (LOCATION_HISTORY.len(), LOCATION_ORDER.lock().unwrap().len())
+    };
+    let (heatmap_cells, evicted_heatmap_cells) = {
+        let heatmap = HEATMAP_DATA.lock().unwrap();
+        (heatmap.len(), heatmap.evicted)
+    };
+    let (visit_cells, evicted_visit_cells) = {
+        let visits = LOCATION_VISITS.lock().unwrap();
+        (visits.len(), visits.evicted)
+    };
+    
+    Response::Stats(TeeStats {
+        uptime_seconds: STARTED_AT.elapsed().as_secs(),
+        users,
+        locations,
+        heatmap_cells,
+        visit_cells,
+        known_stations: NEARBY_STATIONS.iter().map(|entry| entry.len()).sum(),
+        reward_accounts: REWARD_LEDGER.lock().unwrap().len(),
+        caches: CacheSizes {
+            visitor_sketches: VISITOR_SKETCHES.lock().unwrap().values()
+                .map(|cell_sketches| cell_sketches.hourly.len() + cell_sketches.daily.len()).sum(),
+            visitor_days: VISITOR_DAYS.lock().unwrap().values().map(HashMap::len).sum(),
+            device_nonces,
+            reward_cooldowns: REWARD_COOLDOWNS.lock().unwrap().len(),
+            registration_counts: REGISTRATION_COUNTS.lock().unwrap().len(),
+            geofences: GEOFENCES.lock().unwrap().values().map(BTreeMap::len).sum(),
+            geofence_presence: GEOFENCE_PRESENCE.lock().unwrap().len(),
+            reward_claims: REWARD_CLAIMS.lock().unwrap().len(),
+        },
+        rejected_registrations: REJECTED_REGISTRATIONS.lock().unwrap().clone(),
+        evicted: EvictionCounts {
+            locations: EVICTED_LOCATIONS.load(Ordering::Relaxed),
+            heatmap_cells: evicted_heatmap_cells,
+            visit_cells: evicted_visit_cells,
+            devices: evicted_devices,
+        },
+    })
+}
+
+// Placeholder location echoed back in visit analytics
+fn anonymous_location(point: Coordinate, current_time: u64) -> Location {
+    Location::builder()
+        .coordinate(point)
+        .timestamp(Utc.timestamp_opt(current_time as i64, 0).unwrap())
+        .user_id("ANONYMOUS")
+        .device_id("ANONYMOUS")
+        .build()
+        .unwrap()
 }
 
 // Print help information
@@ -525,7 +2509,8 @@ fn print_help() -> Response {
 TEE Location Services - Available Commands:
 
 1. Register location:
-   {"RegisterLocation": {"lat": 37.7749, "lon": -122.4194, "timestamp": 1617984000, "user_id": "user123", "device_id": "device456", "sensors": {...}}}
+   {"RegisterLocation": {"lat": 37.7749, "lon": -122.4194, "timestamp": "2021-04-09T16:00:00Z", "user_id": "user123", "device_id": "device456", "sensors": {...}, "nonce": 1718000000000}}
+   "nonce" must be greater than every nonce the device sent before; the time in milliseconds works.
 
 2. Get a specific location (by encrypted ID):
    {"GetLocation": "ENCRYPTED_LOCATION_ID"}
@@ -535,13 +2520,71 @@ TEE Location Services - Available Commands:
 
 4. Get visit analytics for a location:
    {"GetVisitAnalytics": {"lat": 37.7749, "lon": -122.4194}}
+   Optional "window_days": [7, 30] sets the windows of the new vs. returning visitor metrics, and optional
+   "start_time" / "end_time" (RFC 3339 or Unix seconds) the range of the counts (default: the last 24 hours, at most the last 7 days).
+
+5. Get a user's reward balance and history (newest first):
+   {"GetRewardHistory": {"user_id": "user123", "offset": 0, "limit": 50}}
+
+6. Claim the reward of a registration, by the location id it returned:
+   {"ClaimReward": {"location_id": "ENCRYPTED_LOCATION_ID"}}
+   Within 24 hours of the registration, once per cooldown per cell and up to the daily cap. The reward
+   carries a token signed by the enclave: base64url(JSON grant) "." base64url(sr25519 signature).
+
+7. Add or replace a geofence, a circle whose entering and leaving is reported for each user:
+   {"RegisterGeofence": {"id": "downtown", "name": "Downtown", "lat": 37.7749, "lon": -122.4194, "radius_meters": 500}}
+   RegisterLocation responses list the "geofence_events" ("enter" or "exit") the location raised.
+   In the framed protocol each event is also written on a line of its own after the response:
+   {"tenant_id": "default", "protocol_version": 7, "event": {"Geofence": {...}}}
+
+8. List the geofences:
+   {"ListGeofences": null}
+
+9. Reload the reward tiers, cooldown and multiplier from the file named by REWARD_CONFIG (admin):
+   {"ReloadConfig": {"admin_token": "..."}}
+   A "path", if given, must be that file.
+
+10. Run several commands at once (answered by a Batch response, in order):
+   {"Batch": [{"GetLocation": "ENCRYPTED_LOCATION_ID"}, {"GetVisitAnalytics": {"lat": 37.7749, "lon": -122.4194}}]}
+
+11. Check that the TEE is alive:
+   {"Ping": null}
+
+12. Get the command protocol version and the build version:
+   {"Version": null}
+
+13. Get the enclave's public key and the key and signing context of reward tokens (base64):
+   {"GetPublicKey": null}
 
-5. Help:
+14. Get the versions, uptime, record counts and an estimate of the memory they use:
+   {"GetInfo": null}
+
+15. Get the number of entries in every store, rejected registrations by reason and uptime:
+   {"GetStats": null}
+
+16. Fold visit data older than a week into compact totals (also done automatically every hour):
+   {"Compact": null}
+
+17. Change the grid size, verification thresholds, retention windows or registration rate limit (admin):
+   {"Configure": {"admin_token": "...", "grid_size": 0.001, "min_matching_stations": 2, "min_matching_ratio": 0.3, "retention_windows": [7, 30], "registrations_per_hour": 60}}
+   Fields left out keep their value; with none the current settings are returned.
+   Changing the grid size clears the heatmap, visit and cooldown data.
+
+18. Help:
    {"Help": null}
 
-6. Exit:
+19. Exit:
    {"Exit": null}
 
+Any command can be tagged with an id, which the response echoes:
+   {"id": 1, "command": {"Help": null}} is answered with {"id": 1, "protocol_version": 7, "response": {...}}
+   An optional "request_id" string is echoed too, and logged to stderr with the command's processing time.
+   An optional "tenant_id" keeps the locations, heatmap, analytics and rewards of each tenant apart (default: "default").
+
+Several commands may be sent at once, one per line; they are answered in order.
+
+With TEE_PROTOCOL=protobuf the same commands are length-prefixed Request messages of proto/tee.proto.
+
 All data processing happens securely within the TEE.
 "#;
 
@@ -551,28 +2594,100 @@ All data processing happens securely within the TEE.
     }
 }
 
-// Process a command
-fn process_command(cmd_str: &str) -> Response {
-    match serde_json::from_str::<Command>(cmd_str) {
+// Process a parsed command
+// Each command of a batch is parsed and answered on its own, so one bad item does not fail
+// the others; batches cannot be nested and cannot exit the enclave
+fn process_batch(commands: Vec<serde_json::Value>, tenant: &str) -> Response {
+    if commands.len() > MAX_BATCH_COMMANDS {
+        return Response::Message {
+            success: false,
+            message: format!("A batch may hold at most {} commands", MAX_BATCH_COMMANDS),
+        };
+    }
+    Response::Batch(commands.into_iter().map(|command| {
+        match serde_json::from_value::<Command>(command) {
+            Ok(Command::Batch(_)) | Ok(Command::Exit) => Response::Message {
+                success: false,
+                message: "Batch and Exit are not allowed inside a batch".to_string(),
+            },
+            command => process_command(command, tenant),
+        }
+    }).collect())
+}
+
+// Commands reading or writing user and cell data act within the tenant; the others are global
+fn process_command(command: Result<Command, serde_json::Error>, tenant: &str) -> Response {
+    match command {
+        // Commands that change state are logged and then run again, at the logged time
+        Ok(command @ (Command::RegisterLocation(_) | Command::RegisterGeofence(_))) if COMMAND_TIME.load(Ordering::Relaxed) == 0 => {
+            run_logged(command, tenant)
+        },
         Ok(command) => {
             match command {
-                Command::RegisterLocation(location) => {
-                    register_location(location)
+                Command::RegisterLocation(Registration { location, nonce }) => {
+                    register_location(location, nonce, tenant)
                 },
                 Command::GetLocation(encrypted_data) => {
-                    get_location(encrypted_data)
+                    get_location(encrypted_data, tenant)
                 },
                 Command::GenerateHeatmap { min_lat, min_lon, max_lat, max_lon } => {
-                    generate_heatmap(min_lat, min_lon, max_lat, max_lon)
+                    generate_heatmap(min_lat, min_lon, max_lat, max_lon, tenant)
                 },
-                Command::GetVisitAnalytics { lat, lon } => {
-                    get_visit_analytics(lat, lon)
+                Command::GetVisitAnalytics { lat, lon, window_days, start_time, end_time } => {
+                    get_visit_analytics(lat, lon, window_days, (start_time, end_time), tenant)
+                },
+                Command::GetRewardHistory { user_id, offset, limit } => {
+                    get_reward_history(user_id, offset, limit, tenant)
+                },
+                Command::ClaimReward { location_id } => {
+                    claim_reward(&location_id, tenant)
+                },
+                Command::RegisterGeofence(geofence) => {
+                    register_geofence(geofence, tenant)
+                },
+                Command::ListGeofences => {
+                    list_geofences(tenant)
+                },
+                Command::ReloadConfig { admin_token, path } => {
+                    reload_config(admin_token, path)
+                },
+                Command::Batch(commands) => {
+                    process_batch(commands, tenant)
+                },
+                Command::Ping => {
+                    Response::Pong { timestamp: now() }
+                },
+                Command::Version => {
+                    Response::Version {
+                        protocol_version: PROTOCOL_VERSION,
+                        build_version: env!("CARGO_PKG_VERSION").to_string(),
+                    }
+                },
+                Command::GetPublicKey => {
+                    Response::PublicKey {
+                        public_key: public_key_base64(),
+                        reward_public_key: general_purpose::STANDARD.encode(REWARD_SIGNING_KEY.public.to_bytes()),
+                        reward_context: String::from_utf8_lossy(REWARD_CONTEXT).into_owned(),
+                    }
+                },
+                Command::GetInfo => {
+                    get_info()
+                },
+                Command::GetStats => {
+                    get_stats()
+                },
+                Command::Compact => {
+                    compact(now())
+                },
+                Command::Configure { admin_token, changes } => {
+                    configure(admin_token, changes)
                 },
                 Command::Help => {
                     print_help()
                 },
                 Command::Exit => {
-                    println!("Exiting program");
+                    eprintln!("Exiting program");
+                    flush_output();
                     exit(0);
                 },
             }
@@ -587,40 +2702,317 @@ fn process_command(cmd_str: &str) -> Response {
 }
 
 fn main() {
-    println!("TEE Location Services - Running in Trusted Execution Environment");
-    println!("The public key for this TEE is: {}", general_purpose::STANDARD.encode(PUBLIC_KEY.as_bytes()));
-    println!("Type a JSON command or 'Help' for available commands.");
+    // The web interface runs the TEE with TEE_PROTOCOL=ndjson: no banner or prompt on stdout,
+    // one compact JSON command per input line and exactly one compact JSON response line each.
+    // TEE_PROTOCOL=protobuf frames the same exchange as protobuf messages
+    let protocol = match std::env::var("TEE_PROTOCOL").as_deref() {
+        Ok("ndjson") => Protocol::Ndjson,
+        Ok("protobuf") => Protocol::Protobuf,
+        #[cfg(feature = "cbor")]
+        Ok("cbor") => Protocol::Cbor,
+        #[cfg(not(feature = "cbor"))]
+        Ok("cbor") => {
+            eprintln!("TEE_PROTOCOL=cbor needs a build with the cbor feature");
+            exit(1);
+        },
+        _ => Protocol::Console,
+    };
+    let framed = protocol != Protocol::Console;
+    Lazy::force(&STARTED_AT);
+    match MemoryLimits::from_env() {
+        Ok(limits) => {
+            let _ = MEMORY_LIMITS.set(limits);
+        },
+        Err(message) => {
+            eprintln!("{}", message);
+            exit(1);
+        },
+    }
+    let banner = [
+        "TEE Location Services - Running in Trusted Execution Environment".to_string(),
+        format!("The public key for this TEE is: {}", public_key_base64()),
+        "Type a JSON command or 'Help' for available commands.".to_string(),
+    ];
+    for line in banner {
+        if framed {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+    
+    // Admin token and tuning sealed by an earlier run
+    match load_admin_settings() {
+        Ok(Some(message)) if framed => eprintln!("{}", message),
+        Ok(Some(message)) => println!("{}", message),
+        Ok(None) => {},
+        Err(message) => {
+            eprintln!("{}", message);
+            exit(1);
+        },
+    }
+    
+    // State logged by an earlier run, restored before anything new is logged
+    match CommandLog::open_from_env() {
+        Ok(Some(message)) if framed => eprintln!("{}", message),
+        Ok(Some(message)) => println!("{}", message),
+        Ok(None) => {},
+        Err(message) => {
+            eprintln!("{}", message);
+            exit(1);
+        },
+    }
+    
+    // Reward campaign settings, if a config file is given
+    if let Ok(path) = std::env::var("REWARD_CONFIG") {
+        let response = load_reward_config(&path);
+        let _ = REWARD_CONFIG_PATH.set(path);
+        match response {
+            Response::Message { success: true, message } if framed => eprintln!("{}", message),
+            Response::Message { success: true, message } => println!("{}", message),
+            Response::Message { message, .. } => {
+                eprintln!("{}", message);
+                exit(1);
+            },
+            _ => unreachable!(),
+        }
+    }
     
-    let stdin = io::stdin();
-    let mut handle = stdin.lock();
+    let mut handle = io::BufReader::with_capacity(INPUT_BUFFER_BYTES, io::stdin());
+    let mut queue = CommandQueue::default();
     
     loop {
-        print!("> ");
-        io::stdout().flush().unwrap();
-        
-        let mut input = String::new();
-        if handle.read_line(&mut input).is_err() {
-            println!("Error reading input");
-            continue;
+        // Read every command already waiting, blocking only when none is queued. Commands read
+        // ahead are answered without a prompt or a flush in between, so a client writing many
+        // commands at once gets their responses in a few writes
+        while queue.lines.len() < MAX_QUEUED_COMMANDS && (queue.lines.is_empty() || command_waiting(handle.buffer(), protocol)) {
+            if !command_waiting(handle.buffer(), protocol) {
+                if !framed {
+                    let _ = write!(OUTPUT.lock().unwrap(), "> ");
+                }
+                flush_output();
+            }
+            
+            if protocol != Protocol::Console && protocol != Protocol::Ndjson {
+                read_request_frame(&mut handle, &mut queue, protocol);
+                continue;
+            }
+            
+            let mut input = String::new();
+            match handle.read_line(&mut input) {
+                // The web interface went away, or the input ended
+                Ok(0) => {
+                    flush_output();
+                    exit(0);
+                },
+                Ok(_) => queue.push(input.trim()),
+                Err(_) if framed => {
+                    write_response(&Response::Message { success: false, message: "Error reading input".to_string() }, None, protocol);
+                },
+                Err(_) => {
+                    let _ = writeln!(OUTPUT.lock().unwrap(), "Error reading input");
+                },
+            }
         }
         
-        let input = input.trim();
-        
-        // Simple handling for "Help" and "Exit" without requiring JSON
-        if input.eq_ignore_ascii_case("help") {
-            let response = print_help();
-            println!("{}", serde_json::to_string_pretty(&response).unwrap());
-            continue;
-        } else if input.eq_ignore_ascii_case("exit") || input.eq_ignore_ascii_case("quit") {
-            println!("Exiting program");
-            exit(0);
+        match queue.pop() {
+            Some(Queued::Tagged { request, slow, read_at }) => {
+                // The answers to the quick commands go out before a slow one runs
+                if slow {
+                    flush_output();
+                }
+                compact_if_due(now());
+                
+                // Process the command and print the response, echoing its id
+                let started = Instant::now();
+                let name = request.command.as_object()
+                    .and_then(|command| command.keys().next().cloned())
+                    .unwrap_or_else(|| "invalid".to_string());
+                let tenant = request.tenant_id.as_deref().unwrap_or(DEFAULT_TENANT);
+                let newer_version = request.protocol_version.filter(|&version| version > PROTOCOL_VERSION);
+                let response = match (check_tenant_id(tenant), serde_json::from_value(request.command), newer_version) {
+                    (Err(message), _, _) => Response::Message { success: false, message },
+                    (Ok(()), Err(e), Some(version)) => Response::Message {
+                        success: false,
+                        message: format!("Failed to parse command: {}. It may be one of protocol version {}; this enclave speaks version {}", e, version, PROTOCOL_VERSION),
+                    },
+                    (Ok(()), command, _) => process_command(command, tenant),
+                };
+                if let Some(request_id) = &request.request_id {
+                    eprintln!("request_id={} command={} queued_us={} elapsed_us={}", request_id, name, (started - read_at).as_micros(), started.elapsed().as_micros());
+                }
+                write_response(&response, Some(Reply { id: request.id, request_id: request.request_id, protocol_version: PROTOCOL_VERSION, response: &response }), protocol)
+            },
+            Some(Queued::Line(input)) => {
+                // Simple handling for "Help" and "Exit" without requiring JSON
+                if input.eq_ignore_ascii_case("help") {
+                    write_response(&print_help(), None, protocol);
+                    continue;
+                } else if input.eq_ignore_ascii_case("exit") || input.eq_ignore_ascii_case("quit") {
+                    if !framed {
+                        let _ = writeln!(OUTPUT.lock().unwrap(), "Exiting program");
+                    }
+                    flush_output();
+                    exit(0);
+                }
+                compact_if_due(now());
+                write_response(&process_command(serde_json::from_str(&input), DEFAULT_TENANT), None, protocol);
+            },
+            None => continue,
         }
-        
-        // Process the command and print the response
-        let response = process_command(input);
-        match serde_json::to_string_pretty(&response) {
-            Ok(json) => println!("{}", json),
-            Err(e) => println!("Error serializing response: {}", e),
+        write_events(protocol);
+        snapshot_if_due();
+    }
+}
+
+// Commands read ahead of the one being run. A quick command overtakes the slow ones (heatmaps,
+// analytics, ...) queued before it, so a large heatmap does not hold up the registrations sent
+// after it; the reply to a tagged command carries its id, so clients match replies in any order.
+// An untagged line is answered in order: no command queued after it runs before it. A command
+// that is running is not interrupted: WASI gives the enclave no threads
+#[derive(Default)]
+struct CommandQueue {
+    lines: VecDeque<Queued>,
+}
+
+enum Queued {
+    Tagged { request: Request, slow: bool, read_at: Instant },
+    // Help, Exit or an untagged command
+    Line(String),
+}
+
+impl CommandQueue {
+    fn push(&mut self, input: &str) {
+        match serde_json::from_str::<Request>(input) {
+            Ok(request) => self.push_request(request),
+            Err(_) => self.lines.push_back(Queued::Line(input.to_string())),
+        }
+    }
+
+    fn push_request(&mut self, request: Request) {
+        self.lines.push_back(Queued::Tagged { slow: is_slow(&request.command), request, read_at: Instant::now() });
+    }
+
+    // The oldest quick command queued before any untagged line, or else the oldest command
+    fn pop(&mut self) -> Option<Queued> {
+        let quick = self.lines.iter()
+            .take_while(|queued| matches!(queued, Queued::Tagged { .. }))
+            .position(|queued| matches!(queued, Queued::Tagged { slow: false, .. }));
+        self.lines.remove(quick.unwrap_or(0))
+    }
+}
+
+// Commands that may walk much of the enclave's data
+fn is_slow(command: &serde_json::Value) -> bool {
+    let Some((name, body)) = command.as_object().and_then(|command| command.iter().next()) else { return false };
+    match name.as_str() {
+        "GenerateHeatmap" | "GetVisitAnalytics" | "GetInfo" | "Compact" => true,
+        "Batch" => body.as_array().is_some_and(|commands| commands.iter().any(is_slow)),
+        _ => false,
+    }
+}
+
+// Whether a whole command is already waiting in the input
+fn command_waiting(buffer: &[u8], protocol: Protocol) -> bool {
+    match protocol {
+        Protocol::Console | Protocol::Ndjson => buffer.contains(&b'\n'),
+        _ => wire::frame_waiting(buffer),
+    }
+}
+
+// Queue the next protobuf or CBOR request. A request that does not convert is answered at once;
+// a frame that cannot be read leaves the stream out of step, so it ends the session
+fn read_request_frame(input: &mut impl BufRead, queue: &mut CommandQueue, protocol: Protocol) {
+    match wire::read_frame(input) {
+        Ok(None) => {
+            flush_output();
+            exit(0);
+        },
+        Ok(Some(frame)) => {
+            let request = match protocol {
+                #[cfg(feature = "cbor")]
+                Protocol::Cbor => oyster_rewards::models::cbor::from_slice::<Request>(&frame)
+                    .map_err(|e| (serde_json::Value::Null, None, format!("Failed to decode request: {}", e))),
+                _ => wire::to_request(&frame),
+            };
+            match request {
+                Ok(request) => queue.push_request(request),
+                Err((id, request_id, message)) => {
+                    let response = Response::Message { success: false, message };
+                    write_response(&response, Some(Reply { id, request_id, protocol_version: PROTOCOL_VERSION, response: &response }), protocol);
+                },
+            }
+        },
+        Err(e) => {
+            eprintln!("Error reading input: {}", e);
+            flush_output();
+            exit(1);
+        },
+    }
+}
+
+// Write the events the last command raised, one line (or frame) each, after its response; at
+// the console they are only part of the responses
+fn write_events(protocol: Protocol) {
+    let events = std::mem::take(&mut *PENDING_EVENTS.lock().unwrap());
+    let mut output = OUTPUT.lock().unwrap();
+    for (tenant_id, event) in &events {
+        match protocol {
+            Protocol::Console => return,
+            Protocol::Protobuf => {
+                let _ = output.write_all(&wire::encode_event(tenant_id, event));
+            },
+            #[cfg(feature = "cbor")]
+            Protocol::Cbor => {
+                if let Ok(cbor) = oyster_rewards::models::cbor::to_vec(&EventLine { tenant_id, protocol_version: PROTOCOL_VERSION, event }) {
+                    let _ = output.write_all(&wire::encode_frame(&cbor));
+                }
+            },
+            Protocol::Ndjson => {
+                if let Ok(json) = serde_json::to_string(&EventLine { tenant_id, protocol_version: PROTOCOL_VERSION, event }) {
+                    let _ = writeln!(output, "{}", json);
+                }
+            },
         }
     }
-}
\ No newline at end of file
+}
+
+// Print a response: pretty for people at the console, a single line in the framed protocol, a
+// frame in protobuf and CBOR (where every response is a reply)
+fn write_response(response: &Response, reply: Option<Reply>, protocol: Protocol) {
+    let framed = match protocol {
+        Protocol::Console => false,
+        Protocol::Ndjson => true,
+        Protocol::Protobuf => {
+            let reply = reply.unwrap_or(Reply { id: serde_json::Value::Null, request_id: None, protocol_version: PROTOCOL_VERSION, response });
+            let _ = OUTPUT.lock().unwrap().write_all(&wire::encode_reply(&reply));
+            return;
+        },
+        #[cfg(feature = "cbor")]
+        Protocol::Cbor => {
+            let reply = reply.unwrap_or(Reply { id: serde_json::Value::Null, request_id: None, protocol_version: PROTOCOL_VERSION, response });
+            let frame = oyster_rewards::models::cbor::to_vec(&reply)
+                .or_else(|e| oyster_rewards::models::cbor::to_vec(&Reply { response: &Response::Message { success: false, message: e }, ..reply }));
+            if let Ok(frame) = frame {
+                let _ = OUTPUT.lock().unwrap().write_all(&wire::encode_frame(&frame));
+            }
+            return;
+        },
+    };
+    let json = match (reply, framed) {
+        (Some(reply), true) => serde_json::to_string(&reply),
+        (Some(reply), false) => serde_json::to_string_pretty(&reply),
+        (None, true) => serde_json::to_string(response),
+        (None, false) => serde_json::to_string_pretty(response),
+    };
+    let mut output = OUTPUT.lock().unwrap();
+    let _ = match json {
+        Ok(json) => writeln!(output, "{}", json),
+        Err(e) if framed => writeln!(output, "{}", serde_json::json!({ "Message": { "success": false, "message": format!("Error serializing response: {}", e) } })),
+        Err(e) => writeln!(output, "Error serializing response: {}", e),
+    };
+}
+
+fn flush_output() {
+    let _ = OUTPUT.lock().unwrap().flush();
+}
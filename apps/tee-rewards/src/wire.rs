@@ -0,0 +1,500 @@
+use std::io::{self, BufRead};
+use prost::Message;
+use oyster_rewards::{AnalyticsWarningCode, BleBeacon, CellTower, Latitude, Location, Longitude, SensorData, WifiNetwork};
+use oyster_rewards::models::timestamp;
+use super::{
+    Command, Event, Geofence, GeofenceEvent, GeofenceTransition, Registration, Reply, Request, Response, RetentionMetrics,
+    RewardEntry, SignedReward, TuningChanges, EvictionCounts, RejectionCounts, PROTOCOL_VERSION
+};
+
+// The protobuf encoding of the command protocol (TEE_PROTOCOL=protobuf), generated from
+// proto/tee.proto. Requests are converted to the JSON protocol's commands as they are read and
+// responses back as they are written, so both encodings run the same commands
+
+#[allow(dead_code, clippy::large_enum_variant)]
+pub mod proto {
+    pub mod orbitalmap {
+        // The core models, shared with the oyster-rewards gRPC service
+        pub mod v1 {
+            include!(concat!(env!("OUT_DIR"), "/orbitalmap.v1.rs"));
+        }
+        pub mod tee {
+            pub mod v1 {
+                include!(concat!(env!("OUT_DIR"), "/orbitalmap.tee.v1.rs"));
+            }
+        }
+    }
+}
+
+use proto::orbitalmap::v1 as models;
+use proto::orbitalmap::tee::v1 as tee;
+
+// Largest request accepted; a longer length prefix is taken to be garbage
+const MAX_FRAME_BYTES: u64 = 16 * 1024 * 1024;
+
+// Read the next length-prefixed frame; None once the input has ended
+pub fn read_frame(input: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut length = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8];
+        match input.read_exact(&mut byte) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && shift == 0 => return Ok(None),
+            result => result?,
+        }
+        length |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 != 0 {
+            continue;
+        }
+        if length > MAX_FRAME_BYTES {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("A frame of {} bytes exceeds the limit of {}", length, MAX_FRAME_BYTES)));
+        }
+        let mut frame = vec![0; length as usize];
+        input.read_exact(&mut frame)?;
+        return Ok(Some(frame));
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid frame length"))
+}
+
+// A payload prefixed with its length, as read_frame reads it
+pub fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + prost::length_delimiter_len(payload.len()));
+    prost::encode_length_delimiter(payload.len(), &mut frame).expect("a Vec grows as needed");
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// Whether the buffer holds a whole frame
+pub fn frame_waiting(mut buffer: &[u8]) -> bool {
+    match prost::decode_length_delimiter(&mut buffer) {
+        Ok(length) => buffer.len() >= length,
+        Err(_) => false,
+    }
+}
+
+// A frame's request with its command in JSON, ready to be queued. A request whose command does
+// not convert is refused with its id, request id and the message to answer it with
+pub fn to_request(frame: &[u8]) -> Result<Request, (serde_json::Value, Option<String>, String)> {
+    let request = tee::Request::decode(frame)
+        .map_err(|e| (serde_json::Value::Null, None, format!("Failed to decode request: {}", e)))?;
+    let command = request.command
+        .ok_or_else(|| "command is required".to_string())
+        .and_then(to_command)
+        .and_then(|command| serde_json::to_value(command).map_err(|e| e.to_string()));
+    match command {
+        Ok(command) => Ok(Request {
+            id: request.id.into(),
+            request_id: request.request_id,
+            tenant_id: request.tenant_id,
+            protocol_version: request.protocol_version,
+            command,
+        }),
+        Err(message) => Err((request.id.into(), request.request_id, format!("Failed to parse command: {}", message))),
+    }
+}
+
+// The frame of a reply
+pub fn encode_reply(reply: &Reply) -> Vec<u8> {
+    let output = tee::Output {
+        output: Some(tee::output::Output::Reply(tee::Reply {
+            id: reply.id.as_u64().unwrap_or(0),
+            request_id: reply.request_id.clone(),
+            protocol_version: reply.protocol_version,
+            response: Some(to_proto_response(reply.response)),
+        })),
+    };
+    encode_frame(&output.encode_to_vec())
+}
+
+// The frame of an event
+pub fn encode_event(tenant_id: &str, event: &Event) -> Vec<u8> {
+    let event = match event {
+        Event::Geofence(event) => tee::event_line::Event::Geofence(to_proto_geofence_event(event)),
+    };
+    let output = tee::Output {
+        output: Some(tee::output::Output::Event(tee::EventLine {
+            tenant_id: tenant_id.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            event: Some(event),
+        })),
+    };
+    encode_frame(&output.encode_to_vec())
+}
+
+fn to_command(command: tee::Command) -> Result<Command, String> {
+    use tee::command::Command as Proto;
+
+    let command = command.command.ok_or("command is required")?;
+    Ok(match command {
+        Proto::RegisterLocation(registration) => Command::RegisterLocation(Registration {
+            location: to_location(registration.location.ok_or("location is required")?)?,
+            nonce: registration.nonce,
+        }),
+        Proto::GetLocation(location_id) => Command::GetLocation(location_id),
+        Proto::GenerateHeatmap(bounds) => Command::GenerateHeatmap {
+            min_lat: bounds.min_lat,
+            min_lon: bounds.min_lon,
+            max_lat: bounds.max_lat,
+            max_lon: bounds.max_lon,
+        },
+        Proto::GetVisitAnalytics(request) => Command::GetVisitAnalytics {
+            lat: request.lat,
+            lon: request.lon,
+            window_days: request.window_days.map(|windows| windows.days),
+            start_time: request.start_time,
+            end_time: request.end_time,
+        },
+        Proto::GetRewardHistory(request) => Command::GetRewardHistory {
+            user_id: request.user_id,
+            offset: to_usize(request.offset),
+            limit: request.limit.map(to_usize),
+        },
+        Proto::ClaimReward(request) => Command::ClaimReward { location_id: request.location_id },
+        Proto::RegisterGeofence(geofence) => Command::RegisterGeofence(Geofence {
+            id: geofence.id,
+            name: geofence.name,
+            lat: Latitude::new(geofence.lat).map_err(|e| e.to_string())?,
+            lon: Longitude::new(geofence.lon).map_err(|e| e.to_string())?,
+            radius_meters: geofence.radius_meters,
+        }),
+        Proto::ListGeofences(_) => Command::ListGeofences,
+        Proto::ReloadConfig(request) => Command::ReloadConfig { admin_token: request.admin_token, path: request.path },
+        // A command of the batch that does not convert fails the batch, since the JSON
+        // protocol's per-command errors are only reported for commands it could read
+        Proto::Batch(batch) => Command::Batch(batch.commands.into_iter()
+            .enumerate()
+            .map(|(index, command)| {
+                to_command(command)
+                    .and_then(|command| serde_json::to_value(command).map_err(|e| e.to_string()))
+                    .map_err(|message| format!("batch command {}: {}", index, message))
+            })
+            .collect::<Result<_, _>>()?),
+        Proto::Ping(_) => Command::Ping,
+        Proto::Version(_) => Command::Version,
+        Proto::GetPublicKey(_) => Command::GetPublicKey,
+        Proto::GetInfo(_) => Command::GetInfo,
+        Proto::GetStats(_) => Command::GetStats,
+        Proto::Compact(_) => Command::Compact,
+        Proto::Configure(configure) => Command::Configure {
+            admin_token: configure.admin_token,
+            changes: TuningChanges {
+                grid_size: configure.grid_size,
+                min_matching_stations: configure.min_matching_stations.map(to_usize),
+                min_matching_ratio: configure.min_matching_ratio,
+                retention_windows: configure.retention_windows.map(|windows| windows.days),
+                registrations_per_hour: configure.registrations_per_hour,
+            },
+        },
+        Proto::Help(_) => Command::Help,
+        Proto::Exit(_) => Command::Exit,
+    })
+}
+
+// Counts beyond the address space are clamped; they only bound pages
+fn to_usize(value: u64) -> usize {
+    usize::try_from(value).unwrap_or(usize::MAX)
+}
+
+fn to_location(location: models::Location) -> Result<Location, String> {
+    let sensors = location.sensors.ok_or("sensors is required")?;
+    let reading = |values: Vec<f64>| if values.is_empty() { None } else { Some(values) };
+
+    let mut builder = Location::builder()
+        .position(location.lat, location.lon)
+        .timestamp(timestamp::parse(&location.timestamp)
+            .ok_or("timestamp must be an RFC 3339 time or Unix seconds")?)
+        .user_id(location.user_id)
+        .device_id(location.device_id)
+        .sensors(SensorData {
+            wifi_networks: sensors.wifi_networks.into_iter().map(|network| WifiNetwork {
+                ssid: network.ssid,
+                bssid: network.bssid,
+                signal_strength: network.signal_strength,
+                frequency: network.frequency,
+            }).collect(),
+            cell_towers: sensors.cell_towers.into_iter().map(|tower| CellTower {
+                cell_id: tower.cell_id,
+                signal_strength: tower.signal_strength,
+                mcc: tower.mcc,
+                mnc: tower.mnc,
+                lac: tower.lac,
+            }).collect(),
+            ble_beacons: sensors.ble_beacons.into_iter().map(to_beacon).collect::<Result<_, _>>()?,
+            accelerometer: reading(sensors.accelerometer),
+            gyroscope: reading(sensors.gyroscope),
+            pressure_hpa: sensors.pressure_hpa,
+            is_mock_location: sensors.is_mock_location,
+            additional_data: sensors.additional_data.into_iter().collect(),
+        });
+    if let Some(floor_level) = location.floor_level {
+        builder = builder.floor_level(floor_level);
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+fn to_beacon(beacon: models::BleBeacon) -> Result<BleBeacon, String> {
+    let part = |value: u32| u16::try_from(value)
+        .map_err(|_| "A beacon's major and minor must fit in 16 bits".to_string());
+    Ok(BleBeacon {
+        uuid: beacon.uuid,
+        major: part(beacon.major)?,
+        minor: part(beacon.minor)?,
+        rssi: beacon.rssi,
+    })
+}
+
+fn to_proto_location(location: &Location) -> models::Location {
+    let sensors = &location.sensors;
+    models::Location {
+        lat: location.lat.degrees(),
+        lon: location.lon.degrees(),
+        timestamp: location.timestamp.to_rfc3339(),
+        user_id: location.user_id.clone(),
+        device_id: location.device_id.clone(),
+        sensors: Some(models::SensorData {
+            wifi_networks: sensors.wifi_networks.iter().map(|network| models::WifiNetwork {
+                ssid: network.ssid.clone(),
+                bssid: network.bssid.clone(),
+                signal_strength: network.signal_strength,
+                frequency: network.frequency,
+            }).collect(),
+            cell_towers: sensors.cell_towers.iter().map(|tower| models::CellTower {
+                cell_id: tower.cell_id.clone(),
+                signal_strength: tower.signal_strength,
+                mcc: tower.mcc,
+                mnc: tower.mnc,
+                lac: tower.lac,
+            }).collect(),
+            ble_beacons: sensors.ble_beacons.iter().map(|beacon| models::BleBeacon {
+                uuid: beacon.uuid.clone(),
+                major: beacon.major.into(),
+                minor: beacon.minor.into(),
+                rssi: beacon.rssi,
+            }).collect(),
+            accelerometer: sensors.accelerometer.clone().unwrap_or_default(),
+            gyroscope: sensors.gyroscope.clone().unwrap_or_default(),
+            pressure_hpa: sensors.pressure_hpa,
+            is_mock_location: sensors.is_mock_location,
+            additional_data: sensors.additional_data.iter().map(|(key, value)| (key.clone(), value.clone())).collect(),
+        }),
+        floor_level: location.floor_level,
+    }
+}
+
+fn to_proto_geofence(geofence: &Geofence) -> tee::Geofence {
+    tee::Geofence {
+        id: geofence.id.clone(),
+        name: geofence.name.clone(),
+        lat: geofence.lat.degrees(),
+        lon: geofence.lon.degrees(),
+        radius_meters: geofence.radius_meters,
+    }
+}
+
+fn to_proto_geofence_event(event: &GeofenceEvent) -> tee::GeofenceEvent {
+    let transition = match event.transition {
+        GeofenceTransition::Enter => tee::GeofenceTransition::Enter,
+        GeofenceTransition::Exit => tee::GeofenceTransition::Exit,
+    };
+    tee::GeofenceEvent {
+        geofence_id: event.geofence_id.clone(),
+        geofence_name: event.geofence_name.clone(),
+        transition: transition.into(),
+        user_id: event.user_id.clone(),
+        timestamp: event.timestamp,
+    }
+}
+
+fn to_proto_entry(entry: &RewardEntry) -> tee::RewardEntry {
+    tee::RewardEntry {
+        id: entry.id,
+        kind: entry.kind.clone(),
+        points: entry.points,
+        reason: entry.reason.clone(),
+        balance_after: entry.balance_after,
+        timestamp: entry.timestamp,
+    }
+}
+
+fn to_proto_reward(reward: &SignedReward) -> tee::SignedReward {
+    tee::SignedReward {
+        user_id: reward.user_id.clone(),
+        entry: Some(to_proto_entry(&reward.entry)),
+        token: reward.token.clone(),
+    }
+}
+
+fn to_proto_retention(metrics: &RetentionMetrics) -> tee::RetentionMetrics {
+    tee::RetentionMetrics {
+        window_days: metrics.window_days,
+        visitors: metrics.visitors,
+        new_visitors: metrics.new_visitors,
+        returning_visitors: metrics.returning_visitors,
+        return_rate: metrics.return_rate,
+    }
+}
+
+fn to_proto_evictions(evicted: &EvictionCounts) -> tee::EvictionCounts {
+    tee::EvictionCounts {
+        locations: evicted.locations,
+        heatmap_cells: evicted.heatmap_cells,
+        visit_cells: evicted.visit_cells,
+        devices: evicted.devices,
+    }
+}
+
+fn to_proto_rejections(rejected: &RejectionCounts) -> tee::RejectionCounts {
+    tee::RejectionCounts {
+        rate_limited: rejected.rate_limited,
+        replayed: rejected.replayed,
+        verification_failed: rejected.verification_failed,
+        encryption_failed: rejected.encryption_failed,
+    }
+}
+
+fn to_proto_response(response: &Response) -> tee::Response {
+    use tee::response::Response as Proto;
+
+    let response = match response {
+        Response::LocationRegistered { enc_location, success, message, reward_cooldown_seconds, daily_remaining, geofence_events } => {
+            Proto::LocationRegistered(tee::LocationRegistered {
+                enc_location: enc_location.clone(),
+                success: *success,
+                message: message.clone(),
+                reward_cooldown_seconds: *reward_cooldown_seconds,
+                daily_remaining: *daily_remaining,
+                geofence_events: geofence_events.iter().map(to_proto_geofence_event).collect(),
+            })
+        },
+        Response::LocationData { location, success, message } => Proto::LocationData(tee::LocationData {
+            location: location.as_ref().map(to_proto_location),
+            success: *success,
+            message: message.clone(),
+        }),
+        Response::Heatmap(heatmap) => Proto::Heatmap(tee::Heatmap {
+            grid_cells: heatmap.grid_cells.iter().map(|cell| tee::HeatmapCell {
+                lat: cell.lat.degrees(),
+                lon: cell.lon.degrees(),
+                value: cell.value,
+            }).collect(),
+            max_value: heatmap.max_value,
+        }),
+        Response::VisitAnalytics(analytics) => Proto::VisitAnalytics(tee::VisitAnalytics {
+            location: Some(to_proto_location(&analytics.location)),
+            range: Some(tee::TimeRange {
+                start: analytics.range.start.to_rfc3339(),
+                end: analytics.range.end.to_rfc3339(),
+            }),
+            visits: analytics.visits,
+            unique_visitors: analytics.unique_visitors,
+            peak_hour: analytics.peak_hour,
+            hourly_counts: analytics.hourly_counts.clone(),
+            weekday_counts: analytics.weekday_counts.clone(),
+            weekday_totals: analytics.weekday_totals.clone(),
+            retention: analytics.retention.iter().map(to_proto_retention).collect(),
+            warnings: analytics.warnings.iter().map(|warning| {
+                let code = match warning.code {
+                    AnalyticsWarningCode::UndecryptableRecord => tee::AnalyticsWarningCode::UndecryptableRecord,
+                    AnalyticsWarningCode::RangeTruncated => tee::AnalyticsWarningCode::RangeTruncated,
+                };
+                tee::AnalyticsWarning {
+                    code: code.into(),
+                    skipped_records: warning.skipped_records as u64,
+                    message: warning.message.clone(),
+                }
+            }).collect(),
+        }),
+        Response::RewardHistory(history) => Proto::RewardHistory(tee::RewardHistory {
+            user_id: history.user_id.clone(),
+            earned: history.earned,
+            spent: history.spent,
+            balance: history.balance,
+            daily_remaining: history.daily_remaining,
+            entries: history.entries.iter().map(to_proto_entry).collect(),
+            total: history.total as u64,
+            offset: history.offset as u64,
+            limit: history.limit as u64,
+        }),
+        Response::RewardClaimed { success, message, reward, reward_cooldown_seconds, daily_remaining } => {
+            Proto::RewardClaimed(tee::RewardClaimed {
+                success: *success,
+                message: message.clone(),
+                reward: reward.as_ref().map(to_proto_reward),
+                reward_cooldown_seconds: *reward_cooldown_seconds,
+                daily_remaining: *daily_remaining,
+            })
+        },
+        Response::Batch(responses) => Proto::Batch(tee::Responses {
+            responses: responses.iter().map(to_proto_response).collect(),
+        }),
+        Response::Pong { timestamp } => Proto::Pong(tee::Pong { timestamp: *timestamp }),
+        Response::Version { protocol_version, build_version } => Proto::Version(tee::Version {
+            protocol_version: *protocol_version,
+            build_version: build_version.clone(),
+        }),
+        Response::PublicKey { public_key, reward_public_key, reward_context } => Proto::PublicKey(tee::PublicKey {
+            public_key: public_key.clone(),
+            reward_public_key: reward_public_key.clone(),
+            reward_context: reward_context.clone(),
+        }),
+        Response::Info(info) => Proto::Info(tee::Info {
+            protocol_version: info.protocol_version,
+            build_version: info.build_version.clone(),
+            uptime_seconds: info.uptime_seconds,
+            users: info.users as u64,
+            locations: info.locations as u64,
+            heatmap_cells: info.heatmap_cells as u64,
+            visits: info.visits as u64,
+            known_stations: info.known_stations as u64,
+            reward_accounts: info.reward_accounts as u64,
+            memory_estimate_bytes: info.memory_estimate_bytes as u64,
+            evicted: Some(to_proto_evictions(&info.evicted)),
+        }),
+        Response::Stats(stats) => Proto::Stats(tee::Stats {
+            uptime_seconds: stats.uptime_seconds,
+            users: stats.users as u64,
+            locations: stats.locations as u64,
+            heatmap_cells: stats.heatmap_cells as u64,
+            visit_cells: stats.visit_cells as u64,
+            known_stations: stats.known_stations as u64,
+            reward_accounts: stats.reward_accounts as u64,
+            caches: Some(tee::CacheSizes {
+                visitor_sketches: stats.caches.visitor_sketches as u64,
+                visitor_days: stats.caches.visitor_days as u64,
+                device_nonces: stats.caches.device_nonces as u64,
+                reward_cooldowns: stats.caches.reward_cooldowns as u64,
+                registration_counts: stats.caches.registration_counts as u64,
+                geofences: stats.caches.geofences as u64,
+                geofence_presence: stats.caches.geofence_presence as u64,
+                reward_claims: stats.caches.reward_claims as u64,
+            }),
+            rejected_registrations: Some(to_proto_rejections(&stats.rejected_registrations)),
+            evicted: Some(to_proto_evictions(&stats.evicted)),
+        }),
+        Response::Configured { success, message, tuning } => Proto::Configured(tee::Configured {
+            success: *success,
+            message: message.clone(),
+            tuning: tuning.as_ref().map(|tuning| tee::Tuning {
+                grid_size: tuning.grid_size,
+                verification: Some(tee::VerificationThresholds {
+                    min_matching_stations: tuning.verification.min_matching_stations as u64,
+                    min_matching_ratio: tuning.verification.min_matching_ratio,
+                }),
+                retention_windows: tuning.retention_windows.clone(),
+                registrations_per_hour: tuning.registrations_per_hour,
+            }),
+        }),
+        Response::GeofenceRegistered { success, message, geofence } => Proto::GeofenceRegistered(tee::GeofenceRegistered {
+            success: *success,
+            message: message.clone(),
+            geofence: geofence.as_ref().map(to_proto_geofence),
+        }),
+        Response::Geofences(geofences) => Proto::Geofences(tee::Geofences {
+            geofences: geofences.iter().map(to_proto_geofence).collect(),
+        }),
+        Response::Message { success, message } => Proto::Message(tee::Message {
+            success: *success,
+            message: message.clone(),
+        }),
+    };
+    tee::Response { response: Some(response) }
+}
@@ -1,13 +1,22 @@
 use actix_cors::Cors;
 use actix_web::{web, App, HttpResponse, HttpServer, Responder, Error};
+use actix_web::dev::Service;
+use futures::future::{Either, FutureExt};
 use serde::{Deserialize, Serialize};
-use std::process::{Command, Stdio};
-use std::io::{self, Write, BufRead, BufReader};
-use log::{info, error};
-use std::sync::Mutex;
-use actix_web::rt::time::sleep;
-use std::time::Duration;
+use std::io;
+use std::process::Stdio;
+use tracing::{debug, error, info, warn, Instrument};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
+use actix_web::rt::time::{sleep, timeout};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 
 // Request Models
 #[derive(Debug, Serialize, Deserialize)]
@@ -18,9 +27,21 @@ struct LocationRegistrationRequest {
     device_id: String,
     wifi_networks: Vec<WifiNetwork>,
     cell_towers: Vec<CellTower>,
+    // Bluetooth beacons of indoor venues, where WiFi and cell readings are too coarse
+    #[serde(default)]
+    ble_beacons: Vec<BleBeacon>,
     accelerometer: Option<[f32; 3]>,
     gyroscope: Option<[f32; 3]>,
+    // Barometric pressure (hPa), from which the TEE derives the floor when none is given
+    #[serde(default)]
+    pressure_hpa: Option<f64>,
+    // Floor of a multi-story venue, 0 at its ground level
+    #[serde(default)]
+    floor_level: Option<i32>,
     is_mock_location: bool,
+    // Greater than every nonce the device sent before (e.g. the time in milliseconds); the
+    // enclave refuses the registration otherwise, so a captured request cannot be replayed
+    nonce: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -28,6 +49,12 @@ struct LocationLookupRequest {
     encrypted_location_id: String,
 }
 
+// Claim the reward of a registration, by the id the registration returned
+#[derive(Debug, Serialize, Deserialize)]
+struct RewardClaimRequest {
+    encrypted_location_id: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct HeatmapRequest {
     min_lat: f64,
@@ -36,10 +63,26 @@ struct HeatmapRequest {
     max_lon: f64,
 }
 
+// A circle whose entering and leaving the TEE reports for every user of the tenant; registering
+// an id again replaces it
+#[derive(Debug, Serialize, Deserialize)]
+struct GeofenceRequest {
+    id: String,
+    name: String,
+    lat: f64,
+    lon: f64,
+    radius_meters: f64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct VisitAnalyticsRequest {
     lat: f64,
     lon: f64,
+    // RFC 3339 bounds of the range; the TEE defaults to the last 24 hours
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    start_time: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    end_time: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -47,12 +90,370 @@ struct WifiNetwork {
     ssid: String,
     bssid: String,
     signal_strength: i32,
+    // MHz; 0 when the device does not report it
+    #[serde(default)]
+    frequency: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct CellTower {
     cell_id: String,
     signal_strength: i32,
+    // Network identifiers; 0 when the device does not report them
+    #[serde(default)]
+    mcc: u32,
+    #[serde(default)]
+    mnc: u32,
+    #[serde(default)]
+    lac: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BleBeacon {
+    uuid: String,
+    major: u16,
+    minor: u16,
+    rssi: i32,
+}
+
+// The TEE command each request is forwarded as
+impl LocationRegistrationRequest {
+    fn tee_command(&self) -> serde_json::Value {
+        serde_json::json!({
+            "RegisterLocation": {
+                "lat": self.lat,
+                "lon": self.lon,
+                "timestamp": chrono::Utc::now().to_rfc3339(),
+                "user_id": self.user_id,
+                "device_id": self.device_id,
+                "sensors": {
+                    "wifi_networks": self.wifi_networks,
+                    "cell_towers": self.cell_towers,
+                    "ble_beacons": self.ble_beacons,
+                    "accelerometer": self.accelerometer,
+                    "gyroscope": self.gyroscope,
+                    "pressure_hpa": self.pressure_hpa,
+                    "is_mock_location": self.is_mock_location,
+                    "additional_data": {}
+                },
+                "floor_level": self.floor_level,
+                "nonce": self.nonce
+            }
+        })
+    }
+}
+
+impl LocationLookupRequest {
+    fn tee_command(&self) -> serde_json::Value {
+        serde_json::json!({
+            "GetLocation": self.encrypted_location_id
+        })
+    }
+}
+
+impl RewardClaimRequest {
+    fn tee_command(&self) -> serde_json::Value {
+        serde_json::json!({
+            "ClaimReward": { "location_id": self.encrypted_location_id }
+        })
+    }
+}
+
+impl HeatmapRequest {
+    fn tee_command(&self) -> serde_json::Value {
+        serde_json::json!({
+            "GenerateHeatmap": {
+                "min_lat": self.min_lat,
+                "min_lon": self.min_lon,
+                "max_lat": self.max_lat,
+                "max_lon": self.max_lon
+            }
+        })
+    }
+}
+
+impl GeofenceRequest {
+    fn tee_command(&self) -> serde_json::Value {
+        serde_json::json!({ "RegisterGeofence": self })
+    }
+}
+
+impl VisitAnalyticsRequest {
+    fn tee_command(&self) -> serde_json::Value {
+        serde_json::json!({
+            "GetVisitAnalytics": {
+                "lat": self.lat,
+                "lon": self.lon,
+                "start_time": self.start_time,
+                "end_time": self.end_time
+            }
+        })
+    }
+}
+
+// One sub-request of POST /api/batch, tagged by "type": {"type": "lookup_location", ...}
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BatchItem {
+    RegisterLocation(LocationRegistrationRequest),
+    LookupLocation(LocationLookupRequest),
+    ClaimReward(RewardClaimRequest),
+    Heatmap(HeatmapRequest),
+    VisitAnalytics(VisitAnalyticsRequest),
+}
+
+impl BatchItem {
+    fn tee_command(&self) -> serde_json::Value {
+        match self {
+            BatchItem::RegisterLocation(req) => req.tee_command(),
+            BatchItem::LookupLocation(req) => req.tee_command(),
+            BatchItem::ClaimReward(req) => req.tee_command(),
+            BatchItem::Heatmap(req) => req.tee_command(),
+            BatchItem::VisitAnalytics(req) => req.tee_command(),
+        }
+    }
+}
+
+// A request sent over /ws: a batch item plus an id its reply echoes
+#[derive(Debug, Deserialize)]
+struct WsCommand {
+    #[serde(default)]
+    id: serde_json::Value,
+    #[serde(flatten)]
+    request: BatchItem,
+}
+
+// Most sub-requests a batch may carry; the TEE accepts up to 64 commands per batch
+const MAX_BATCH_SIZE: usize = 64;
+
+// Bounds on request fields, checked before anything reaches the TEE
+const MAX_ID_LENGTH: usize = 128;
+const MAX_ENCRYPTED_ID_LENGTH: usize = 4096;
+const MAX_WIFI_NETWORKS: usize = 64;
+const MAX_CELL_TOWERS: usize = 32;
+const MAX_BLE_BEACONS: usize = 32;
+const MAX_GEOFENCE_NAME_LENGTH: usize = 128;
+const MIN_GEOFENCE_RADIUS_METERS: f64 = 10.0;
+const MAX_GEOFENCE_RADIUS_METERS: f64 = 100_000.0;
+
+// A request field that failed validation
+#[derive(Debug, Serialize)]
+struct FieldError {
+    field: String,
+    message: String,
+}
+
+// Body of a 422 response
+#[derive(Debug, Serialize)]
+struct ValidationErrorResponse {
+    success: bool,
+    message: String,
+    errors: Vec<FieldError>,
+}
+
+// Checks a request body must pass before it is turned into a TEE command
+trait Validate {
+    // Every offending field, or nothing if the request is valid
+    fn validation_errors(&self) -> Vec<FieldError>;
+}
+
+impl Validate for LocationRegistrationRequest {
+    fn validation_errors(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_lat(&mut errors, "lat", self.lat);
+        check_lon(&mut errors, "lon", self.lon);
+        check_id(&mut errors, "user_id", &self.user_id, MAX_ID_LENGTH);
+        check_id(&mut errors, "device_id", &self.device_id, MAX_ID_LENGTH);
+        if self.nonce == 0 {
+            errors.push(field_error("nonce", "must be greater than 0".to_string()));
+        }
+        if self.wifi_networks.len() > MAX_WIFI_NETWORKS {
+            errors.push(field_error("wifi_networks", format!("must have at most {} entries", MAX_WIFI_NETWORKS)));
+        }
+        for (i, network) in self.wifi_networks.iter().enumerate().take(MAX_WIFI_NETWORKS) {
+            check_id(&mut errors, &format!("wifi_networks[{}].bssid", i), &network.bssid, MAX_ID_LENGTH);
+        }
+        if self.cell_towers.len() > MAX_CELL_TOWERS {
+            errors.push(field_error("cell_towers", format!("must have at most {} entries", MAX_CELL_TOWERS)));
+        }
+        for (i, tower) in self.cell_towers.iter().enumerate().take(MAX_CELL_TOWERS) {
+            check_id(&mut errors, &format!("cell_towers[{}].cell_id", i), &tower.cell_id, MAX_ID_LENGTH);
+        }
+        if self.ble_beacons.len() > MAX_BLE_BEACONS {
+            errors.push(field_error("ble_beacons", format!("must have at most {} entries", MAX_BLE_BEACONS)));
+        }
+        for (i, beacon) in self.ble_beacons.iter().enumerate().take(MAX_BLE_BEACONS) {
+            check_id(&mut errors, &format!("ble_beacons[{}].uuid", i), &beacon.uuid, MAX_ID_LENGTH);
+        }
+        for (field, reading) in [("accelerometer", &self.accelerometer), ("gyroscope", &self.gyroscope)] {
+            if reading.is_some_and(|values| values.iter().any(|value| !value.is_finite())) {
+                errors.push(field_error(field, "must contain finite numbers".to_string()));
+            }
+        }
+        if self.pressure_hpa.is_some_and(|pressure| !pressure.is_finite()) {
+            errors.push(field_error("pressure_hpa", "must be a finite number".to_string()));
+        }
+        errors
+    }
+}
+
+impl Validate for LocationLookupRequest {
+    fn validation_errors(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_id(&mut errors, "encrypted_location_id", &self.encrypted_location_id, MAX_ENCRYPTED_ID_LENGTH);
+        errors
+    }
+}
+
+impl Validate for RewardClaimRequest {
+    fn validation_errors(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_id(&mut errors, "encrypted_location_id", &self.encrypted_location_id, MAX_ENCRYPTED_ID_LENGTH);
+        errors
+    }
+}
+
+impl Validate for HeatmapRequest {
+    fn validation_errors(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_lat(&mut errors, "min_lat", self.min_lat);
+        check_lon(&mut errors, "min_lon", self.min_lon);
+        check_lat(&mut errors, "max_lat", self.max_lat);
+        check_lon(&mut errors, "max_lon", self.max_lon);
+        if self.min_lat >= self.max_lat {
+            errors.push(field_error("max_lat", "must be greater than min_lat".to_string()));
+        }
+        if self.min_lon >= self.max_lon {
+            errors.push(field_error("max_lon", "must be greater than min_lon".to_string()));
+        }
+        errors
+    }
+}
+
+impl Validate for GeofenceRequest {
+    fn validation_errors(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if !valid_tenant_id(&self.id) {
+            errors.push(field_error("id", format!("must be 1 to {} letters, digits, '-', '_' or '.'", MAX_TENANT_ID_LENGTH)));
+        }
+        if self.name.len() > MAX_GEOFENCE_NAME_LENGTH {
+            errors.push(field_error("name", format!("must be at most {} bytes", MAX_GEOFENCE_NAME_LENGTH)));
+        }
+        check_lat(&mut errors, "lat", self.lat);
+        check_lon(&mut errors, "lon", self.lon);
+        if !(MIN_GEOFENCE_RADIUS_METERS..=MAX_GEOFENCE_RADIUS_METERS).contains(&self.radius_meters) {
+            errors.push(field_error("radius_meters", format!("must be between {} and {}", MIN_GEOFENCE_RADIUS_METERS, MAX_GEOFENCE_RADIUS_METERS)));
+        }
+        errors
+    }
+}
+
+impl Validate for VisitAnalyticsRequest {
+    fn validation_errors(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_lat(&mut errors, "lat", self.lat);
+        check_lon(&mut errors, "lon", self.lon);
+        let start = check_time(&mut errors, "start_time", self.start_time.as_deref());
+        let end = check_time(&mut errors, "end_time", self.end_time.as_deref());
+        if let (Some(start), Some(end)) = (start, end) {
+            if start >= end {
+                errors.push(field_error("end_time", "must be after start_time".to_string()));
+            }
+        }
+        errors
+    }
+}
+
+impl Validate for BatchItem {
+    fn validation_errors(&self) -> Vec<FieldError> {
+        match self {
+            BatchItem::RegisterLocation(req) => req.validation_errors(),
+            BatchItem::LookupLocation(req) => req.validation_errors(),
+            BatchItem::ClaimReward(req) => req.validation_errors(),
+            BatchItem::Heatmap(req) => req.validation_errors(),
+            BatchItem::VisitAnalytics(req) => req.validation_errors(),
+        }
+    }
+}
+
+// A batch is rejected as a whole if any item is invalid; item fields are reported as `[i].field`
+impl Validate for Vec<BatchItem> {
+    fn validation_errors(&self) -> Vec<FieldError> {
+        if self.is_empty() || self.len() > MAX_BATCH_SIZE {
+            return vec![field_error("body", format!("must hold between 1 and {} requests", MAX_BATCH_SIZE))];
+        }
+        let mut errors = Vec::new();
+        for (i, item) in self.iter().enumerate() {
+            errors.extend(item.validation_errors().into_iter().map(|error| FieldError {
+                field: format!("[{}].{}", i, error.field),
+                message: error.message,
+            }));
+        }
+        errors
+    }
+}
+
+fn field_error(field: &str, message: String) -> FieldError {
+    FieldError {
+        field: field.to_string(),
+        message,
+    }
+}
+
+fn check_lat(errors: &mut Vec<FieldError>, field: &str, lat: f64) {
+    if !(-90.0..=90.0).contains(&lat) {
+        errors.push(field_error(field, "must be between -90 and 90".to_string()));
+    }
+}
+
+fn check_lon(errors: &mut Vec<FieldError>, field: &str, lon: f64) {
+    if !(-180.0..=180.0).contains(&lon) {
+        errors.push(field_error(field, "must be between -180 and 180".to_string()));
+    }
+}
+
+fn check_id(errors: &mut Vec<FieldError>, field: &str, value: &str, max_length: usize) {
+    if value.trim().is_empty() {
+        errors.push(field_error(field, "must not be empty".to_string()));
+    } else if value.len() > max_length {
+        errors.push(field_error(field, format!("must be at most {} bytes", max_length)));
+    }
+}
+
+// An optional RFC 3339 time, parsed if present and valid
+fn check_time(errors: &mut Vec<FieldError>, field: &str, value: Option<&str>) -> Option<chrono::DateTime<chrono::FixedOffset>> {
+    let value = value?;
+    match chrono::DateTime::parse_from_rfc3339(value) {
+        Ok(time) => Some(time),
+        Err(_) => {
+            errors.push(field_error(field, "must be an RFC 3339 time".to_string()));
+            None
+        }
+    }
+}
+
+// The 422 response listing a request's offending fields, if it has any
+fn validation_response<T: Validate>(request: &T) -> Option<HttpResponse> {
+    let errors = request.validation_errors();
+    if errors.is_empty() {
+        return None;
+    }
+    info!(?errors, "rejected invalid request");
+    Some(HttpResponse::UnprocessableEntity().json(ValidationErrorResponse {
+        success: false,
+        message: "Request validation failed".to_string(),
+        errors,
+    }))
+}
+
+// Bodies that do not deserialize get the same 422 shape as failed validation
+fn json_error(err: actix_web::error::JsonPayloadError, _req: &actix_web::HttpRequest) -> Error {
+    let response = HttpResponse::UnprocessableEntity().json(ValidationErrorResponse {
+        success: false,
+        message: "Request validation failed".to_string(),
+        errors: vec![field_error("body", err.to_string())],
+    });
+    actix_web::error::InternalError::from_response(err, response).into()
 }
 
 // Response Models
@@ -61,6 +462,9 @@ struct LocationRegistrationResponse {
     encrypted_location_id: String,
     success: bool,
     message: String,
+    // Geofences the user entered or left, as the TEE reported them
+    #[serde(default)]
+    geofence_events: Vec<serde_json::Value>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -91,13 +495,58 @@ struct HeatmapCell {
 struct VisitAnalyticsResponse {
     lat: f64,
     lon: f64,
-    visits_24h: u32,
-    unique_visitors_24h: u32,
-    peak_hour: u32,
+    #[serde(flatten)]
+    analytics: TeeVisitAnalytics,
     success: bool,
     message: String,
 }
 
+// The fields of the TEE's VisitAnalytics response passed on to clients
+#[derive(Debug, Serialize, Deserialize)]
+struct TeeVisitAnalytics {
+    // {"start", "end"} in RFC 3339, in whole hours
+    range: serde_json::Value,
+    visits: u32,
+    unique_visitors: u32,
+    peak_hour: u32,
+    hourly_counts: Vec<u32>,
+    weekday_counts: Vec<u32>,
+    #[serde(default)]
+    warnings: Vec<serde_json::Value>,
+}
+
+// Result of one batch item: the TEE's response to it, e.g. {"LocationRegistered": {...}}
+#[derive(Debug, Serialize)]
+struct BatchItemResult {
+    success: bool,
+    response: serde_json::Value,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchResponse {
+    success: bool,
+    results: Vec<BatchItemResult>,
+}
+
+// Public key and versions of one responsive enclave of the pool; each generates its own key
+#[derive(Debug, Serialize)]
+struct EnclaveInfo {
+    slot: usize,
+    public_key: String,
+    // Verifies the enclave's reward tokens, signed under reward_context
+    reward_public_key: String,
+    reward_context: String,
+    build_version: String,
+    uptime_seconds: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct TeeInfoResponse {
+    success: bool,
+    protocol_version: u32,
+    enclaves: Vec<EnclaveInfo>,
+}
+
 // General response for errors
 #[derive(Debug, Serialize, Deserialize)]
 struct ApiResponse {
@@ -105,602 +554,836 @@ struct ApiResponse {
     message: String,
 }
 
-// Enarx process management
-struct EnarxProcess {
-    child: Mutex<Option<std::process::Child>>,
+// Runtime settings: defaults, overridden by the TOML file named by WEB_CONFIG, overridden in
+// turn by environment variables
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    // Enarx binary and the enclave it runs (ENARX_BINARY, TEE_WASM_PATH)
+    enarx_binary: String,
+    wasm_path: String,
+    // Address the API listens on (BIND_ADDR)
+    bind_addr: String,
+    // How long a TEE may take to answer one command before it is considered hung
+    // (TEE_COMMAND_TIMEOUT_SECONDS)
+    command_timeout_seconds: u64,
+    // How often the supervisor pings every process (TEE_HEALTH_CHECK_INTERVAL_SECONDS)
+    health_check_interval_seconds: u64,
+    // Times a command that never reached a working TEE is sent again (TEE_MAX_RETRIES), waiting
+    // about retry_backoff_ms, doubled for each further retry (TEE_RETRY_BACKOFF_MS)
+    max_retries: u32,
+    retry_backoff_ms: u64,
+    // Commands failing in a row that open the circuit breaker, and how long it then refuses
+    // commands before probing the TEE again (TEE_BREAKER_FAILURES, TEE_BREAKER_OPEN_SECONDS)
+    breaker_failures: u32,
+    breaker_open_seconds: u64,
+    // Enclave processes serving commands (TEE_POOL_SIZE). The enclaves share no state, so each
+    // tenant's commands all go to one of them, its home slot; with command logs the size cannot
+    // change, as that would move tenants away from their data
+    pool_size: usize,
+    // Tokens that open a /ws connection (WS_TOKENS, comma-separated); none disables /ws unless
+    // API keys are set
+    ws_tokens: Vec<String>,
+    // API key of each tenant (API_KEYS, comma-separated <tenant>:<key> pairs). When set, /api
+    // requests need one, and the enclave keeps each tenant's data apart; none serves every
+    // request as the enclave's default tenant
+    api_keys: BTreeMap<String, String>,
+    // How long heatmap and analytics responses are served from memory, 0 to always ask the TEE
+    // (RESPONSE_CACHE_TTL_SECONDS), and how many are kept (RESPONSE_CACHE_MAX_ENTRIES)
+    response_cache_ttl_seconds: u64,
+    response_cache_max_entries: u64,
+    // Directory of the enclaves' sealed command logs (TEE_COMMAND_LOG_DIR), one per pool slot.
+    // When set, an enclave that restarts replays its log to rebuild its state; the enclaves
+    // need TEE_SEALING_KEY to seal it
+    command_log_dir: Option<String>,
 }
 
-impl EnarxProcess {
-    fn new() -> Self {
+impl Default for Config {
+    fn default() -> Self {
         Self {
-            child: Mutex::new(None),
+            enarx_binary: "enarx".to_string(),
+            wasm_path: "/app/tee-rewards.wasm".to_string(),
+            bind_addr: "0.0.0.0:8080".to_string(),
+            command_timeout_seconds: 15,
+            health_check_interval_seconds: 10,
+            max_retries: 1,
+            retry_backoff_ms: 100,
+            breaker_failures: 5,
+            breaker_open_seconds: 30,
+            pool_size: 1,
+            ws_tokens: Vec::new(),
+            api_keys: BTreeMap::new(),
+            response_cache_ttl_seconds: 30,
+            response_cache_max_entries: 10_000,
+            command_log_dir: None,
         }
     }
+}
 
-    async fn start_process(&self) -> Result<(), String> {
-        let mut child_lock = self.child.lock().unwrap();
+const MAX_POOL_SIZE: usize = 16;
+const MIN_WS_TOKEN_LENGTH: usize = 16;
+const MIN_API_KEY_LENGTH: usize = 16;
+const MAX_TENANT_ID_LENGTH: usize = 64;
+
+impl Config {
+    fn load() -> Result<Self, String> {
+        let mut config = match std::env::var("WEB_CONFIG") {
+            Ok(path) => {
+                let contents = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("Failed to read config {}: {}", path, e))?;
+                toml::from_str(&contents).map_err(|e| format!("Invalid config {}: {}", path, e))?
+            },
+            Err(_) => Config::default(),
+        };
         
-        // Only start a new process if one isn't already running
-        if child_lock.is_none() {
-            info!("🚀 Starting new Enarx process");
-            let child = Command::new("enarx")
-                .arg("run")
-                .arg("/app/tee-rewards.wasm")
-                .stdin(Stdio::piped())
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .spawn();
-            
-            match child {
-                Ok(process) => {
-                    *child_lock = Some(process);
-                    
-                    // Wait for process to be ready
-                    info!("⏳ Waiting for Enarx process to initialize...");
-                    sleep(Duration::from_millis(1000)).await;
-                    
-                    // Read initial output until prompt
-                    if let Some(child) = child_lock.as_mut() {
-                        if let Some(stdout) = child.stdout.as_mut() {
-                            let mut reader = BufReader::new(stdout);
-                            let mut line = String::new();
-                            let mut attempts = 0;
-                            
-                            while attempts < 15 { // Increased timeout
-                                match reader.read_line(&mut line) {
-                                    Ok(0) => {
-                                        error!("❌ EOF reached while waiting for TEE prompt");
-                                        break; // EOF
-                                    },
-                                    Ok(bytes) => {
-                                        info!("📝 TEE startup ({}b): {}", bytes, line.trim());
-                                        if line.contains(">") || line.contains("Type a JSON command") {
-                                            info!("✅ TEE ready (prompt detected)");
-                                            return Ok(());
-                                        }
-                                        line.clear();
-                                    },
-                                    Err(e) => {
-                                        error!("❌ Failed to read from stdout: {}", e);
-                                        return Err(format!("Failed to read from stdout: {}", e));
-                                    }
-                                }
-                                attempts += 1;
-                                sleep(Duration::from_millis(200)).await; // Increased wait time
-                            }
-                            
-                            if attempts >= 15 {
-                                error!("⚠️ Timed out waiting for TEE prompt, but continuing");
-                                // Return Ok anyway to try to continue
-                                return Ok(());
-                            }
-                        }
-                        
-                        // Also log stderr in a separate thread
-                        if let Some(stderr) = child_lock.as_mut().unwrap().stderr.take() {
-                            std::thread::spawn(move || {
-                                let reader = BufReader::new(stderr);
-                                for line in reader.lines() {
-                                    if let Ok(line) = line {
-                                        eprintln!("TEE stderr: {}", line);
-                                    }
-                                }
-                            });
-                        }
-                    }
-                    
-                    info!("✅ Enarx process started successfully");
-                    Ok(())
-                },
-                Err(e) => {
-                    error!("❌ Failed to start Enarx process: {}", e);
-                    Err(format!("Failed to start Enarx process: {}", e))
-                }
-            }
-        } else {
-            // Check if the process is still alive
-            let mut need_restart = false;
-            
-            if let Some(child) = child_lock.as_mut() {
-                match child.try_wait() {
-                    Ok(Some(status)) => {
-                        // Process has exited
-                        error!("⚠️ Enarx process exited with status: {:?}", status);
-                        need_restart = true;
-                    },
-                    Ok(None) => {
-                        // Process is still running
-                        info!("ℹ️ Enarx process already running");
-                    },
-                    Err(e) => {
-                        // Error checking process status
-                        error!("❌ Error checking Enarx process status: {}", e);
-                        need_restart = true;
-                    }
-                }
+        if let Ok(value) = std::env::var("ENARX_BINARY") {
+            config.enarx_binary = value;
+        }
+        if let Ok(value) = std::env::var("TEE_WASM_PATH") {
+            config.wasm_path = value;
+        }
+        if let Ok(value) = std::env::var("BIND_ADDR") {
+            config.bind_addr = value;
+        }
+        config.command_timeout_seconds = env_number("TEE_COMMAND_TIMEOUT_SECONDS", config.command_timeout_seconds)?;
+        config.health_check_interval_seconds = env_number("TEE_HEALTH_CHECK_INTERVAL_SECONDS", config.health_check_interval_seconds)?;
+        config.max_retries = env_number("TEE_MAX_RETRIES", config.max_retries)?;
+        config.retry_backoff_ms = env_number("TEE_RETRY_BACKOFF_MS", config.retry_backoff_ms)?;
+        config.breaker_failures = env_number("TEE_BREAKER_FAILURES", config.breaker_failures)?;
+        config.breaker_open_seconds = env_number("TEE_BREAKER_OPEN_SECONDS", config.breaker_open_seconds)?;
+        config.pool_size = env_number("TEE_POOL_SIZE", config.pool_size)?;
+        config.response_cache_ttl_seconds = env_number("RESPONSE_CACHE_TTL_SECONDS", config.response_cache_ttl_seconds)?;
+        config.response_cache_max_entries = env_number("RESPONSE_CACHE_MAX_ENTRIES", config.response_cache_max_entries)?;
+        if let Ok(value) = std::env::var("TEE_COMMAND_LOG_DIR") {
+            config.command_log_dir = Some(value);
+        }
+        if let Ok(value) = std::env::var("WS_TOKENS") {
+            config.ws_tokens = value.split(',').map(str::trim).filter(|token| !token.is_empty()).map(str::to_string).collect();
+        }
+        if let Ok(value) = std::env::var("API_KEYS") {
+            config.api_keys = value.split(',').map(str::trim).filter(|pair| !pair.is_empty())
+                .map(|pair| pair.split_once(':')
+                    .map(|(tenant, key)| (tenant.to_string(), key.to_string()))
+                    .ok_or_else(|| "API_KEYS must list <tenant>:<key> pairs".to_string()))
+                .collect::<Result<_, _>>()?;
+        }
+        
+        config.validate()?;
+        config.check_pool_size()?;
+        Ok(config)
+    }
+
+    // Tenants are assigned to slots by the pool size, so the enclaves' logs only hold the right
+    // tenants for the size they were written with, which is kept next to them
+    fn check_pool_size(&self) -> Result<(), String> {
+        let Some(dir) = &self.command_log_dir else {
+            return Ok(());
+        };
+        let path = format!("{}/pool-size", dir.trim_end_matches('/'));
+        match std::fs::read_to_string(&path) {
+            Ok(recorded) if recorded.trim() == self.pool_size.to_string() => Ok(()),
+            Ok(recorded) => Err(format!(
+                "The command logs in {} were written by a pool of {} enclaves; pool_size cannot change to {} without moving tenants away from their data",
+                dir, recorded.trim(), self.pool_size,
+            )),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => std::fs::write(&path, self.pool_size.to_string())
+                .map_err(|e| format!("Failed to write {}: {}", path, e)),
+            Err(e) => Err(format!("Failed to read {}: {}", path, e)),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.command_timeout_seconds == 0 || self.health_check_interval_seconds == 0 || self.breaker_open_seconds == 0 {
+            return Err("Timeouts and intervals must be at least one second".to_string());
+        }
+        if self.breaker_failures == 0 {
+            return Err("breaker_failures must be at least 1".to_string());
+        }
+        if !(1..=MAX_POOL_SIZE).contains(&self.pool_size) {
+            return Err(format!("pool_size must be between 1 and {}", MAX_POOL_SIZE));
+        }
+        if self.ws_tokens.iter().any(|token| token.len() < MIN_WS_TOKEN_LENGTH) {
+            return Err(format!("ws_tokens must be at least {} characters long", MIN_WS_TOKEN_LENGTH));
+        }
+        // The same rules as the enclave's, so a bad tenant id fails here rather than per request
+        for (tenant, key) in &self.api_keys {
+            if !valid_tenant_id(tenant) {
+                return Err(format!("Tenant ids must be 1 to {} letters, digits, '-', '_' or '.': {}", MAX_TENANT_ID_LENGTH, tenant));
             }
-            
-            if need_restart {
-                // Kill the process if it's still in the struct but not running properly
-                if let Some(mut child) = child_lock.take() {
-                    let _ = child.kill();
-                }
-                
-                // Drop the lock and start a new process (avoid recursion)
-                drop(child_lock);
-                
-                // Use a separate method for restarting to avoid recursion in async fn
-                return self.restart_process().await;
+            if key.len() < MIN_API_KEY_LENGTH {
+                return Err(format!("The API key of tenant {} must be at least {} characters long", tenant, MIN_API_KEY_LENGTH));
             }
-            
-            Ok(())
         }
+        let keys: std::collections::HashSet<&String> = self.api_keys.values().collect();
+        if keys.len() < self.api_keys.len() {
+            return Err("Each tenant needs its own API key".to_string());
+        }
+        self.bind_addr.parse::<std::net::SocketAddr>()
+            .map_err(|e| format!("Invalid bind_addr {}: {}", self.bind_addr, e))?;
+        Ok(())
     }
-    
-    // Separate method to avoid recursion in async fn
-    async fn restart_process(&self) -> Result<(), String> {
-        // Small delay before restart
-        sleep(Duration::from_millis(500)).await;
-        
-        info!("🔄 Restarting Enarx process");
-        
-        let mut child_lock = self.child.lock().unwrap();
-        
-        let child = Command::new("enarx")
-            .arg("run")
-            .arg("/app/tee-rewards.wasm")
+
+    fn command_timeout(&self) -> Duration {
+        Duration::from_secs(self.command_timeout_seconds)
+    }
+
+    fn health_check_interval(&self) -> Duration {
+        Duration::from_secs(self.health_check_interval_seconds)
+    }
+}
+
+// A response body kept by the cache, with the ETag it is served under
+struct CachedResponse {
+    body: String,
+    etag: String,
+}
+
+// Successful heatmap and analytics responses by normalized request, so identical queries within
+// the TTL do not reach the enclave
+struct ResponseCache {
+    entries: Option<moka::future::Cache<String, Arc<CachedResponse>>>,
+    ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ResponseCache {
+    fn new(config: &Config) -> Self {
+        let ttl = Duration::from_secs(config.response_cache_ttl_seconds);
+        let entries = (!ttl.is_zero() && config.response_cache_max_entries > 0).then(|| {
+            moka::future::Cache::builder()
+                .max_capacity(config.response_cache_max_entries)
+                .time_to_live(ttl)
+                .build()
+        });
+        ResponseCache {
+            entries,
+            ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Option<Arc<CachedResponse>> {
+        let entries = self.entries.as_ref()?;
+        let cached = entries.get(key).await;
+        let counter = if cached.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        cached
+    }
+
+    // Cache a successful response's JSON body (when caching is on) and return it with its ETag
+    async fn insert<T: Serialize>(&self, key: String, response: &T) -> Arc<CachedResponse> {
+        let body = serde_json::to_string(response).expect("API responses serialize");
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        std::hash::Hash::hash(&body, &mut hasher);
+        let etag = format!("\"{:016x}\"", std::hash::Hasher::finish(&hasher));
+        let cached = Arc::new(CachedResponse { body, etag });
+        if let Some(entries) = &self.entries {
+            entries.insert(key, cached.clone()).await;
+        }
+        cached
+    }
+
+    // 200 with the body, or 304 if the client already holds this version
+    fn respond(&self, http_req: &actix_web::HttpRequest, cached: &CachedResponse) -> HttpResponse {
+        let cache_control = if self.entries.is_some() {
+            format!("public, max-age={}", self.ttl.as_secs())
+        } else {
+            "no-cache".to_string()
+        };
+        let fresh = http_req.headers().get("If-None-Match")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == cached.etag || tag.trim() == "*"));
+        let mut response = if fresh { HttpResponse::NotModified() } else { HttpResponse::Ok() };
+        response
+            .insert_header(("ETag", cached.etag.clone()))
+            .insert_header(("Cache-Control", cache_control));
+        if fresh {
+            response.finish()
+        } else {
+            response.content_type("application/json").body(cached.body.clone())
+        }
+    }
+}
+
+fn env_number<T: std::str::FromStr>(name: &str, default: T) -> Result<T, String> {
+    match std::env::var(name) {
+        Ok(value) => value.parse().map_err(|_| format!("{} must be a number, got {}", name, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+// Why a command got no response
+#[derive(Debug)]
+enum TeeError {
+    // The command is not valid JSON
+    Invalid(String),
+    // The command never reached a working TEE; it is safe to send again
+    Unavailable(String),
+    // The TEE took the command but did not answer in time; it may still have been applied
+    TimedOut(String),
+    // The circuit breaker is refusing commands for this many more seconds
+    CircuitOpen { retry_after: u64 },
+    // The TEE answered in a protocol version this web interface does not speak
+    Incompatible(String),
+}
+
+impl std::fmt::Display for TeeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TeeError::Invalid(message) | TeeError::Unavailable(message) | TeeError::TimedOut(message) | TeeError::Incompatible(message) => f.write_str(message),
+            TeeError::CircuitOpen { retry_after } => write!(f, "The TEE is failing; commands are refused for {} more second(s)", retry_after),
+        }
+    }
+}
+
+// Version of the TEE command protocol this web interface speaks; a TEE answering in another
+// version is refused, since its responses may not parse the way the handlers expect
+const TEE_PROTOCOL_VERSION: u32 = 7;
+
+// A command's response, or why it was refused
+type TeeAnswer = Result<String, String>;
+
+// Commands sent to a TEE and not answered yet, keyed by id
+type PendingResponses = Arc<std::sync::Mutex<HashMap<u64, oneshot::Sender<TeeAnswer>>>>;
+
+// A command for the writer task: its id, the API request it serves, the tenant it acts for, its
+// body and where its response goes
+type QueuedCommand = (u64, Option<String>, Option<String>, serde_json::Value, oneshot::Sender<TeeAnswer>);
+
+// A line of the framed protocol: a response, or an event the TEE raised
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TeeOutput {
+    Reply(TeeReply),
+    Event(TeeEvent),
+}
+
+// A response line: {"id": 7, "request_id": "...", "protocol_version": 6, "response": {...}}
+#[derive(Debug, Deserialize)]
+struct TeeReply {
+    id: u64,
+    #[serde(default)]
+    request_id: Option<String>,
+    // 0 for TEE builds that predate versioning
+    #[serde(default)]
+    protocol_version: u32,
+    response: serde_json::Value,
+}
+
+// An event line, written after the response of the command that raised it:
+// {"tenant_id": "default", "protocol_version": 6, "event": {"Geofence": {...}}}
+#[derive(Debug, Deserialize)]
+struct TeeEvent {
+    tenant_id: String,
+    protocol_version: u32,
+    event: serde_json::Value,
+}
+
+tokio::task_local! {
+    // ID of the API request being served, set by the request middleware and sent to the TEE
+    // with every command, which logs it and echoes it back
+    static REQUEST_ID: String;
+    // Tenant of the API key the request was authenticated with, sent to the TEE with every
+    // command; None when no API keys are configured, which the TEE serves as its default tenant
+    static TENANT_ID: Option<String>;
+}
+
+fn current_tenant() -> Option<String> {
+    TENANT_ID.try_with(Option::clone).ok().flatten()
+}
+
+// The tenant whose API key a request carries, in an X-API-Key or bearer Authorization header.
+// Only /api requests are authenticated, and only once keys are configured; the public
+// /api/tee-info is not
+fn authenticate(req: &actix_web::dev::ServiceRequest) -> Result<Option<String>, String> {
+    let api_keys = &req.app_data::<web::Data<Arc<EnarxProcess>>>().expect("the TEE pool is registered").config.api_keys;
+    if api_keys.is_empty() || !req.path().starts_with("/api/") || req.path() == "/api/tee-info" {
+        return Ok(None);
+    }
+    let key = req.headers().get(API_KEY_HEADER)
+        .or_else(|| req.headers().get("Authorization"))
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.strip_prefix("Bearer ").unwrap_or(value));
+    match key.and_then(|key| tenant_of_key(api_keys, key)) {
+        Some(tenant) => Ok(Some(tenant)),
+        None => Err(format!("A valid API key is required in the {} header", API_KEY_HEADER)),
+    }
+}
+
+fn tenant_of_key(api_keys: &BTreeMap<String, String>, key: &str) -> Option<String> {
+    api_keys.iter().find(|(_, tenant_key)| secret_matches(tenant_key, key)).map(|(tenant, _)| tenant.clone())
+}
+
+// Compare digests, so the comparison takes the same time wherever the secrets differ
+fn secret_matches(expected: &str, provided: &str) -> bool {
+    Sha256::digest(expected.as_bytes()) == Sha256::digest(provided.as_bytes())
+}
+
+const API_KEY_HEADER: &str = "X-API-Key";
+
+// The tenant the TEE serves commands that name none
+const DEFAULT_TENANT: &str = "default";
+
+// The same rules as the enclave's tenant and geofence ids
+fn valid_tenant_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_TENANT_ID_LENGTH
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+// The caller's X-Request-Id if it is a plausible id, otherwise a fresh one
+fn request_id(req: &actix_web::dev::ServiceRequest) -> String {
+    req.headers().get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| (1..=64).contains(&id.len()) && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')))
+        .map(str::to_string)
+        .unwrap_or_else(new_request_id)
+}
+
+fn new_request_id() -> String {
+    rand::thread_rng().gen::<[u8; 8]>().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+// A running TEE speaking the framed protocol (TEE_PROTOCOL=ndjson): one compact JSON command
+// per line on stdin, answered by exactly one compact JSON line on stdout. Commands are tagged
+// with ids so several can be in flight at once; a writer task drains the command queue into
+// stdin and a reader task hands each response line to its command
+struct TeeProcess {
+    child: Child,
+    commands: mpsc::UnboundedSender<QueuedCommand>,
+}
+
+impl TeeProcess {
+    fn spawn(config: &Config, slot: usize) -> Result<Self, String> {
+        let mut command = Command::new(&config.enarx_binary);
+        command.arg("run").arg(&config.wasm_path).env("TEE_PROTOCOL", "ndjson");
+        // Each slot keeps its own log, which a restarted process replays
+        if let Some(dir) = &config.command_log_dir {
+            command.env("TEE_COMMAND_LOG", format!("{}/enclave-{}.log", dir.trim_end_matches('/'), slot))
+                .env("TEE_STARTUP", "replay");
+        }
+        let mut child = command
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
-            .spawn();
-        
-        match child {
-            Ok(process) => {
-                *child_lock = Some(process);
-                
-                // Wait for process to be ready
-                info!("⏳ Waiting for restarted Enarx process to initialize...");
-                sleep(Duration::from_millis(1000)).await;
-                
-                // Almost the same as start_process but without recursion
-                if let Some(child) = child_lock.as_mut() {
-                    if let Some(stdout) = child.stdout.as_mut() {
-                        let mut reader = BufReader::new(stdout);
-                        let mut line = String::new();
-                        let mut attempts = 0;
-                        
-                        while attempts < 15 {
-                            match reader.read_line(&mut line) {
-                                Ok(0) => {
-                                    error!("❌ EOF reached while waiting for TEE prompt");
-                                    break;
-                                },
-                                Ok(bytes) => {
-                                    info!("📝 TEE restart ({}b): {}", bytes, line.trim());
-                                    if line.contains(">") || line.contains("Type a JSON command") {
-                                        info!("✅ TEE restarted and ready");
-                                        return Ok(());
-                                    }
-                                    line.clear();
-                                },
-                                Err(e) => {
-                                    error!("❌ Failed to read from stdout: {}", e);
-                                    return Err(format!("Failed to read from stdout: {}", e));
-                                }
-                            }
-                            attempts += 1;
-                            sleep(Duration::from_millis(200)).await;
-                        }
-                        
-                        if attempts >= 15 {
-                            error!("⚠️ Timed out waiting for restarted TEE prompt, but continuing");
-                            return Ok(());
-                        }
-                    }
-                    
-                    // Also log stderr in a separate thread
-                    if let Some(stderr) = child_lock.as_mut().unwrap().stderr.take() {
-                        std::thread::spawn(move || {
-                            let reader = BufReader::new(stderr);
-                            for line in reader.lines() {
-                                if let Ok(line) = line {
-                                    eprintln!("TEE stderr: {}", line);
-                                }
-                            }
-                        });
-                    }
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("Failed to start Enarx process: {}", e))?;
+        let stdin = child.stdin.take().ok_or("Failed to get stdin handle")?;
+        let stdout = child.stdout.take().ok_or("Failed to get stdout handle")?;
+        let pending = PendingResponses::default();
+        let (commands, queue) = mpsc::unbounded_channel();
+
+        actix_web::rt::spawn(write_commands(stdin, queue, pending.clone()));
+        actix_web::rt::spawn(read_responses(stdout, pending));
+        // The banner and diagnostics go to stderr in the framed protocol
+        if let Some(stderr) = child.stderr.take() {
+            actix_web::rt::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    info!(target: "tee_rewards", "{}", line);
                 }
-                
-                info!("✅ Enarx process restarted successfully");
-                Ok(())
+            });
+        }
+        Ok(Self { child, commands })
+    }
+
+    // Queue a tagged command; its response is delivered to `sender`
+    fn send(&self, id: u64, request_id: Option<String>, tenant_id: Option<String>, command: serde_json::Value, sender: oneshot::Sender<TeeAnswer>) -> Result<(), String> {
+        self.commands.send((id, request_id, tenant_id, command, sender))
+            .map_err(|_| "The TEE process stopped accepting commands".to_string())
+    }
+
+    fn is_running(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+}
+
+// Write queued commands to the TEE until the queue closes or stdin breaks
+async fn write_commands(mut stdin: ChildStdin, mut queue: mpsc::UnboundedReceiver<QueuedCommand>, pending: PendingResponses) {
+    while let Some((id, request_id, tenant_id, command, sender)) = queue.recv().await {
+        pending.lock().unwrap().insert(id, sender);
+        let mut envelope = serde_json::json!({ "id": id, "protocol_version": TEE_PROTOCOL_VERSION, "command": command });
+        if let Some(request_id) = request_id {
+            envelope["request_id"] = serde_json::Value::String(request_id);
+        }
+        if let Some(tenant_id) = tenant_id {
+            envelope["tenant_id"] = serde_json::Value::String(tenant_id);
+        }
+        let line = format!("{}\n", envelope);
+        if let Err(e) = stdin.write_all(line.as_bytes()).await {
+            error!(error = %e, "failed to write to the TEE");
+            pending.lock().unwrap().remove(&id);
+            return;
+        }
+        if let Err(e) = stdin.flush().await {
+            error!(error = %e, "failed to flush the TEE's stdin");
+            return;
+        }
+    }
+}
+
+// Hand each response line to the command with its id, refusing responses of another protocol
+// version, and push the events to WebSocket clients
+async fn read_responses(stdout: ChildStdout, pending: PendingResponses) {
+    let mut lines = BufReader::new(stdout).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        match serde_json::from_str::<TeeOutput>(&line) {
+            Ok(TeeOutput::Event(event)) if event.protocol_version == TEE_PROTOCOL_VERSION => publish_tee_event(event),
+            Ok(TeeOutput::Event(event)) => warn!(protocol_version = event.protocol_version, "dropped a TEE event of another protocol version"),
+            Ok(TeeOutput::Reply(reply)) => match pending.lock().unwrap().remove(&reply.id) {
+                Some(sender) if reply.protocol_version != TEE_PROTOCOL_VERSION => {
+                    let _ = sender.send(Err(format!(
+                        "The TEE speaks protocol version {} but this web interface needs version {}; deploy matching builds",
+                        reply.protocol_version, TEE_PROTOCOL_VERSION,
+                    )));
+                },
+                Some(sender) => {
+                    let _ = sender.send(Ok(reply.response.to_string()));
+                },
+                None => warn!(id = reply.id, request_id = ?reply.request_id, "TEE response arrived after its command gave up"),
             },
-            Err(e) => {
-                error!("❌ Failed to restart Enarx process: {}", e);
-                Err(format!("Failed to restart Enarx process: {}", e))
-            }
+            Err(e) => error!(error = %e, output = %line, "unframed TEE output"),
         }
     }
-    
-    async fn send_command(&self, command: String) -> Result<String, String> {
-        let mut child_lock = self.child.lock().unwrap();
-        
-        if let Some(child) = child_lock.as_mut() {
-            // Get a handle to stdin and stdout
-            if let Some(stdin) = child.stdin.as_mut() {
-                // Write the command to stdin
-                info!("⏳ Sending command to TEE: {}", command);
-                if let Err(e) = writeln!(stdin, "{}", command) {
-                    error!("❌ Failed to write to stdin: {}", e);
-                    return Err(format!("Failed to write to stdin: {}", e));
-                }
-                
-                // Create a BufReader to read from stdout
-                if let Some(stdout) = child.stdout.as_mut() {
-                    let mut reader = BufReader::new(stdout);
-                    let mut output = String::new();
-                    
-                    // Read until we get the prompt or timeout
-                    let mut retries = 0;
-                    let mut response_started = false;
-                    let mut json_bracket_count = 0;
-                    let mut is_complete_json = false;
-                    
-                    info!("🔍 Waiting for TEE response...");
-                    while retries < 30 { // Increased timeout retries
-                        // Set a timeout for the read operation
-                        match actix_web::rt::time::timeout(
-                            Duration::from_millis(500), 
-                            async {
-                                let mut tmp_line = String::new();
-                                match reader.read_line(&mut tmp_line) {
-                                    Ok(bytes) => Some((tmp_line, bytes)),
-                                    Err(e) => {
-                                        error!("❌ Error reading from stdout: {}", e);
-                                        None
-                                    }
-                                }
-                            }
-                        ).await {
-                            Ok(Some((new_line, bytes))) => {
-                                if bytes == 0 {
-                                    error!("❌ EOF reached while reading TEE response");
-                                    break;
-                                }
-                                
-                                info!("📝 TEE output ({}b): {}", bytes, new_line.trim());
-                                let line = new_line;
-                                
-                                // Track JSON structure brackets to determine if the response is complete
-                                for c in line.chars() {
-                                    if c == '{' {
-                                        json_bracket_count += 1;
-                                    } else if c == '}' {
-                                        json_bracket_count -= 1;
-                                        // When bracket count reaches 0 and we had some brackets, we have a complete JSON
-                                        if json_bracket_count == 0 && response_started {
-                                            is_complete_json = true;
-                                        }
-                                    }
-                                }
-                                
-                                // If we see a line with "{", it's likely the start of JSON response
-                                if line.trim().starts_with('{') {
-                                    info!("✅ JSON response detected");
-                                    response_started = true;
-                                }
-                                
-                                if response_started {
-                                    output.push_str(&line);
-                                }
-                                
-                                // Complete if:
-                                // 1. We see a prompt after getting some response, OR
-                                // 2. We have a complete JSON object (bracket count returned to 0)
-                                if ((line.contains(">") || line.contains("Type a JSON command")) && response_started) || is_complete_json {
-                                    if is_complete_json {
-                                        info!("✅ Complete JSON response detected (balanced brackets)");
-                                    } else {
-                                        info!("✅ Response complete (prompt found)");
-                                    }
-                                    
-                                    // Remove the prompt from the output if it exists
-                                    if let Some(pos) = output.rfind('>') {
-                                        output.truncate(pos);
-                                    }
-                                    
-                                    // Try to parse as JSON to validate
-                                    match serde_json::from_str::<serde_json::Value>(&output) {
-                                        Ok(_) => {
-                                            info!("✅ Successfully parsed JSON response");
-                                            return Ok(output.trim().to_string());
-                                        },
-                                        Err(e) => {
-                                            if is_complete_json {
-                                                error!("❌ Found complete JSON brackets but parsing failed: {}", e);
-                                                // Adding a small delay and continuing as we might need more content
-                                                sleep(Duration::from_millis(200)).await;
-                                                is_complete_json = false;
-                                            } else {
-                                                // If the prompt was found but JSON is invalid, return anyway
-                                                info!("⚠️ Prompt found but JSON parse failed: {}", e);
-                                                return Ok(output.trim().to_string());
-                                            }
-                                        }
-                                    }
-                                }
-                            },
-                            Ok(None) => {
-                                // Read error
-                                retries += 1;
-                            },
-                            Err(_) => {
-                                // Timeout occurred
-                                info!("⏳ Read operation timed out, retrying...");
-                                retries += 1;
-                                
-                                // If we have output but haven't received anything for a while,
-                                // try to parse what we have as JSON and see if it's valid
-                                if response_started && !output.is_empty() && retries > 5 {
-                                    match serde_json::from_str::<serde_json::Value>(&output) {
-                                        Ok(_) => {
-                                            info!("✅ Valid JSON detected after timeout");
-                                            return Ok(output.trim().to_string());
-                                        },
-                                        Err(_) => {
-                                            // Continue waiting, it's not valid JSON yet
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        
-                        if !response_started && retries >= 15 {
-                            // If we haven't received any response after multiple retries,
-                            // the process might be hung
-                            error!("⚠️ No response from TEE after multiple retries, process may be hung");
-                            
-                            // Try to recover by killing and restarting the process
-                            drop(child_lock);
-                            let mut new_lock = self.child.lock().unwrap();
-                            if let Some(mut proc) = new_lock.take() {
-                                let _ = proc.kill();
-                            }
-                            drop(new_lock);
-                            
-                            // Try to restart
-                            if let Err(e) = self.restart_process().await {
-                                return Err(format!("Failed to restart hung process: {}", e));
-                            }
-                            
-                            // Return error, client should retry
-                            return Err("TEE process was unresponsive and has been restarted. Please retry your request.".to_string());
-                        }
-                        
-                        info!("⏳ Waiting for more TEE output... (attempt {}/30)", retries);
-                        sleep(Duration::from_millis(200)).await; // Increased wait time
-                    }
-                    
-                    if output.is_empty() {
-                        error!("❌ No output received from TEE after {} attempts", retries);
-                        return Err("No output received from process".to_string());
-                    }
-                    
-                    // Try to parse what we have as JSON as a last resort
-                    match serde_json::from_str::<serde_json::Value>(&output) {
-                        Ok(_) => {
-                            info!("✅ Valid JSON detected at end of retries");
-                            return Ok(output.trim().to_string());
-                        },
-                        Err(e) => {
-                            info!("⚠️ Timed out waiting for complete response, returning partial output (parse error: {})", e);
-                            return Ok(output.trim().to_string());
-                        }
-                    }
-                } else {
-                    error!("❌ Failed to get stdout handle");
-                    return Err("Failed to get stdout handle".to_string());
-                }
-            } else {
-                error!("❌ Failed to get stdin handle");
-                return Err("Failed to get stdin handle".to_string());
-            }
-        } else {
-            error!("❌ Enarx process not running");
-            // Try to start the process
-            drop(child_lock);
-            if let Err(e) = self.start_process().await {
-                return Err(format!("Failed to start Enarx process: {}", e));
+    // Dropping the senders fails the commands still waiting
+    pending.lock().unwrap().clear();
+}
+
+// One enclave process of the pool with its load and health
+struct PooledTee {
+    // Position in the pool, which names the process's command log
+    slot: usize,
+    process: Mutex<Option<TeeProcess>>,
+    // Whether the process answered the supervisor's last ping
+    healthy: AtomicBool,
+    // Commands sent and not answered yet
+    in_flight: AtomicUsize,
+    // Commands that timed out since the last answered one
+    consecutive_failures: AtomicU32,
+    restarts: AtomicU32,
+    // Commands answered and commands that timed out since startup
+    answered: AtomicU64,
+    timed_out: AtomicU64,
+}
+
+impl PooledTee {
+    fn new(slot: usize) -> Self {
+        Self {
+            slot,
+            process: Mutex::new(None),
+            healthy: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+            consecutive_failures: AtomicU32::new(0),
+            restarts: AtomicU32::new(0),
+            answered: AtomicU64::new(0),
+            timed_out: AtomicU64::new(0),
+        }
+    }
+
+    // Replace the process; dropping the old one kills it
+    fn restart(&self, process: &mut Option<TeeProcess>, config: &Config) {
+        self.healthy.store(false, Ordering::Relaxed);
+        if process.take().is_some() {
+            self.restarts.fetch_add(1, Ordering::Relaxed);
+        }
+        match TeeProcess::spawn(config, self.slot) {
+            Ok(spawned) => *process = Some(spawned),
+            Err(e) => error!(error = %e, "failed to restart TEE process"),
+        }
+    }
+}
+
+// Circuit breaker over the pool: after `breaker_failures` commands in a row fail, commands are
+// refused until `until`; then a single probe command is let through, and its outcome closes the
+// circuit or opens it again
+#[derive(Debug, Clone, Copy)]
+enum CircuitState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen,
+}
+
+impl CircuitState {
+    fn name(&self) -> &'static str {
+        match self {
+            CircuitState::Closed { .. } => "closed",
+            CircuitState::Open { .. } => "open",
+            CircuitState::HalfOpen => "half-open",
+        }
+    }
+}
+
+// A command let through the circuit breaker, whose outcome is recorded when it finishes. A
+// command dropped before that (its client went away and the handler with it) tells nothing
+// about the TEE; if it was the probe, the circuit goes back to open so the next command probes
+struct Admission<'a> {
+    process: &'a EnarxProcess,
+    probe: bool,
+    finished: bool,
+}
+
+impl Admission<'_> {
+    fn finish(mut self, success: bool) {
+        self.finished = true;
+        self.process.record_outcome(success);
+    }
+}
+
+impl Drop for Admission<'_> {
+    fn drop(&mut self) {
+        if self.finished || !self.probe {
+            return;
+        }
+        let mut breaker = self.process.breaker.lock().unwrap();
+        if matches!(*breaker, CircuitState::HalfOpen) {
+            warn!("circuit breaker probe abandoned; the next command probes again");
+            *breaker = CircuitState::Open { until: Instant::now() };
+        }
+    }
+}
+
+// Enarx process management
+struct EnarxProcess {
+    config: Config,
+    breaker: std::sync::Mutex<CircuitState>,
+    pool: Vec<PooledTee>,
+    next_id: AtomicU64,
+}
+
+impl EnarxProcess {
+    fn new(config: Config) -> Self {
+        Self {
+            pool: (0..config.pool_size).map(PooledTee::new).collect(),
+            breaker: std::sync::Mutex::new(CircuitState::Closed { failures: 0 }),
+            config,
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    // Start the processes that were never started; ones that died are left to the supervisor
+    async fn start_process(&self) -> Result<(), String> {
+        for (slot, tee) in self.pool.iter().enumerate() {
+            let mut process_lock = tee.process.lock().await;
+            if process_lock.is_none() {
+                info!(slot, "starting TEE process");
+                *process_lock = Some(TeeProcess::spawn(&self.config, slot).map_err(|e| {
+                    error!(slot, error = %e, "failed to start TEE process");
+                    e
+                })?);
             }
-            
-            // Try again with the process started
-            sleep(Duration::from_millis(500)).await;
-            
-            // Use a separate method for retry to avoid recursion
-            return self.retry_command(command).await;
         }
+        Ok(())
+    }
+
+    // Whether any process answered its last ping
+    fn is_ready(&self) -> bool {
+        self.pool.iter().any(|tee| tee.healthy.load(Ordering::Relaxed))
     }
     
-    // Separate method to avoid recursion in async fn
-    async fn retry_command(&self, command: String) -> Result<String, String> {
-        info!("🔄 Retrying command after process restart");
-        
-        let mut child_lock = self.child.lock().unwrap();
-        
-        if let Some(child) = child_lock.as_mut() {
-            // Get a handle to stdin and stdout
-            if let Some(stdin) = child.stdin.as_mut() {
-                // Write the command to stdin
-                info!("⏳ Sending command to restarted TEE: {}", command);
-                if let Err(e) = writeln!(stdin, "{}", command) {
-                    error!("❌ Failed to write to stdin after restart: {}", e);
-                    return Err(format!("Failed to write to stdin after restart: {}", e));
-                }
-                
-                // Create a BufReader to read from stdout
-                if let Some(stdout) = child.stdout.as_mut() {
-                    let mut reader = BufReader::new(stdout);
-                    let mut output = String::new();
-                    
-                    // Read until we get the prompt or timeout
-                    let mut retries = 0;
-                    let mut response_started = false;
-                    let mut json_bracket_count = 0;
-                    let mut is_complete_json = false;
-                    
-                    info!("🔍 Waiting for response from restarted TEE...");
-                    while retries < 30 {
-                        // Similar timeout logic to send_command
-                        match actix_web::rt::time::timeout(
-                            Duration::from_millis(500), 
-                            async {
-                                let mut tmp_line = String::new();
-                                match reader.read_line(&mut tmp_line) {
-                                    Ok(bytes) => Some((tmp_line, bytes)),
-                                    Err(e) => {
-                                        error!("❌ Error reading from stdout after restart: {}", e);
-                                        None
-                                    }
-                                }
-                            }
-                        ).await {
-                            Ok(Some((line, bytes))) => {
-                                if bytes == 0 {
-                                    error!("❌ EOF reached while reading restarted TEE response");
-                                    break;
-                                }
-                                
-                                info!("📝 Restarted TEE output ({}b): {}", bytes, line.trim());
-                                
-                                // Track JSON structure brackets to determine if the response is complete
-                                for c in line.chars() {
-                                    if c == '{' {
-                                        json_bracket_count += 1;
-                                    } else if c == '}' {
-                                        json_bracket_count -= 1;
-                                        // When bracket count reaches 0 and we had some brackets, we have a complete JSON
-                                        if json_bracket_count == 0 && response_started {
-                                            is_complete_json = true;
-                                        }
-                                    }
-                                }
-                                
-                                // If we see a line with "{", it's likely the start of JSON response
-                                if line.trim().starts_with('{') {
-                                    info!("✅ JSON response detected from restarted TEE");
-                                    response_started = true;
-                                }
-                                
-                                if response_started {
-                                    output.push_str(&line);
-                                }
-                                
-                                // Complete if:
-                                // 1. We see a prompt after getting some response, OR
-                                // 2. We have a complete JSON object (bracket count returned to 0)
-                                if ((line.contains(">") || line.contains("Type a JSON command")) && response_started) || is_complete_json {
-                                    if is_complete_json {
-                                        info!("✅ Complete JSON response detected from restarted TEE (balanced brackets)");
-                                    } else {
-                                        info!("✅ Response complete from restarted TEE (prompt found)");
-                                    }
-                                    
-                                    // Remove the prompt from the output if it exists
-                                    if let Some(pos) = output.rfind('>') {
-                                        output.truncate(pos);
-                                    }
-                                    
-                                    // Try to parse as JSON to validate
-                                    match serde_json::from_str::<serde_json::Value>(&output) {
-                                        Ok(_) => {
-                                            info!("✅ Successfully parsed JSON response from restarted TEE");
-                                            return Ok(output.trim().to_string());
-                                        },
-                                        Err(e) => {
-                                            if is_complete_json {
-                                                error!("❌ Found complete JSON brackets but parsing failed: {}", e);
-                                                // Adding a small delay and continuing as we might need more content
-                                                sleep(Duration::from_millis(200)).await;
-                                                is_complete_json = false;
-                                            } else {
-                                                // If the prompt was found but JSON is invalid, return anyway
-                                                info!("⚠️ Prompt found but JSON parse failed for restarted TEE: {}", e);
-                                                return Ok(output.trim().to_string());
-                                            }
-                                        }
-                                    }
-                                }
-                            },
-                            Ok(None) => {
-                                // Read error
-                                retries += 1;
-                            },
-                            Err(_) => {
-                                // Timeout occurred
-                                info!("⏳ Read operation on restarted TEE timed out, retrying...");
-                                retries += 1;
-                                
-                                // If we have output but haven't received anything for a while,
-                                // try to parse what we have as JSON and see if it's valid
-                                if response_started && !output.is_empty() && retries > 5 {
-                                    match serde_json::from_str::<serde_json::Value>(&output) {
-                                        Ok(_) => {
-                                            info!("✅ Valid JSON detected from restarted TEE after timeout");
-                                            return Ok(output.trim().to_string());
-                                        },
-                                        Err(_) => {
-                                            // Continue waiting, it's not valid JSON yet
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        
-                        info!("⏳ Waiting for more output from restarted TEE... (attempt {}/30)", retries);
-                        sleep(Duration::from_millis(200)).await;
-                    }
-                    
-                    if output.is_empty() {
-                        error!("❌ No output received from restarted TEE after {} attempts", retries);
-                        return Err("No output received from restarted process".to_string());
-                    }
-                    
-                    // Try to parse what we have as JSON as a last resort
-                    match serde_json::from_str::<serde_json::Value>(&output) {
-                        Ok(_) => {
-                            info!("✅ Valid JSON detected from restarted TEE at end of retries");
-                            return Ok(output.trim().to_string());
-                        },
-                        Err(e) => {
-                            info!("⚠️ Timed out waiting for complete response from restarted TEE, returning partial output (parse error: {})", e);
-                            return Ok(output.trim().to_string());
-                        }
-                    }
-                } else {
-                    error!("❌ Failed to get stdout handle from restarted TEE");
-                    return Err("Failed to get stdout handle from restarted TEE".to_string());
-                }
-            } else {
-                error!("❌ Failed to get stdin handle from restarted TEE");
-                return Err("Failed to get stdin handle from restarted TEE".to_string());
+    // Send a command to the home slot of the request's tenant and wait for its response, sending
+    // it again with exponential backoff if it never reached a working TEE. While the circuit
+    // breaker is open commands fail at once instead of waiting on a broken TEE
+    async fn send_command(&self, command: String) -> Result<String, TeeError> {
+        let command: serde_json::Value = serde_json::from_str(&command)
+            .map_err(|e| TeeError::Invalid(format!("Invalid command: {}", e)))?;
+        let admission = self.admit()?;
+        
+        let slot = self.home_slot(current_tenant().as_deref().unwrap_or(DEFAULT_TENANT));
+        let mut retries = 0;
+        let result = loop {
+            match self.send_command_to(slot, command.clone()).await {
+                Err(TeeError::Unavailable(e)) if retries < self.config.max_retries => {
+                    // Full backoff for the first retry, doubled after; jitter keeps handlers that
+                    // failed together from retrying together
+                    let backoff = self.config.retry_backoff_ms.saturating_mul(1 << retries.min(16));
+                    let backoff = Duration::from_millis(rand::thread_rng().gen_range(backoff / 2..=backoff));
+                    retries += 1;
+                    warn!(error = %e, ?backoff, retry = retries, max_retries = self.config.max_retries, "TEE unavailable; retrying");
+                    sleep(backoff).await;
+                },
+                result => break result,
             }
+        };
+        admission.finish(result.is_ok());
+        result
+    }
+
+    // Let a command through the circuit breaker, or refuse it while the circuit is open
+    fn admit(&self) -> Result<Admission<'_>, TeeError> {
+        let mut breaker = self.breaker.lock().unwrap();
+        match *breaker {
+            CircuitState::Closed { .. } => Ok(Admission { process: self, probe: false, finished: false }),
+            CircuitState::Open { until } if until > Instant::now() => Err(TeeError::CircuitOpen {
+                retry_after: (until - Instant::now()).as_secs() + 1,
+            }),
+            CircuitState::Open { .. } => {
+                info!("circuit breaker half-open; probing the TEE");
+                *breaker = CircuitState::HalfOpen;
+                Ok(Admission { process: self, probe: true, finished: false })
+            },
+            // Another command is probing
+            CircuitState::HalfOpen => Err(TeeError::CircuitOpen { retry_after: 1 }),
+        }
+    }
+
+    fn record_outcome(&self, success: bool) {
+        let mut breaker = self.breaker.lock().unwrap();
+        let failures = match (*breaker, success) {
+            (_, true) => {
+                if !matches!(*breaker, CircuitState::Closed { .. }) {
+                    info!("circuit breaker closed");
+                }
+                *breaker = CircuitState::Closed { failures: 0 };
+                return;
+            },
+            (CircuitState::Closed { failures }, false) => failures + 1,
+            (CircuitState::HalfOpen, false) => self.config.breaker_failures,
+            (CircuitState::Open { .. }, false) => return,
+        };
+        *breaker = if failures >= self.config.breaker_failures {
+            error!(failures, open_seconds = self.config.breaker_open_seconds, "circuit breaker opened");
+            CircuitState::Open { until: Instant::now() + Duration::from_secs(self.config.breaker_open_seconds) }
         } else {
-            error!("❌ Restarted Enarx process unexpectedly not found");
-            return Err("Restarted Enarx process unexpectedly not found".to_string());
+            CircuitState::Closed { failures }
+        };
+    }
+
+    // The process holding a tenant's data. Every enclave keeps its tenants' locations, claims,
+    // nonces and limits to itself, so a tenant is never served by another one, even while its
+    // own is down. FNV-1a, so the slot stays the same across restarts of this server
+    fn home_slot(&self, tenant: &str) -> usize {
+        let hash = tenant.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+        (hash % self.pool.len() as u64) as usize
+    }
+    
+    // Send a command to one process and wait for its response; the process lock is only held
+    // while queueing, so other commands can be sent while this one is in flight
+    async fn send_command_to(&self, slot: usize, command: serde_json::Value) -> Result<String, TeeError> {
+        let tee = &self.pool[slot];
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, response) = oneshot::channel();
+        
+        {
+            let mut process_lock = tee.process.lock().await;
+            if process_lock.is_none() {
+                info!(slot, "starting TEE process");
+                *process_lock = Some(TeeProcess::spawn(&self.config, slot).map_err(TeeError::Unavailable)?);
+            }
+            let process = process_lock.as_ref().unwrap();
+            debug!(id, slot, %command, "queueing TEE command");
+            process.send(id, REQUEST_ID.try_with(String::clone).ok(), current_tenant(), command, sender).map_err(TeeError::Unavailable)?;
+        }
+        
+        tee.in_flight.fetch_add(1, Ordering::Relaxed);
+        let started = Instant::now();
+        let result = timeout(self.config.command_timeout(), response).await;
+        tee.in_flight.fetch_sub(1, Ordering::Relaxed);
+        match result {
+            Ok(Ok(Err(message))) => Err(TeeError::Incompatible(message)),
+            Ok(Ok(Ok(response))) => {
+                debug!(id, slot, elapsed_ms = started.elapsed().as_millis() as u64, "TEE answered");
+                tee.consecutive_failures.store(0, Ordering::Relaxed);
+                tee.answered.fetch_add(1, Ordering::Relaxed);
+                Ok(response)
+            },
+            Ok(Err(_)) => Err(TeeError::Unavailable("The TEE process closed its output".to_string())),
+            Err(_) => {
+                // A hung process is replaced by the supervisor at its next check
+                warn!(id, slot, "TEE did not answer in time");
+                tee.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+                tee.timed_out.fetch_add(1, Ordering::Relaxed);
+                Err(TeeError::TimedOut(format!("No response from the TEE within {} seconds; please retry your request.", self.config.command_timeout_seconds)))
+            }
+        }
+    }
+
+    // Start or restart one process if it is down, then ping it; a process that does not answer
+    // is replaced and pinged again at the next check
+    async fn check(&self, slot: usize) {
+        let tee = &self.pool[slot];
+        {
+            let mut process_lock = tee.process.lock().await;
+            match process_lock.as_mut().map(TeeProcess::is_running) {
+                Some(true) => {},
+                Some(false) => {
+                    warn!(slot, "TEE process exited; restarting");
+                    tee.restart(&mut process_lock, &self.config);
+                },
+                None => {
+                    info!(slot, "starting TEE process");
+                    tee.restart(&mut process_lock, &self.config);
+                },
+            }
+        }
+        
+        // Pings queue behind the commands in flight, so they get the same timeout
+        match self.send_command_to(slot, serde_json::json!({ "Ping": null })).await {
+            Ok(response) if response.contains("Pong") => {
+                if !tee.healthy.swap(true, Ordering::Relaxed) {
+                    info!(slot, "TEE process is healthy");
+                }
+            },
+            // Restarting the same build would not help; it stays unhealthy until redeployed
+            Err(TeeError::Incompatible(e)) => {
+                tee.healthy.store(false, Ordering::Relaxed);
+                error!(slot, error = %e, "TEE process is incompatible");
+            },
+            failure => {
+                warn!(slot, ?failure, "TEE process failed its health check; restarting");
+                let mut process_lock = tee.process.lock().await;
+                tee.restart(&mut process_lock, &self.config);
+            }
         }
     }
 }
 
+// Keep every process of the pool alive for as long as the server runs
+async fn supervise(enarx_process: Arc<EnarxProcess>) {
+    loop {
+        for slot in 0..enarx_process.pool.len() {
+            enarx_process.check(slot).await;
+        }
+        sleep(enarx_process.config.health_check_interval()).await;
+    }
+}
+
+// The HTTP response for a command the TEE did not answer; while the circuit breaker is open
+// clients are told when to come back
+fn tee_error_response(e: &TeeError) -> HttpResponse {
+    error!(error = %e, "failed to communicate with the TEE");
+    let body = ApiResponse {
+        success: false,
+        message: format!("Failed to communicate with Enarx: {}", e),
+    };
+    match e {
+        TeeError::CircuitOpen { retry_after } => HttpResponse::ServiceUnavailable()
+            .insert_header(("Retry-After", retry_after.to_string()))
+            .json(body),
+        TeeError::Invalid(_) => HttpResponse::BadRequest().json(body),
+        TeeError::Incompatible(_) => HttpResponse::ServiceUnavailable().json(body),
+        _ => HttpResponse::InternalServerError().json(body),
+    }
+}
+
 // API endpoints
 async fn register_location(
     enarx_process: web::Data<Arc<EnarxProcess>>, 
     req: web::Json<LocationRegistrationRequest>
 ) -> Result<HttpResponse, Error> {
-    info!("📥 Received location registration request for user: {}", req.user_id);
+    info!(user_id = %req.user_id, "location registration");
+    if let Some(response) = validation_response(&req.0) {
+        return Ok(response);
+    }
     
     // Ensure process is running
-    info!("🔄 Starting/checking Enarx process");
     if let Err(e) = enarx_process.start_process().await {
-        error!("❌ Failed to start Enarx process: {}", e);
+        error!(error = %e, "failed to start the TEE");
         return Ok(HttpResponse::InternalServerError().json(ApiResponse {
             success: false,
             message: format!("Failed to start Enarx process: {}", e),
@@ -708,37 +1391,14 @@ async fn register_location(
     }
     
     // Prepare the command for the TEE
-    let timestamp = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    
-    info!("🔧 Preparing location registration command");
-    let command = serde_json::json!({
-        "RegisterLocation": {
-            "lat": req.lat,
-            "lon": req.lon,
-            "timestamp": timestamp,
-            "user_id": req.user_id,
-            "device_id": req.device_id,
-            "sensors": {
-                "wifi_networks": req.wifi_networks,
-                "cell_towers": req.cell_towers,
-                "accelerometer": req.accelerometer,
-                "gyroscope": req.gyroscope,
-                "is_mock_location": req.is_mock_location
-            }
-        }
-    });
+    let command = req.tee_command();
     
     // Send command to process
-    info!("📤 Sending registration command to Enarx process");
     match enarx_process.send_command(command.to_string()).await {
         Ok(output) => {
-            info!("📩 Received TEE response: {}", output);
+            debug!(response = %output, "TEE responded");
             
             // Parse the response
-            info!("🔍 Parsing TEE response");
             match serde_json::from_str::<serde_json::Value>(&output) {
                 Ok(response) => {
                     if let Some(location_registered) = response.get("LocationRegistered") {
@@ -758,39 +1418,37 @@ async fn register_location(
                             encrypted_location_id: enc_location.to_string(),
                             success,
                             message: message.to_string(),
+                            geofence_events: location_registered.get("geofence_events")
+                                .and_then(|v| v.as_array())
+                                .cloned()
+                                .unwrap_or_default(),
                         };
                         
                         if success {
-                            info!("✅ Registration successful: {}", enc_location);
-                            return Ok(HttpResponse::Ok().json(response));
+                            info!("registration succeeded");
+                            Ok(HttpResponse::Ok().json(response))
                         } else {
-                            error!("⚠️ Registration failed: {}", message);
-                            return Ok(HttpResponse::BadRequest().json(response));
+                            warn!(message, "registration rejected");
+                            Ok(HttpResponse::BadRequest().json(response))
                         }
                     } else {
-                        error!("❌ Unexpected response format from TEE: {}", output);
-                        return Ok(HttpResponse::InternalServerError().json(ApiResponse {
+                        error!(response = %output, "unexpected response format from the TEE");
+                        Ok(HttpResponse::InternalServerError().json(ApiResponse {
                             success: false,
                             message: "Unexpected response format from TEE".to_string(),
-                        }));
+                        }))
                     }
                 },
                 Err(e) => {
-                    error!("❌ Failed to parse TEE response: {} - Raw output: {}", e, output);
-                    return Ok(HttpResponse::InternalServerError().json(ApiResponse {
+                    error!(error = %e, response = %output, "failed to parse the TEE response");
+                    Ok(HttpResponse::InternalServerError().json(ApiResponse {
                         success: false,
                         message: format!("Failed to parse TEE response: {}", e),
-                    }));
+                    }))
                 }
             }
         },
-        Err(e) => {
-            error!("❌ Failed to communicate with Enarx: {}", e);
-            return Ok(HttpResponse::InternalServerError().json(ApiResponse {
-                success: false,
-                message: format!("Failed to communicate with Enarx: {}", e),
-            }));
-        }
+        Err(e) => Ok(tee_error_response(&e)),
     }
 }
 
@@ -798,12 +1456,14 @@ async fn get_location(
     enarx_process: web::Data<Arc<EnarxProcess>>, 
     req: web::Json<LocationLookupRequest>
 ) -> Result<HttpResponse, Error> {
-    info!("📥 Received location lookup request for encrypted ID: {}", req.encrypted_location_id);
+    info!(encrypted_location_id = %req.encrypted_location_id, "location lookup");
+    if let Some(response) = validation_response(&req.0) {
+        return Ok(response);
+    }
     
     // Ensure process is running
-    info!("🔄 Starting/checking Enarx process");
     if let Err(e) = enarx_process.start_process().await {
-        error!("❌ Failed to start Enarx process: {}", e);
+        error!(error = %e, "failed to start the TEE");
         return Ok(HttpResponse::InternalServerError().json(ApiResponse {
             success: false,
             message: format!("Failed to start Enarx process: {}", e),
@@ -811,27 +1471,26 @@ async fn get_location(
     }
     
     // Prepare command
-    info!("🔧 Preparing location lookup command");
-    let command = serde_json::json!({
-        "GetLocation": req.encrypted_location_id
-    });
+    let command = req.tee_command();
     
     // Send command to process
-    info!("📤 Sending location lookup command to Enarx process");
     match enarx_process.send_command(command.to_string()).await {
         Ok(output) => {
-            info!("📩 Received TEE response: {}", output);
+            debug!(response = %output, "TEE responded");
             
             // Parse the response
-            info!("🔍 Parsing TEE response");
             match serde_json::from_str::<serde_json::Value>(&output) {
                 Ok(response) => {
-                    if let Some(location) = response.get("Location") {
+                    if let Some(location) = response.get("LocationData") {
                         // Check if location was found
                         if location.get("success").and_then(|v| v.as_bool()).unwrap_or(false) {
-                            let lat = location.get("lat").and_then(|v| v.as_f64());
-                            let lon = location.get("lon").and_then(|v| v.as_f64());
-                            let timestamp = location.get("timestamp").and_then(|v| v.as_u64());
+                            let found = location.get("location");
+                            let lat = found.and_then(|l| l.get("lat")).and_then(|v| v.as_f64());
+                            let lon = found.and_then(|l| l.get("lon")).and_then(|v| v.as_f64());
+                            // The TEE keeps RFC 3339 timestamps; this API reports Unix seconds
+                            let timestamp = found.and_then(|l| l.get("timestamp")).and_then(|v| v.as_str())
+                                .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+                                .map(|t| t.timestamp() as u64);
                             let message = location.get("message").and_then(|v| v.as_str()).unwrap_or("Success");
                             
                             let response = LocationResponse {
@@ -842,8 +1501,8 @@ async fn get_location(
                                 message: message.to_string(),
                             };
                             
-                            info!("✅ Location found: lat={:?}, lon={:?}", lat, lon);
-                            return Ok(HttpResponse::Ok().json(response));
+                            info!(?lat, ?lon, "location found");
+                            Ok(HttpResponse::Ok().json(response))
                         } else {
                             let message = location.get("message").and_then(|v| v.as_str()).unwrap_or("Location not found");
                             
@@ -855,47 +1514,49 @@ async fn get_location(
                                 message: message.to_string(),
                             };
                             
-                            error!("⚠️ Location not found: {}", message);
-                            return Ok(HttpResponse::NotFound().json(response));
+                            warn!(message, "location not found");
+                            Ok(HttpResponse::NotFound().json(response))
                         }
                     } else {
-                        error!("❌ Unexpected response format from TEE: {}", output);
-                        return Ok(HttpResponse::InternalServerError().json(ApiResponse {
+                        error!(response = %output, "unexpected response format from the TEE");
+                        Ok(HttpResponse::InternalServerError().json(ApiResponse {
                             success: false,
                             message: "Unexpected response format from TEE".to_string(),
-                        }));
+                        }))
                     }
                 },
                 Err(e) => {
-                    error!("❌ Failed to parse TEE response: {} - Raw output: {}", e, output);
-                    return Ok(HttpResponse::InternalServerError().json(ApiResponse {
+                    error!(error = %e, response = %output, "failed to parse the TEE response");
+                    Ok(HttpResponse::InternalServerError().json(ApiResponse {
                         success: false,
                         message: format!("Failed to parse TEE response: {}", e),
-                    }));
+                    }))
                 }
             }
         },
-        Err(e) => {
-            error!("❌ Failed to communicate with Enarx: {}", e);
-            return Ok(HttpResponse::InternalServerError().json(ApiResponse {
-                success: false,
-                message: format!("Failed to communicate with Enarx: {}", e),
-            }));
-        }
+        Err(e) => Ok(tee_error_response(&e)),
     }
 }
 
 async fn generate_heatmap(
     enarx_process: web::Data<Arc<EnarxProcess>>, 
+    cache: web::Data<Arc<ResponseCache>>,
+    http_req: actix_web::HttpRequest,
     req: web::Json<HeatmapRequest>
 ) -> Result<HttpResponse, Error> {
-    info!("📥 Received heatmap request for area: [{}, {}] to [{}, {}]", 
-        req.min_lat, req.min_lon, req.max_lat, req.max_lon);
+    info!(min_lat = req.min_lat, min_lon = req.min_lon, max_lat = req.max_lat, max_lon = req.max_lon, "heatmap");
+    if let Some(response) = validation_response(&req.0) {
+        return Ok(response);
+    }
+    let cache_key = format!("{}/heatmap:{:.6},{:.6},{:.6},{:.6}", current_tenant().unwrap_or_default(), req.min_lat, req.min_lon, req.max_lat, req.max_lon);
+    if let Some(cached) = cache.get(&cache_key).await {
+        info!("heatmap served from cache");
+        return Ok(cache.respond(&http_req, &cached));
+    }
     
     // Ensure process is running
-    info!("🔄 Starting/checking Enarx process");
     if let Err(e) = enarx_process.start_process().await {
-        error!("❌ Failed to start Enarx process: {}", e);
+        error!(error = %e, "failed to start the TEE");
         return Ok(HttpResponse::InternalServerError().json(ApiResponse {
             success: false,
             message: format!("Failed to start Enarx process: {}", e),
@@ -903,24 +1564,14 @@ async fn generate_heatmap(
     }
     
     // Prepare command
-    info!("🔧 Preparing heatmap generation command");
-    let command = serde_json::json!({
-        "GenerateHeatmap": {
-            "min_lat": req.min_lat,
-            "min_lon": req.min_lon,
-            "max_lat": req.max_lat,
-            "max_lon": req.max_lon
-        }
-    });
+    let command = req.tee_command();
     
     // Send command to process
-    info!("📤 Sending heatmap command to Enarx process");
     match enarx_process.send_command(command.to_string()).await {
         Ok(output) => {
-            info!("📩 Received TEE response: {}", output);
+            debug!(response = %output, "TEE responded");
             
             // Parse the response
-            info!("🔍 Parsing TEE response");
             match serde_json::from_str::<serde_json::Value>(&output) {
                 Ok(response) => {
                     if let Some(heatmap) = response.get("Heatmap") {
@@ -956,7 +1607,7 @@ async fn generate_heatmap(
 
                         
                         let cell_count = grid_cells.len();
-                        info!("✅ Heatmap generated with {} cells, max value: {}", cell_count, max_value);
+                        info!(cells = cell_count, max_value, "heatmap generated");
                         
                         let response = HeatmapResponse {
                             grid_cells,
@@ -964,44 +1615,307 @@ async fn generate_heatmap(
                             success: true,
                             message: "Heatmap generated successfully".to_string(),
                         };
-                        return Ok(HttpResponse::Ok().json(response));
+                        let cached = cache.insert(cache_key, &response).await;
+                        Ok(cache.respond(&http_req, &cached))
                     } else {
-                        error!("❌ Unexpected response format from TEE: {}", output);
-                        return Ok(HttpResponse::InternalServerError().json(ApiResponse {
+                        error!(response = %output, "unexpected response format from the TEE");
+                        Ok(HttpResponse::InternalServerError().json(ApiResponse {
                             success: false,
                             message: "Unexpected response format from TEE".to_string(),
-                        }));
+                        }))
                     }
                 },
                 Err(e) => {
-                    error!("❌ Failed to parse TEE response: {} - Raw output: {}", e, output);
-                    return Ok(HttpResponse::InternalServerError().json(ApiResponse {
+                    error!(error = %e, response = %output, "failed to parse the TEE response");
+                    Ok(HttpResponse::InternalServerError().json(ApiResponse {
                         success: false,
                         message: format!("Failed to parse TEE response: {}", e),
-                    }));
+                    }))
                 }
             }
         },
-        Err(e) => {
-            error!("❌ Failed to communicate with Enarx: {}", e);
-            return Ok(HttpResponse::InternalServerError().json(ApiResponse {
-                success: false,
-                message: format!("Failed to communicate with Enarx: {}", e),
-            }));
+        Err(e) => Ok(tee_error_response(&e)),
+    }
+}
+
+// WebSocket command channel: an authenticated client sends the sub-requests of /api/batch one
+// at a time, tagged with an id, over a single connection:
+//   -> {"id": 1, "type": "lookup_location", "encrypted_location_id": "..."}
+//   <- {"id": 1, "success": true, "response": {"LocationData": {...}}}
+// and is pushed the reward events of every user it has registered a location or claimed a
+// reward for:
+//   <- {"event": "reward", "user_id": "user123", "data": {...}}
+async fn ws(
+    enarx_process: web::Data<Arc<EnarxProcess>>,
+    req: actix_web::HttpRequest,
+    body: web::Payload
+) -> Result<HttpResponse, Error> {
+    let tokens = &enarx_process.config.ws_tokens;
+    let api_keys = &enarx_process.config.api_keys;
+    if tokens.is_empty() && api_keys.is_empty() {
+        return Ok(HttpResponse::NotFound().json(ApiResponse {
+            success: false,
+            message: "The WebSocket channel is disabled".to_string(),
+        }));
+    }
+    // Browsers cannot set headers on a WebSocket handshake, so the token may also be a query parameter
+    let bearer = req.headers().get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+    let query = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.get("token").cloned());
+    // A WebSocket token acts for the default tenant, an API key for its own
+    let tenant = match bearer.or(query) {
+        Some(token) if tokens.iter().any(|expected| secret_matches(expected, &token)) => None,
+        token => match token.and_then(|token| tenant_of_key(api_keys, &token)) {
+            Some(tenant) => Some(tenant),
+            None => {
+                return Ok(HttpResponse::Unauthorized().json(ApiResponse {
+                    success: false,
+                    message: "A valid token is required".to_string(),
+                }));
+            },
+        },
+    };
+
+    let (response, session, stream) = actix_ws::handle(&req, body)?;
+    info!(?tenant, "WebSocket client connected");
+    actix_web::rt::spawn(ws_session(enarx_process.get_ref().clone(), tenant, session, stream));
+    Ok(response)
+}
+
+// A reward credited to a user, or a geofence they entered or left, pushed to the WebSocket
+// clients following them
+#[derive(Debug, Clone, Serialize)]
+struct PushEvent {
+    // Only clients of the same tenant follow a user id; None is the TEE's default tenant
+    #[serde(skip)]
+    tenant: Option<String>,
+    event: &'static str,
+    user_id: String,
+    data: serde_json::Value,
+}
+
+// Events buffered for each WebSocket client; a client that falls further behind misses some
+const PUSH_EVENT_CAPACITY: usize = 256;
+
+static PUSH_EVENTS: std::sync::LazyLock<broadcast::Sender<PushEvent>> =
+    std::sync::LazyLock::new(|| broadcast::channel(PUSH_EVENT_CAPACITY).0);
+
+// The user a ClaimReward response credited a reward to
+fn rewarded_user(response: &serde_json::Value) -> Option<&str> {
+    response["RewardClaimed"]["reward"]["user_id"].as_str()
+}
+
+// Push the reward of a ClaimReward response, if it credited one
+fn publish_reward(response: &serde_json::Value) {
+    if let Some(user_id) = rewarded_user(response) {
+        // Sending only fails when no client is connected
+        let _ = PUSH_EVENTS.send(PushEvent {
+            tenant: current_tenant(),
+            event: "reward",
+            user_id: user_id.to_string(),
+            data: response["RewardClaimed"]["reward"].clone(),
+        });
+    }
+}
+
+// Push a geofence event from a TEE's event stream
+fn publish_tee_event(event: TeeEvent) {
+    match event.event.get("Geofence") {
+        Some(geofence) => {
+            let user_id = geofence.get("user_id").and_then(|v| v.as_str()).unwrap_or_default();
+            let _ = PUSH_EVENTS.send(PushEvent {
+                tenant: Some(event.tenant_id),
+                event: "geofence",
+                user_id: user_id.to_string(),
+                data: geofence.clone(),
+            });
+        },
+        None => warn!(event = %event.event, "unknown TEE event"),
+    }
+}
+
+async fn ws_session(enarx_process: Arc<EnarxProcess>, tenant: Option<String>, mut session: actix_ws::Session, stream: actix_ws::MessageStream) {
+    let mut stream = stream.max_frame_size(MAX_WS_FRAME_BYTES);
+    let mut events = PUSH_EVENTS.subscribe();
+    // Users this connection registered locations for, whose events it is pushed
+    let followed = Arc::new(std::sync::Mutex::new(std::collections::HashSet::new()));
+
+    loop {
+        tokio::select! {
+            message = stream.recv() => {
+                match message {
+                    Some(Ok(actix_ws::Message::Text(text))) => {
+                        // Each command runs on its own task so a slow one does not hold up the rest
+                        let (enarx_process, session, followed) = (enarx_process.clone(), session.clone(), followed.clone());
+                        let request_id = new_request_id();
+                        let span = tracing::info_span!("ws_command", request_id = %request_id);
+                        actix_web::rt::spawn(TENANT_ID.scope(tenant.clone(), REQUEST_ID.scope(request_id.clone(), async move {
+                            let mut reply = ws_command(&enarx_process, &text, &followed).await;
+                            reply["request_id"] = serde_json::Value::String(request_id);
+                            let _ = session.clone().text(reply.to_string()).await;
+                        })).instrument(span));
+                    },
+                    Some(Ok(actix_ws::Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            break;
+                        }
+                    },
+                    Some(Ok(actix_ws::Message::Close(_))) | None => break,
+                    Some(Ok(_)) => {},
+                    Some(Err(e)) => {
+                        error!(error = %e, "WebSocket protocol error");
+                        break;
+                    },
+                }
+            },
+            event = events.recv() => {
+                match event {
+                    Ok(event) => {
+                        let same_tenant = event.tenant.as_deref().unwrap_or(DEFAULT_TENANT) == tenant.as_deref().unwrap_or(DEFAULT_TENANT);
+                        if same_tenant && followed.lock().unwrap().contains(&event.user_id) {
+                            let text = serde_json::to_string(&event).expect("push events serialize");
+                            if session.text(text).await.is_err() {
+                                break;
+                            }
+                        }
+                    },
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "WebSocket client fell behind; events were dropped");
+                    },
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            },
         }
     }
+    let _ = session.close(None).await;
+    info!("WebSocket client disconnected");
+}
+
+// Largest WebSocket frame a client may send
+const MAX_WS_FRAME_BYTES: usize = 64 * 1024;
+
+// Run one WebSocket command and build its reply
+async fn ws_command(enarx_process: &EnarxProcess, text: &str, followed: &std::sync::Mutex<std::collections::HashSet<String>>) -> serde_json::Value {
+    let command = match serde_json::from_str::<WsCommand>(text) {
+        Ok(command) => command,
+        Err(e) => {
+            let id = serde_json::from_str::<serde_json::Value>(text).ok()
+                .and_then(|value| value.get("id").cloned())
+                .unwrap_or_default();
+            return serde_json::json!({"id": id, "success": false, "errors": [field_error("body", e.to_string())]});
+        },
+    };
+    let errors = command.request.validation_errors();
+    if !errors.is_empty() {
+        return serde_json::json!({"id": command.id, "success": false, "errors": errors});
+    }
+
+    match enarx_process.send_command(command.request.tee_command().to_string()).await {
+        Ok(output) => match serde_json::from_str::<serde_json::Value>(&output) {
+            Ok(response) => {
+                match &command.request {
+                    BatchItem::RegisterLocation(req) => {
+                        followed.lock().unwrap().insert(req.user_id.clone());
+                    },
+                    BatchItem::ClaimReward(_) => {
+                        if let Some(user_id) = rewarded_user(&response) {
+                            followed.lock().unwrap().insert(user_id.to_string());
+                        }
+                        publish_reward(&response);
+                    },
+                    _ => {},
+                }
+                let success = response_succeeded(&response);
+                serde_json::json!({"id": command.id, "success": success, "response": response})
+            },
+            Err(e) => serde_json::json!({"id": command.id, "success": false, "message": format!("Failed to parse TEE response: {}", e)}),
+        },
+        Err(e) => serde_json::json!({"id": command.id, "success": false, "message": format!("Failed to communicate with Enarx: {}", e)}),
+    }
+}
+
+// Every TEE response is a single-variant object; its body says whether the command succeeded
+fn response_succeeded(response: &serde_json::Value) -> bool {
+    response.as_object()
+        .and_then(|variant| variant.values().next())
+        .map(|body| body.get("success").and_then(|v| v.as_bool()).unwrap_or(true))
+        .unwrap_or(false)
+}
+
+// Forward several sub-requests as one Batch command, so a client syncing a burst of activity
+// pays for a single round trip to the TEE
+async fn batch(
+    enarx_process: web::Data<Arc<EnarxProcess>>,
+    req: web::Json<Vec<BatchItem>>
+) -> Result<HttpResponse, Error> {
+    info!(items = req.len(), "batch");
+    if let Some(response) = validation_response(&req.0) {
+        return Ok(response);
+    }
+
+    let command = serde_json::json!({
+        "Batch": req.iter().map(BatchItem::tee_command).collect::<Vec<_>>()
+    });
+    match enarx_process.send_command(command.to_string()).await {
+        Ok(output) => {
+            let responses = serde_json::from_str::<serde_json::Value>(&output)
+                .ok()
+                .and_then(|response| response.get("Batch").and_then(|batch| batch.as_array()).cloned())
+                .filter(|responses| responses.len() == req.len());
+            let Some(responses) = responses else {
+                error!(response = %output, "unexpected response format from the TEE");
+                return Ok(HttpResponse::InternalServerError().json(ApiResponse {
+                    success: false,
+                    message: "Unexpected response format from TEE".to_string(),
+                }));
+            };
+
+            let results: Vec<BatchItemResult> = req.iter().zip(responses).map(|(item, response)| {
+                if let BatchItem::ClaimReward(_) = item {
+                    publish_reward(&response);
+                }
+                BatchItemResult { success: response_succeeded(&response), response }
+            }).collect();
+            info!(succeeded = results.iter().filter(|result| result.success).count(), items = results.len(), "batch answered");
+            Ok(HttpResponse::Ok().json(BatchResponse {
+                success: results.iter().all(|result| result.success),
+                results,
+            }))
+        },
+        Err(e) => Ok(tee_error_response(&e)),
+    }
 }
 
 async fn get_visit_analytics(
     enarx_process: web::Data<Arc<EnarxProcess>>, 
+    cache: web::Data<Arc<ResponseCache>>,
+    http_req: actix_web::HttpRequest,
     req: web::Json<VisitAnalyticsRequest>
 ) -> Result<HttpResponse, Error> {
-    info!("📥 Received visit analytics request for location: [{}, {}]", req.lat, req.lon);
+    info!(lat = req.lat, lon = req.lon, "visit analytics");
+    if let Some(response) = validation_response(&req.0) {
+        return Ok(response);
+    }
+    let cache_key = format!(
+        "{}/visits:{:.6},{:.6}:{}:{}",
+        current_tenant().unwrap_or_default(),
+        req.lat,
+        req.lon,
+        req.start_time.as_deref().unwrap_or_default(),
+        req.end_time.as_deref().unwrap_or_default()
+    );
+    if let Some(cached) = cache.get(&cache_key).await {
+        info!("visit analytics served from cache");
+        return Ok(cache.respond(&http_req, &cached));
+    }
     
     // Ensure process is running
-    info!("🔄 Starting/checking Enarx process");
     if let Err(e) = enarx_process.start_process().await {
-        error!("❌ Failed to start Enarx process: {}", e);
+        error!(error = %e, "failed to start the TEE");
         return Ok(HttpResponse::InternalServerError().json(ApiResponse {
             success: false,
             message: format!("Failed to start Enarx process: {}", e),
@@ -1009,128 +1923,407 @@ async fn get_visit_analytics(
     }
     
     // Prepare command
-    info!("🔧 Preparing visit analytics command");
-    let command = serde_json::json!({
-        "GetVisitAnalytics": {
-            "lat": req.lat,
-            "lon": req.lon
-        }
-    });
+    let command = req.tee_command();
     
     // Send command to process
-    info!("📤 Sending visit analytics command to Enarx process");
     match enarx_process.send_command(command.to_string()).await {
         Ok(output) => {
-            info!("📩 Received TEE response: {}", output);
+            debug!(response = %output, "TEE responded");
             
             // Parse the response
-            info!("🔍 Parsing TEE response");
             match serde_json::from_str::<serde_json::Value>(&output) {
                 Ok(response) => {
                     if let Some(analytics) = response.get("VisitAnalytics") {
-                        if let (
-                            Some(_location),
-                            Some(visits_24h),
-                            Some(unique_visitors_24h),
-                            Some(peak_hour)
-                        ) = (
-                            analytics.get("location"),
-                            analytics.get("visits_24h").and_then(|v| v.as_u64()),
-                            analytics.get("unique_visitors_24h").and_then(|v| v.as_u64()),
-                            analytics.get("peak_hour").and_then(|v| v.as_u64())
-                        ) {
+                        if let Ok(analytics) = serde_json::from_value::<TeeVisitAnalytics>(analytics.clone()) {
+                            info!(
+                                visits = analytics.visits,
+                                unique_visitors = analytics.unique_visitors,
+                                peak_hour = analytics.peak_hour,
+                                "visit analytics generated"
+                            );
                             let response = VisitAnalyticsResponse {
                                 lat: req.lat,
                                 lon: req.lon,
-                                visits_24h: visits_24h as u32,
-                                unique_visitors_24h: unique_visitors_24h as u32,
-                                peak_hour: peak_hour as u32,
+                                analytics,
                                 success: true,
                                 message: "Visit analytics generated successfully".to_string(),
                             };
                             
-                            info!("✅ Visit analytics generated: visits_24h={}, unique_visitors_24h={}, peak_hour={}", 
-                                visits_24h, unique_visitors_24h, peak_hour);
-                            return Ok(HttpResponse::Ok().json(response));
+                            let cached = cache.insert(cache_key, &response).await;
+                            Ok(cache.respond(&http_req, &cached))
                         } else {
-                            error!("❌ Incomplete analytics data in TEE response: {}", output);
-                            return Ok(HttpResponse::InternalServerError().json(ApiResponse {
+                            error!(response = %output, "incomplete analytics data in the TEE response");
+                            Ok(HttpResponse::InternalServerError().json(ApiResponse {
                                 success: false,
                                 message: "Incomplete analytics data in TEE response".to_string(),
-                            }));
+                            }))
                         }
+                    } else if let Some(message) = response.get("Message").and_then(|m| m.get("message")).and_then(|m| m.as_str()) {
+                        // The TEE refused the request, e.g. a range that starts in the future
+                        warn!(message, "visit analytics rejected");
+                        Ok(HttpResponse::BadRequest().json(ApiResponse {
+                            success: false,
+                            message: message.to_string(),
+                        }))
                     } else {
-                        error!("❌ Unexpected response format from TEE: {}", output);
-                        return Ok(HttpResponse::InternalServerError().json(ApiResponse {
+                        error!(response = %output, "unexpected response format from the TEE");
+                        Ok(HttpResponse::InternalServerError().json(ApiResponse {
                             success: false,
                             message: "Unexpected response format from TEE".to_string(),
-                        }));
+                        }))
                     }
                 },
                 Err(e) => {
-                    error!("❌ Failed to parse TEE response: {} - Raw output: {}", e, output);
-                    return Ok(HttpResponse::InternalServerError().json(ApiResponse {
+                    error!(error = %e, response = %output, "failed to parse the TEE response");
+                    Ok(HttpResponse::InternalServerError().json(ApiResponse {
                         success: false,
                         message: format!("Failed to parse TEE response: {}", e),
-                    }));
+                    }))
                 }
             }
         },
-        Err(e) => {
-            error!("❌ Failed to communicate with Enarx: {}", e);
-            return Ok(HttpResponse::InternalServerError().json(ApiResponse {
-                success: false,
-                message: format!("Failed to communicate with Enarx: {}", e),
-            }));
+        Err(e) => Ok(tee_error_response(&e)),
+    }
+}
+
+async fn register_geofence(
+    enarx_process: web::Data<Arc<EnarxProcess>>,
+    req: web::Json<GeofenceRequest>
+) -> Result<HttpResponse, Error> {
+    info!(id = %req.id, "geofence registration");
+    if let Some(response) = validation_response(&req.0) {
+        return Ok(response);
+    }
+    if let Err(e) = enarx_process.start_process().await {
+        error!(error = %e, "failed to start the TEE");
+        return Ok(HttpResponse::InternalServerError().json(ApiResponse {
+            success: false,
+            message: format!("Failed to start Enarx process: {}", e),
+        }));
+    }
+
+    match enarx_process.send_command(req.tee_command().to_string()).await {
+        Ok(output) => {
+            let mut response: serde_json::Value = serde_json::from_str(&output).unwrap_or_default();
+            let success = response["GeofenceRegistered"]["success"].as_bool().unwrap_or(false);
+            if !success {
+                warn!(response = %output, "geofence registration rejected");
+                return Ok(HttpResponse::BadRequest().json(ApiResponse {
+                    success: false,
+                    message: response["GeofenceRegistered"]["message"].as_str().unwrap_or("Unexpected response format from TEE").to_string(),
+                }));
+            }
+            Ok(HttpResponse::Ok().json(serde_json::json!({
+                "success": true,
+                "message": "Geofence registered",
+                "geofence": response["GeofenceRegistered"]["geofence"].take(),
+            })))
+        },
+        Err(e) => Ok(tee_error_response(&e)),
+    }
+}
+
+async fn claim_reward(
+    enarx_process: web::Data<Arc<EnarxProcess>>,
+    req: web::Json<RewardClaimRequest>
+) -> Result<HttpResponse, Error> {
+    info!(encrypted_location_id = %req.encrypted_location_id, "reward claim");
+    if let Some(response) = validation_response(&req.0) {
+        return Ok(response);
+    }
+    if let Err(e) = enarx_process.start_process().await {
+        error!(error = %e, "failed to start the TEE");
+        return Ok(HttpResponse::InternalServerError().json(ApiResponse {
+            success: false,
+            message: format!("Failed to start Enarx process: {}", e),
+        }));
+    }
+    match enarx_process.send_command(req.tee_command().to_string()).await {
+        Ok(output) => {
+            let response: serde_json::Value = serde_json::from_str(&output).unwrap_or_default();
+            publish_reward(&response);
+            match response.get("RewardClaimed") {
+                Some(claimed) if claimed["success"].as_bool() == Some(true) => Ok(HttpResponse::Ok().json(claimed)),
+                Some(claimed) => {
+                    warn!(message = claimed["message"].as_str().unwrap_or_default(), "reward claim rejected");
+                    Ok(HttpResponse::BadRequest().json(claimed))
+                },
+                None => {
+                    error!(response = %output, "unexpected response format from the TEE");
+                    Ok(HttpResponse::InternalServerError().json(ApiResponse {
+                        success: false,
+                        message: "Unexpected response format from TEE".to_string(),
+                    }))
+                },
+            }
+        },
+        Err(e) => Ok(tee_error_response(&e)),
+    }
+}
+
+async fn list_geofences(enarx_process: web::Data<Arc<EnarxProcess>>) -> Result<HttpResponse, Error> {
+    if let Err(e) = enarx_process.start_process().await {
+        error!(error = %e, "failed to start the TEE");
+        return Ok(HttpResponse::InternalServerError().json(ApiResponse {
+            success: false,
+            message: format!("Failed to start Enarx process: {}", e),
+        }));
+    }
+    match enarx_process.send_command(serde_json::json!({ "ListGeofences": null }).to_string()).await {
+        Ok(output) => {
+            let response: serde_json::Value = serde_json::from_str(&output).unwrap_or_default();
+            match response.get("Geofences") {
+                Some(geofences) => Ok(HttpResponse::Ok().json(serde_json::json!({
+                    "success": true,
+                    "geofences": geofences,
+                }))),
+                None => {
+                    error!(response = %output, "unexpected response format from the TEE");
+                    Ok(HttpResponse::InternalServerError().json(ApiResponse {
+                        success: false,
+                        message: "Unexpected response format from TEE".to_string(),
+                    }))
+                },
+            }
+        },
+        Err(e) => Ok(tee_error_response(&e)),
+    }
+}
+
+// Upper bounds (seconds) of the request latency histogram buckets
+const LATENCY_BUCKETS: [f64; 11] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+// Requests by method, route pattern and status, and latency histograms by method and route
+// (cumulative bucket counts, count, sum of seconds)
+type RequestCounts = BTreeMap<(String, String, u16), u64>;
+type LatencyHistograms = BTreeMap<(String, String), ([u64; LATENCY_BUCKETS.len()], u64, f64)>;
+static REQUEST_COUNTS: std::sync::Mutex<RequestCounts> = std::sync::Mutex::new(BTreeMap::new());
+static REQUEST_LATENCIES: std::sync::Mutex<LatencyHistograms> = std::sync::Mutex::new(BTreeMap::new());
+
+fn record_request(method: &str, route: &str, status: u16, elapsed: Duration) {
+    let key = (method.to_string(), route.to_string());
+    *REQUEST_COUNTS.lock().unwrap().entry((key.0.clone(), key.1.clone(), status)).or_default() += 1;
+    let mut latencies = REQUEST_LATENCIES.lock().unwrap();
+    let (buckets, count, sum) = latencies.entry(key).or_insert(([0; LATENCY_BUCKETS.len()], 0, 0.0));
+    let seconds = elapsed.as_secs_f64();
+    for (bucket, bound) in buckets.iter_mut().zip(LATENCY_BUCKETS) {
+        if seconds <= bound {
+            *bucket += 1;
+        }
+    }
+    *count += 1;
+    *sum += seconds;
+}
+
+// Prometheus metrics: HTTP traffic, the load and health of every enclave process, and the
+// contents each healthy enclave reports to GetStats
+async fn metrics(enarx_process: web::Data<Arc<EnarxProcess>>, cache: web::Data<Arc<ResponseCache>>) -> impl Responder {
+    let mut out = String::new();
+    out.push_str("# HELP tee_http_requests_total HTTP requests by method, route and status.\n");
+    out.push_str("# TYPE tee_http_requests_total counter\n");
+    for ((method, route, status), count) in REQUEST_COUNTS.lock().unwrap().iter() {
+        let _ = writeln!(out, "tee_http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}", method, route, status, count);
+    }
+    out.push_str("# HELP tee_http_request_duration_seconds HTTP request latency by method and route.\n");
+    out.push_str("# TYPE tee_http_request_duration_seconds histogram\n");
+    for ((method, route), (buckets, count, sum)) in REQUEST_LATENCIES.lock().unwrap().iter() {
+        let labels = format!("method=\"{}\",route=\"{}\"", method, route);
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(buckets) {
+            let _ = writeln!(out, "tee_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, bucket);
+        }
+        let _ = writeln!(out, "tee_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, count);
+        let _ = writeln!(out, "tee_http_request_duration_seconds_sum{{{}}} {}", labels, sum);
+        let _ = writeln!(out, "tee_http_request_duration_seconds_count{{{}}} {}", labels, count);
+    }
+
+    let circuit = enarx_process.breaker.lock().unwrap().name();
+    out.push_str("# HELP tee_circuit_breaker_state State of the circuit breaker in front of the TEE pool.\n");
+    out.push_str("# TYPE tee_circuit_breaker_state gauge\n");
+    for state in ["closed", "open", "half-open"] {
+        let _ = writeln!(out, "tee_circuit_breaker_state{{state=\"{}\"}} {}", state, (state == circuit) as u8);
+    }
+
+    out.push_str("# HELP tee_response_cache_lookups_total Heatmap and analytics cache lookups by result.\n");
+    out.push_str("# TYPE tee_response_cache_lookups_total counter\n");
+    let _ = writeln!(out, "tee_response_cache_lookups_total{{result=\"hit\"}} {}", cache.hits.load(Ordering::Relaxed));
+    let _ = writeln!(out, "tee_response_cache_lookups_total{{result=\"miss\"}} {}", cache.misses.load(Ordering::Relaxed));
+
+    let pool = &enarx_process.pool;
+    let process_metrics: [(&str, &str, &str, Vec<u64>); 5] = [
+        ("tee_process_healthy", "gauge", "Whether the process answered its last health check.", pool.iter().map(|tee| tee.healthy.load(Ordering::Relaxed) as u64).collect()),
+        ("tee_process_in_flight", "gauge", "Commands queued for the process and not answered yet.", pool.iter().map(|tee| tee.in_flight.load(Ordering::Relaxed) as u64).collect()),
+        ("tee_process_restarts_total", "counter", "Times the process was restarted.", pool.iter().map(|tee| tee.restarts.load(Ordering::Relaxed) as u64).collect()),
+        ("tee_commands_answered_total", "counter", "Commands the process answered.", pool.iter().map(|tee| tee.answered.load(Ordering::Relaxed)).collect()),
+        ("tee_commands_timed_out_total", "counter", "Commands the process did not answer in time.", pool.iter().map(|tee| tee.timed_out.load(Ordering::Relaxed)).collect()),
+    ];
+    for (name, kind, help, values) in process_metrics {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (slot, value) in values.iter().enumerate() {
+            let _ = writeln!(out, "{}{{slot=\"{}\"}} {}", name, slot, value);
         }
     }
+    
+    // What each healthy enclave reports about its contents
+    let mut stats = Vec::new();
+    for (slot, tee) in pool.iter().enumerate() {
+        if !tee.healthy.load(Ordering::Relaxed) {
+            continue;
+        }
+        match enarx_process.send_command_to(slot, serde_json::json!({ "GetStats": null })).await {
+            Ok(response) => {
+                let response: serde_json::Value = serde_json::from_str(&response).unwrap_or_default();
+                if let Some(enclave_stats) = response.get("Stats") {
+                    stats.push((slot, enclave_stats.clone()));
+                }
+            },
+            Err(e) => warn!(slot, error = %e, "TEE did not report its stats"),
+        }
+    }
+    out.push_str("# HELP tee_enclave_uptime_seconds Seconds since the enclave started.\n");
+    out.push_str("# TYPE tee_enclave_uptime_seconds gauge\n");
+    for (slot, enclave_stats) in &stats {
+        let _ = writeln!(out, "tee_enclave_uptime_seconds{{slot=\"{}\"}} {}", slot, enclave_stats["uptime_seconds"].as_u64().unwrap_or_default());
+    }
+    out.push_str("# HELP tee_enclave_records Records held by the enclave by store.\n");
+    out.push_str("# TYPE tee_enclave_records gauge\n");
+    for (slot, enclave_stats) in &stats {
+        for store in ["users", "locations", "heatmap_cells", "visit_cells", "known_stations", "reward_accounts"] {
+            let _ = writeln!(out, "tee_enclave_records{{slot=\"{}\",store=\"{}\"}} {}", slot, store, enclave_stats[store].as_u64().unwrap_or_default());
+        }
+    }
+    // The nested counters are labelled by their keys, so new ones show up without changes here
+    let labelled: [(&str, &str, &str, &str, &str); 3] = [
+        ("tee_enclave_cache_entries", "gauge", "Entries of the enclave's internal caches.", "caches", "cache"),
+        ("tee_enclave_rejected_registrations_total", "counter", "Registrations the enclave refused, by reason.", "rejected_registrations", "reason"),
+        ("tee_enclave_evictions_total", "counter", "Entries evicted from the enclave's bounded stores.", "evicted", "store"),
+    ];
+    for (name, kind, help, field, label) in labelled {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (slot, enclave_stats) in &stats {
+            let Some(counts) = enclave_stats[field].as_object() else { continue };
+            for (key, value) in counts {
+                let _ = writeln!(out, "{}{{slot=\"{}\",{}=\"{}\"}} {}", name, slot, label, key, value.as_u64().unwrap_or_default());
+            }
+        }
+    }
+    
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(out)
 }
 
-async fn health_check() -> impl Responder {
-    info!("Received health check request");
-    HttpResponse::Ok().json(ApiResponse {
+// Readiness: whether any enclave answered its last health check
+// The public key and versions of every responsive enclave, so clients need not read them off
+// the startup banner
+async fn tee_info(enarx_process: web::Data<Arc<EnarxProcess>>) -> impl Responder {
+    let mut enclaves = Vec::new();
+    for (slot, tee) in enarx_process.pool.iter().enumerate() {
+        if !tee.healthy.load(Ordering::Relaxed) {
+            continue;
+        }
+        let command = serde_json::json!({ "Batch": [{ "GetPublicKey": null }, { "GetInfo": null }] });
+        let response = match enarx_process.send_command_to(slot, command).await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!(slot, error = %e, "TEE did not report its info");
+                continue;
+            },
+        };
+        let response: serde_json::Value = serde_json::from_str(&response).unwrap_or_default();
+        let keys = &response["Batch"][0]["PublicKey"];
+        let info = &response["Batch"][1]["Info"];
+        let public_keys = (keys["public_key"].as_str(), keys["reward_public_key"].as_str(), keys["reward_context"].as_str());
+        if let ((Some(public_key), Some(reward_public_key), Some(reward_context)), Some(build_version), Some(uptime_seconds)) = (public_keys, info["build_version"].as_str(), info["uptime_seconds"].as_u64()) {
+            enclaves.push(EnclaveInfo {
+                slot,
+                public_key: public_key.to_string(),
+                reward_public_key: reward_public_key.to_string(),
+                reward_context: reward_context.to_string(),
+                build_version: build_version.to_string(),
+                uptime_seconds,
+            });
+        } else {
+            error!(slot, %response, "unexpected response format from the TEE");
+        }
+    }
+    
+    if enclaves.is_empty() {
+        return HttpResponse::ServiceUnavailable().json(ApiResponse {
+            success: false,
+            message: "No TEE process is ready yet".to_string(),
+        });
+    }
+    HttpResponse::Ok().json(TeeInfoResponse {
         success: true,
-        message: "TEE Location Services API is running".to_string(),
+        protocol_version: TEE_PROTOCOL_VERSION,
+        enclaves,
     })
 }
 
+async fn health_check(enarx_process: web::Data<Arc<EnarxProcess>>) -> impl Responder {
+    debug!("health check");
+    if enarx_process.is_ready() {
+        HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            message: "TEE Location Services API is running".to_string(),
+        })
+    } else {
+        HttpResponse::ServiceUnavailable().json(ApiResponse {
+            success: false,
+            message: "No TEE process is ready yet".to_string(),
+        })
+    }
+}
+
 // New debug endpoint to check Enarx process status
 async fn debug_enarx_status(enarx_process: web::Data<Arc<EnarxProcess>>) -> Result<HttpResponse, Error> {
-    info!("📥 Received debug status request");
     
-    // Check if Enarx process is running
-    let status = {
-        let child_lock = enarx_process.child.lock().unwrap();
-        if child_lock.is_some() {
-            "running"
+    // Liveness of each process of the pool, as of the supervisor's last check
+    let mut processes = Vec::new();
+    for (slot, tee) in enarx_process.pool.iter().enumerate() {
+        let running = tee.process.lock().await.as_mut().is_some_and(TeeProcess::is_running);
+        let tee_status = match (running, tee.healthy.load(Ordering::Relaxed)) {
+            (true, true) => "responsive",
+            (true, false) => "unresponsive",
+            (false, _) => "unknown",
+        };
+        // What a responsive enclave reports about its contents
+        let info = if tee_status == "responsive" {
+            enarx_process.send_command_to(slot, serde_json::json!({ "GetInfo": null })).await.ok()
+                .and_then(|response| serde_json::from_str::<serde_json::Value>(&response).ok())
+                .and_then(|response| response.get("Info").cloned())
         } else {
-            "not running"
-        }
-    };
-    
-    // Try to send a simple command to check TEE responsiveness
-    let tee_status = if status == "running" {
-        info!("🔍 Testing TEE responsiveness with Help command");
-        match enarx_process.send_command(r#"{"Help": null}"#.to_string()).await {
-            Ok(_output) => {
-                info!("✅ TEE responded to Help command");
-                "responsive"
-            },
-            Err(e) => {
-                error!("❌ TEE failed to respond to Help command: {}", e);
-                "unresponsive"
-            }
-        }
+            None
+        };
+        processes.push(serde_json::json!({
+            "slot": slot,
+            "enarx_process": if running { "running" } else { "not running" },
+            "tee_status": tee_status,
+            "in_flight": tee.in_flight.load(Ordering::Relaxed),
+            "consecutive_failures": tee.consecutive_failures.load(Ordering::Relaxed),
+            "restarts": tee.restarts.load(Ordering::Relaxed),
+            "info": info,
+        }));
+    }
+    let status = if processes.iter().any(|process| process["enarx_process"] == "running") { "running" } else { "not running" };
+    let tee_status = if processes.iter().any(|process| process["tee_status"] == "responsive") {
+        "responsive"
+    } else if status == "running" {
+        "unresponsive"
     } else {
         "unknown"
     };
     
     // Return debug information
-    info!("📊 Enarx status: {}, TEE status: {}", status, tee_status);
+    info!(status, tee_status, "debug status");
     Ok(HttpResponse::Ok().json(serde_json::json!({
         "success": true,
         "enarx_process": status,
         "tee_status": tee_status,
+        "circuit_breaker": enarx_process.breaker.lock().unwrap().name(),
+        "processes": processes,
         "timestamp": std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
@@ -1143,12 +2336,11 @@ async fn debug_send_command(
     enarx_process: web::Data<Arc<EnarxProcess>>,
     req: web::Json<serde_json::Value>
 ) -> Result<HttpResponse, Error> {
-    info!("📥 Received debug command request: {}", serde_json::to_string(&req.0).unwrap_or_default());
+    info!(command = %serde_json::to_string(&req.0).unwrap_or_default(), "debug command");
     
     // Ensure process is running
-    info!("🔄 Starting/checking Enarx process");
     if let Err(e) = enarx_process.start_process().await {
-        error!("❌ Failed to start Enarx process: {}", e);
+        error!(error = %e, "failed to start the TEE");
         return Ok(HttpResponse::InternalServerError().json(ApiResponse {
             success: false,
             message: format!("Failed to start Enarx process: {}", e),
@@ -1157,11 +2349,10 @@ async fn debug_send_command(
     
     // Send raw command to TEE
     let command = serde_json::to_string(&req.0).unwrap_or_default();
-    info!("📤 Sending debug command to TEE: {}", command);
     
     match enarx_process.send_command(command).await {
         Ok(output) => {
-            info!("📩 Received TEE response: {}", output);
+            debug!(response = %output, "TEE responded");
             
             // Try to parse as JSON for nice formatting
             match serde_json::from_str::<serde_json::Value>(&output) {
@@ -1181,25 +2372,38 @@ async fn debug_send_command(
                 }
             }
         },
-        Err(e) => {
-            error!("❌ Failed to communicate with Enarx: {}", e);
-            Ok(HttpResponse::InternalServerError().json(ApiResponse {
-                success: false,
-                message: format!("Failed to communicate with Enarx: {}", e),
-            }))
-        }
+        Err(e) => Ok(tee_error_response(&e)),
     }
 }
 
 #[actix_web::main]
 async fn main() -> io::Result<()> {
-    // Set default log level to debug to get more detailed logs
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("debug"));
+    // Default log level is debug for more detailed logs (RUST_LOG overrides it); LOG_FORMAT=json
+    // writes one JSON object per event, with the request span's fields, for log pipelines
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("debug"));
+    if std::env::var("LOG_FORMAT").as_deref() == Ok("json") {
+        tracing_subscriber::fmt().json().with_env_filter(filter).init();
+    } else {
+        let ansi = std::io::IsTerminal::is_terminal(&io::stdout());
+        tracing_subscriber::fmt().with_env_filter(filter).with_ansi(ansi).init();
+    }
     
-    info!("Starting TEE Location Services API at http://0.0.0.0:8080");
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            error!(error = %e, "invalid configuration");
+            std::process::exit(1);
+        }
+    };
+    let bind_addr = config.bind_addr.clone();
+    info!(%bind_addr, "starting TEE Location Services API");
     
-    // Create shared Enarx process instance
-    let enarx_process = Arc::new(EnarxProcess::new());
+    // Create the shared pool of Enarx processes
+    info!(pool_size = config.pool_size, wasm_path = %config.wasm_path, "serving commands from the TEE pool");
+    let response_cache = Arc::new(ResponseCache::new(&config));
+    let enarx_process = Arc::new(EnarxProcess::new(config));
+    actix_web::rt::spawn(supervise(enarx_process.clone()));
     
     HttpServer::new(move || {
         let cors = Cors::default()
@@ -1208,18 +2412,58 @@ async fn main() -> io::Result<()> {
             .allow_any_header();
             
         App::new()
+            // Resolve the tenant of the request's API key; inside CORS, so preflight requests
+            // need no key
+            .wrap_fn(|req, srv| {
+                match authenticate(&req) {
+                    Ok(tenant) => Either::Left(TENANT_ID.scope(tenant, srv.call(req))
+                        .map(|response| response.map(actix_web::dev::ServiceResponse::map_into_boxed_body))),
+                    Err(message) => {
+                        let response = HttpResponse::Unauthorized().json(ApiResponse { success: false, message });
+                        Either::Right(futures::future::ready(Ok(req.into_response(response))))
+                    },
+                }
+            })
             .wrap(cors)
+            // Run every request in a span carrying its request ID, which is returned in the
+            // X-Request-Id header, and count it under its route pattern
+            .wrap_fn(|req, srv| {
+                let started = Instant::now();
+                let request_id = request_id(&req);
+                let span = tracing::info_span!("request", request_id = %request_id, method = %req.method(), path = %req.path());
+                let response = REQUEST_ID.scope(request_id.clone(), srv.call(req)).instrument(span.clone());
+                async move {
+                    let mut response = response.await?;
+                    let route = response.request().match_pattern().unwrap_or_else(|| "unmatched".to_string());
+                    let status = response.status().as_u16();
+                    record_request(response.request().method().as_str(), &route, status, started.elapsed());
+                    span.in_scope(|| info!(status, elapsed_ms = started.elapsed().as_millis() as u64, "request completed"));
+                    if let Ok(value) = actix_web::http::header::HeaderValue::from_str(&request_id) {
+                        response.headers_mut().insert(actix_web::http::header::HeaderName::from_static("x-request-id"), value);
+                    }
+                    Ok(response)
+                }
+            })
             .app_data(web::Data::new(enarx_process.clone()))
+            .app_data(web::Data::new(response_cache.clone()))
+            .app_data(web::JsonConfig::default().error_handler(json_error))
             .route("/health", web::get().to(health_check))
+            .route("/metrics", web::get().to(metrics))
             .route("/api/location/register", web::post().to(register_location))
+            .route("/api/batch", web::post().to(batch))
+            .route("/ws", web::get().to(ws))
             .route("/api/location/get", web::post().to(get_location))
             .route("/api/heatmap", web::post().to(generate_heatmap))
             .route("/api/analytics/visits", web::post().to(get_visit_analytics))
+            .route("/api/geofences", web::post().to(register_geofence))
+            .route("/api/geofences", web::get().to(list_geofences))
+            .route("/api/rewards/claim", web::post().to(claim_reward))
             // Add debug endpoints
+            .route("/api/tee-info", web::get().to(tee_info))
             .route("/debug/status", web::get().to(debug_enarx_status))
             .route("/debug/command", web::post().to(debug_send_command))
     })
-    .bind(("0.0.0.0", 8080))?
+    .bind(bind_addr)?
     .run()
     .await
 } 
\ No newline at end of file
@@ -70,8 +70,8 @@ interface Result {
   lat?: number;
   lon?: number;
   timestamp?: number;
-  visits_24h?: number;
-  unique_visitors_24h?: number;
+  visits?: number;
+  unique_visitors?: number;
   peak_hour?: number;
   grid_cells?: HeatmapCell[];
   max_value?: number;
@@ -233,7 +233,8 @@ export function TEEZone({ onClose }: TEEZoneProps) {
           accelerometer: registrationForm.accelerometer,
           gyroscope: registrationForm.gyroscope,
           is_mock_location: registrationForm.is_mock_location,
-          timestamp: new Date().toISOString()
+          timestamp: new Date().toISOString(),
+          nonce: Date.now()
         }),
       });
 
@@ -631,13 +632,13 @@ export function TEEZone({ onClose }: TEEZoneProps) {
         )}
         
         {/* Visit Analytics Result */}
-        {result.visits_24h !== undefined && (
+        {result.visits !== undefined && (
           <>
             <div className="result-detail">
-              <strong>{t('teeZone.visits24h')}:</strong> {result.visits_24h}
+              <strong>{t('teeZone.visits24h')}:</strong> {result.visits}
             </div>
             <div className="result-detail">
-              <strong>{t('teeZone.uniqueVisitors24h')}:</strong> {result.unique_visitors_24h}
+              <strong>{t('teeZone.uniqueVisitors24h')}:</strong> {result.unique_visitors}
             </div>
             <div className="result-detail">
               <strong>{t('teeZone.peakHour')}:</strong> {result.peak_hour}:00
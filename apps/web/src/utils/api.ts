@@ -128,6 +128,9 @@ export async function teeRegisterLocation(formData: {
   device_id: string;
   wifi_networks: Array<{ ssid: string; bssid: string; signal_strength: number }>;
   cell_towers: Array<{ cell_id: string; signal_strength: number }>;
+  ble_beacons?: Array<{ uuid: string; major: number; minor: number; rssi: number }>;
+  pressure_hpa?: number;
+  floor_level?: number;
   accelerometer: [number, number, number] | null;
   gyroscope: [number, number, number] | null;
   is_mock_location: boolean;
@@ -138,7 +141,7 @@ export async function teeRegisterLocation(formData: {
       headers: {
         'Content-Type': 'application/json',
       },
-      body: JSON.stringify(formData),
+      body: JSON.stringify({ ...formData, nonce: Date.now() }),
     });
 
     if (!response.ok) {